@@ -0,0 +1,476 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use prost::Message;
+use protos::log::v1::log_client::LogClient;
+use protos::log::v1::{
+    ConsumeRequest, GetServersRequest, InitProducerRequest, NotLeader, ProduceBatchRequest,
+    ProduceRequest, Record, Server,
+};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status};
+
+/// Settings of a [`Client`].
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Addresses of servers to discover the cluster through.
+    pub bootstrap: Vec<String>,
+    /// How many times a request is retried on another server, or after the
+    /// leader changed.
+    pub max_retries: usize,
+    /// Wait before retrying when the leader is unknown, doubled every time.
+    pub retry_backoff: Duration,
+    /// Send appends as batches of an idempotent producer, so retrying one
+    /// the server already took does not write it twice.
+    pub idempotent: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            bootstrap: vec![],
+            max_retries: 10,
+            retry_backoff: Duration::from_millis(50),
+            idempotent: true,
+        }
+    }
+}
+
+/// Talks to a cluster of log servers as if it were one.
+///
+/// Appends go to the leader. A follower that gets one answers with the
+/// leader's address, and a leader that went away makes the client rediscover
+/// the servers through `GetServers`; either way the append is retried. An
+/// idempotent client numbers its batches, so a retry of one the leader
+/// appended before failing returns its offsets instead of writing it twice;
+/// otherwise it may be written twice. Reads go round-robin to the followers,
+/// falling back to the leader for records a follower does not have yet. A
+/// server that is not replicated is its own leader.
+pub struct Client {
+    config: ClientConfig,
+    state: Mutex<State>,
+    /// Id and next sequence of the idempotent producer, once initialized.
+    /// Held across an append and its retries, so batches go out in order.
+    producer: tokio::sync::Mutex<Option<(u64, u64)>>,
+}
+
+struct State {
+    /// Known servers, the bootstrap ones until discovery.
+    servers: Vec<Server>,
+    leader: Option<String>,
+    /// Where the next read starts among the followers.
+    next_follower: usize,
+    clients: HashMap<String, LogClient<Channel>>,
+}
+
+impl Client {
+    /// Creates a client and discovers the cluster through `config.bootstrap`.
+    pub async fn connect(config: ClientConfig) -> Result<Self> {
+        let servers = config
+            .bootstrap
+            .iter()
+            .map(|addr| Server {
+                rpc_addr: addr.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let client = Client {
+            config,
+            state: Mutex::new(State {
+                servers,
+                leader: None,
+                next_follower: 0,
+                clients: HashMap::new(),
+            }),
+            producer: tokio::sync::Mutex::new(None),
+        };
+        client.refresh().await?;
+        Ok(client)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// The servers as of the last discovery.
+    pub fn servers(&self) -> Vec<Server> {
+        self.state().servers.clone()
+    }
+
+    /// Address of the leader, if known.
+    pub fn leader(&self) -> Option<String> {
+        self.state().leader.clone()
+    }
+
+    /// Asks the known servers, then the bootstrap ones, for the current
+    /// servers and leader, taking the first answer.
+    pub async fn refresh(&self) -> Result<()> {
+        let mut addrs: Vec<String> = self
+            .state()
+            .servers
+            .iter()
+            .map(|s| s.rpc_addr.clone())
+            .collect();
+        addrs.extend(self.config.bootstrap.iter().cloned());
+        let mut seen = HashSet::new();
+        addrs.retain(|addr| seen.insert(addr.clone()));
+        let mut last_err = anyhow!("no servers to discover the cluster through");
+        for addr in addrs {
+            let res = self.client(&addr)?.get_servers(GetServersRequest {}).await;
+            match res {
+                Ok(res) => {
+                    let mut servers = res.into_inner().servers;
+                    if servers.is_empty() {
+                        servers.push(Server {
+                            rpc_addr: addr,
+                            is_leader: true,
+                            ..Default::default()
+                        });
+                    }
+                    let mut st = self.state();
+                    st.leader = servers
+                        .iter()
+                        .find(|s| s.is_leader)
+                        .map(|s| s.rpc_addr.clone());
+                    st.servers = servers;
+                    return Ok(());
+                }
+                Err(status) => {
+                    debug!("discovery through {} failed: {}", addr, status);
+                    last_err = status.into();
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn client(&self, addr: &str) -> Result<LogClient<Channel>> {
+        let mut st = self.state();
+        if let Some(client) = st.clients.get(addr) {
+            return Ok(client.clone());
+        }
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.connect_lazy();
+        let client = LogClient::new(channel);
+        st.clients.insert(addr.to_owned(), client.clone());
+        Ok(client)
+    }
+
+    /// Appends `record` and returns its offset.
+    pub async fn produce(&self, record: Record) -> Result<u64> {
+        if self.config.idempotent {
+            return Ok(self.produce_batch(vec![record]).await?.start);
+        }
+        self.on_leader(false, |mut client| {
+            let record = record.clone();
+            async move {
+                let req = ProduceRequest {
+                    record: Some(record),
+                    ..Default::default()
+                };
+                Ok(client.produce(req).await?.into_inner().offset)
+            }
+        })
+        .await
+    }
+
+    /// Appends `records` as one batch and returns their offsets.
+    pub async fn produce_batch(&self, records: Vec<Record>) -> Result<Range<u64>> {
+        if !self.config.idempotent {
+            return self.send_batch(records, 0, 0).await;
+        }
+        let mut producer = self.producer.lock().await;
+        let (id, sequence) = match *producer {
+            Some(p) => p,
+            None => (self.init_producer().await?, 0),
+        };
+        match self.send_batch(records, id, sequence).await {
+            Ok(range) => {
+                *producer = Some((id, sequence + 1));
+                Ok(range)
+            }
+            Err(e) => {
+                // the batch may still have been appended; a new producer
+                // goes on without depending on it
+                *producer = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends `records` as batch `sequence` of `producer_id`, or of no
+    /// producer if it is 0.
+    async fn send_batch(
+        &self,
+        records: Vec<Record>,
+        producer_id: u64,
+        sequence: u64,
+    ) -> Result<Range<u64>> {
+        self.on_leader(producer_id != 0, |mut client| {
+            let records = records.clone();
+            async move {
+                let res = client
+                    .produce_batch(ProduceBatchRequest {
+                        records,
+                        producer_id,
+                        sequence,
+                        ..Default::default()
+                    })
+                    .await?
+                    .into_inner();
+                Ok(res.first_offset..res.last_offset + 1)
+            }
+        })
+        .await
+    }
+
+    /// A new producer id for idempotent appends.
+    async fn init_producer(&self) -> Result<u64> {
+        self.on_leader(true, |mut client| async move {
+            Ok(client
+                .init_producer(InitProducerRequest {})
+                .await?
+                .into_inner()
+                .producer_id)
+        })
+        .await
+    }
+
+    /// Reads the record at `offset` from a follower, or from the leader if no
+    /// follower has it.
+    pub async fn consume(&self, offset: u64) -> Result<Record> {
+        let mut last_err = anyhow!("no servers known");
+        for addr in self.read_order() {
+            match self
+                .client(&addr)?
+                .consume(ConsumeRequest {
+                    offset,
+                    ..Default::default()
+                })
+                .await
+            {
+                Ok(res) => {
+                    return res
+                        .into_inner()
+                        .record
+                        .ok_or_else(|| anyhow!("{} answered without a record", addr))
+                }
+                // reads change nothing, so even a failure that may have
+                // reached the server is tried on the next one
+                Err(status)
+                    if matches!(
+                        status.code(),
+                        Code::OutOfRange | Code::Unknown | Code::Unavailable
+                    ) =>
+                {
+                    last_err = status.into();
+                }
+                Err(status) => return Err(status.into()),
+            }
+        }
+        Err(last_err)
+    }
+
+    /// The followers, starting at the next one round-robin, then the leader.
+    fn read_order(&self) -> Vec<String> {
+        let mut st = self.state();
+        let mut order: Vec<String> = st
+            .servers
+            .iter()
+            .map(|s| s.rpc_addr.clone())
+            .filter(|addr| st.leader.as_ref() != Some(addr))
+            .collect();
+        if !order.is_empty() {
+            let start = st.next_follower % order.len();
+            order.rotate_left(start);
+            st.next_follower = start + 1;
+        }
+        order.extend(st.leader.clone());
+        order
+    }
+
+    /// Runs `call` against the leader, following it when it moves. An
+    /// `idempotent` call is also retried after failures that may have
+    /// reached the server.
+    async fn on_leader<T, F, Fut>(&self, idempotent: bool, call: F) -> Result<T>
+    where
+        F: Fn(LogClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut backoff = self.config.retry_backoff;
+        let mut retries = 0;
+        loop {
+            let leader = self.leader();
+            let status = match &leader {
+                Some(addr) => match call(self.client(addr)?).await {
+                    Ok(t) => return Ok(t),
+                    // unavailable means the server was down or not the
+                    // leader, so another may take the call; a connection lost
+                    // mid-call is unknown and the call may have been applied,
+                    // so only idempotent ones are retried then
+                    Err(status)
+                        if status.code() == Code::Unavailable
+                            || (idempotent && status.code() == Code::Unknown) =>
+                    {
+                        status
+                    }
+                    Err(status) => return Err(status.into()),
+                },
+                None => Status::unavailable("no leader known"),
+            };
+            if retries == self.config.max_retries {
+                return Err(status.into());
+            }
+            retries += 1;
+            match leader_hint(&status) {
+                Some(hint) if Some(&hint) != leader.as_ref() => {
+                    debug!("retrying on the leader at {}", hint);
+                    self.state().leader = Some(hint);
+                }
+                _ => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    self.state().leader = None;
+                    if let Err(e) = self.refresh().await {
+                        debug!("rediscovering the leader failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The leader address a follower sent along with refusing an append.
+fn leader_hint(status: &Status) -> Option<String> {
+    NotLeader::decode(status.details())
+        .ok()
+        .map(|d| d.leader_addr)
+        .filter(|addr| !addr.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use log_server::{GrpcTransport, LogService, RaftConfig, RaftService, ReplicatedLog};
+    use protos::log::v1::log_server::LogServer;
+    use protos::log::v1::raft_server::RaftServer;
+    use tempfile::{tempdir, TempDir};
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport;
+
+    use super::*;
+
+    struct Node {
+        _dir: TempDir,
+        log: Arc<ReplicatedLog>,
+        stop: oneshot::Sender<()>,
+        server: JoinHandle<Result<(), transport::Error>>,
+    }
+
+    async fn cluster(n: usize) -> (Vec<SocketAddr>, Vec<Node>) {
+        let mut listeners = vec![];
+        for _ in 0..n {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let mut nodes = vec![];
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let dir = tempdir().unwrap();
+            let raft = RaftConfig {
+                id: i.to_string(),
+                peers: (0..n)
+                    .map(|j| (j.to_string(), addrs[j].to_string()))
+                    .collect(),
+                heartbeat_interval: Duration::from_millis(20),
+                election_timeout: Duration::from_millis(100),
+                ..Default::default()
+            };
+            let transport = Arc::new(GrpcTransport::default());
+            let log = Arc::new(
+                ReplicatedLog::start(dir.path(), Default::default(), raft, transport).unwrap(),
+            );
+            let (stop, stopped) = oneshot::channel();
+            let server = tokio::spawn(
+                transport::Server::builder()
+                    .add_service(LogServer::new(LogService::replicated(log.clone())))
+                    .add_service(RaftServer::new(RaftService::new(&log)))
+                    .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                        let _ = stopped.await;
+                    }),
+            );
+            nodes.push(Node {
+                _dir: dir,
+                log,
+                stop,
+                server,
+            });
+        }
+        (addrs, nodes)
+    }
+
+    async fn wait_for_leader(nodes: &[Node]) -> usize {
+        for _ in 0..500 {
+            if let Some(i) = nodes.iter().position(|n| n.log.is_leader()) {
+                return i;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no leader elected");
+    }
+
+    fn record(value: &str) -> Record {
+        Record {
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_and_fails_over() -> Result<()> {
+        let (addrs, mut nodes) = cluster(3).await;
+        let leader = wait_for_leader(&nodes).await;
+        let follower = (leader + 1) % 3;
+        let client = Client::connect(ClientConfig {
+            bootstrap: vec![addrs[follower].to_string()],
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(client.servers().len(), 3);
+        assert_eq!(client.leader(), Some(addrs[leader].to_string()));
+
+        assert_eq!(client.produce(record("a")).await?, 0);
+        let range = client.produce_batch(vec![record("b"), record("c")]).await?;
+        assert_eq!(range, 1..3);
+        for (offset, value) in ["a", "b", "c"].iter().enumerate() {
+            let record = client.consume(offset as u64).await?;
+            assert_eq!(record.value, value.as_bytes());
+        }
+
+        // the leader goes away; the next append finds the new one
+        let old = nodes.remove(leader);
+        let _ = old.stop.send(());
+        old.server.await??;
+        drop(old.log);
+        assert_eq!(client.produce(record("d")).await?, 3);
+        assert_ne!(client.leader(), Some(addrs[leader].to_string()));
+        assert_eq!(client.consume(3).await?.value, b"d");
+
+        // a retry of the last batch reaching the new leader is not written twice
+        let (id, sequence) = client.producer.lock().await.expect("idempotent producer");
+        let range = client
+            .send_batch(vec![record("d")], id, sequence - 1)
+            .await?;
+        assert_eq!(range, 3..4);
+        assert!(client.consume(4).await.is_err());
+        Ok(())
+    }
+}
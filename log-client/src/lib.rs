@@ -0,0 +1,9 @@
+//! A client for a cluster of log servers.
+//!
+//! [`Client`] finds the servers through any one of them, sends appends to the
+//! leader and spreads reads across the followers.
+
+mod client;
+
+pub use crate::client::{Client, ClientConfig};
+pub use protos::log::v1::{Record, Server};
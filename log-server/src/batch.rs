@@ -0,0 +1,355 @@
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use prost::Message;
+use protos::log::v1::Record;
+
+use crate::config::Compression;
+use crate::producer::BatchProducer;
+use crate::store::{self, Store, VERSION_CRC};
+
+const CODEC_WIDTH: usize = 1;
+const RAW_LEN_WIDTH: usize = 4;
+const HEADER_WIDTH: usize = CODEC_WIDTH + RAW_LEN_WIDTH;
+/// Set in the codec byte of a batch whose header holds its producer.
+const PRODUCER_FLAG: u8 = 0x80;
+const PRODUCER_WIDTH: usize = 16;
+
+/// Encodes `records` as one batch payload:
+/// `codec u8 | raw_len u32 | [producer_id u64 | sequence u64] |
+/// codec(length-delimited records)`, where `raw_len` is the size of the
+/// records before compression. The producer fields are only there for a
+/// batch of an idempotent producer, which [`PRODUCER_FLAG`] marks.
+pub(crate) fn encode(
+    records: &[Record],
+    codec: Compression,
+    producer: Option<BatchProducer>,
+) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    for r in records {
+        r.encode_length_delimited(&mut raw)
+            .expect("vec grows to fit the record");
+    }
+    let raw_len = u32::try_from(raw.len()).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("batch of {} bytes is too large", raw.len()),
+        )
+    })?;
+    let body = codec.compress(&raw)?;
+    let mut payload = Vec::with_capacity(HEADER_WIDTH + PRODUCER_WIDTH + body.len());
+    match producer {
+        Some(p) => {
+            payload.push(codec.id() | PRODUCER_FLAG);
+            payload.extend_from_slice(&raw_len.to_le_bytes());
+            payload.extend_from_slice(&p.id.to_le_bytes());
+            payload.extend_from_slice(&p.sequence.to_le_bytes());
+        }
+        None => {
+            payload.push(codec.id());
+            payload.extend_from_slice(&raw_len.to_le_bytes());
+        }
+    }
+    payload.extend_from_slice(&body);
+    Ok(payload)
+}
+
+/// Decodes a payload written by [`encode`], with whichever codec it names.
+pub(crate) fn decode(payload: &[u8]) -> io::Result<Vec<Record>> {
+    let (codec, raw_len) = header(payload)?;
+    let body = &payload[HEADER_WIDTH + producer_width(payload)?..];
+    let raw = codec.decompress(body, raw_len as usize)?;
+    if raw.len() != raw_len as usize {
+        return Err(invalid(format!(
+            "batch decompressed to {} bytes, expected {}",
+            raw.len(),
+            raw_len
+        )));
+    }
+    let mut buf = &raw[..];
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        records
+            .push(Record::decode_length_delimited(&mut buf).map_err(|e| invalid(e.to_string()))?);
+    }
+    Ok(records)
+}
+
+/// Codec and uncompressed size of a batch payload.
+pub(crate) fn header(payload: &[u8]) -> io::Result<(Compression, u32)> {
+    if payload.len() < HEADER_WIDTH {
+        return Err(invalid(format!(
+            "batch of {} bytes has no header",
+            payload.len()
+        )));
+    }
+    let codec = Compression::from_id(payload[0] & !PRODUCER_FLAG)
+        .ok_or_else(|| invalid(format!("unknown batch codec {}", payload[0])))?;
+    let mut raw_len = [0u8; RAW_LEN_WIDTH];
+    raw_len.copy_from_slice(&payload[CODEC_WIDTH..HEADER_WIDTH]);
+    Ok((codec, u32::from_le_bytes(raw_len)))
+}
+
+/// The producer of a batch payload, read from its header without decoding
+/// the records.
+pub(crate) fn producer(payload: &[u8]) -> io::Result<Option<BatchProducer>> {
+    header(payload)?;
+    if producer_width(payload)? == 0 {
+        return Ok(None);
+    }
+    let field = |at: usize| {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&payload[HEADER_WIDTH + at..HEADER_WIDTH + at + 8]);
+        u64::from_le_bytes(b)
+    };
+    Ok(Some(BatchProducer {
+        id: field(0),
+        sequence: field(8),
+    }))
+}
+
+/// Bytes the producer fields take after the header of a batch payload.
+fn producer_width(payload: &[u8]) -> io::Result<usize> {
+    if payload[0] & PRODUCER_FLAG == 0 {
+        return Ok(0);
+    }
+    if payload.len() < HEADER_WIDTH + PRODUCER_WIDTH {
+        return Err(invalid(format!(
+            "batch of {} bytes has no room for its producer",
+            payload.len()
+        )));
+    }
+    Ok(PRODUCER_WIDTH)
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+impl Compression {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+            Compression::Snappy => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            3 => Some(Compression::Snappy),
+            _ => None,
+        }
+    }
+
+    fn compress(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(raw.to_vec()),
+            Compression::Zstd => zstd::bulk::compress(raw, 0),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(raw)),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(raw)
+                .map_err(io::Error::other),
+        }
+    }
+
+    fn decompress(self, body: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(body.to_vec()),
+            Compression::Zstd => zstd::bulk::decompress(body, raw_len),
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(body, raw_len).map_err(|e| invalid(e.to_string()))
+            }
+            Compression::Snappy => snap::raw::Decoder::new()
+                .decompress_vec(body)
+                .map_err(|e| invalid(e.to_string())),
+        }
+    }
+}
+
+/// How well one segment's batches compress.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompressionStats {
+    pub base_offset: u64,
+    pub batches: u64,
+    /// Size of the encoded records before compression.
+    pub uncompressed_bytes: u64,
+    /// Size of the payloads as stored, batch headers included.
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Uncompressed over compressed bytes; 1 for an empty segment.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
+}
+
+/// Streams the records of a batched store as `len | crc32c | record` frames,
+/// the layout of [`VERSION_CRC`] stores, so [`Log::reader`](crate::Log::reader)
+/// output does not depend on how the records were compressed.
+///
+/// Stream positions only exist once the batches before them are decoded, so
+/// seeking forward decodes every batch on the way; the stream position of
+/// each batch seen is kept so seeking back does not.
+pub(crate) struct BatchReader {
+    store: Arc<Store>,
+    /// Records below this offset are left out.
+    from: u64,
+    /// Store position of the next batch to decode.
+    pos: u64,
+    /// Stream position of `frames[0]`.
+    start: u64,
+    frames: Vec<u8>,
+    off: usize,
+    /// Store and stream positions of every batch decoded so far, in order.
+    seen: Vec<(u64, u64)>,
+}
+
+impl BatchReader {
+    pub fn new(store: Arc<Store>) -> Self {
+        let pos = store.data_start();
+        BatchReader::starting_at(store, pos, 0)
+    }
+
+    /// Streams from the batch at store position `pos`, leaving out the
+    /// records in it below offset `from`.
+    pub fn starting_at(store: Arc<Store>, pos: u64, from: u64) -> Self {
+        BatchReader {
+            store,
+            from,
+            pos,
+            start: 0,
+            frames: Vec::new(),
+            off: 0,
+            seen: Vec::new(),
+        }
+    }
+
+    /// Decodes the next batch into `frames`; false at the end of the store.
+    fn next_batch(&mut self) -> io::Result<bool> {
+        if self.pos >= self.store.size() {
+            return Ok(false);
+        }
+        let (payload, next) = self.store.read_frame(self.pos)?;
+        let mut frames = Vec::new();
+        for r in decode(&payload)?
+            .into_iter()
+            .filter(|r| r.offset >= self.from)
+        {
+            store::encode_frame(&r.encode_to_vec(), VERSION_CRC, &mut frames);
+        }
+        self.start += self.frames.len() as u64;
+        if self.seen.last().is_none_or(|&(pos, _)| pos < self.pos) {
+            self.seen.push((self.pos, self.start));
+        }
+        self.pos = next;
+        self.frames = frames;
+        self.off = 0;
+        Ok(true)
+    }
+
+    /// Decodes batches until the one holding stream position `n`, or to the
+    /// end of the store, starting from the closest batch already seen.
+    fn seek_to(&mut self, n: u64) -> io::Result<()> {
+        let i = self.seen.partition_point(|&(_, start)| start <= n);
+        if let Some(&(pos, start)) = i.checked_sub(1).map(|i| &self.seen[i]) {
+            if n < self.start || start > self.start {
+                self.pos = pos;
+                self.start = start;
+                self.frames.clear();
+            }
+        }
+        while n >= self.start + self.frames.len() as u64 && self.next_batch()? {}
+        self.off = self.frames.len().min((n - self.start) as usize);
+        Ok(())
+    }
+}
+
+impl Read for BatchReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.off == self.frames.len() {
+            if !self.next_batch()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.frames.len() - self.off);
+        buf[..n].copy_from_slice(&self.frames[self.off..self.off + n]);
+        self.off += n;
+        Ok(n)
+    }
+}
+
+impl Seek for BatchReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let current = self.start + self.off as u64;
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => current.checked_add_signed(d),
+            SeekFrom::End(d) => {
+                self.seek_to(u64::MAX)?;
+                let len = self.start + self.frames.len() as u64;
+                len.checked_add_signed(d)
+            }
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the start"))?;
+        self.seek_to(target)?;
+        let reached = self.start + self.off as u64;
+        if reached != target {
+            self.seek_to(current)?;
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("seek past the {} bytes of the batches", reached),
+            ));
+        }
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let records: Vec<_> = (0..10)
+            .map(|i| Record {
+                value: format!("{{\"id\":{},\"payload\":\"aaaaaaaaaaaaaaaa\"}}", i).into_bytes(),
+                offset: i,
+                ..Default::default()
+            })
+            .collect();
+        for codec in [
+            Compression::None,
+            Compression::Zstd,
+            Compression::Lz4,
+            Compression::Snappy,
+        ] {
+            let payload = encode(&records, codec, None).unwrap();
+            assert_eq!(header(&payload).unwrap().0, codec);
+            assert_eq!(decode(&payload).unwrap(), records);
+            assert_eq!(producer(&payload).unwrap(), None);
+            if codec != Compression::None {
+                assert!(payload.len() < encode(&records, Compression::None, None).unwrap().len());
+            }
+        }
+
+        let p = BatchProducer { id: 7, sequence: 3 };
+        let payload = encode(&records, Compression::Zstd, Some(p)).unwrap();
+        assert_eq!(header(&payload).unwrap().0, Compression::Zstd);
+        assert_eq!(producer(&payload).unwrap(), Some(p));
+        assert_eq!(decode(&payload).unwrap(), records);
+
+        let mut payload = encode(&records, Compression::Lz4, None).unwrap();
+        payload[0] = 42;
+        assert_eq!(decode(&payload).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
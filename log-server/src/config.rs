@@ -1,11 +1,259 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::encryption::{Key, KeyProvider};
+
 #[derive(Default, Clone)]
-pub(crate) struct SegmentConfig {
+pub struct SegmentConfig {
+    /// Size in bytes at which a segment's store file is considered full.
     pub max_store_bytes: u64,
+    /// Size in bytes at which a segment's index file is considered full.
     pub max_index_bytes: u64,
+    /// Size in bytes at which a segment's time index file is considered full.
+    pub max_time_index_bytes: u64,
+    /// Offset of the first record of a brand-new log.
     pub initial_offset: u64,
+    /// Store bytes between two time index entries; 0 indexes every batch that
+    /// raises the segment's largest timestamp.
+    pub time_index_interval_bytes: u64,
+    /// Store bytes between two index entries; 0 indexes every batch by its
+    /// first offset. Reads scan forward from the closest entry, so a sparse
+    /// index trades a little read time for much smaller index files.
+    pub index_interval_bytes: u64,
+    /// Codec for record batches written from now on. Each batch records its
+    /// own codec, so existing segments stay readable when this changes.
+    pub compression: Compression,
+    /// Keep a per-segment map from each record key to its latest offset, for
+    /// [`Log::read_latest_by_key`](crate::Log::read_latest_by_key).
+    pub key_index: bool,
+}
+
+/// Codec applied to a record batch before it is written to the store.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+    Snappy,
+}
+
+/// AEAD cipher store frames are encrypted with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+/// At-rest encryption of a segment's store frames and of its index, time
+/// index and key index files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// Cipher for new segments; existing ones keep the cipher in their header.
+    pub cipher: Cipher,
+    /// Key new segments are encrypted with. It is recorded in each segment's
+    /// header, so rotating only needs the provider to keep serving old ids.
+    pub key_id: String,
+}
+
+/// Who decides a record's timestamp.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampType {
+    /// Keep the producer's timestamp, using the append time if it is unset.
+    #[default]
+    CreateTime,
+    /// Always overwrite the timestamp with the append time.
+    LogAppendTime,
+}
+
+/// What the background cleaner does with closed segments besides retention.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CleanupPolicy {
+    /// Only delete whole segments through retention.
+    #[default]
+    Delete,
+    /// Also compact closed segments down to the latest record per key.
+    Compact,
+}
+
+/// When appended records are fsynced to disk.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave write-back to the OS; only [`Log::sync`](crate::Log::sync) and
+    /// close fsync.
+    #[default]
+    OsManaged,
+    /// Every append returns after its record is fsynced.
+    SyncEveryAppend,
+    /// Every nth append fsyncs itself and the appends before it.
+    SyncEveryNRecords(u64),
+    /// The first append after the interval elapsed fsyncs itself and the
+    /// appends before it. Run [`Log::sync`](crate::Log::sync) on a timer to
+    /// bound the window on a quiet log.
+    SyncInterval(Duration),
 }
 
 #[derive(Default, Clone)]
-pub(crate) struct Config {
+pub struct Config {
     pub segment: SegmentConfig,
+    pub timestamp_type: TimestampType,
+    /// Once the segments take more bytes than this, the oldest closed ones are
+    /// deleted by retention.
+    pub retention_bytes: Option<u64>,
+    /// Closed segments whose newest record is older than this are deleted by
+    /// retention.
+    pub retention_duration: Option<Duration>,
+    pub cleanup_policy: CleanupPolicy,
+    /// How long compaction keeps tombstones so consumers can observe deletes,
+    /// counted from when their segment was last appended to.
+    pub tombstone_retention: Duration,
+    pub durability: Durability,
+    /// Encrypts segments created from now on; segments written in plaintext
+    /// stay readable.
+    pub encryption: Option<EncryptionConfig>,
+    /// Serves the keys of encrypted segments, including ones written before
+    /// `encryption` changed or was turned off.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+    /// Idempotent producers that appended nothing for this long are
+    /// forgotten, and may start over at any sequence.
+    pub producer_expiry: Option<Duration>,
+}
+
+/// Settings of a topic of a [`LogManager`](crate::LogManager). Fields left
+/// unset keep the manager's [`Config`].
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TopicConfig {
+    /// Number of partitions, fixed at creation.
+    pub partitions: u32,
+    pub max_store_bytes: Option<u64>,
+    pub max_index_bytes: Option<u64>,
+    pub compression: Option<Compression>,
+    pub key_index: Option<bool>,
+    pub retention_bytes: Option<u64>,
+    pub retention_duration: Option<Duration>,
+    pub cleanup_policy: Option<CleanupPolicy>,
+}
+
+impl TopicConfig {
+    /// The config of the topic's partitions, `base` with the overrides applied.
+    pub fn apply(&self, base: &Config) -> Config {
+        let mut config = base.clone();
+        if let Some(bytes) = self.max_store_bytes {
+            config.segment.max_store_bytes = bytes;
+        }
+        if let Some(bytes) = self.max_index_bytes {
+            config.segment.max_index_bytes = bytes;
+        }
+        if let Some(compression) = self.compression {
+            config.segment.compression = compression;
+        }
+        if let Some(key_index) = self.key_index {
+            config.segment.key_index = key_index;
+        }
+        if self.retention_bytes.is_some() {
+            config.retention_bytes = self.retention_bytes;
+        }
+        if self.retention_duration.is_some() {
+            config.retention_duration = self.retention_duration;
+        }
+        if let Some(policy) = self.cleanup_policy {
+            config.cleanup_policy = policy;
+        }
+        config
+    }
+}
+
+/// Settings of the consumer groups of a
+/// [`GroupCoordinator`](crate::GroupCoordinator).
+#[derive(Clone, Debug)]
+pub struct GroupConfig {
+    /// How long a member may go without a heartbeat before it is removed,
+    /// unless it asked for another timeout when joining.
+    pub session_timeout: Duration,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            session_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Settings of one node of a [`ReplicatedLog`](crate::ReplicatedLog).
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// This node's id, unique in the cluster.
+    pub id: String,
+    /// Address of the Raft service of every voter, this node included, by id.
+    pub peers: BTreeMap<String, String>,
+    /// How often a leader sends each follower new entries or a heartbeat.
+    pub heartbeat_interval: Duration,
+    /// A follower that hears from no leader for this long, plus a random
+    /// jitter of up to as much again, starts an election.
+    pub election_timeout: Duration,
+    /// Most entries sent in one `AppendEntries` request.
+    pub max_append_entries: usize,
+    /// Applied entries a node keeps in its Raft log. Once twice as many piled
+    /// up, it trims the older half, whose records its log already holds; a
+    /// follower further behind than that catches up from a snapshot.
+    pub snapshot_entries: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            id: String::new(),
+            peers: BTreeMap::new(),
+            heartbeat_interval: Duration::from_millis(100),
+            election_timeout: Duration::from_secs(1),
+            max_append_entries: 64,
+            snapshot_entries: 4096,
+        }
+    }
+}
+
+/// Settings of a node's [`Membership`](crate::Membership) gossip.
+#[derive(Clone, Debug)]
+pub struct MembershipConfig {
+    /// This node's name, unique in the cluster; its Raft id when replicating.
+    pub name: String,
+    /// UDP address to gossip on, which the other members reach it at.
+    pub bind_addr: SocketAddr,
+    /// Address of this node's gRPC services, announced to the others.
+    pub rpc_addr: String,
+    /// Gossip addresses of members to join through; empty to start a cluster.
+    pub seeds: Vec<SocketAddr>,
+    /// How often one member is probed.
+    pub probe_interval: Duration,
+    /// How long a probe waits for an ack before asking others to probe.
+    pub probe_timeout: Duration,
+    /// How many others are asked to probe a member that did not ack.
+    pub indirect_checks: usize,
+    /// How long a suspected member has to refute before it is declared failed.
+    pub suspect_timeout: Duration,
+    /// How often full member lists are exchanged with a random member.
+    pub push_pull_interval: Duration,
+    /// Key every gossip message is sealed with, so only nodes holding it can
+    /// join or speak for members. Without one gossip is not authenticated.
+    pub key: Option<Key>,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        MembershipConfig {
+            name: String::new(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 7946)),
+            rpc_addr: String::new(),
+            seeds: vec![],
+            probe_interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(500),
+            indirect_checks: 3,
+            suspect_timeout: Duration::from_secs(5),
+            push_pull_interval: Duration::from_secs(30),
+            key: None,
+        }
+    }
 }
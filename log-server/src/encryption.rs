@@ -0,0 +1,333 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::config::Cipher;
+
+/// Width of the keys every [`Cipher`] takes.
+pub const KEY_WIDTH: usize = 32;
+const NONCE_WIDTH: usize = 12;
+const TAG_WIDTH: usize = 16;
+/// Bytes sealing adds to a payload.
+pub(crate) const OVERHEAD: u64 = (NONCE_WIDTH + TAG_WIDTH) as u64;
+
+pub type Key = [u8; KEY_WIDTH];
+
+/// Looks up encryption keys by the id recorded in each segment's header.
+/// Keep every id that still names a segment on disk available after
+/// rotating to a new one.
+pub trait KeyProvider: Send + Sync {
+    fn key(&self, key_id: &str) -> Result<Key>;
+}
+
+/// Reads key `id` from the file `<dir>/<id>.key` holding it hex encoded.
+pub struct FileKeyProvider {
+    dir: PathBuf,
+}
+
+impl FileKeyProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileKeyProvider { dir: dir.into() }
+    }
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn key(&self, key_id: &str) -> Result<Key> {
+        let valid = !key_id.is_empty()
+            && key_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(anyhow!("invalid key id {:?}", key_id));
+        }
+        let path = self.dir.join(format!("{}.key", key_id));
+        let hex = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read key file {:?}", path))?;
+        parse_key(key_id, &hex)
+    }
+}
+
+/// Reads key `id` hex encoded from the environment variable `<prefix><ID>`,
+/// where the id is upper-cased and anything but letters and digits becomes `_`.
+pub struct EnvKeyProvider {
+    prefix: String,
+}
+
+impl EnvKeyProvider {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        EnvKeyProvider {
+            prefix: prefix.into(),
+        }
+    }
+
+    fn var(&self, key_id: &str) -> String {
+        let id: String = key_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}{}", self.prefix, id)
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn key(&self, key_id: &str) -> Result<Key> {
+        let var = self.var(key_id);
+        let hex = std::env::var(&var).with_context(|| format!("failed to read ${}", var))?;
+        parse_key(key_id, &hex)
+    }
+}
+
+fn parse_key(key_id: &str, hex: &str) -> Result<Key> {
+    let mut key = [0u8; KEY_WIDTH];
+    hex::decode_to_slice(hex.trim(), &mut key)
+        .with_context(|| format!("key {:?} must be {} hex-encoded bytes", key_id, KEY_WIDTH))?;
+    Ok(key)
+}
+
+impl Cipher {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// Returned inside an [`io::Error`] when a frame fails authentication.
+#[derive(Debug)]
+pub(crate) struct Unauthenticated;
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame failed authentication")
+    }
+}
+
+impl std::error::Error for Unauthenticated {}
+
+/// What a sealed payload is. It is authenticated along with the payload, so
+/// one kind cannot pass for another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Sealed {
+    /// The store header, sealed empty behind it.
+    Header = 0,
+    /// A store frame payload.
+    Frame = 1,
+    IndexEntry = 2,
+    TimeIndexEntry = 3,
+    KeyIndex = 4,
+    /// A gossip message between cluster members.
+    Gossip = 5,
+}
+
+enum Aeads {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// Seals the payloads of one segment's files as `nonce | ciphertext | tag`
+/// under a fresh random nonce. Each payload is authenticated along with the
+/// segment's base offset and store header, what it is and where it sits, so
+/// nothing can be moved within or between segments, and the header cannot be
+/// altered.
+pub(crate) struct FrameCipher {
+    pub(crate) key_id: String,
+    aead: Aeads,
+    /// Base offset and store header of the segment.
+    context: Vec<u8>,
+}
+
+impl FrameCipher {
+    pub fn new(cipher: Cipher, key_id: &str, key: &Key) -> Self {
+        let aead = match cipher {
+            Cipher::Aes256Gcm => Aeads::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Cipher::ChaCha20Poly1305 => {
+                Aeads::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into())))
+            }
+        };
+        FrameCipher {
+            key_id: key_id.to_owned(),
+            aead,
+            context: vec![],
+        }
+    }
+
+    /// Binds everything sealed from now on to the segment at `base_offset`
+    /// whose store starts with `header`.
+    pub fn with_context(mut self, base_offset: u64, header: &[u8]) -> Self {
+        self.context = base_offset.to_le_bytes().to_vec();
+        self.context.extend_from_slice(header);
+        self
+    }
+
+    fn aad(&self, kind: Sealed, pos: u64) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(self.context.len() + 9);
+        aad.extend_from_slice(&self.context);
+        aad.push(kind as u8);
+        aad.extend_from_slice(&pos.to_le_bytes());
+        aad
+    }
+
+    /// Bytes sealing adds to a payload.
+    pub fn overhead(&self) -> u64 {
+        OVERHEAD
+    }
+
+    /// Seals `p`, a payload of `kind` at `pos`.
+    pub fn seal(&self, kind: Sealed, pos: u64, p: &[u8]) -> Vec<u8> {
+        let aad = self.aad(kind, pos);
+        let payload = Payload { msg: p, aad: &aad };
+        let (nonce, sealed) = match &self.aead {
+            Aeads::Aes256Gcm(a) => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                (nonce, a.encrypt(&nonce, payload))
+            }
+            Aeads::ChaCha20Poly1305(a) => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                (nonce, a.encrypt(&nonce, payload))
+            }
+        };
+        let mut out = nonce.to_vec();
+        out.extend(sealed.expect("payload fits the cipher"));
+        out
+    }
+
+    /// Authenticates and decrypts a payload of `kind` sealed at `pos`.
+    /// Failures carry [`Unauthenticated`].
+    pub fn open(&self, kind: Sealed, pos: u64, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_WIDTH + TAG_WIDTH {
+            return Err(io::Error::new(ErrorKind::InvalidData, Unauthenticated));
+        }
+        let (nonce, msg) = sealed.split_at(NONCE_WIDTH);
+        let aad = self.aad(kind, pos);
+        let payload = Payload { msg, aad: &aad };
+        match &self.aead {
+            Aeads::Aes256Gcm(a) => a.decrypt(nonce.into(), payload),
+            Aeads::ChaCha20Poly1305(a) => a.decrypt(nonce.into(), payload),
+        }
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, Unauthenticated))
+    }
+}
+
+/// Fixed-width index entries sealed one by one under their entry number. The
+/// index reads a plaintext copy it keeps in memory; only the file gets the
+/// sealed entries.
+pub(crate) struct SealedEntries {
+    cipher: Arc<FrameCipher>,
+    kind: Sealed,
+    /// Width of a plaintext entry.
+    width: usize,
+}
+
+impl SealedEntries {
+    pub fn new(cipher: Arc<FrameCipher>, kind: Sealed, width: usize) -> Self {
+        SealedEntries {
+            cipher,
+            kind,
+            width,
+        }
+    }
+
+    /// Width of a sealed entry in the file.
+    pub fn sealed_width(&self) -> u64 {
+        self.width as u64 + OVERHEAD
+    }
+
+    /// Writes entry `n` to a file whose entries start at byte `start`.
+    pub fn write(&self, file: &File, start: u64, n: u64, entry: &[u8]) -> io::Result<()> {
+        let sealed = self.cipher.seal(self.kind, n, entry);
+        file.write_all_at(&sealed, start + n * self.sealed_width())
+    }
+
+    /// Reads the entries of a file `len` bytes long whose entries start at
+    /// byte `start`, back to back in plaintext. Stops at the first entry that
+    /// fails authentication, e.g. one torn by a crash.
+    pub fn read_all(&self, file: &File, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut b = vec![0; len.saturating_sub(start) as usize];
+        file.read_exact_at(&mut b, start)?;
+        let mut entries = Vec::with_capacity(b.len());
+        for (n, sealed) in b.chunks_exact(self.sealed_width() as usize).enumerate() {
+            match self.cipher.open(self.kind, n as u64, sealed) {
+                Ok(entry) => entries.extend_from_slice(&entry),
+                Err(_) => break,
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Whether `e` reports a frame that failed authentication.
+pub(crate) fn is_unauthenticated(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Unauthenticated>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305] {
+            let c = FrameCipher::new(cipher, "k1", &[7; KEY_WIDTH]).with_context(0, b"DLOG");
+            let sealed = c.seal(Sealed::Frame, 8, b"hello world");
+            assert_eq!(sealed.len() as u64, 11 + c.overhead());
+            assert_eq!(c.open(Sealed::Frame, 8, &sealed).unwrap(), b"hello world");
+            // moved frame
+            let err = c.open(Sealed::Frame, 9, &sealed).unwrap_err();
+            assert!(is_unauthenticated(&err));
+            // passed off as an index entry
+            let err = c.open(Sealed::IndexEntry, 8, &sealed).unwrap_err();
+            assert!(is_unauthenticated(&err));
+            // moved to another segment, or behind another header
+            for other in [
+                FrameCipher::new(cipher, "k1", &[7; KEY_WIDTH]).with_context(10, b"DLOG"),
+                FrameCipher::new(cipher, "k1", &[7; KEY_WIDTH]).with_context(0, b"DLOX"),
+            ] {
+                let err = other.open(Sealed::Frame, 8, &sealed).unwrap_err();
+                assert!(is_unauthenticated(&err));
+            }
+            // wrong key
+            let other = FrameCipher::new(cipher, "k1", &[8; KEY_WIDTH]).with_context(0, b"DLOG");
+            let err = other.open(Sealed::Frame, 8, &sealed).unwrap_err();
+            assert!(is_unauthenticated(&err));
+        }
+    }
+
+    #[test]
+    fn key_providers() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("k-1.key"), format!("{}\n", "ab".repeat(32))).unwrap();
+        let files = FileKeyProvider::new(dir.path());
+        assert_eq!(files.key("k-1").unwrap(), [0xab; KEY_WIDTH]);
+        assert!(files.key("../k-1").is_err());
+        assert!(files.key("k-2").is_err());
+
+        std::env::set_var("DLOG_TEST_KEY_K_1", "cd".repeat(32));
+        std::env::set_var("DLOG_TEST_KEY_SHORT", "cd");
+        let env = EnvKeyProvider::new("DLOG_TEST_KEY_");
+        assert_eq!(env.key("k-1").unwrap(), [0xcd; KEY_WIDTH]);
+        assert!(env.key("short").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use memmap::MmapMut;
+
+use crate::encryption::{FrameCipher, Sealed, SealedEntries};
+
+const MAGIC_WIDTH: usize = 4;
+const VERSION_WIDTH: usize = 4;
+pub(crate) const HEADER_WIDTH: usize = MAGIC_WIDTH + VERSION_WIDTH;
+/// `magic | version u32`, then the plaintext entries back to back.
+pub(crate) const VERSION_1: u32 = 1;
+pub(crate) const CURRENT_VERSION: u32 = VERSION_1;
+/// As [`VERSION_1`], with each entry sealed by the cipher of the segment's
+/// store, see [`SealedEntries`].
+pub(crate) const VERSION_ENCRYPTED: u32 = 2;
+
+/// What sets one kind of entry file apart from the others.
+pub(crate) struct Format {
+    /// Opens the header, telling the kinds of file apart.
+    pub magic: [u8; MAGIC_WIDTH],
+    /// Width of a plaintext entry.
+    pub width: usize,
+    /// Domain the entries are sealed in.
+    pub sealed: Sealed,
+    /// Names the file in logs and errors.
+    pub name: &'static str,
+}
+
+impl Format {
+    /// The version in the file's header, if it starts with one. Fails for a
+    /// version this build does not read.
+    pub fn header_version(&self, file: &File) -> io::Result<Option<u32>> {
+        if file.metadata()?.len() < HEADER_WIDTH as u64 {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_WIDTH];
+        file.read_exact_at(&mut header, 0)?;
+        if header[..MAGIC_WIDTH] != self.magic {
+            return Ok(None);
+        }
+        let mut v = [0u8; VERSION_WIDTH];
+        v.copy_from_slice(&header[MAGIC_WIDTH..]);
+        let version = u32::from_le_bytes(v);
+        if version > VERSION_ENCRYPTED {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported {} version {}", self.name, version),
+            ));
+        }
+        Ok(Some(version))
+    }
+
+    /// The header of a file of `version`.
+    pub fn header(&self, version: u32) -> [u8; HEADER_WIDTH] {
+        let mut header = [0u8; HEADER_WIDTH];
+        header[..MAGIC_WIDTH].copy_from_slice(&self.magic);
+        header[MAGIC_WIDTH..].copy_from_slice(&version.to_le_bytes());
+        header
+    }
+}
+
+/// A headered file of fixed-width entries, mapped in memory. Entries are
+/// sealed one by one if the file is encrypted; `max_bytes` bounds them and
+/// the header comes on top.
+pub(crate) struct EntryFile {
+    file: File,
+    format: &'static Format,
+    /// Bytes of plaintext entries, header excluded.
+    size: u64,
+    /// The file, or for encrypted files a plaintext copy of it in memory.
+    mmap: MmapMut,
+    sealed: Option<SealedEntries>,
+}
+
+impl EntryFile {
+    /// Opens an entry file, encrypted if `cipher` is given. One without a
+    /// matching header, torn while being created or written with other
+    /// encryption, is emptied: its segment rebuilds it from the records. So is
+    /// a torn last entry.
+    pub fn open(
+        file: File,
+        format: &'static Format,
+        max_bytes: u64,
+        cipher: Option<Arc<FrameCipher>>,
+    ) -> io::Result<Self> {
+        let sealed = cipher.map(|c| SealedEntries::new(c, format.sealed, format.width));
+        let version = if sealed.is_some() {
+            VERSION_ENCRYPTED
+        } else {
+            CURRENT_VERSION
+        };
+        let mut len = file.metadata()?.len();
+        if len > 0 && format.header_version(&file)? != Some(version) {
+            warn!(
+                "discarding {} of {} bytes without a matching header",
+                format.name, len
+            );
+            file.set_len(0)?;
+            len = 0;
+        }
+        if len == 0 {
+            file.write_all_at(&format.header(version), 0)?;
+            len = HEADER_WIDTH as u64;
+        }
+        let (size, mmap) = match &sealed {
+            Some(sealed) => {
+                let entries = sealed.read_all(&file, HEADER_WIDTH as u64, len)?;
+                let n = (entries.len() / format.width) as u64;
+                file.set_len(HEADER_WIDTH as u64 + n * sealed.sealed_width())?;
+                let max = max_bytes.max(entries.len() as u64);
+                let mut mmap = MmapMut::map_anon(HEADER_WIDTH + max as usize)?;
+                mmap[HEADER_WIDTH..HEADER_WIDTH + entries.len()].copy_from_slice(&entries);
+                (entries.len() as u64, mmap)
+            }
+            None => {
+                let size = len - HEADER_WIDTH as u64;
+                let size = size - size % format.width as u64;
+                file.set_len(HEADER_WIDTH as u64 + max_bytes.max(size))?;
+                (size, unsafe { MmapMut::map_mut(&file)? })
+            }
+        };
+        Ok(EntryFile {
+            file,
+            format,
+            size,
+            mmap,
+            sealed,
+        })
+    }
+
+    /// Appends `entry`, which must be one entry wide.
+    pub fn append(&mut self, entry: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(entry.len(), self.format.width);
+        let start = HEADER_WIDTH + self.size as usize;
+        let end = start + self.format.width;
+        if self.mmap.len() < end {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("mmap length {} is less than {}", self.mmap.len(), end),
+            ));
+        }
+        self.mmap[start..end].copy_from_slice(entry);
+        if let Some(sealed) = &self.sealed {
+            sealed.write(&self.file, HEADER_WIDTH as u64, self.len(), entry)?;
+        }
+        self.size += self.format.width as u64;
+        Ok(())
+    }
+
+    /// The `n`th entry; it must exist.
+    pub fn entry(&self, n: u64) -> &[u8] {
+        let start = HEADER_WIDTH + n as usize * self.format.width;
+        &self.mmap[start..start + self.format.width]
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> u64 {
+        self.size / self.format.width as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Forgets every entry from the `n`th on. A plain file is cut on close;
+    /// sealed entries are cut right away, as they would still authenticate.
+    pub fn truncate(&mut self, n: u64) -> io::Result<()> {
+        let size = n * self.format.width as u64;
+        if size < self.size {
+            let start = HEADER_WIDTH + size as usize;
+            self.mmap[start..HEADER_WIDTH + self.size as usize].fill(0);
+            self.size = size;
+            if self.sealed.is_some() {
+                self.file.set_len(HEADER_WIDTH as u64 + self.size())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the entries back to the file and waits for the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        match self.sealed {
+            Some(_) => self.file.sync_data(),
+            None => self.mmap.flush(),
+        }
+    }
+
+    /// Bytes one more entry takes in the file.
+    pub fn entry_width(&self) -> u64 {
+        self.sealed
+            .as_ref()
+            .map_or(self.format.width as u64, |s| s.sealed_width())
+    }
+
+    /// Bytes of entries in the file, header excluded.
+    pub fn size(&self) -> u64 {
+        self.len() * self.entry_width()
+    }
+
+    /// Flushes the entries and cuts the file down to them.
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.sealed.is_none() {
+            self.mmap.flush()?;
+        }
+        debug!(
+            "truncating {} file to size={}",
+            self.format.name,
+            self.size()
+        );
+        self.file.set_len(HEADER_WIDTH as u64 + self.size())?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for EntryFile {
+    fn drop(&mut self) {
+        self.close().expect("entry file fail to close")
+    }
+}
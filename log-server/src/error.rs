@@ -0,0 +1,143 @@
+use std::fmt;
+
+/// Errors the log reports that callers may want to match on, usually via
+/// [`anyhow::Error::downcast_ref`].
+#[derive(Debug)]
+pub enum Error {
+    /// No record exists at `offset`.
+    OffsetOutOfRange { offset: u64 },
+    /// The record at `offset` was removed by compaction; later offsets may exist.
+    Compacted { offset: u64 },
+    /// A store frame failed verification: bad checksum, impossible length or torn write.
+    Corrupt {
+        base_offset: u64,
+        pos: u64,
+        reason: String,
+    },
+    /// A store frame passed its checksum but not authentication: the key
+    /// behind `key_id` is not the one it was encrypted with, or the frame was
+    /// tampered with.
+    Unauthenticated {
+        base_offset: u64,
+        pos: u64,
+        key_id: String,
+    },
+    /// The log was truncated to `next_offset` under a subscriber that had
+    /// read up to `offset`; the records it read from `next_offset` on are gone.
+    Truncated { offset: u64, next_offset: u64 },
+    /// Only the leader of a replicated log takes appends. `leader` is the
+    /// address of the current one, if this node knows it.
+    NotLeader { leader: Option<String> },
+    /// The log manager has no topic by this name.
+    UnknownTopic { topic: String },
+    /// The topic has fewer partitions than `partition + 1`.
+    UnknownPartition { topic: String, partition: u32 },
+    /// A topic by this name already exists.
+    TopicExists { topic: String },
+    /// A topic cannot be created with this name or config.
+    InvalidTopic { topic: String, reason: String },
+    /// The consumer group has no member by this id, which has to join again
+    /// without one.
+    UnknownMember { group: String, member_id: String },
+    /// The member is of an earlier generation of the group and has to rejoin.
+    StaleGeneration {
+        group: String,
+        generation: u64,
+        current: u64,
+    },
+    /// A member committed an offset of a partition not assigned to it, or
+    /// before the group finished rebalancing.
+    NotAssigned {
+        group: String,
+        member_id: String,
+        topic: String,
+        partition: u32,
+    },
+    /// An idempotent producer sent a batch other than its next one or a retry
+    /// of its last one.
+    OutOfOrderSequence {
+        producer_id: u64,
+        sequence: u64,
+        expected: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OffsetOutOfRange { offset } => write!(f, "offset={} is out of range", offset),
+            Error::Compacted { offset } => write!(f, "offset={} was compacted away", offset),
+            Error::Corrupt {
+                base_offset,
+                pos,
+                reason,
+            } => write!(
+                f,
+                "corrupt record in segment base_offset={} at pos={}: {}",
+                base_offset, pos, reason
+            ),
+            Error::Unauthenticated {
+                base_offset,
+                pos,
+                key_id,
+            } => write!(
+                f,
+                "record in segment base_offset={} at pos={} failed authentication with key id {:?}: wrong key or tampered data",
+                base_offset, pos, key_id
+            ),
+            Error::Truncated {
+                offset,
+                next_offset,
+            } => write!(
+                f,
+                "log was truncated to offset={} below offset={} already read",
+                next_offset, offset
+            ),
+            Error::NotLeader { leader: Some(leader) } => {
+                write!(f, "not the leader; the leader is at {}", leader)
+            }
+            Error::NotLeader { leader: None } => write!(f, "not the leader; no leader is known"),
+            Error::UnknownTopic { topic } => write!(f, "unknown topic {:?}", topic),
+            Error::UnknownPartition { topic, partition } => {
+                write!(f, "topic {:?} has no partition {}", topic, partition)
+            }
+            Error::TopicExists { topic } => write!(f, "topic {:?} already exists", topic),
+            Error::InvalidTopic { topic, reason } => {
+                write!(f, "invalid topic {:?}: {}", topic, reason)
+            }
+            Error::UnknownMember { group, member_id } => {
+                write!(f, "group {:?} has no member {:?}", group, member_id)
+            }
+            Error::StaleGeneration {
+                group,
+                generation,
+                current,
+            } => write!(
+                f,
+                "generation {} of group {:?} is stale; the group is at {}",
+                generation, group, current
+            ),
+            Error::NotAssigned {
+                group,
+                member_id,
+                topic,
+                partition,
+            } => write!(
+                f,
+                "partition {} of topic {:?} is not assigned to {:?} of group {:?}",
+                partition, topic, member_id, group
+            ),
+            Error::OutOfOrderSequence {
+                producer_id,
+                sequence,
+                expected,
+            } => write!(
+                f,
+                "producer {} sent batch {} but batch {} was expected",
+                producer_id, sequence, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
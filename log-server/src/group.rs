@@ -0,0 +1,611 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use protos::log::v1::AssignmentStrategy;
+
+use crate::config::GroupConfig;
+use crate::error::Error;
+use crate::manager::LogManager;
+use crate::offsets::OffsetStore;
+
+/// Where a [`GroupCoordinator`] gets the time from, so tests can move it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time.
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// What a member gets from joining a group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub member_id: String,
+    pub generation: u64,
+    /// The member's partitions, as topic and partition, sorted.
+    pub partitions: Vec<(String, u32)>,
+    /// Members of the previous generation have not all rejoined yet, so the
+    /// member gets no partitions for now and has to join again shortly.
+    pub pending: bool,
+}
+
+/// Tracks the members of consumer groups and assigns them partitions.
+///
+/// Rebalances are eager and decided here: a member joining, leaving, changing
+/// its topics or missing its session timeout starts a new generation with
+/// every partition assigned anew. The other members learn of it from their
+/// next heartbeat, which fails with [`Error::StaleGeneration`], and rejoin to
+/// get their new partitions; until then their offset commits are refused, so
+/// a member that lost a partition cannot overwrite the progress of its new
+/// owner. Joins are a barrier: until every member rejoined the new
+/// generation and no commit of the old one is in flight, joining members get
+/// [`Assignment::pending`] and no partitions, so an old and a new owner never
+/// consume a partition at once. Members may only commit offsets of their own
+/// partitions. Sessions are checked on every call, so no timer is needed, and
+/// groups left without members are dropped. Groups live in memory: after a
+/// restart members find themselves unknown and join again.
+pub struct GroupCoordinator {
+    config: GroupConfig,
+    clock: Arc<dyn Clock>,
+    offsets: Arc<OffsetStore>,
+    topics: Option<Arc<LogManager>>,
+    groups: Mutex<HashMap<String, Group>>,
+}
+
+#[derive(Default)]
+struct Group {
+    /// 0 until the first member joins.
+    generation: u64,
+    strategy: AssignmentStrategy,
+    members: BTreeMap<String, Member>,
+    /// Offset commits checked against the group but not yet on disk, counted
+    /// by the generation they were checked in.
+    committing: BTreeMap<u64, usize>,
+}
+
+struct Member {
+    topics: Vec<String>,
+    session_timeout: Duration,
+    last_heartbeat: Instant,
+    partitions: Vec<(String, u32)>,
+    /// The generation the member last joined.
+    joined: u64,
+}
+
+impl Group {
+    /// Whether every member joined the current generation and nothing
+    /// checked against an earlier one is still being committed.
+    fn synced(&self) -> bool {
+        self.committing.keys().all(|&gen| gen == self.generation)
+            && self.members.values().all(|m| m.joined == self.generation)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.members.is_empty() && self.committing.is_empty()
+    }
+}
+
+impl GroupCoordinator {
+    pub fn new(offsets: Arc<OffsetStore>, config: GroupConfig, clock: Arc<dyn Clock>) -> Self {
+        GroupCoordinator {
+            config,
+            clock,
+            offsets,
+            topics: None,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Assigns the partitions of `topics` too, besides the single partition
+    /// of the server's own log, the empty topic.
+    pub fn with_topics(mut self, topics: Arc<LogManager>) -> Self {
+        self.topics = Some(topics);
+        self
+    }
+
+    /// Adds a member to `group`, or rejoins one with its `member_id` after a
+    /// rebalance, and returns its partitions in the current generation.
+    /// `session_timeout` overrides the configured one.
+    pub fn join(
+        &self,
+        group: &str,
+        member_id: &str,
+        topics: &[String],
+        strategy: AssignmentStrategy,
+        session_timeout: Option<Duration>,
+    ) -> Result<Assignment> {
+        if group.is_empty() {
+            return Err(anyhow!("empty consumer group"));
+        }
+        let now = self.clock.now();
+        let mut groups = self.groups.lock().unwrap();
+        self.expire_all(&mut groups, now);
+        let g = groups.entry(group.to_owned()).or_default();
+        if g.members.is_empty() {
+            g.strategy = strategy;
+        } else if g.strategy != strategy {
+            return Err(anyhow!(
+                "group {:?} assigns with {:?}, not {:?}",
+                group,
+                g.strategy,
+                strategy
+            ));
+        }
+        let mut topics = topics.to_vec();
+        topics.sort();
+        topics.dedup();
+        for topic in &topics {
+            self.partitions(topic)?;
+        }
+        let session_timeout = session_timeout.unwrap_or(self.config.session_timeout);
+        let (member_id, rebalance) = if member_id.is_empty() {
+            let id = format!("{}-{:016x}", group, rand::random::<u64>());
+            let member = Member {
+                topics,
+                session_timeout,
+                last_heartbeat: now,
+                partitions: vec![],
+                joined: 0,
+            };
+            g.members.insert(id.clone(), member);
+            (id, true)
+        } else {
+            let m = g
+                .members
+                .get_mut(member_id)
+                .ok_or_else(|| unknown_member(group, member_id))?;
+            m.last_heartbeat = now;
+            m.session_timeout = session_timeout;
+            let changed = m.topics != topics;
+            m.topics = topics;
+            (member_id.to_owned(), changed)
+        };
+        if rebalance {
+            self.rebalance(group, g);
+        }
+        let generation = g.generation;
+        g.members.get_mut(&member_id).expect("joined above").joined = generation;
+        let pending = !g.synced();
+        let partitions = match pending {
+            true => vec![],
+            false => g.members[&member_id].partitions.clone(),
+        };
+        Ok(Assignment {
+            member_id,
+            generation,
+            partitions,
+            pending,
+        })
+    }
+
+    /// Records that the member is alive. Fails with
+    /// [`Error::StaleGeneration`] once the group moved on, and with
+    /// [`Error::UnknownMember`] once the member was removed.
+    pub fn heartbeat(&self, group: &str, member_id: &str, generation: u64) -> Result<()> {
+        let now = self.clock.now();
+        let mut groups = self.groups.lock().unwrap();
+        let g = groups
+            .get_mut(group)
+            .ok_or_else(|| unknown_member(group, member_id))?;
+        self.expire(group, g, now);
+        if g.is_empty() {
+            groups.remove(group);
+            return Err(unknown_member(group, member_id));
+        }
+        let current = g.generation;
+        let m = g
+            .members
+            .get_mut(member_id)
+            .ok_or_else(|| unknown_member(group, member_id))?;
+        m.last_heartbeat = now;
+        check_generation(group, generation, current)
+    }
+
+    /// Removes the member and rebalances the others.
+    pub fn leave(&self, group: &str, member_id: &str) -> Result<()> {
+        let now = self.clock.now();
+        let mut groups = self.groups.lock().unwrap();
+        self.expire_all(&mut groups, now);
+        let g = groups
+            .get_mut(group)
+            .ok_or_else(|| unknown_member(group, member_id))?;
+        if g.members.remove(member_id).is_none() {
+            return Err(unknown_member(group, member_id));
+        }
+        info!("{} left group {:?}", member_id, group);
+        if g.is_empty() {
+            groups.remove(group);
+        } else {
+            self.rebalance(group, g);
+        }
+        Ok(())
+    }
+
+    /// Commits an offset for `group` if the partition is assigned to
+    /// `member_id` in the current `generation`. Consumers outside the group,
+    /// with an empty `member_id`, may only commit while the group has no
+    /// members.
+    pub fn commit(
+        &self,
+        group: &str,
+        member_id: &str,
+        generation: u64,
+        topic: &str,
+        partition: u32,
+        offset: u64,
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let checked = {
+            let mut groups = self.groups.lock().unwrap();
+            if !member_id.is_empty() && !groups.contains_key(group) {
+                return Err(unknown_member(group, member_id));
+            }
+            let g = groups.entry(group.to_owned()).or_default();
+            self.expire(group, g, now);
+            if !member_id.is_empty() || !g.members.is_empty() {
+                let m = g
+                    .members
+                    .get(member_id)
+                    .ok_or_else(|| unknown_member(group, member_id))?;
+                check_generation(group, generation, g.generation)?;
+                let owned = g.synced()
+                    && m.partitions
+                        .iter()
+                        .any(|(t, p)| t == topic && *p == partition);
+                if !owned {
+                    return Err(Error::NotAssigned {
+                        group: group.to_owned(),
+                        member_id: member_id.to_owned(),
+                        topic: topic.to_owned(),
+                        partition,
+                    }
+                    .into());
+                }
+            }
+            // until the commit is on disk the group counts as not synced, so
+            // no rebalance hands the partition to a new owner meanwhile
+            *g.committing.entry(g.generation).or_default() += 1;
+            g.generation
+        };
+        let res = self.offsets.commit(group, topic, partition, offset);
+        let mut groups = self.groups.lock().unwrap();
+        let g = groups.get_mut(group).expect("kept while committing");
+        let count = g.committing.get_mut(&checked).expect("counted above");
+        *count -= 1;
+        if *count == 0 {
+            g.committing.remove(&checked);
+        }
+        if g.is_empty() {
+            groups.remove(group);
+        }
+        res
+    }
+
+    /// Members of `group` with their partitions in the current generation.
+    pub fn members(&self, group: &str) -> Vec<Assignment> {
+        let groups = self.groups.lock().unwrap();
+        let g = match groups.get(group) {
+            Some(g) => g,
+            None => return vec![],
+        };
+        g.members
+            .iter()
+            .map(|(id, m)| Assignment {
+                member_id: id.clone(),
+                generation: g.generation,
+                partitions: m.partitions.clone(),
+                pending: !g.synced(),
+            })
+            .collect()
+    }
+
+    /// Expires the members of every group, dropping the groups left empty.
+    fn expire_all(&self, groups: &mut HashMap<String, Group>, now: Instant) {
+        groups.retain(|name, g| {
+            self.expire(name, g, now);
+            !g.is_empty()
+        });
+    }
+
+    /// Removes the members whose session timed out, rebalancing if any did.
+    fn expire(&self, group: &str, g: &mut Group, now: Instant) {
+        let before = g.members.len();
+        g.members.retain(|id, m| {
+            let alive = now.duration_since(m.last_heartbeat) <= m.session_timeout;
+            if !alive {
+                info!("{} of group {:?} timed out", id, group);
+            }
+            alive
+        });
+        if g.members.len() < before {
+            self.rebalance(group, g);
+        }
+    }
+
+    /// Starts a new generation and assigns the partitions of the members'
+    /// topics among them. Topics deleted since members joined have none.
+    fn rebalance(&self, group: &str, g: &mut Group) {
+        let mut partitions = BTreeMap::new();
+        for m in g.members.values() {
+            for topic in &m.topics {
+                if let Ok(n) = self.partitions(topic) {
+                    partitions.insert(topic.clone(), n);
+                }
+            }
+        }
+        let subscriptions = g
+            .members
+            .iter()
+            .map(|(id, m)| (id.clone(), m.topics.clone()))
+            .collect();
+        let mut assignment = assign(g.strategy, &subscriptions, &partitions);
+        for (id, m) in g.members.iter_mut() {
+            m.partitions = assignment.remove(id).unwrap_or_default();
+        }
+        g.generation += 1;
+        info!(
+            "group {:?} is at generation {} with {} members",
+            group,
+            g.generation,
+            g.members.len()
+        );
+    }
+
+    fn partitions(&self, topic: &str) -> Result<u32> {
+        match (topic, &self.topics) {
+            ("", _) => Ok(1),
+            (_, Some(topics)) => Ok(topics.topic_config(topic)?.partitions),
+            (_, None) => Err(Error::UnknownTopic {
+                topic: topic.to_owned(),
+            }
+            .into()),
+        }
+    }
+}
+
+fn unknown_member(group: &str, member_id: &str) -> anyhow::Error {
+    Error::UnknownMember {
+        group: group.to_owned(),
+        member_id: member_id.to_owned(),
+    }
+    .into()
+}
+
+fn check_generation(group: &str, generation: u64, current: u64) -> Result<()> {
+    if generation == current {
+        Ok(())
+    } else {
+        Err(Error::StaleGeneration {
+            group: group.to_owned(),
+            generation,
+            current,
+        }
+        .into())
+    }
+}
+
+/// Assigns the partitions of each topic, by count, among the members
+/// subscribed to it, both sorted.
+fn assign(
+    strategy: AssignmentStrategy,
+    subscriptions: &BTreeMap<String, Vec<String>>,
+    partitions: &BTreeMap<String, u32>,
+) -> HashMap<String, Vec<(String, u32)>> {
+    let mut assignment: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+    let subscribed = |id: &str, topic: &str| subscriptions[id].iter().any(|t| t == topic);
+    match strategy {
+        AssignmentStrategy::Range => {
+            for (topic, &n) in partitions {
+                let members: Vec<&String> = subscriptions
+                    .keys()
+                    .filter(|id| subscribed(id, topic))
+                    .collect();
+                let (per_member, extra) = (n / members.len() as u32, n % members.len() as u32);
+                let mut next = 0;
+                for (i, id) in members.into_iter().enumerate() {
+                    let count = per_member + u32::from((i as u32) < extra);
+                    let owned = assignment.entry(id.clone()).or_default();
+                    owned.extend((next..next + count).map(|p| (topic.clone(), p)));
+                    next += count;
+                }
+            }
+        }
+        AssignmentStrategy::RoundRobin => {
+            let members: Vec<&String> = subscriptions.keys().collect();
+            let mut turn = 0;
+            for (topic, &n) in partitions {
+                for p in 0..n {
+                    // the next member in turn that is subscribed to the topic
+                    let i = (0..members.len())
+                        .map(|k| (turn + k) % members.len())
+                        .find(|&i| subscribed(members[i], topic))
+                        .expect("a member subscribed to the topic");
+                    assignment
+                        .entry(members[i].clone())
+                        .or_default()
+                        .push((topic.clone(), p));
+                    turn = i + 1;
+                }
+            }
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::{tempdir, TempDir};
+
+    use crate::config::{Config, TopicConfig};
+
+    use super::*;
+
+    fn parts(list: &[(&str, u32)]) -> Vec<(String, u32)> {
+        list.iter().map(|(t, p)| (t.to_string(), *p)).collect()
+    }
+
+    #[test]
+    fn strategies() {
+        let both = vec!["a".to_owned(), "b".to_owned()];
+        let subscriptions: BTreeMap<String, Vec<String>> = [
+            ("m1".to_owned(), both.clone()),
+            ("m2".to_owned(), both),
+            ("m3".to_owned(), vec!["a".to_owned()]),
+        ]
+        .into();
+        let partitions: BTreeMap<String, u32> = [("a".into(), 4), ("b".into(), 3)].into();
+
+        let range = assign(AssignmentStrategy::Range, &subscriptions, &partitions);
+        assert_eq!(
+            range["m1"],
+            parts(&[("a", 0), ("a", 1), ("b", 0), ("b", 1)])
+        );
+        assert_eq!(range["m2"], parts(&[("a", 2), ("b", 2)]));
+        assert_eq!(range["m3"], parts(&[("a", 3)]));
+
+        let round_robin = assign(AssignmentStrategy::RoundRobin, &subscriptions, &partitions);
+        assert_eq!(round_robin["m1"], parts(&[("a", 0), ("a", 3), ("b", 1)]));
+        assert_eq!(round_robin["m2"], parts(&[("a", 1), ("b", 0), ("b", 2)]));
+        assert_eq!(round_robin["m3"], parts(&[("a", 2)]));
+    }
+
+    fn coordinator(clock: Arc<MockClock>) -> (TempDir, GroupCoordinator) {
+        let dir = tempdir().unwrap();
+        let offsets =
+            Arc::new(OffsetStore::open(&dir.path().join("offsets"), &Config::default()).unwrap());
+        let topics_dir = dir.path().join("topics");
+        std::fs::create_dir(&topics_dir).unwrap();
+        let topics = LogManager::open(&topics_dir, Config::default()).unwrap();
+        topics
+            .create_topic(
+                "events",
+                TopicConfig {
+                    partitions: 4,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let groups = GroupCoordinator::new(offsets, GroupConfig::default(), clock)
+            .with_topics(Arc::new(topics));
+        (dir, groups)
+    }
+
+    fn is_stale(err: anyhow::Error) -> bool {
+        matches!(err.downcast_ref(), Some(Error::StaleGeneration { .. }))
+    }
+
+    #[test]
+    fn rebalances() -> Result<()> {
+        let clock = Arc::new(MockClock::new());
+        let (_dir, groups) = coordinator(clock.clone());
+        let topics = ["events".to_owned()];
+        let join = |id: &str| groups.join("g", id, &topics, AssignmentStrategy::Range, None);
+
+        let a = join("")?;
+        assert_eq!(a.generation, 1);
+        assert_eq!(a.partitions.len(), 4);
+        groups.commit("g", &a.member_id, 1, "events", 0, 10)?;
+
+        // b joining moves the group on; a is fenced until it rejoins, and b
+        // waits for that before it gets any partitions
+        let b = join("")?;
+        assert_eq!(b.generation, 2);
+        assert!(b.pending && b.partitions.is_empty());
+        assert!(is_stale(
+            groups.heartbeat("g", &a.member_id, 1).unwrap_err()
+        ));
+        let err = groups.commit("g", &a.member_id, 1, "events", 0, 11);
+        assert!(is_stale(err.unwrap_err()));
+        let a = join(&a.member_id)?;
+        assert_eq!(a.generation, 2);
+        assert!(!a.pending);
+        let b = join(&b.member_id)?;
+        assert!(!b.pending);
+        assert_eq!(a.partitions.len() + b.partitions.len(), 4);
+        groups.heartbeat("g", &a.member_id, 2)?;
+        let (topic, p) = &a.partitions[0];
+        groups.commit("g", &a.member_id, 2, topic, *p, 11)?;
+        let (topic, p) = &b.partitions[0];
+        let err = groups
+            .commit("g", &a.member_id, 2, topic, *p, 11)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::NotAssigned { .. })
+        ));
+
+        // outsiders, unknown members and other strategies are refused
+        assert!(groups.commit("g", "", 0, "events", 0, 12).is_err());
+        let err = groups.heartbeat("g", "g-unknown", 2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnknownMember { .. })
+        ));
+        assert!(join("g-unknown").is_err());
+        assert!(groups
+            .join("g", "", &topics, AssignmentStrategy::RoundRobin, None)
+            .is_err());
+
+        // b goes quiet and times out; a takes everything
+        clock.advance(Duration::from_secs(6));
+        groups.heartbeat("g", &a.member_id, 2)?;
+        clock.advance(Duration::from_secs(6));
+        assert!(is_stale(
+            groups.heartbeat("g", &a.member_id, 2).unwrap_err()
+        ));
+        let a = join(&a.member_id)?;
+        assert_eq!(a.generation, 3);
+        assert_eq!(a.partitions.len(), 4);
+        assert!(groups.heartbeat("g", &b.member_id, 2).is_err());
+
+        // once the last member leaves the group is gone and anyone may commit
+        groups.leave("g", &a.member_id)?;
+        assert!(groups.members("g").is_empty());
+        assert!(groups.groups.lock().unwrap().is_empty());
+        groups.commit("g", "", 0, "events", 0, 12)?;
+        assert_eq!(groups.offsets.fetch("g", "events", 0), Some(12));
+        assert!(groups.groups.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
@@ -1,34 +1,51 @@
-use std::fs::File;
-use std::io::{ErrorKind, Read, Write};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use log::debug;
-use memmap::MmapMut;
+use log::info;
 
 use crate::config::Config;
+use crate::encryption::{FrameCipher, Sealed};
+use crate::entry_file::{EntryFile, Format, CURRENT_VERSION, HEADER_WIDTH};
 
 const OFF_WIDTH: usize = 4;
 const POS_WIDTH: usize = 8;
 pub(crate) const ENTRY_WIDTH: usize = OFF_WIDTH + POS_WIDTH;
 
+/// Entries of `rel_offset u32 | pos u64`. Index files written before the
+/// header existed hold only the entries and are rewritten by [`migrate`]
+/// when their segment is opened.
+static FORMAT: Format = Format {
+    magic: *b"DIDX",
+    width: ENTRY_WIDTH,
+    sealed: Sealed::IndexEntry,
+    name: "index",
+};
+
+/// Maps offsets relative to the segment's base offset to store positions.
+/// `max_index_bytes` bounds the entries.
 pub(crate) struct Index {
-    file: File,
+    entries: EntryFile,
     /// [`PathBuf`] of the file
     pub(crate) file_path: Option<PathBuf>,
-    size: u64,
-    mmap: MmapMut,
 }
 
 impl Index {
-    pub fn new(file: File, config: &Config) -> std::io::Result<Self> {
-        let size = file.metadata()?.len();
-        file.set_len(config.segment.max_index_bytes)?;
-        let mmap = unsafe { MmapMut::map_mut(&file).unwrap() };
+    /// Opens an index, encrypted if `cipher` is given, see [`EntryFile::open`].
+    /// Fails for one from before the header existed, which needs [`migrate`].
+    pub fn new(file: File, config: &Config, cipher: Option<Arc<FrameCipher>>) -> io::Result<Self> {
+        if file.metadata()?.len() >= HEADER_WIDTH as u64 && FORMAT.header_version(&file)?.is_none()
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "index file has no header, it needs migrating",
+            ));
+        }
+        let max = config.segment.max_index_bytes;
         Ok(Index {
-            file,
+            entries: EntryFile::open(file, &FORMAT, max, cipher)?,
             file_path: None,
-            size,
-            mmap,
         })
     }
 
@@ -37,65 +54,139 @@ impl Index {
         self
     }
 
-    pub fn write(&mut self, off: u32, pos: u64) -> std::io::Result<()> {
-        let s = self.size + ENTRY_WIDTH as u64;
-        let mmap_len = self.mmap.len();
-        if mmap_len < s as usize {
-            return Err(std::io::Error::new(
+    pub fn write(&mut self, off: u32, pos: u64) -> io::Result<()> {
+        let mut entry = [0u8; ENTRY_WIDTH];
+        entry[..OFF_WIDTH].copy_from_slice(&off.to_le_bytes());
+        entry[OFF_WIDTH..].copy_from_slice(&pos.to_le_bytes());
+        self.entries.append(&entry)
+    }
+
+    /// Reads the `n`th entry as `(relative offset, store position)`.
+    pub fn read(&self, n: u64) -> io::Result<(u32, u64)> {
+        if n >= self.len() {
+            return Err(io::Error::new(
                 ErrorKind::UnexpectedEof,
-                format!("mmap length {} is less than {}", mmap_len, s),
+                format!("entry {} is past the {} entries", n, self.len()),
             ));
         }
-        let sz = self.size as usize;
-        (&mut self.mmap[sz..sz + OFF_WIDTH]).write_all(off.to_le_bytes().as_slice())?;
-        (&mut self.mmap[sz + OFF_WIDTH..sz + ENTRY_WIDTH])
-            .write_all(pos.to_le_bytes().as_slice())?;
-        self.size += ENTRY_WIDTH as u64;
-        Ok(())
+        let entry = self.entries.entry(n);
+        let mut off = [0u8; OFF_WIDTH];
+        off.copy_from_slice(&entry[..OFF_WIDTH]);
+        let mut pos = [0u8; POS_WIDTH];
+        pos.copy_from_slice(&entry[OFF_WIDTH..]);
+        Ok((u32::from_le_bytes(off), u64::from_le_bytes(pos)))
     }
 
-    pub fn read(&self, offset: i64) -> std::io::Result<(u32, u64)> {
-        if self.size == 0 {
-            return Err(std::io::Error::new(ErrorKind::UnexpectedEof, ""));
+    /// The last entry, if any.
+    pub fn last(&self) -> io::Result<Option<(u32, u64)>> {
+        match self.len() {
+            0 => Ok(None),
+            n => self.read(n - 1).map(Some),
+        }
+    }
+    /// Returns the number of the first entry whose relative offset is at least
+    /// `off`, or [`Index::len`] if there is none. Offsets grow from entry to
+    /// entry but may have gaps once a segment is compacted.
+    pub fn lower_bound(&self, off: u32) -> io::Result<u64> {
+        let n = off as u64;
+        if n < self.len() && self.read(n)?.0 == off {
+            return Ok(n);
         }
-        let out = {
-            if offset == -1 {
-                ((self.size / ENTRY_WIDTH as u64) - 1) as u32
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.read(mid)?.0 < off {
+                lo = mid + 1;
             } else {
-                offset as u32
+                hi = mid;
             }
-        };
-        let pos = (out as usize * ENTRY_WIDTH) as usize;
-        let mut ba = [0u8; OFF_WIDTH];
-        (&self.mmap[pos..pos + OFF_WIDTH]).read_exact(&mut ba)?;
-        let out = u32::from_le_bytes(ba);
-        let mut ba = [0u8; POS_WIDTH];
-        (&self.mmap[pos + OFF_WIDTH..pos + ENTRY_WIDTH]).read_exact(&mut ba)?;
-        let pos = u64::from_le_bytes(ba);
-        Ok((out, pos))
+        }
+        Ok(lo)
+    }
+
+    /// Returns the last entry whose relative offset is at most `off`.
+    pub fn floor(&self, off: u32) -> io::Result<Option<(u32, u64)>> {
+        let n = self.lower_bound(off)?;
+        if n < self.len() {
+            let e = self.read(n)?;
+            if e.0 == off {
+                return Ok(Some(e));
+            }
+        }
+        if n == 0 {
+            Ok(None)
+        } else {
+            self.read(n - 1).map(Some)
+        }
+    }
+
+    /// Returns the store position of relative offset `off`, if it has an entry.
+    pub fn find(&self, off: u32) -> io::Result<Option<u64>> {
+        let n = self.lower_bound(off)?;
+        if n == self.len() {
+            return Ok(None);
+        }
+        let (out, pos) = self.read(n)?;
+        Ok(if out == off { Some(pos) } else { None })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    pub fn truncate(&mut self, n: u64) -> io::Result<()> {
+        self.entries.truncate(n)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.size == 0
+        self.entries.is_empty()
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.entries.sync()
+    }
+
+    pub fn entry_width(&self) -> u64 {
+        self.entries.entry_width()
     }
 
     pub fn size(&self) -> u64 {
-        self.size
+        self.entries.size()
     }
 
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        self.mmap.flush().expect("Index mmap failed to flush");
-        self.file.flush().expect("Index file failed to flush");
-        debug!("dropping index file and truncate to size={}", self.size);
-        self.file.set_len(self.size)?;
-        Ok(())
+    pub fn close(&mut self) -> io::Result<()> {
+        self.entries.close()
     }
 }
 
-impl Drop for Index {
-    fn drop(&mut self) {
-        self.close().expect("index file fail to close")
+/// Rewrites an index file from before the header existed into the current
+/// format and returns whether it did. The new file is written next to the old
+/// one and renamed over it, so a crash leaves one or the other intact. Legacy
+/// files start with an entry, so one passes for headered only if its first
+/// relative offset spells the magic, 1,480,870,212, and the low half of its
+/// store position is a known version. Compaction can leave the first entry at
+/// any relative offset, so this is not ruled out, only unlikely: it takes a
+/// segment that once spanned that many offsets.
+pub(crate) fn migrate(path: &Path) -> io::Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() < HEADER_WIDTH as u64 || FORMAT.header_version(&file)?.is_some() {
+        return Ok(false);
+    }
+    let tmp = path.with_extension("index.migrating");
+    let mut f = File::create(&tmp)?;
+    f.write_all(&FORMAT.header(CURRENT_VERSION))?;
+    io::copy(&mut file, &mut f)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
     }
+    info!("migrated index {:?} to version {}", path, CURRENT_VERSION);
+    Ok(true)
 }
 
 #[cfg(test)]
@@ -114,26 +205,135 @@ mod tests {
                 max_index_bytes: 1024,
                 ..Default::default()
             },
+            ..Default::default()
         };
-        let mut index = Index::new(file, &config).unwrap();
-        assert!(index.read(-1).is_err());
+        let mut index = Index::new(file, &config, None).unwrap();
+        assert!(index.read(0).is_err());
+        assert_eq!(index.last().unwrap(), None);
 
         struct Entry {
             off: u32,
             pos: u64,
         }
 
-        for e in &vec![Entry { off: 0, pos: 0 }, Entry { off: 1, pos: 10 }] {
+        for e in &[Entry { off: 0, pos: 0 }, Entry { off: 1, pos: 10 }] {
             assert!(index.write(e.off, e.pos).is_ok());
 
-            let t = index.read(e.off as i64).unwrap();
+            let t = index.read(e.off as u64).unwrap();
             assert_eq!(t.0, e.off);
             assert_eq!(t.1, e.pos);
         }
-        assert_eq!(index.size, 2 * ENTRY_WIDTH as u64);
+        assert_eq!(index.size(), 2 * ENTRY_WIDTH as u64);
+
+        assert_eq!(index.last().unwrap(), Some((1, 10)));
+        assert!(index.read(2).is_err());
+    }
+
+    #[test]
+    fn test_index_gaps() {
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut index = Index::new(tempfile().unwrap(), &config, None).unwrap();
+        for (off, pos) in [(0, 0), (3, 10), (4, 20), (9, 30)] {
+            index.write(off, pos).unwrap();
+        }
+        assert_eq!(index.find(0).unwrap(), Some(0));
+        assert_eq!(index.find(1).unwrap(), None);
+        assert_eq!(index.find(4).unwrap(), Some(20));
+        assert_eq!(index.find(9).unwrap(), Some(30));
+        assert_eq!(index.lower_bound(5).unwrap(), 3);
+        assert_eq!(index.lower_bound(10).unwrap(), 4);
+        assert_eq!(index.floor(5).unwrap(), Some((4, 20)));
+        assert_eq!(index.floor(9).unwrap(), Some((9, 30)));
+        assert_eq!(index.floor(100).unwrap(), Some((9, 30)));
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.index");
+        let open = || {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+        let cipher = || {
+            let c = FrameCipher::new(crate::config::Cipher::Aes256Gcm, "k1", &[7; 32]);
+            Some(Arc::new(c.with_context(0, b"DLOG")))
+        };
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut index = Index::new(open(), &config, cipher()).unwrap();
+        for (off, pos) in [(0, 8), (1, 0x5eed), (2, 0x7eed)] {
+            index.write(off, pos).unwrap();
+        }
+        assert_eq!(index.size(), 3 * index.entry_width());
+        drop(index);
+        let b = fs::read(&path).unwrap();
+        assert!(!b.windows(2).any(|w| w == 0x5eedu16.to_le_bytes()));
+
+        // a torn last entry is dropped
+        let file = open();
+        file.set_len(b.len() as u64 - 1).unwrap();
+        let mut index = Index::new(file, &config, cipher()).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.find(1).unwrap(), Some(0x5eed));
+        index.truncate(1).unwrap();
+        drop(index);
+
+        let index = Index::new(open(), &config, cipher()).unwrap();
+        assert_eq!(index.last().unwrap(), Some((0, 8)));
+        drop(index);
+        // opened as plaintext, it is emptied for the segment to rebuild
+        let index = Index::new(open(), &config, None).unwrap();
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.index");
+        let mut legacy = Vec::new();
+        for (off, pos) in [(0u32, 0u64), (1, 10), (2, 20)] {
+            legacy.extend_from_slice(&off.to_le_bytes());
+            legacy.extend_from_slice(&pos.to_le_bytes());
+        }
+        fs::write(&path, &legacy).unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_index_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Index::new(
+            File::options().read(true).write(true).open(&path).unwrap(),
+            &config,
+            None
+        )
+        .is_err());
 
-        let (out, pos) = index.read(-1).unwrap();
-        assert_eq!(out, 1);
-        assert_eq!(pos, 10);
+        assert!(migrate(&path).unwrap());
+        assert!(!migrate(&path).unwrap());
+        let file = File::options().read(true).write(true).open(&path).unwrap();
+        assert_eq!(FORMAT.header_version(&file).unwrap(), Some(CURRENT_VERSION));
+        let index = Index::new(file, &config, None).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.find(1).unwrap(), Some(10));
+        assert_eq!(index.last().unwrap(), Some((2, 20)));
     }
 }
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::encryption::{FrameCipher, Sealed};
+
+const MAGIC: [u8; 4] = *b"DKEY";
+const VERSION: u32 = 1;
+/// `MAGIC | version u32`, then a whole [`VERSION`] snapshot sealed by the
+/// cipher of the segment's store.
+const VERSION_ENCRYPTED: u32 = 2;
+const HEADER_WIDTH: usize = MAGIC.len() + 4 + 8 + 8;
+const CRC_WIDTH: usize = 4;
+
+/// Maps each key in a segment to the relative offset of its latest record.
+///
+/// The map lives in memory and is saved to a snapshot file on close:
+/// `MAGIC | version u32 | next_offset u64 | count u64`, then `count` entries
+/// of `key_len u32 | key | rel_offset u32`, then a crc32c of everything
+/// before it. `next_offset` tells which records the snapshot covers, so
+/// opening only has to replay the records appended after it was saved.
+/// Segments with an encrypted store get the snapshot sealed as a whole, see
+/// [`VERSION_ENCRYPTED`].
+pub(crate) struct KeyIndex {
+    pub(crate) file_path: PathBuf,
+    keys: HashMap<Vec<u8>, u32>,
+    /// Size of the snapshot last loaded or saved.
+    file_size: u64,
+    cipher: Option<Arc<FrameCipher>>,
+}
+
+impl KeyIndex {
+    pub fn new(path: &Path, cipher: Option<Arc<FrameCipher>>) -> Self {
+        KeyIndex {
+            file_path: path.to_path_buf(),
+            keys: HashMap::new(),
+            file_size: 0,
+            cipher,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], rel: u32) {
+        match self.keys.get_mut(key) {
+            Some(r) => *r = rel,
+            None => {
+                self.keys.insert(key.to_vec(), rel);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<u32> {
+        self.keys.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Size in bytes of the snapshot on disk.
+    pub fn size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    /// Loads the snapshot, if there is a valid one, and returns the offset up
+    /// to which it covers the segment.
+    pub fn load(&mut self) -> io::Result<Option<u64>> {
+        let b = match fs::read(&self.file_path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let file_size = b.len() as u64;
+        let sealed = b.len() >= MAGIC.len() + 4 && u32_at(&b, 4) == VERSION_ENCRYPTED;
+        let b = match &self.cipher {
+            Some(c) if sealed => c.open(Sealed::KeyIndex, 0, &b[MAGIC.len() + 4..])?,
+            None if !sealed => b,
+            _ => {
+                return Err(invalid(
+                    "key index snapshot was saved with other encryption",
+                ))
+            }
+        };
+        if b.len() < HEADER_WIDTH + CRC_WIDTH || b[..MAGIC.len()] != MAGIC {
+            return Err(invalid("key index snapshot has no header"));
+        }
+        let (body, crc) = b.split_at(b.len() - CRC_WIDTH);
+        if crc32c::crc32c(body).to_le_bytes() != crc {
+            return Err(invalid("key index snapshot checksum mismatch"));
+        }
+        if u32_at(body, 4) != VERSION {
+            return Err(invalid("unsupported key index snapshot version"));
+        }
+        let next_offset = u64::from_le_bytes(body[8..16].try_into().unwrap());
+        let count = u64::from_le_bytes(body[16..24].try_into().unwrap());
+        let mut keys = HashMap::new();
+        let mut at = HEADER_WIDTH;
+        for _ in 0..count {
+            if at + 4 > body.len() {
+                return Err(invalid("key index snapshot is truncated"));
+            }
+            let len = u32_at(body, at) as usize;
+            at += 4;
+            if at + len + 4 > body.len() {
+                return Err(invalid("key index snapshot is truncated"));
+            }
+            let key = body[at..at + len].to_vec();
+            at += len;
+            keys.insert(key, u32_at(body, at));
+            at += 4;
+        }
+        self.keys = keys;
+        self.file_size = file_size;
+        Ok(Some(next_offset))
+    }
+
+    /// Saves the map as covering the records below `next_offset`. The snapshot
+    /// is written next to the old one and renamed over it.
+    pub fn save(&mut self, next_offset: u64) -> io::Result<()> {
+        let mut b = Vec::with_capacity(HEADER_WIDTH);
+        b.extend_from_slice(&MAGIC);
+        b.extend_from_slice(&VERSION.to_le_bytes());
+        b.extend_from_slice(&next_offset.to_le_bytes());
+        b.extend_from_slice(&(self.keys.len() as u64).to_le_bytes());
+        for (key, rel) in &self.keys {
+            b.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            b.extend_from_slice(key);
+            b.extend_from_slice(&rel.to_le_bytes());
+        }
+        let crc = crc32c::crc32c(&b);
+        b.extend_from_slice(&crc.to_le_bytes());
+        if let Some(c) = &self.cipher {
+            let mut sealed = MAGIC.to_vec();
+            sealed.extend_from_slice(&VERSION_ENCRYPTED.to_le_bytes());
+            sealed.extend(c.seal(Sealed::KeyIndex, 0, &b));
+            b = sealed;
+        }
+
+        let tmp = self.file_path.with_extension("keyindex.saving");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&b)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.file_path)?;
+        // the rename is only durable once the directory is synced
+        if let Some(dir) = self.file_path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.file_size = b.len() as u64;
+        Ok(())
+    }
+
+    /// Deletes the snapshot file, if any.
+    pub fn remove(&self) -> io::Result<()> {
+        remove(&self.file_path)
+    }
+}
+
+/// Deletes a snapshot that may be left over from when the key index was on.
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn save_load() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.keyindex");
+        let mut index = KeyIndex::new(&path, None);
+        assert_eq!(index.load().unwrap(), None);
+        index.insert(b"a", 0);
+        index.insert(b"b", 1);
+        index.insert(b"a", 2);
+        assert_eq!(index.get(b"a"), Some(2));
+        index.save(3).unwrap();
+
+        let mut loaded = KeyIndex::new(&path, None);
+        assert_eq!(loaded.load().unwrap(), Some(3));
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(b"a"), Some(2));
+        assert_eq!(loaded.get(b"b"), Some(1));
+        assert_eq!(loaded.get(b"c"), None);
+        assert_eq!(loaded.size(), index.size());
+        assert_eq!(index.size(), fs::metadata(&path).unwrap().len());
+
+        let mut b = fs::read(&path).unwrap();
+        b[HEADER_WIDTH] ^= 1;
+        fs::write(&path, b).unwrap();
+        assert!(KeyIndex::new(&path, None).load().is_err());
+    }
+
+    #[test]
+    fn sealed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("0.keyindex");
+        let cipher = || {
+            let c = FrameCipher::new(crate::config::Cipher::Aes256Gcm, "k1", &[7; 32]);
+            Some(Arc::new(c.with_context(0, b"DLOG")))
+        };
+        let mut index = KeyIndex::new(&path, cipher());
+        index.insert(b"secret-key", 4);
+        index.save(5).unwrap();
+        let b = fs::read(&path).unwrap();
+        assert!(!b.windows(10).any(|w| w == b"secret-key"));
+
+        let mut loaded = KeyIndex::new(&path, cipher());
+        assert_eq!(loaded.load().unwrap(), Some(5));
+        assert_eq!(loaded.get(b"secret-key"), Some(4));
+        assert_eq!(loaded.size(), b.len() as u64);
+        assert!(KeyIndex::new(&path, None).load().is_err());
+    }
+}
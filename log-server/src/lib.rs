@@ -1,8 +1,51 @@
 #![allow(dead_code)]
 
+//! A segmented, append-only commit log.
+//!
+//! [`Log`] is the entry point; it is safe to share between threads.
+//! [`LogManager`] keeps many of them as the partitions of named topics.
+//! [`ReplicatedLog`] replicates one across a cluster with Raft, and
+//! [`Membership`] lets the nodes of a cluster find each other.
+
+mod batch;
 mod config;
+mod encryption;
+mod entry_file;
+mod error;
+mod group;
 mod index;
+mod key_index;
 mod log;
+mod manager;
+mod membership;
 mod multi_reader;
+mod notify;
+mod offsets;
+mod producer;
+mod raft_log;
+mod replication;
+mod retention;
 mod segment;
+mod server;
 mod store;
+mod time_index;
+mod transport;
+
+pub use crate::batch::CompressionStats;
+pub use crate::config::{
+    Cipher, CleanupPolicy, Compression, Config, Durability, EncryptionConfig, GroupConfig,
+    MembershipConfig, RaftConfig, SegmentConfig, TimestampType, TopicConfig,
+};
+pub use crate::encryption::{EnvKeyProvider, FileKeyProvider, Key, KeyProvider, KEY_WIDTH};
+pub use crate::error::Error;
+pub use crate::group::{Assignment, Clock, GroupCoordinator, MockClock, SystemClock};
+pub use crate::log::{Log, LogIter, LogReader};
+pub use crate::manager::LogManager;
+pub use crate::membership::{Member, MemberEvent, Membership, MembershipHandler};
+pub use crate::offsets::{OffsetStore, OFFSETS_LOG};
+pub use crate::producer::new_producer_id;
+pub use crate::replication::ReplicatedLog;
+pub use crate::retention::{CompactionStats, RemovedSegment, RetentionCleaner};
+pub use crate::server::{LogService, RaftService};
+pub use crate::transport::{GrpcTransport, MemNetwork, Transport};
+pub use protos::log::v1::{AssignmentStrategy, Header, Record};
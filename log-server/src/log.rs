@@ -1,29 +1,70 @@
-use std::collections::HashSet;
-use std::fs::read_dir;
-use std::io::Read;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, read_dir};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::slice;
 use std::sync;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Result};
-use log::debug;
+use async_stream::try_stream;
+use log::{debug, info, warn};
+use tokio_stream::Stream;
 
-use protos::log::v1::Record;
+use protos::log::v1::{ProducerBatch, Record};
 
-use crate::config::Config;
+use crate::batch::{BatchReader, CompressionStats};
+use crate::config::{Config, Durability};
+use crate::error::Error;
 use crate::multi_reader::MultiReader;
-use crate::segment::Segment;
-use crate::store::StoreReader;
+use crate::notify::OffsetNotifier;
+use crate::producer::{BatchProducer, ProducerState, SNAPSHOT_FILE};
+use crate::retention::{CompactionStats, RemovedSegment};
+use crate::segment::{now_millis, Segment};
+use crate::store::{StoreReader, VERSION_BATCH};
 
-struct Log {
-    lock: sync::RwLock<()>,
+/// Subdirectory compaction writes cleaned segments to before swapping them in.
+const COMPACTION_DIR: &str = "compaction";
+/// [`COMPACTION_DIR`] once every cleaned segment in it is on disk. Renaming
+/// it is what commits a compaction: a log opened with this directory around
+/// moves its segments into place, one opened with [`COMPACTION_DIR`] around
+/// drops it.
+const COMPACTED_DIR: &str = "compaction.done";
+/// Most records [`Log::subscribe`] reads in one go on the blocking pool.
+const SUBSCRIBE_CHUNK: usize = 256;
+
+/// An append-only log of [`Record`]s stored as a sequence of segments in one
+/// directory.
+///
+/// Every method takes `&self` and locks internally, so a `Log` is `Send + Sync`
+/// and can be shared between producers and consumers by wrapping it in an
+/// [`Arc`](std::sync::Arc).
+pub struct Log {
     dir: PathBuf,
     config: Config,
-    segments: Vec<Segment>,
-    active_segment_idx: Option<usize>,
+    /// Ordered by base offset; the last segment is the active one.
+    segments: sync::RwLock<Vec<Segment>>,
+    head: OffsetNotifier,
+    sync_state: sync::Mutex<SyncState>,
+    /// Last batch of every idempotent producer. Locked after the segments
+    /// when both are.
+    producers: sync::Mutex<ProducerState>,
+    /// Held by [`Log::compact`], which owns [`COMPACTION_DIR`] meanwhile.
+    compacting: sync::Mutex<()>,
+}
+
+/// Appends since the last fsync, which [`Durability`] decides when to flush.
+struct SyncState {
+    unsynced: u64,
+    last_sync: Instant,
+    /// Highest offset known to be on disk.
+    durable_offset: Option<u64>,
 }
 
 impl Log {
-    fn new(dir: &Path, config: Config) -> Result<Log> {
+    /// Opens the log in `dir`, recovering any segments already there.
+    pub fn new(dir: &Path, config: Config) -> Result<Log> {
         if !dir.is_dir() {
             return Err(anyhow!("{:?} is not a directory", dir));
         }
@@ -34,66 +75,317 @@ impl Log {
         if config.segment.max_index_bytes == 0 {
             config.segment.max_index_bytes = 1024;
         }
-        let mut log = Log {
-            lock: sync::RwLock::new(()),
+        if config.segment.max_time_index_bytes == 0 {
+            config.segment.max_time_index_bytes = 1024;
+        }
+        let (producers, checkpoint) =
+            ProducerState::load(dir, config.durability != Durability::OsManaged)?;
+        let log = Log {
             dir: dir.into(),
             config,
-            segments: vec![],
-            active_segment_idx: None,
+            segments: sync::RwLock::new(vec![]),
+            head: OffsetNotifier::new(0),
+            sync_state: sync::Mutex::new(SyncState {
+                unsynced: 0,
+                last_sync: Instant::now(),
+                durable_offset: None,
+            }),
+            producers: sync::Mutex::new(producers),
+            compacting: sync::Mutex::new(()),
         };
-        log.setup()?;
+        // finish a compaction a crash interrupted after it committed, or
+        // drop one interrupted before
+        if dir.join(COMPACTED_DIR).is_dir() {
+            install_compacted(dir)?;
+        }
+        let tmp = dir.join(COMPACTION_DIR);
+        if tmp.is_dir() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        log.setup(checkpoint)?;
         Ok(log)
     }
 
-    fn new_segment(&mut self, off: u64) -> Result<()> {
+    fn new_segment(&self, segments: &mut Vec<Segment>, off: u64) -> Result<()> {
         let s = Segment::new(&self.dir, off, &self.config)?;
-        self.segments.push(s);
-        self.active_segment_idx = Some(self.segments.len() - 1);
+        if self.config.durability != Durability::OsManaged {
+            // make the new files' directory entries durable too
+            fs::File::open(&self.dir)?.sync_all()?;
+        }
+        segments.push(s);
         Ok(())
     }
 
-    fn append(&mut self, record: &mut Record) -> Result<u64> {
-        let _l = self.lock.get_mut().expect("failed to get mutable lock");
-        if self.active_segment_idx.is_none() {
-            return Err(anyhow!("there is not active segment"));
+    /// Appends `record`, sets its offset and returns it once the configured
+    /// [`Durability`] is met for the store and index files.
+    pub fn append(&self, record: &mut Record) -> Result<u64> {
+        Ok(self.append_batch(slice::from_mut(record))?.start)
+    }
+
+    /// Appends `records` under contiguous offsets with a single store write and
+    /// returns their range. A batch always lands in one segment: if the active
+    /// segment cannot take all of it, the log rolls first.
+    pub fn append_batch(&self, records: &mut [Record]) -> Result<Range<u64>> {
+        self.append_records(records, None)
+    }
+
+    /// Appends `records` as [`Log::append_batch`] does, as a batch of
+    /// `producer` if there is one, unless it is a retry of the producer's
+    /// last batch.
+    fn append_records(
+        &self,
+        records: &mut [Record],
+        producer: Option<BatchProducer>,
+    ) -> Result<Range<u64>> {
+        let mut segments = self.segments.write().unwrap();
+        if let Some(p) = producer {
+            if let Some(range) = self.producers.lock().unwrap().check(p)? {
+                debug!(
+                    "producer {} retried batch {} at {:?}",
+                    p.id, p.sequence, range
+                );
+                return Ok(range);
+            }
         }
-        let s = self
-            .segments
-            .get_mut(self.active_segment_idx.unwrap())
-            .expect("no segment at the active segment idx");
-        let offset = s.append(record)?;
+        let s = segments
+            .last_mut()
+            .ok_or_else(|| anyhow!("there is not active segment"))?;
+        if records.is_empty() {
+            return Ok(s.next_offset..s.next_offset);
+        }
+        let range = match s.try_append_batch(records, producer)? {
+            Some(range) => range,
+            None => {
+                let next = s.next_offset;
+                self.roll(&mut segments, next)?;
+                let s = segments.last_mut().expect("active segment");
+                s.append_batch(records, producer)?
+            }
+        };
+        if let Some(p) = producer {
+            self.producers
+                .lock()
+                .unwrap()
+                .update(p, range.clone(), now_millis());
+        }
+
+        let s = segments.last().expect("active segment");
+        let synced = self.synced_if_due(s, records.len());
+        // the records are in the store even if the fsync failed, so the head
+        // moves past them either way
+        self.head.advance(range.end);
+        synced?;
         if s.is_maxed() {
-            self.new_segment(offset + 1)
-                .expect("error adding new segment");
+            // a failed roll is retried by the next append, which finds no room
+            self.roll(&mut segments, range.end)?;
         }
-        Ok(offset)
+        Ok(range)
     }
 
-    fn read(&self, off: u64) -> Result<Record> {
-        let _l = self.lock.read().unwrap();
-        let s = self
-            .segments
+    /// Counts `n` appended records and fsyncs the active segment `s` if
+    /// [`Durability`] says it is time.
+    fn synced_if_due(&self, s: &Segment, n: usize) -> Result<()> {
+        let mut state = self.sync_state.lock().unwrap();
+        state.unsynced += n as u64;
+        let due = match self.config.durability {
+            Durability::OsManaged => false,
+            Durability::SyncEveryAppend => true,
+            Durability::SyncEveryNRecords(n) => state.unsynced >= n,
+            Durability::SyncInterval(d) => state.last_sync.elapsed() >= d,
+        };
+        if due {
+            s.sync()?;
+            state.synced(s.next_offset - 1);
+        }
+        Ok(())
+    }
+
+    /// Closes the active segment and starts a new one at `next`. The
+    /// producers are checkpointed first, so their snapshot always covers
+    /// the closed segments, which compaction rewrites without producers.
+    fn roll(&self, segments: &mut Vec<Segment>, next: u64) -> Result<()> {
+        self.checkpoint_producers(next)?;
+        if self.config.durability != Durability::OsManaged {
+            // a segment is never written again once rolled, so sync it on the way out
+            let s = segments.last().expect("active segment");
+            s.sync()?;
+            if s.next_offset > s.base_offset {
+                self.sync_state.lock().unwrap().synced(s.next_offset - 1);
+            }
+        }
+        self.new_segment(segments, next)
+    }
+
+    /// fsyncs every segment holding records past the durable offset,
+    /// regardless of [`Durability`]; usually only the active one. Blocks, so
+    /// async callers should run it with `spawn_blocking`.
+    pub fn sync(&self) -> Result<()> {
+        let segments = self.segments.read().unwrap();
+        let mut state = self.sync_state.lock().unwrap();
+        let durable = state.durable_offset;
+        let dirty = segments
+            .iter()
+            .rev()
+            .take_while(|s| durable.is_none_or(|d| s.next_offset > d + 1))
+            .filter(|s| s.next_offset > s.base_offset);
+        let mut new_files = false;
+        for s in dirty {
+            s.sync()?;
+            new_files |= durable.is_none_or(|d| s.base_offset > d);
+        }
+        if new_files && self.config.durability == Durability::OsManaged {
+            // other durabilities synced the directory when the segment was made
+            fs::File::open(&self.dir)?.sync_all()?;
+        }
+        if let Some(s) = segments.last() {
+            if s.next_offset > s.base_offset {
+                state.synced(s.next_offset - 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Highest offset known to survive power loss, or `None` if nothing has
+    /// been synced yet. Records found when the log was opened count as synced.
+    pub fn durable_offset(&self) -> Option<u64> {
+        self.sync_state.lock().unwrap().durable_offset
+    }
+
+    /// Reads the record at offset `off`.
+    pub fn read(&self, off: u64) -> Result<Record> {
+        let segments = self.segments.read().unwrap();
+        match segments
             .iter()
             .find(|&s| s.base_offset <= off && s.next_offset > off)
-            .ok_or_else(|| anyhow!(format!("offset={} is out of range", off)))?;
-        s.read(off)
+        {
+            Some(s) => s.read(off),
+            None => Err(missing(&segments, off).into()),
+        }
     }
 
-    fn lowest_offset(&self) -> Result<u64> {
-        let _l = self.lock.read().unwrap();
-        let s = self
-            .segments
-            .get(0)
+    /// Reads the record at `off`, blocking for up to `timeout` if it has not
+    /// been appended yet. Offsets below the log are out of range immediately.
+    pub fn read_or_wait(&self, off: u64, timeout: Duration) -> Result<Record> {
+        self.head.wait_past(off, timeout);
+        self.read(off)
+    }
+
+    /// Async counterpart of [`Log::read_or_wait`].
+    pub async fn read_or_wait_async(&self, off: u64, timeout: Duration) -> Result<Record> {
+        self.head.wait_past_async(off, timeout).await;
+        self.read(off)
+    }
+
+    /// Streams every record from `from_offset` on and then follows the head of
+    /// the log, like `tail -f`. The stream ends after the first error, e.g. when
+    /// `from_offset` has already been truncated away, or with
+    /// [`Error::Truncated`] once records it streamed are truncated.
+    pub fn subscribe(
+        self: sync::Arc<Self>,
+        from_offset: u64,
+    ) -> impl Stream<Item = Result<Record>> + Send + 'static {
+        try_stream! {
+            let mut offset = from_offset;
+            let mut sub = self.head.subscribe();
+            loop {
+                let (next, low) = self.head.observe(&mut sub);
+                if let Some(low) = low.filter(|&low| low < offset) {
+                    Err(Error::Truncated { offset, next_offset: low })?;
+                }
+                while offset < next {
+                    // reads are disk I/O, so they run off the async runtime;
+                    // compacted offsets are skipped
+                    let log = self.clone();
+                    let from = offset;
+                    let records = tokio::task::spawn_blocking(move || {
+                        log.iter_from(from)
+                            .take_while(|r| r.as_ref().map_or(true, |r| r.offset < next))
+                            .take(SUBSCRIBE_CHUNK)
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .await??;
+                    offset = match records.last() {
+                        Some(r) if records.len() == SUBSCRIBE_CHUNK => r.offset + 1,
+                        _ => next,
+                    };
+                    for r in records {
+                        yield r;
+                    }
+                }
+                if !sub.changed().await {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Returns the first offset whose timestamp is at or after `ts`, or `None`
+    /// if every record is older. Segments are binary-searched by their largest
+    /// timestamp, which assumes timestamps mostly grow with offsets.
+    pub fn offset_for_timestamp(&self, ts: i64) -> Result<Option<u64>> {
+        let segments = self.segments.read().unwrap();
+        let i = segments.partition_point(|s| s.max_timestamp < ts);
+        match segments.get(i) {
+            Some(s) => s.offset_for_timestamp(ts),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the latest record with `key`, looking through the segments' key
+    /// indexes from the newest one back. Returns `None` if no record has the
+    /// key or the latest one is a tombstone. Without
+    /// [`SegmentConfig::key_index`](crate::SegmentConfig::key_index) every
+    /// segment is scanned instead.
+    pub fn read_latest_by_key(&self, key: &[u8]) -> Result<Option<Record>> {
+        let segments = self.segments.read().unwrap();
+        for s in segments.iter().rev() {
+            if let Some(r) = s.read_by_key(key)? {
+                return Ok(Some(r).filter(|r| !r.value.is_empty()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterates over the records from `offset` up to the head of the log at
+    /// the time of the call, reading a store frame at a time. Iterating from
+    /// the back walks the same records newest first. Compacted offsets are
+    /// skipped, an `offset` past the head yields nothing, and one that was
+    /// truncated away yields [`Error::OffsetOutOfRange`]. The log lock is only
+    /// held while a frame is read.
+    pub fn iter_from(&self, offset: u64) -> LogIter<'_> {
+        let segments = self.segments.read().unwrap();
+        let back = segments.last().map_or(0, |s| s.next_offset);
+        LogIter {
+            log: self,
+            front: offset,
+            back,
+            front_records: VecDeque::new(),
+            back_records: VecDeque::new(),
+            failed: false,
+        }
+    }
+
+    /// Offset the next appended record will get.
+    pub(crate) fn next_offset(&self) -> u64 {
+        self.head.next_offset()
+    }
+
+    /// Offset of the oldest record still in the log.
+    pub fn lowest_offset(&self) -> Result<u64> {
+        let segments = self.segments.read().unwrap();
+        let s = segments
+            .first()
             .ok_or_else(|| anyhow!("segments is empty"))?;
         Ok(s.base_offset)
     }
 
-    fn highest_offset(&self) -> Result<u64> {
-        let _l = self.lock.read().unwrap();
-        let s = self
-            .segments
-            .last()
-            .ok_or_else(|| anyhow!("empty segments"))?;
+    /// Offset of the newest record, one below the offset the next record
+    /// gets. A log nothing was appended to yet returns one below
+    /// [`SegmentConfig::initial_offset`](crate::SegmentConfig::initial_offset),
+    /// or 0 if that is 0.
+    pub fn highest_offset(&self) -> Result<u64> {
+        let segments = self.segments.read().unwrap();
+        let s = segments.last().ok_or_else(|| anyhow!("empty segments"))?;
         let off = s.next_offset;
         if off == 0 {
             Ok(off)
@@ -102,87 +394,637 @@ impl Log {
         }
     }
 
-    fn close(&mut self) -> Result<()> {
-        let _l = self.lock.write().unwrap();
-        for s in &mut self.segments {
+    /// Appends `records` like [`Log::append_batch`] as batch `sequence` of
+    /// `producer_id`, which numbers its batches to this log in order from
+    /// any start. A retry of the producer's last batch is not appended again;
+    /// its offsets are returned instead. Any other sequence but the next
+    /// fails with [`Error::OutOfOrderSequence`]. The producer and sequence
+    /// are stored with the batch, so retries are recognized after a crash.
+    pub fn append_idempotent(
+        &self,
+        producer_id: u64,
+        sequence: u64,
+        records: &mut [Record],
+    ) -> Result<Range<u64>> {
+        let producer = BatchProducer {
+            id: producer_id,
+            sequence,
+        };
+        self.append_records(records, Some(producer))
+    }
+
+    /// Offsets of `p`'s batch if it is a retry of the producer's last one;
+    /// see [`Log::append_idempotent`].
+    pub(crate) fn check_producer(&self, p: BatchProducer) -> Result<Option<Range<u64>>> {
+        self.producers.lock().unwrap().check(p)
+    }
+
+    /// The last batch of each producer, among those ending in `range`.
+    pub(crate) fn producer_batches(&self, range: Range<u64>) -> Vec<ProducerBatch> {
+        self.producers.lock().unwrap().ending_in(range)
+    }
+
+    /// Takes the last batches of producers whose records were appended
+    /// without them, as a replica does with those of a snapshot it
+    /// installs, and checkpoints them since the log cannot tell them again.
+    pub(crate) fn restore_producers(&self, batches: Vec<ProducerBatch>) -> Result<()> {
+        if batches.is_empty() {
+            return Ok(());
+        }
+        let segments = self.segments.read().unwrap();
+        let mut producers = self.producers.lock().unwrap();
+        for b in batches {
+            producers.insert(b);
+        }
+        drop(producers);
+        self.checkpoint_producers(segments.last().expect("active segment").next_offset)
+    }
+
+    /// Forgets the idempotent producers idle for longer than
+    /// [`Config::producer_expiry`].
+    fn expire_producers(&self, producers: &mut ProducerState) {
+        if let Some(expiry) = self.config.producer_expiry {
+            producers.expire(now_millis() - expiry.as_millis() as i64);
+        }
+    }
+
+    /// Saves the producer snapshot as of `next_offset`, the end of the log.
+    fn checkpoint_producers(&self, next_offset: u64) -> Result<()> {
+        let mut producers = self.producers.lock().unwrap();
+        self.expire_producers(&mut producers);
+        producers.checkpoint(next_offset)
+    }
+
+    /// Flushes and truncates every segment file. The log stays readable.
+    pub fn close(&self) -> Result<()> {
+        let mut segments = self.segments.write().unwrap();
+        for s in segments.iter_mut() {
             s.close()?
         }
+        if let Some(s) = segments.last() {
+            self.checkpoint_producers(s.next_offset)?;
+        }
         Ok(())
     }
 
-    fn setup(&mut self) -> Result<()> {
+    /// Opens the segments in the directory and rebuilds the producers from
+    /// the batches past their `checkpoint`.
+    fn setup(&self, checkpoint: u64) -> Result<()> {
         let paths = read_dir(&self.dir)?;
         let files: Vec<PathBuf> = paths
             .filter(|entry| entry.is_ok())
             .map(|entry| entry.unwrap().path())
             .filter(|p| p.is_file())
+            .filter(|p| {
+                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                !name.starts_with(SNAPSHOT_FILE)
+            })
             .collect();
         let mut base_offsets = HashSet::new();
         for path in &files {
-            let off_str = path.file_stem().unwrap();
-            let off = off_str.to_str().unwrap().parse::<u64>().unwrap();
-            base_offsets.insert(off);
+            let off = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok());
+            match off {
+                Some(off) => {
+                    base_offsets.insert(off);
+                }
+                None => warn!("skipping unrecognized file {:?}", path),
+            }
         }
         let mut base_offsets = Vec::from_iter(base_offsets);
         base_offsets.sort_unstable();
+        let mut segments = self.segments.write().unwrap();
         for base_offset in base_offsets {
             debug!("init from base_offsets={}", base_offset);
-            self.new_segment(base_offset)?;
+            self.new_segment(&mut segments, base_offset)?;
         }
-        if self.segments.is_empty() {
+        if segments.is_empty() {
             debug!("create new segment");
-            self.new_segment(self.config.segment.initial_offset)?;
+            self.new_segment(&mut segments, self.config.segment.initial_offset)?;
+        }
+        let last = segments.last().expect("active segment");
+        self.head.advance(last.next_offset);
+        if last.next_offset > last.base_offset {
+            self.sync_state.lock().unwrap().durable_offset = Some(last.next_offset - 1);
         }
 
+        // the checkpoint may hold batches a crash lost or retention removed
+        let next = last.next_offset;
+        let mut producers = self.producers.lock().unwrap();
+        producers.truncate_from(next);
+        producers.remove_below(segments[0].base_offset);
+        let from = checkpoint.min(next);
+        for s in segments.iter().filter(|s| s.next_offset > from) {
+            for b in s.producer_batches(from)? {
+                producers.insert(b);
+            }
+        }
         Ok(())
     }
 
-    fn reader(&'_ self) -> Box<dyn Read + '_> {
+    /// Returns a reader over the raw store frames of every segment, oldest first.
+    /// [`Log::iter_from`] yields decoded records instead.
+    ///
+    /// The reader does not hold the log lock; it sees the segments that existed
+    /// when it was created.
+    pub fn reader(&self) -> LogReader {
         let mut mr = MultiReader::default();
-        let _l = self.lock.read().expect("fail to acquire log read lock");
-        for segment in &self.segments {
-            let sr = StoreReader {
-                store: &segment.store,
-                off: 0,
+        let segments = self.segments.read().expect("fail to acquire log read lock");
+        for segment in segments.iter() {
+            let store = segment.store.clone();
+            let r: Box<dyn SegmentReader> = if store.version() >= VERSION_BATCH {
+                Box::new(BatchReader::new(store))
+            } else {
+                let start = store.data_start();
+                Box::new(StoreReader::new(store, start))
             };
-            mr.inner.push_back(sr)
+            mr.inner.push_back(r)
         }
-        Box::new(mr)
+        LogReader { inner: mr }
     }
 
-    fn truncate(&mut self, loweset: u64) -> Result<()> {
-        let _l = self
-            .lock
+    /// Like [`Log::reader`], but starting at the frame of the record at
+    /// `offset`, or of the next record if it was compacted away. Stream
+    /// positions count from there.
+    pub fn reader_from(&self, offset: u64) -> Result<LogReader> {
+        let mut mr = MultiReader::default();
+        let segments = self.segments.read().expect("fail to acquire log read lock");
+        let in_range = segments.first().is_some_and(|s| s.base_offset <= offset)
+            && segments.last().is_some_and(|s| offset <= s.next_offset);
+        if !in_range {
+            return Err(Error::OffsetOutOfRange { offset }.into());
+        }
+        for segment in segments.iter().filter(|s| s.next_offset > offset) {
+            let pos = match segment.scan(offset).next() {
+                Some(e) => e?.0,
+                None => continue,
+            };
+            let store = segment.store.clone();
+            let r: Box<dyn SegmentReader> = if store.version() >= VERSION_BATCH {
+                Box::new(BatchReader::starting_at(store, pos, offset))
+            } else {
+                Box::new(StoreReader::new(store, pos))
+            };
+            mr.inner.push_back(r)
+        }
+        Ok(LogReader { inner: mr })
+    }
+
+    /// Removes every closed segment whose records are all at or below
+    /// `lowest`. The active segment is kept, so appends go on where they were.
+    pub fn truncate(&self, lowest: u64) -> Result<()> {
+        let mut segments = self
+            .segments
             .write()
             .expect("acquire write lock during truncate");
-        self.segments.retain_mut(|s| {
-            if s.next_offset <= loweset + 1 {
-                s.remove().expect("remove segment");
-                false
-            } else {
-                true
+        while segments.len() > 1 && segments[0].next_offset <= lowest + 1 {
+            segments[0].remove()?;
+            segments.remove(0);
+        }
+        let lowest = segments[0].base_offset;
+        self.producers.lock().unwrap().remove_below(lowest);
+        Ok(())
+    }
+
+    /// Removes every record at or past `offset`, the opposite end from
+    /// [`Log::truncate`]. Replication uses it to drop entries a new leader
+    /// does not have.
+    pub(crate) fn truncate_from(&self, offset: u64) -> Result<()> {
+        let mut segments = self.segments.write().unwrap();
+        while segments.len() > 1 && segments.last().is_some_and(|s| s.base_offset >= offset) {
+            segments.pop().expect("segment").remove()?;
+        }
+        let s = segments.last_mut().expect("active segment");
+        if s.base_offset > offset {
+            s.remove()?;
+            segments.clear();
+            self.new_segment(&mut segments, offset)?;
+        } else {
+            s.truncate_from(offset)?;
+            s.sync()?;
+        }
+        let next = segments.last().expect("active segment").next_offset;
+        let mut state = self.sync_state.lock().unwrap();
+        state.durable_offset = state.durable_offset.filter(|&o| o < next);
+        self.head.advance(next);
+        self.producers.lock().unwrap().truncate_from(next);
+        self.checkpoint_producers(next)
+    }
+
+    /// Removes every record and starts over with an empty segment at
+    /// `offset`, as a replica does on taking a snapshot past its entries.
+    pub(crate) fn reset(&self, offset: u64) -> Result<()> {
+        let mut segments = self.segments.write().unwrap();
+        while let Some(s) = segments.last_mut() {
+            s.remove()?;
+            segments.pop();
+        }
+        self.producers.lock().unwrap().truncate_from(0);
+        self.checkpoint_producers(offset)?;
+        self.new_segment(&mut segments, offset)?;
+        fs::File::open(&self.dir)?.sync_all()?;
+        self.sync_state.lock().unwrap().durable_offset = None;
+        self.head.advance(offset);
+        Ok(())
+    }
+
+    /// Appends `records` keeping the offsets and timestamps they already
+    /// have, as a batch of `producer` if there is one, as a replica applies
+    /// what its leader assigned.
+    pub(crate) fn append_at(
+        &self,
+        records: &[Record],
+        producer: Option<BatchProducer>,
+    ) -> Result<()> {
+        let mut segments = self.segments.write().unwrap();
+        let s = segments.last_mut().expect("active segment");
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            return Ok(());
+        };
+        if !s.try_append_at(records, producer)? {
+            let next = s.next_offset;
+            self.roll(&mut segments, next)?;
+            segments
+                .last_mut()
+                .expect("active segment")
+                .append_at(records, producer)?;
+        }
+        if let Some(p) = producer {
+            let range = first.offset..last.offset + 1;
+            self.producers
+                .lock()
+                .unwrap()
+                .update(p, range, now_millis());
+        }
+        let s = segments.last().expect("active segment");
+        let next = s.next_offset;
+        let synced = self.synced_if_due(s, records.len());
+        self.head.advance(next);
+        synced?;
+        if s.is_maxed() {
+            self.roll(&mut segments, next)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest closed segments that exceed
+    /// [`Config::retention_bytes`] or [`Config::retention_duration`] and reports
+    /// them. The active segment is never removed.
+    pub fn enforce_retention(&self) -> Result<Vec<RemovedSegment>> {
+        let mut segments = self.segments.write().unwrap();
+        let mut total: u64 = segments.iter().map(|s| s.size()).sum();
+        let expired_before = self
+            .config
+            .retention_duration
+            .map(|d| now_millis() - d.as_millis() as i64);
+
+        let mut removed = vec![];
+        while segments.len() > 1 {
+            let s = &segments[0];
+            let over_size = self.config.retention_bytes.is_some_and(|max| total > max);
+            let expired = expired_before.is_some_and(|before| s.max_timestamp < before);
+            if !over_size && !expired {
+                break;
             }
-        });
+            let r = RemovedSegment {
+                base_offset: s.base_offset,
+                next_offset: s.next_offset,
+                bytes: s.size(),
+            };
+            // the files go first, so a failure leaves the segment in the list
+            segments[0].remove()?;
+            segments.remove(0);
+            info!(
+                "retention removed segment base_offset={} next_offset={} bytes={}",
+                r.base_offset, r.next_offset, r.bytes
+            );
+            total -= r.bytes;
+            removed.push(r);
+        }
+        let mut producers = self.producers.lock().unwrap();
+        producers.remove_below(segments[0].base_offset);
+        self.expire_producers(&mut producers);
+        Ok(removed)
+    }
+
+    /// Rewrites every closed segment so that only the latest record per key
+    /// survives, keeping original offsets. Records without a key are always
+    /// kept; tombstones, records with a key and an empty value, are dropped
+    /// once their segment was last appended to longer than
+    /// [`Config::tombstone_retention`] ago.
+    ///
+    /// The closed segments are read and rewritten without the log's lock, so
+    /// appends and reads go on meanwhile; the lock is only taken to swap the
+    /// cleaned segments in. The swap survives a crash whole or not at all.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        let _compacting = self.compacting.lock().unwrap();
+        let mut stats = CompactionStats::default();
+        let closed = {
+            let segments = self.segments.read().unwrap();
+            let n = segments.len().saturating_sub(1);
+            segments[..n]
+                .iter()
+                .map(|s| s.closed())
+                .collect::<Result<Vec<_>>>()?
+        };
+        let Some(end) = closed.last().map(|s| s.next_offset) else {
+            return Ok(stats);
+        };
+
+        let mut latest = HashMap::new();
+        for s in &closed {
+            for records in s.frames() {
+                for r in records? {
+                    if let Some(key) = r.key {
+                        latest.insert(key, r.offset);
+                    }
+                }
+            }
+        }
+        // records appended since can still supersede the closed ones
+        for r in self.iter_from(end) {
+            let r = r?;
+            if let Some(key) = r.key {
+                latest.insert(key, r.offset);
+            }
+        }
+        let tombstones_before = SystemTime::now()
+            .checked_sub(self.config.tombstone_retention)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        let tmp = self.dir.join(COMPACTION_DIR);
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir(&tmp)?;
+        let mut cleaned = vec![];
+        for s in &closed {
+            let drop_tombstones = s.modified < tombstones_before;
+            let keep = |r: &Record| match &r.key {
+                None => true,
+                Some(key) => latest[key] == r.offset && !(r.value.is_empty() && drop_tombstones),
+            };
+            let mut segment = Segment::new(&tmp, s.base_offset, &self.config)?;
+            let mut removed = 0;
+            for records in s.frames() {
+                let records = records?;
+                let before = records.len();
+                // kept records stay batched the way they were appended
+                let kept: Vec<_> = records.into_iter().filter(keep).collect();
+                removed += (before - kept.len()) as u64;
+                segment.append_at(&kept, None)?;
+            }
+            if removed == 0 {
+                segment.remove()?;
+                continue;
+            }
+            segment.close()?;
+            let paths = segment.file_paths();
+            let store_path = segment.store.file_path.clone().expect("store file path");
+            drop(segment);
+            // tombstones keep aging from their last append, not from now
+            let store = fs::File::options().write(true).open(store_path)?;
+            store.set_modified(s.modified)?;
+            store.sync_all()?;
+            cleaned.push((s, removed, paths));
+        }
+
+        let mut segments = self.segments.write().unwrap();
+        let mut swapped = vec![];
+        for (closed, removed, paths) in cleaned {
+            match segments.iter().position(|s| s.is_unchanged(closed)) {
+                Some(i) => swapped.push((i, removed)),
+                // truncated or removed by retention meanwhile
+                None => {
+                    for path in paths {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+        if swapped.is_empty() {
+            fs::remove_dir_all(&tmp)?;
+            return Ok(stats);
+        }
+        let done = self.dir.join(COMPACTED_DIR);
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &done)?;
+        fs::File::open(&self.dir)?.sync_all()?;
+        install_compacted(&self.dir)?;
+        for (i, removed) in swapped {
+            let s = &mut segments[i];
+            let bytes_before = s.size();
+            *s = Segment::new(&self.dir, s.base_offset, &self.config)?;
+            stats.segments += 1;
+            stats.records_removed += removed;
+            stats.bytes_removed += bytes_before.saturating_sub(s.size());
+        }
+        drop(segments);
+        if stats.segments > 0 {
+            info!(
+                "compacted {} segments, removed {} records and {} bytes",
+                stats.segments, stats.records_removed, stats.bytes_removed
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Compression stats of every segment, oldest first.
+    pub fn compression_stats(&self) -> Result<Vec<CompressionStats>> {
+        let segments = self.segments.read().unwrap();
+        segments.iter().map(|s| s.compression_stats()).collect()
+    }
+
+    /// Directory holding the segment files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Configuration the log was opened with, after defaults were applied.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
+/// Moves the segment files of a committed compaction from [`COMPACTED_DIR`]
+/// over the ones in `dir` they replace.
+fn install_compacted(dir: &Path) -> Result<()> {
+    let done = dir.join(COMPACTED_DIR);
+    for entry in read_dir(&done)? {
+        let path = entry?.path();
+        fs::rename(&path, dir.join(path.file_name().expect("file name")))?;
+    }
+    fs::File::open(dir)?.sync_all()?;
+    fs::remove_dir(&done)?;
+    Ok(())
+}
+
+impl SyncState {
+    fn synced(&mut self, offset: u64) {
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        self.durable_offset = Some(offset);
+    }
+}
+
+/// Reader over the store frames of a [`Log`], returned by [`Log::reader`] and
+/// [`Log::reader_from`]. It can seek to any position up to the end of the
+/// segments it was created with, so a copy that broke off can resume from
+/// the number of bytes it already has.
+pub struct LogReader {
+    inner: MultiReader<Box<dyn SegmentReader>>,
+}
+
+trait SegmentReader: Read + Seek + Send {}
+
+impl<R: Read + Seek + Send> SegmentReader for R {}
+
+impl Read for LogReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for LogReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Iterator over the records of a [`Log`], returned by [`Log::iter_from`].
+/// The first error ends it.
+pub struct LogIter<'a> {
+    log: &'a Log,
+    /// Next offset to read from the front.
+    front: u64,
+    /// Offsets at or past this were already read from the back.
+    back: u64,
+    front_records: VecDeque<Record>,
+    back_records: VecDeque<Record>,
+    failed: bool,
+}
+
+impl LogIter<'_> {
+    /// Reads the next frame from the front into `front_records`.
+    fn fill_front(&mut self) -> Result<()> {
+        let segments = self.log.segments.read().unwrap();
+        while self.front_records.is_empty() && self.front < self.back {
+            let i = match segments.iter().position(|s| self.front < s.next_offset) {
+                Some(i) => i,
+                None => break,
+            };
+            let s = &segments[i];
+            if self.front < s.base_offset {
+                if i == 0 {
+                    return Err(Error::OffsetOutOfRange { offset: self.front }.into());
+                }
+                // records compacted away from the end of the previous segment
+                self.front = s.base_offset;
+            }
+            let records = s.read_batch_from(self.front)?;
+            self.front = records.last().map_or(s.next_offset, |r| r.offset + 1);
+            let back = self.back;
+            self.front_records
+                .extend(records.into_iter().filter(|r| r.offset < back));
+        }
+        Ok(())
+    }
+
+    /// Reads the previous frame from the back into `back_records`.
+    fn fill_back(&mut self) -> Result<()> {
+        let segments = self.log.segments.read().unwrap();
+        while self.back_records.is_empty() && self.front < self.back {
+            let s = match segments.iter().rev().find(|s| s.base_offset < self.back) {
+                Some(s) => s,
+                None => return Err(Error::OffsetOutOfRange { offset: self.front }.into()),
+            };
+            let records = s.read_batch_before(self.back.min(s.next_offset))?;
+            self.back = records.first().map_or(s.base_offset, |r| r.offset);
+            let front = self.front;
+            self.back_records
+                .extend(records.into_iter().filter(|r| r.offset >= front));
+        }
         Ok(())
     }
 }
 
+impl Iterator for LogIter<'_> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.front_records.is_empty() {
+            if let Err(e) = self.fill_front() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        // the back may already hold the last records
+        self.front_records
+            .pop_front()
+            .or_else(|| self.back_records.pop_front())
+            .map(Ok)
+    }
+}
+
+impl DoubleEndedIterator for LogIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.back_records.is_empty() {
+            if let Err(e) = self.fill_back() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+        self.back_records
+            .pop_back()
+            .or_else(|| self.front_records.pop_back())
+            .map(Ok)
+    }
+}
+
+/// Error for an offset no segment holds: compacted if it falls inside the
+/// log, out of range otherwise.
+fn missing(segments: &[Segment], offset: u64) -> Error {
+    match (segments.first(), segments.last()) {
+        (Some(first), Some(last)) if first.base_offset <= offset && offset < last.next_offset => {
+            Error::Compacted { offset }
+        }
+        _ => Error::OffsetOutOfRange { offset },
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use prost::Message;
     use tempfile::tempdir;
 
-    use crate::store::LEN_WIDTH;
+    use crate::config::{Cipher, Compression, EncryptionConfig};
+    use crate::encryption::{Key, KeyProvider, KEY_WIDTH};
+    use crate::store::{CRC_WIDTH, LEN_WIDTH};
 
     use super::*;
 
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn log_is_send_sync() {
+        assert_send_sync::<Log>();
+    }
+
     #[test]
     fn it_works() -> Result<()> {
         let _ = env_logger::builder().is_test(true).try_init();
         {
             let dir = tempdir()?;
-            let mut log = Log::new(dir.path(), Config::default())?;
-            test_append_and_read(&mut log)?;
+            let log = Log::new(dir.path(), Config::default())?;
+            test_append_and_read(&log)?;
         }
         {
             let dir = tempdir()?;
@@ -191,26 +1033,31 @@ mod tests {
         }
         {
             let dir = tempdir()?;
-            let mut log = Log::new(dir.path(), Config::default())?;
-            test_init_existing(&mut log)?;
+            let log = Log::new(dir.path(), Config::default())?;
+            test_init_existing(&log)?;
         }
         {
             let dir = tempdir()?;
-            let mut log = Log::new(dir.path(), Config::default())?;
-            test_reader(&mut log)?;
+            let log = Log::new(dir.path(), Config::default())?;
+            test_reader(&log)?;
         }
         {
             let dir = tempdir()?;
             let mut c = Config::default();
             c.segment.max_store_bytes = 32;
-            let mut log = Log::new(dir.path(), c)?;
-            test_truncate(&mut log)?;
+            let log = Log::new(dir.path(), c)?;
+            test_truncate(&log)?;
+        }
+        {
+            let dir = tempdir()?;
+            let log = sync::Arc::new(Log::new(dir.path(), Config::default())?);
+            test_concurrent_append(log)?;
         }
 
         Ok(())
     }
 
-    fn test_append_and_read(log: &mut Log) -> Result<()> {
+    fn test_append_and_read(log: &Log) -> Result<()> {
         let mut r1 = Record {
             value: vec![1, 2, 3],
             ..Default::default()
@@ -228,7 +1075,7 @@ mod tests {
         Ok(())
     }
 
-    fn test_init_existing(log: &mut Log) -> Result<()> {
+    fn test_init_existing(log: &Log) -> Result<()> {
         for _i in 0..3 {
             let mut r1 = Record {
                 value: "hello world".to_owned().into_bytes(),
@@ -242,7 +1089,7 @@ mod tests {
         let off = log.highest_offset()?;
         assert_eq!(2, off);
 
-        let log = Log::new(&log.dir, log.config.clone())?;
+        let log = Log::new(log.dir(), log.config().clone())?;
         let off = log.lowest_offset()?;
         assert_eq!(0, off);
         let off = log.highest_offset()?;
@@ -251,21 +1098,22 @@ mod tests {
         Ok(())
     }
 
-    fn test_reader(log: &mut Log) -> Result<()> {
+    fn test_reader(log: &Log) -> Result<()> {
         let mut r1 = Record {
             value: "hello world".to_owned().into_bytes(),
+            key: Some(b"greeting".to_vec()),
             ..Default::default()
         };
         log.append(&mut r1).expect("append");
         let mut r = log.reader();
         let mut buf = vec![];
         r.read_to_end(&mut buf)?;
-        let r2 = Record::decode(&buf[LEN_WIDTH as usize..])?;
-        assert_eq!(r1.value, r2.value);
+        let r2 = Record::decode(&buf[(LEN_WIDTH + CRC_WIDTH) as usize..])?;
+        assert_eq!(r1, r2);
         Ok(())
     }
 
-    fn test_truncate(log: &mut Log) -> Result<()> {
+    fn test_truncate(log: &Log) -> Result<()> {
         let mut r1 = Record {
             value: "hello world".to_owned().into_bytes(),
             ..Default::default()
@@ -278,4 +1126,734 @@ mod tests {
 
         Ok(())
     }
+
+    fn test_concurrent_append(log: sync::Arc<Log>) -> Result<()> {
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        let mut r = Record {
+                            value: "hello world".to_owned().into_bytes(),
+                            ..Default::default()
+                        };
+                        log.append(&mut r).expect("append");
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(99, log.highest_offset()?);
+        for off in 0..100 {
+            assert_eq!(log.read(off)?.value, b"hello world");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_or_wait() -> Result<()> {
+        let dir = tempdir()?;
+        let log = sync::Arc::new(Log::new(dir.path(), Config::default())?);
+        let r = log.read_or_wait(0, Duration::from_millis(10));
+        assert!(r.unwrap_err().to_string().contains("out of range"));
+
+        let reader = {
+            let log = log.clone();
+            std::thread::spawn(move || log.read_or_wait(0, Duration::from_secs(10)))
+        };
+        let mut r1 = Record {
+            value: "hello world".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        log.append(&mut r1)?;
+        assert_eq!(reader.join().unwrap()?.value, r1.value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = tempdir()?;
+        let log = sync::Arc::new(Log::new(dir.path(), Config::default())?);
+        let mut r1 = Record {
+            value: "hello world".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        log.append(&mut r1)?;
+
+        let mut stream = Box::pin(log.clone().subscribe(0));
+        assert_eq!(stream.next().await.unwrap()?.value, r1.value);
+
+        let producer = {
+            let log = log.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let mut r2 = Record {
+                    value: "second".to_owned().into_bytes(),
+                    ..Default::default()
+                };
+                log.append(&mut r2).unwrap();
+            })
+        };
+        assert_eq!(stream.next().await.unwrap()?.value, b"second");
+        producer.await?;
+
+        let r = log.read_or_wait_async(2, Duration::from_millis(10)).await;
+        assert!(r.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncate_then_append() -> Result<()> {
+        use tokio_stream::StreamExt;
+
+        let dir = tempdir()?;
+        let log = sync::Arc::new(Log::new(dir.path(), Config::default())?);
+        let append = |value: &str| {
+            let mut r = Record {
+                value: value.as_bytes().to_vec(),
+                ..Default::default()
+            };
+            log.append(&mut r)
+        };
+        for value in ["a", "b", "c"] {
+            append(value)?;
+        }
+        let mut stream = Box::pin(log.clone().subscribe(0));
+        for _ in 0..3 {
+            stream.next().await.unwrap()?;
+        }
+        let reader = {
+            let log = log.clone();
+            std::thread::spawn(move || log.read_or_wait(3, Duration::from_secs(10)))
+        };
+
+        // the head falls below the subscriber and passes it again before it
+        // looks, and the reader waiting past the old head sees the new record
+        log.truncate_from(1)?;
+        for value in ["x", "y", "z"] {
+            append(value)?;
+        }
+        assert_eq!(reader.join().unwrap()?.value, b"z");
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::Truncated {
+                offset: 3,
+                next_offset: 1
+            })
+        ));
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn offset_for_timestamp() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 64;
+        let log = Log::new(dir.path(), c)?;
+        for ts in 1..=10 {
+            let mut r = Record {
+                value: "hello world".to_owned().into_bytes(),
+                timestamp: ts * 1000,
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        assert_eq!(log.offset_for_timestamp(0)?, Some(0));
+        assert_eq!(log.offset_for_timestamp(4500)?, Some(4));
+        assert_eq!(log.offset_for_timestamp(10_000)?, Some(9));
+        assert_eq!(log.offset_for_timestamp(10_001)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 128;
+        let log = sync::Arc::new(Log::new(dir.path(), c)?);
+        let keys = ["a", "b", "a", "c", "b", "a", "c", "a"];
+        for (i, key) in keys.iter().enumerate() {
+            let mut r = Record {
+                value: format!("value-{}", i).into_bytes(),
+                key: Some(key.as_bytes().to_vec()),
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        // a tombstone for "c", and a record without a key
+        let mut r = Record {
+            key: Some(b"c".to_vec()),
+            ..Default::default()
+        };
+        log.append(&mut r)?;
+        let mut r = Record {
+            value: b"unkeyed".to_vec(),
+            ..Default::default()
+        };
+        log.append(&mut r)?;
+        let mut r = Record::default();
+        log.append(&mut r)?;
+        let highest = log.highest_offset()?;
+
+        let stats = log.compact()?;
+        assert!(stats.segments > 0);
+        let err = log.read(0).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::Compacted { offset: 0 })
+        ));
+        assert_eq!(log.read(4)?.value, b"value-4");
+        assert_eq!(log.read(7)?.value, b"value-7");
+        assert_eq!(log.read(9)?.value, b"unkeyed");
+        assert_eq!(log.highest_offset()?, highest);
+        assert!(log
+            .read(highest + 1)
+            .unwrap_err()
+            .to_string()
+            .contains("out of range"));
+
+        // compaction survives reopening and consumers skip the gaps
+        log.close()?;
+        let log = sync::Arc::new(Log::new(log.dir(), log.config().clone())?);
+        assert!(log.read(0).is_err());
+        assert_eq!(log.read(4)?.value, b"value-4");
+        let values: Vec<_> = tokio::runtime::Runtime::new()?.block_on(async {
+            use tokio_stream::StreamExt;
+            log.clone()
+                .subscribe(0)
+                .take(4)
+                .map(|r| r.unwrap().value)
+                .collect()
+                .await
+        });
+        assert_eq!(values[0], b"value-4");
+        Ok(())
+    }
+
+    #[test]
+    fn compact_ages_tombstones_by_append_time() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 64;
+        c.tombstone_retention = Duration::from_secs(3600);
+        let log = Log::new(dir.path(), c)?;
+        // a tombstone whose producer claims it is decades old
+        let mut r = Record {
+            key: Some(b"a".to_vec()),
+            timestamp: 1,
+            ..Default::default()
+        };
+        log.append(&mut r)?;
+        for _ in 0..4 {
+            let mut r = Record {
+                value: b"unkeyed".to_vec(),
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        log.compact()?;
+        assert_eq!(log.read(0)?.key.as_deref(), Some(&b"a"[..]));
+
+        // an interrupted compaction is dropped on open
+        log.close()?;
+        let config = log.config().clone();
+        drop(log);
+        fs::create_dir(dir.path().join(COMPACTION_DIR))?;
+        fs::write(dir.path().join(COMPACTION_DIR).join("0.store"), b"partial")?;
+        let log = Log::new(dir.path(), config)?;
+        assert!(!dir.path().join(COMPACTION_DIR).exists());
+        assert_eq!(log.read(0)?.key.as_deref(), Some(&b"a"[..]));
+
+        // once its segment was last written long enough ago, it goes
+        let store = fs::File::options()
+            .write(true)
+            .open(dir.path().join("0.store"))?;
+        store.set_modified(SystemTime::now() - Duration::from_secs(7200))?;
+        let stats = log.compact()?;
+        assert_eq!(stats.records_removed, 1);
+        assert!(log.read(0).is_err());
+        assert_eq!(log.read(1)?.value, b"unkeyed");
+        Ok(())
+    }
+
+    #[test]
+    fn iter_from() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 256;
+        c.segment.index_interval_bytes = 64;
+        let log = Log::new(dir.path(), c)?;
+        let record = |i: u64| Record {
+            value: format!("value-{}", i).into_bytes(),
+            key: Some(format!("key-{}", i % 10).into_bytes()),
+            ..Default::default()
+        };
+        for i in 0..20 {
+            log.append(&mut record(i))?;
+        }
+        let mut batch: Vec<_> = (20..30).map(record).collect();
+        log.append_batch(&mut batch)?;
+        for i in 30..40 {
+            log.append(&mut record(i))?;
+        }
+        assert!(log.segments.read().unwrap().len() > 2);
+        fn offsets(it: impl Iterator<Item = Result<Record>>) -> Result<Vec<u64>> {
+            it.map(|r| Ok(r?.offset)).collect()
+        }
+
+        assert_eq!(offsets(log.iter_from(0))?, (0..40).collect::<Vec<_>>());
+        assert_eq!(offsets(log.iter_from(25))?, (25..40).collect::<Vec<_>>());
+        assert_eq!(
+            offsets(log.iter_from(5).rev())?,
+            (5..40).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(log.iter_from(12).next().unwrap()?, log.read(12)?);
+        assert_eq!(log.iter_from(40).count(), 0);
+
+        // both ends meet without repeating records
+        let mut it = log.iter_from(18);
+        let mut seen = vec![];
+        while let (Some(a), b) = (it.next(), it.next_back()) {
+            seen.push(a?.offset);
+            if let Some(b) = b {
+                seen.push(b?.offset);
+            }
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, (18..40).collect::<Vec<_>>());
+
+        // compacted offsets are skipped in both directions
+        log.compact()?;
+        let kept: Vec<_> = (30..40).collect();
+        assert_eq!(offsets(log.iter_from(0))?, kept);
+        assert_eq!(
+            offsets(log.iter_from(0).rev())?,
+            kept.iter().rev().copied().collect::<Vec<_>>()
+        );
+
+        log.truncate(29)?;
+        let err = log.iter_from(0).next().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::OffsetOutOfRange { offset: 0 })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn read_latest_by_key() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 128;
+        c.segment.key_index = true;
+        let log = Log::new(dir.path(), c)?;
+        let keys = ["a", "b", "a", "c", "b", "a", "c", "d"];
+        for (i, key) in keys.iter().enumerate() {
+            let mut r = Record {
+                value: format!("value-{}", i).into_bytes(),
+                key: Some(key.as_bytes().to_vec()),
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        let mut r = Record {
+            key: Some(b"c".to_vec()),
+            ..Default::default()
+        };
+        log.append(&mut r)?;
+        assert!(log.segments.read().unwrap().len() > 2);
+
+        let latest = |log: &Log, key: &str| -> Result<Option<Vec<u8>>> {
+            Ok(log.read_latest_by_key(key.as_bytes())?.map(|r| r.value))
+        };
+        assert_eq!(latest(&log, "a")?, Some(b"value-5".to_vec()));
+        assert_eq!(latest(&log, "b")?, Some(b"value-4".to_vec()));
+        assert_eq!(latest(&log, "d")?, Some(b"value-7".to_vec()));
+        assert_eq!(latest(&log, "c")?, None);
+        assert_eq!(latest(&log, "e")?, None);
+
+        // reopened from the snapshots, then rebuilt without them
+        log.close()?;
+        let log = Log::new(log.dir(), log.config().clone())?;
+        assert_eq!(latest(&log, "a")?, Some(b"value-5".to_vec()));
+        log.close()?;
+        let paths = log.segments.read().unwrap()[0].file_paths();
+        let config = log.config().clone();
+        drop(log);
+        fs::remove_file(paths.last().unwrap())?;
+        let log = Log::new(dir.path(), config)?;
+        assert_eq!(latest(&log, "b")?, Some(b"value-4".to_vec()));
+
+        // compaction leaves the latest records where the index says
+        log.compact()?;
+        assert_eq!(latest(&log, "a")?, Some(b"value-5".to_vec()));
+        assert_eq!(latest(&log, "b")?, Some(b"value-4".to_vec()));
+
+        // without key indexes the segments are scanned
+        log.close()?;
+        let mut config = log.config().clone();
+        config.segment.key_index = false;
+        drop(log);
+        let log = Log::new(dir.path(), config)?;
+        assert_eq!(latest(&log, "a")?, Some(b"value-5".to_vec()));
+        assert_eq!(latest(&log, "d")?, Some(b"value-7".to_vec()));
+        assert_eq!(latest(&log, "c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn durability() -> Result<()> {
+        let append = |log: &Log| {
+            let mut r = Record {
+                value: "hello world".to_owned().into_bytes(),
+                ..Default::default()
+            };
+            log.append(&mut r)
+        };
+
+        let dir = tempdir()?;
+        let log = Log::new(dir.path(), Config::default())?;
+        append(&log)?;
+        assert_eq!(log.durable_offset(), None);
+        log.sync()?;
+        assert_eq!(log.durable_offset(), Some(0));
+
+        let dir = tempdir()?;
+        let c = Config {
+            durability: Durability::SyncEveryAppend,
+            ..Default::default()
+        };
+        let log = Log::new(dir.path(), c)?;
+        append(&log)?;
+        assert_eq!(log.durable_offset(), Some(0));
+
+        let dir = tempdir()?;
+        let c = Config {
+            durability: Durability::SyncEveryNRecords(3),
+            ..Default::default()
+        };
+        let log = Log::new(dir.path(), c)?;
+        for _ in 0..5 {
+            append(&log)?;
+        }
+        assert_eq!(log.durable_offset(), Some(2));
+        log.close()?;
+        let log = Log::new(log.dir(), log.config().clone())?;
+        assert_eq!(log.durable_offset(), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn append_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 100;
+        let log = Log::new(dir.path(), c)?;
+        let batch = |n: usize| -> Vec<Record> {
+            (0..n)
+                .map(|i| Record {
+                    value: format!("record-{}", i).into_bytes(),
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        let mut first = batch(2);
+        assert_eq!(log.append_batch(&mut first)?, 0..2);
+        assert_eq!(first[1].offset, 1);
+        // too big for what is left of the active segment, so it rolls first
+        let mut second = batch(3);
+        assert_eq!(log.append_batch(&mut second)?, 2..5);
+        {
+            let segments = log.segments.read().unwrap();
+            assert!(segments
+                .iter()
+                .any(|s| s.base_offset == 2 && s.next_offset == 5));
+        }
+        for (i, r) in first.iter().chain(second.iter()).enumerate() {
+            assert_eq!(&log.read(i as u64)?, r);
+        }
+        assert_eq!(log.append_batch(&mut [])?, 5..5);
+
+        // a batch takes one index entry, however many records it holds
+        for n in [100, 10_000] {
+            let mut records = batch(n);
+            let range = log.append_batch(&mut records)?;
+            assert_eq!(range.end - range.start, n as u64);
+            assert_eq!(log.read(range.end - 1)?, records[n - 1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn append_idempotent() -> Result<()> {
+        let dir = tempdir()?;
+        let record = |v: &str| Record {
+            value: v.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        {
+            let log = Log::new(dir.path(), Config::default())?;
+            assert_eq!(
+                log.append_idempotent(1, 0, &mut [record("a"), record("b")])?,
+                0..2
+            );
+            assert_eq!(log.append_idempotent(2, 0, &mut [record("x")])?, 2..3);
+            assert_eq!(log.append_idempotent(1, 1, &mut [record("c")])?, 3..4);
+            log.close()?;
+        }
+        // the snapshot survives a restart and is not taken for a segment
+        let log = Log::new(dir.path(), Config::default())?;
+        assert_eq!(log.append_idempotent(1, 1, &mut [record("c")])?, 3..4);
+        let err = log.append_idempotent(1, 0, &mut [record("a")]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::OutOfOrderSequence { expected: 2, .. })
+        ));
+        assert_eq!(log.append_idempotent(2, 1, &mut [record("y")])?, 4..5);
+        assert_eq!(log.highest_offset()?, 4);
+        assert_eq!(log.read(3)?.value, b"c");
+        assert_eq!(log.append_idempotent(3, 9, &mut [record("z")])?, 5..6);
+        drop(log);
+
+        // without a checkpoint past them, the batches are found in the log
+        let log = Log::new(dir.path(), Config::default())?;
+        assert_eq!(log.append_idempotent(2, 1, &mut [record("y")])?, 4..5);
+        assert_eq!(log.append_idempotent(3, 9, &mut [record("z")])?, 5..6);
+        assert_eq!(log.highest_offset()?, 5);
+
+        // and forgotten once the log no longer holds them
+        log.truncate_from(5)?;
+        assert_eq!(log.append_idempotent(3, 9, &mut [record("z")])?, 5..6);
+        log.truncate_from(4)?;
+        drop(log);
+        let log = Log::new(dir.path(), Config::default())?;
+        assert_eq!(log.append_idempotent(2, 1, &mut [record("y")])?, 4..5);
+        assert_eq!(log.highest_offset()?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_reader() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.compression = Compression::Snappy;
+        let log = Log::new(dir.path(), c)?;
+        let mut records: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|v| Record {
+                value: v.repeat(32).into_bytes(),
+                ..Default::default()
+            })
+            .collect();
+        log.append_batch(&mut records)?;
+
+        // the reader hands out one uncompressed frame per record
+        let mut buf = vec![];
+        log.reader().read_to_end(&mut buf)?;
+        let mut read = vec![];
+        let mut b = &buf[..];
+        while !b.is_empty() {
+            let len = u64::from_le_bytes(b[..LEN_WIDTH as usize].try_into()?) as usize;
+            let start = (LEN_WIDTH + CRC_WIDTH) as usize;
+            read.push(Record::decode(&b[start..start + len])?);
+            b = &b[start + len..];
+        }
+        assert_eq!(read, records);
+
+        let stats = log.compression_stats()?;
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].ratio() > 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn resume_reader() -> Result<()> {
+        let dir = tempdir()?;
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 256;
+        c.segment.compression = Compression::Lz4;
+        let log = Log::new(dir.path(), c)?;
+        for i in 0..8 {
+            let mut batch: Vec<_> = (0..5)
+                .map(|j| Record {
+                    value: format!("value-{}-{}", i, j).into_bytes(),
+                    ..Default::default()
+                })
+                .collect();
+            log.append_batch(&mut batch)?;
+        }
+        assert!(log.segments.read().unwrap().len() > 2);
+        let decode = |mut b: &[u8]| -> Result<Vec<u64>> {
+            let mut offsets = vec![];
+            while !b.is_empty() {
+                let len = u64::from_le_bytes(b[..LEN_WIDTH as usize].try_into()?) as usize;
+                let start = (LEN_WIDTH + CRC_WIDTH) as usize;
+                offsets.push(Record::decode(&b[start..start + len])?.offset);
+                b = &b[start + len..];
+            }
+            Ok(offsets)
+        };
+
+        let mut full = vec![];
+        log.reader().read_to_end(&mut full)?;
+        assert_eq!(decode(&full)?, (0..40).collect::<Vec<_>>());
+
+        // a copy that stopped partway resumes from its byte count
+        let mut r = log.reader();
+        let mut copy = vec![0; full.len() / 2 + 3];
+        r.read_exact(&mut copy)?;
+        drop(r);
+        let mut r = log.reader();
+        assert_eq!(
+            r.seek(SeekFrom::Start(copy.len() as u64))?,
+            copy.len() as u64
+        );
+        r.read_to_end(&mut copy)?;
+        assert_eq!(copy, full);
+
+        assert_eq!(r.seek(SeekFrom::End(0))?, full.len() as u64);
+        assert_eq!(r.seek(SeekFrom::Current(-10))?, full.len() as u64 - 10);
+        let mut tail = vec![];
+        r.read_to_end(&mut tail)?;
+        assert_eq!(tail, full[full.len() - 10..]);
+        assert!(r.seek(SeekFrom::Start(full.len() as u64 + 1)).is_err());
+
+        let mut from = vec![];
+        log.reader_from(17)?.read_to_end(&mut from)?;
+        assert_eq!(decode(&from)?, (17..40).collect::<Vec<_>>());
+        assert_eq!(from, full[full.len() - from.len()..]);
+        assert_eq!(log.reader_from(40)?.read(&mut [0; 8])?, 0);
+        assert!(log.reader_from(41).is_err());
+        Ok(())
+    }
+
+    struct TestKeys(HashMap<&'static str, Key>);
+
+    impl KeyProvider for TestKeys {
+        fn key(&self, key_id: &str) -> Result<Key> {
+            self.0
+                .get(key_id)
+                .copied()
+                .ok_or_else(|| anyhow!("no key {}", key_id))
+        }
+    }
+
+    #[test]
+    fn encryption() -> Result<()> {
+        let dir = tempdir()?;
+        let config = |cipher, key_id: &str, keys: TestKeys| {
+            let mut c = Config::default();
+            c.segment.max_store_bytes = 256;
+            c.segment.key_index = true;
+            c.encryption = Some(EncryptionConfig {
+                cipher,
+                key_id: key_id.to_owned(),
+            });
+            c.key_provider = Some(sync::Arc::new(keys));
+            c
+        };
+        let secret = |i: usize| format!("secret-{}", i).into_bytes();
+        let keys = || {
+            TestKeys(HashMap::from([
+                ("k1", [1; KEY_WIDTH]),
+                ("k2", [2; KEY_WIDTH]),
+            ]))
+        };
+
+        let log = Log::new(dir.path(), config(Cipher::Aes256Gcm, "k1", keys()))?;
+        for i in 0..10 {
+            let mut r = Record {
+                value: secret(i),
+                key: Some(secret(i)),
+                timestamp: 0x5eed_5eed,
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        log.close()?;
+        drop(log);
+        for entry in read_dir(dir.path())? {
+            let data = fs::read(entry?.path())?;
+            assert!(!data.windows(7).any(|w| w == b"secret-"));
+            assert!(!data.windows(4).any(|w| w == 0x5eed_5eedu32.to_le_bytes()));
+        }
+
+        // rotating only affects new segments
+        let log = Log::new(dir.path(), config(Cipher::ChaCha20Poly1305, "k2", keys()))?;
+        for i in 10..20 {
+            let mut r = Record {
+                value: secret(i),
+                ..Default::default()
+            };
+            log.append(&mut r)?;
+        }
+        for i in 0..20 {
+            assert_eq!(log.read(i as u64)?.value, secret(i));
+        }
+        {
+            let segments = log.segments.read().unwrap();
+            assert_eq!(segments[0].store.key_id(), Some("k1"));
+            assert_eq!(segments.last().unwrap().store.key_id(), Some("k2"));
+        }
+        log.close()?;
+        drop(log);
+
+        let wrong = TestKeys(HashMap::from([
+            ("k1", [9; KEY_WIDTH]),
+            ("k2", [2; KEY_WIDTH]),
+        ]));
+        let err = Log::new(dir.path(), config(Cipher::Aes256Gcm, "k2", wrong))
+            .and_then(|log| log.read(0))
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<Error>(), Some(Error::Unauthenticated { key_id, .. }) if key_id == "k1"),
+            "{:?}",
+            err
+        );
+        assert!(Log::new(dir.path(), Config::default()).is_err());
+
+        // a store moved to another segment, or with its header altered, fails
+        // to open
+        let base =
+            |path: &Path| -> u64 { path.file_stem().unwrap().to_str().unwrap().parse().unwrap() };
+        let mut stores: Vec<_> = read_dir(dir.path())?
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "store"))
+            .collect();
+        stores.sort_by_key(|p| base(p));
+        let second = fs::read(&stores[1])?;
+        fs::copy(&stores[0], &stores[1])?;
+        let err = Log::new(dir.path(), config(Cipher::Aes256Gcm, "k2", keys()))
+            .map(drop)
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<Error>(), Some(Error::Unauthenticated { base_offset, .. }) if *base_offset == base(&stores[1])),
+            "{:?}",
+            err
+        );
+        fs::write(&stores[1], &second)?;
+        let mut first = fs::read(&stores[0])?;
+        // the cipher id, right after the magic and version
+        first[8] = Cipher::ChaCha20Poly1305.id();
+        fs::write(&stores[0], &first)?;
+        let err = Log::new(dir.path(), config(Cipher::Aes256Gcm, "k2", keys()))
+            .map(drop)
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::Unauthenticated { base_offset: 0, .. })
+            ),
+            "{:?}",
+            err
+        );
+        Ok(())
+    }
 }
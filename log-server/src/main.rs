@@ -0,0 +1,357 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::{ArgEnum, Parser};
+use log::{error, info};
+use tonic::transport::Server;
+
+use log_server::{
+    Cipher, CleanupPolicy, Compression, Config, Durability, EncryptionConfig, EnvKeyProvider,
+    FileKeyProvider, GroupConfig, GroupCoordinator, GrpcTransport, KeyProvider, Log, LogManager,
+    LogService, Membership, MembershipConfig, OffsetStore, RaftConfig, RaftService, RemovedSegment,
+    ReplicatedLog, RetentionCleaner, SystemClock, TopicConfig, OFFSETS_LOG,
+};
+use protos::log::v1::log_server::LogServer;
+use protos::log::v1::raft_server::RaftServer;
+
+/// Serves a log directory over gRPC.
+#[derive(Parser)]
+struct Args {
+    /// Address to listen on.
+    #[clap(long, default_value = "127.0.0.1:8400")]
+    addr: SocketAddr,
+    /// Directory holding the log segments; created if missing.
+    #[clap(long)]
+    dir: PathBuf,
+    /// Size in bytes at which a segment's store file is rolled.
+    #[clap(long, default_value_t = 1 << 30)]
+    max_store_bytes: u64,
+    /// Size in bytes at which a segment's index file is rolled.
+    #[clap(long, default_value_t = 10 << 20)]
+    max_index_bytes: u64,
+    /// Size in bytes at which a segment's time index file is rolled.
+    #[clap(long, default_value_t = 10 << 20)]
+    max_time_index_bytes: u64,
+    /// Store bytes between two index entries; 0 indexes every batch.
+    #[clap(long, default_value_t = 0)]
+    index_interval_bytes: u64,
+    /// Codec for record batches.
+    #[clap(long, arg_enum, default_value = "none")]
+    compression: CompressionArg,
+    /// Index record keys so the latest record per key can be looked up.
+    #[clap(long)]
+    key_index: bool,
+    /// Encrypt new segments with this cipher.
+    #[clap(long, arg_enum, requires = "key-id")]
+    encryption: Option<CipherArg>,
+    /// Id of the key new segments are encrypted with.
+    #[clap(long)]
+    key_id: Option<String>,
+    /// Directory of `<key id>.key` files holding hex-encoded keys. Without it,
+    /// keys are read from `DLOG_KEY_<KEY ID>` environment variables.
+    #[clap(long)]
+    key_dir: Option<PathBuf>,
+    /// Delete the oldest closed segments once the log is larger than this.
+    #[clap(long)]
+    retention_bytes: Option<u64>,
+    /// Delete closed segments whose newest record is older than this many seconds.
+    #[clap(long)]
+    retention_secs: Option<u64>,
+    /// Compact closed segments down to the latest record per key.
+    #[clap(long)]
+    compact: bool,
+    /// How long compaction keeps tombstones, in seconds.
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    tombstone_retention_secs: u64,
+    /// Forget idempotent producers that appended nothing for this many seconds.
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    producer_expiry_secs: u64,
+    /// How often retention and compaction run, in seconds.
+    #[clap(long, default_value_t = 60)]
+    retention_check_secs: u64,
+    /// fsync before acknowledging every append. At most one of the sync
+    /// options may be given; without any the OS decides when to write back.
+    #[clap(long, group = "durability")]
+    sync_every_append: bool,
+    /// fsync once every this many appends.
+    #[clap(long, group = "durability")]
+    sync_every_n_records: Option<u64>,
+    /// fsync at least this often, in milliseconds.
+    #[clap(long, group = "durability")]
+    sync_interval_ms: Option<u64>,
+    /// Replicate the log with Raft, as the node with this id.
+    #[clap(long)]
+    node_id: Option<String>,
+    /// Another node of the cluster, as `id=host:port`; repeat for each.
+    #[clap(long, requires = "node-id", parse(try_from_str = parse_peer))]
+    peer: Vec<(String, String)>,
+    /// UDP address to gossip cluster membership on; members that join
+    /// become voters.
+    #[clap(long, requires_all = &["node-id", "gossip-key-id"])]
+    gossip_addr: Option<SocketAddr>,
+    /// Id of the key gossip is sealed with, served like the segment keys.
+    /// Only nodes holding it can join and so become voters.
+    #[clap(long)]
+    gossip_key_id: Option<String>,
+    /// Gossip address of a member to join the cluster through; repeat for
+    /// more. The node then waits for the leader to add it as a voter.
+    #[clap(long, requires = "gossip-addr")]
+    join: Vec<SocketAddr>,
+    /// Directory holding topics, each partition a log of its own; created if
+    /// missing. Topics are not replicated.
+    #[clap(long)]
+    topics_dir: Option<PathBuf>,
+    /// How long a consumer group member may go without a heartbeat, in
+    /// milliseconds, unless it asks for another timeout.
+    #[clap(long, default_value_t = 10_000)]
+    session_timeout_ms: u64,
+    /// A topic to create if missing, as `name=partitions`; repeat for each.
+    #[clap(long, requires = "topics-dir", parse(try_from_str = parse_topic))]
+    topic: Vec<(String, u32)>,
+}
+
+/// Values of `--compression`.
+#[derive(ArgEnum, Clone, Copy)]
+enum CompressionArg {
+    None,
+    Zstd,
+    Lz4,
+    Snappy,
+}
+
+impl From<CompressionArg> for Compression {
+    fn from(arg: CompressionArg) -> Self {
+        match arg {
+            CompressionArg::None => Compression::None,
+            CompressionArg::Zstd => Compression::Zstd,
+            CompressionArg::Lz4 => Compression::Lz4,
+            CompressionArg::Snappy => Compression::Snappy,
+        }
+    }
+}
+
+/// Values of `--encryption`.
+#[derive(ArgEnum, Clone, Copy)]
+enum CipherArg {
+    #[clap(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[clap(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl From<CipherArg> for Cipher {
+    fn from(arg: CipherArg) -> Self {
+        match arg {
+            CipherArg::Aes256Gcm => Cipher::Aes256Gcm,
+            CipherArg::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305,
+        }
+    }
+}
+
+fn parse_peer(s: &str) -> Result<(String, String)> {
+    let (id, addr) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected id=host:port, got {:?}", s))?;
+    Ok((id.to_owned(), addr.to_owned()))
+}
+
+fn parse_topic(s: &str) -> Result<(String, u32)> {
+    let (name, partitions) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected name=partitions, got {:?}", s))?;
+    Ok((name.to_owned(), partitions.parse()?))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    std::fs::create_dir_all(&args.dir)?;
+    let mut config = Config::default();
+    config.segment.max_store_bytes = args.max_store_bytes;
+    config.segment.max_index_bytes = args.max_index_bytes;
+    config.segment.max_time_index_bytes = args.max_time_index_bytes;
+    config.segment.index_interval_bytes = args.index_interval_bytes;
+    config.segment.compression = args.compression.into();
+    config.segment.key_index = args.key_index;
+    config.encryption = args.encryption.map(|cipher| EncryptionConfig {
+        cipher: cipher.into(),
+        key_id: args.key_id.clone().unwrap_or_default(),
+    });
+    // existing encrypted segments need their keys even with encryption off
+    let key_provider: Arc<dyn KeyProvider> = match &args.key_dir {
+        Some(dir) => Arc::new(FileKeyProvider::new(dir)),
+        None => Arc::new(EnvKeyProvider::new("DLOG_KEY_")),
+    };
+    config.key_provider = Some(key_provider.clone());
+    config.retention_bytes = args.retention_bytes;
+    config.retention_duration = args.retention_secs.map(Duration::from_secs);
+    if args.compact {
+        config.cleanup_policy = CleanupPolicy::Compact;
+    }
+    config.tombstone_retention = Duration::from_secs(args.tombstone_retention_secs);
+    config.producer_expiry = Some(Duration::from_secs(args.producer_expiry_secs));
+    config.durability = if args.sync_every_append {
+        Durability::SyncEveryAppend
+    } else if let Some(n) = args.sync_every_n_records {
+        Durability::SyncEveryNRecords(n)
+    } else if let Some(ms) = args.sync_interval_ms {
+        Durability::SyncInterval(Duration::from_millis(ms))
+    } else {
+        Durability::OsManaged
+    };
+    // consumer group offsets, kept next to the log's segments like Raft state
+    let offsets = Arc::new(OffsetStore::open(&args.dir.join(OFFSETS_LOG), &config)?);
+    let _offsets_cleaner = RetentionCleaner::start(
+        offsets.log().clone(),
+        Duration::from_secs(args.retention_check_secs),
+        |_| {},
+    );
+    let topics = match &args.topics_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            let topics = LogManager::open(dir, config.clone())?;
+            for (name, partitions) in &args.topic {
+                if !topics.topics().contains(name) {
+                    topics.create_topic(
+                        name,
+                        TopicConfig {
+                            partitions: *partitions,
+                            ..Default::default()
+                        },
+                    )?;
+                }
+            }
+            Some(Arc::new(topics))
+        }
+        None => None,
+    };
+    // nothing to clean without a retention limit or compaction
+    let cleans = config.retention_bytes.is_some()
+        || config.retention_duration.is_some()
+        || config.cleanup_policy == CleanupPolicy::Compact;
+    let clean_interval = Duration::from_secs(args.retention_check_secs);
+    let on_removed =
+        |removed: Vec<RemovedSegment>| info!("retention removed {} segments", removed.len());
+    let (log, replicated, _cleaner) = match &args.node_id {
+        Some(id) => {
+            let mut raft = RaftConfig {
+                id: id.clone(),
+                peers: args.peer.iter().cloned().collect(),
+                ..Default::default()
+            };
+            if args.join.is_empty() {
+                raft.peers.insert(id.clone(), args.addr.to_string());
+            }
+            let transport = Arc::new(GrpcTransport::default());
+            let replicated = ReplicatedLog::start(&args.dir, config, raft, transport)?;
+            let cleaner = cleans.then(|| replicated.start_cleaner(clean_interval, on_removed));
+            (None, Some(Arc::new(replicated)), cleaner)
+        }
+        None => {
+            let log = Arc::new(Log::new(&args.dir, config)?);
+            let cleaner =
+                cleans.then(|| RetentionCleaner::start(log.clone(), clean_interval, on_removed));
+            (Some(log), None, cleaner)
+        }
+    };
+
+    // a replicated log is synced through its Raft log
+    let sync_interval = match log.as_ref().map(|log| log.config().durability) {
+        Some(Durability::SyncInterval(interval)) => Some(interval),
+        _ => None,
+    };
+    if let (Some(log), Some(interval)) = (&log, sync_interval) {
+        let log = log.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let log = log.clone();
+                let res = tokio::task::spawn_blocking(move || log.sync()).await;
+                if let Err(e) = res.map_err(anyhow::Error::from).and_then(|r| r) {
+                    error!("periodic sync failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    let topics_cleaner = topics.clone().map(|topics| {
+        RetentionCleaner::start_all(
+            move || topics.all_partitions(),
+            clean_interval,
+            |log, removed| {
+                let dir = log.dir();
+                info!("retention removed {} segments of {:?}", removed.len(), dir);
+            },
+        )
+    });
+
+    let mut membership = None;
+    if let (Some(r), Some(gossip_addr)) = (&replicated, args.gossip_addr) {
+        let key_id = args.gossip_key_id.as_deref().unwrap_or_default();
+        let key = key_provider.key(key_id)?;
+        let m = Membership::start(MembershipConfig {
+            name: r.id().to_owned(),
+            bind_addr: gossip_addr,
+            rpc_addr: args.addr.to_string(),
+            seeds: args.join.clone(),
+            key: Some(key),
+            ..Default::default()
+        })
+        .await?;
+        m.handle(r.clone());
+        membership = Some(m);
+    }
+
+    info!("serving {:?} on {}", args.dir, args.addr);
+    let mut service = match (&log, &replicated) {
+        (_, Some(r)) => LogService::replicated(r.clone()),
+        (Some(log), None) => LogService::new(log.clone()),
+        (None, None) => unreachable!("a log is either local or replicated"),
+    }
+    .with_offsets(offsets.clone());
+    let mut groups = GroupCoordinator::new(
+        offsets.clone(),
+        GroupConfig {
+            session_timeout: Duration::from_millis(args.session_timeout_ms),
+        },
+        Arc::new(SystemClock),
+    );
+    if let Some(topics) = &topics {
+        service = service.with_topics(topics.clone());
+        groups = groups.with_topics(topics.clone());
+    }
+    service = service.with_groups(Arc::new(groups));
+    Server::builder()
+        .add_service(LogServer::new(service))
+        .add_optional_service(
+            replicated
+                .as_deref()
+                .map(|r| RaftServer::new(RaftService::new(r))),
+        )
+        .serve_with_shutdown(args.addr, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    if let Some(m) = membership {
+        m.leave().await?;
+    }
+    // the membership handler may still hold the replicated log
+    if let Some(r) = &replicated {
+        r.close().await?;
+    }
+    if let Some(log) = &log {
+        log.close()?;
+    }
+    if let Some(topics) = topics {
+        drop(topics_cleaner);
+        topics.close()?;
+    }
+    offsets.close()?;
+    Ok(())
+}
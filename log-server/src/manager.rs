@@ -0,0 +1,499 @@
+use std::collections::BTreeMap;
+use std::fs::{self, read_dir};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use prost::Message;
+
+use protos::log::v1::{Record, TopicMetadata};
+
+use crate::config::{CleanupPolicy, Compression, Config, TopicConfig};
+use crate::error::Error;
+use crate::log::Log;
+
+/// File in a topic's directory holding its [`TopicMetadata`].
+const TOPIC_FILE: &str = "topic.meta";
+
+/// Owns many logs under one directory, organized as topics split into
+/// partitions. Partition `p` of topic `t` is a [`Log`] in `t/p/`, opened on
+/// first use with the topic's config.
+pub struct LogManager {
+    dir: PathBuf,
+    config: Config,
+    /// Topics by name; `None` while one is being deleted, so no topic of the
+    /// same name is created before its directory is gone.
+    topics: RwLock<BTreeMap<String, Option<Arc<Topic>>>>,
+}
+
+struct Topic {
+    dir: PathBuf,
+    config: TopicConfig,
+    /// Opened partitions, by partition number. Only held briefly, never while
+    /// a partition is opened.
+    partitions: Mutex<Vec<Option<Arc<Log>>>>,
+    /// Held while partition `p` is opened, so it is opened only once.
+    opening: Vec<Mutex<()>>,
+    /// Set once the topic is deleted. Opening a partition holds it for
+    /// reading, so deleting waits for opens in flight and no later one
+    /// recreates the partition's directory.
+    deleted: RwLock<bool>,
+    /// Partition the next record without a key goes to.
+    next: AtomicU32,
+}
+
+impl LogManager {
+    /// Opens the topics in `dir`, with `config` as the default for their
+    /// partitions. No partition is opened yet.
+    pub fn open(dir: &Path, config: Config) -> Result<Self> {
+        if !dir.is_dir() {
+            return Err(anyhow!("{:?} is not a directory", dir));
+        }
+        let mut topics = BTreeMap::new();
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            let meta = path.join(TOPIC_FILE);
+            if !meta.is_file() {
+                continue;
+            }
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| anyhow!("bad topic directory {:?}", path))?
+                .to_owned();
+            let config = topic_config(&TopicMetadata::decode(&*fs::read(&meta)?)?)?;
+            topics.insert(name, Some(Arc::new(Topic::new(path, config))));
+        }
+        info!("opened {} topics in {:?}", topics.len(), dir);
+        Ok(LogManager {
+            dir: dir.into(),
+            config,
+            topics: RwLock::new(topics),
+        })
+    }
+
+    /// Creates topic `name` with its partition directories.
+    pub fn create_topic(&self, name: &str, config: TopicConfig) -> Result<()> {
+        validate_name(name)?;
+        if config.partitions == 0 {
+            return Err(invalid_topic(name, "a topic needs at least one partition"));
+        }
+        let mut topics = self.topics.write().unwrap();
+        match topics.get(name) {
+            Some(Some(_)) => {
+                return Err(Error::TopicExists {
+                    topic: name.to_owned(),
+                }
+                .into())
+            }
+            Some(None) => return Err(invalid_topic(name, "the topic is still being deleted")),
+            None => {}
+        }
+        let dir = self.dir.join(name);
+        if dir.exists() {
+            // left by a creation or deletion cut short, as it has no metadata
+            fs::remove_dir_all(&dir)?;
+        }
+        for p in 0..config.partitions {
+            fs::create_dir_all(dir.join(p.to_string()))?;
+        }
+        // the metadata file marks the topic as complete, so write it last
+        let tmp = dir.join(format!("{}.tmp", TOPIC_FILE));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&metadata(&config).encode_to_vec())?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(TOPIC_FILE))?;
+        fs::File::open(&self.dir)?.sync_all()?;
+        topics.insert(name.to_owned(), Some(Arc::new(Topic::new(dir, config))));
+        Ok(())
+    }
+
+    /// Closes the topic's partitions and removes its directory. Logs of the
+    /// topic still held elsewhere keep working until dropped, but on
+    /// unlinked files.
+    pub fn delete_topic(&self, name: &str) -> Result<()> {
+        let topic = {
+            let mut topics = self.topics.write().unwrap();
+            let topic = topics
+                .get(name)
+                .cloned()
+                .flatten()
+                .ok_or_else(|| unknown_topic(name))?;
+            // without its metadata file the topic is gone even if removing
+            // the rest is cut short
+            fs::remove_file(topic.dir.join(TOPIC_FILE))?;
+            topics.insert(name.to_owned(), None);
+            topic
+        };
+        let removed = topic.remove();
+        self.topics.write().unwrap().remove(name);
+        removed
+    }
+
+    /// Names of the topics, sorted.
+    pub fn topics(&self) -> Vec<String> {
+        let topics = self.topics.read().unwrap();
+        topics
+            .iter()
+            .filter(|(_, t)| t.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn topic_config(&self, name: &str) -> Result<TopicConfig> {
+        Ok(self.topic(name)?.config.clone())
+    }
+
+    fn topic(&self, name: &str) -> Result<Arc<Topic>> {
+        self.topics
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .flatten()
+            .ok_or_else(|| unknown_topic(name))
+    }
+
+    /// The log of a partition, opening it if needed.
+    pub fn partition(&self, topic: &str, partition: u32) -> Result<Arc<Log>> {
+        let t = self.topic(topic)?;
+        let opening = t
+            .opening
+            .get(partition as usize)
+            .ok_or_else(|| Error::UnknownPartition {
+                topic: topic.to_owned(),
+                partition,
+            })?;
+        if let Some(log) = t.opened(partition) {
+            return Ok(log);
+        }
+        let deleted = t.deleted.read().unwrap();
+        if *deleted {
+            return Err(unknown_topic(topic));
+        }
+        let _opening = opening.lock().unwrap();
+        if let Some(log) = t.opened(partition) {
+            return Ok(log);
+        }
+        let dir = t.dir.join(partition.to_string());
+        fs::create_dir_all(&dir)?;
+        let log = Arc::new(Log::new(&dir, t.config.apply(&self.config))?);
+        t.partitions.lock().unwrap()[partition as usize] = Some(log.clone());
+        Ok(log)
+    }
+
+    /// The partitions opened so far.
+    pub fn open_partitions(&self) -> Vec<Arc<Log>> {
+        let topics = self.topics.read().unwrap();
+        topics
+            .values()
+            .flatten()
+            .flat_map(|t| {
+                let partitions = t.partitions.lock().unwrap();
+                partitions.iter().flatten().cloned().collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Every partition of every topic, opening the ones not opened yet, for
+    /// background work like retention. Partitions that fail to open are
+    /// logged and left out.
+    pub fn all_partitions(&self) -> Vec<Arc<Log>> {
+        let topics: Vec<_> = self
+            .topics
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, t)| Some((name.clone(), t.as_ref()?.config.partitions)))
+            .collect();
+        let mut logs = vec![];
+        for (topic, partitions) in topics {
+            for p in 0..partitions {
+                match self.partition(&topic, p) {
+                    Ok(log) => logs.push(log),
+                    Err(e) => error!("failed to open partition {} of {:?}: {:?}", p, topic, e),
+                }
+            }
+        }
+        logs
+    }
+
+    /// Picks the partition of a record: by the hash of its key, so records
+    /// with the same key stay in order, or round-robin without a key.
+    pub fn partition_for(&self, topic: &str, key: Option<&[u8]>) -> Result<u32> {
+        let t = self.topic(topic)?;
+        let n = t.config.partitions;
+        Ok(match key {
+            Some(key) => crc32c::crc32c(key) % n,
+            None => t.next.fetch_add(1, Ordering::Relaxed) % n,
+        })
+    }
+
+    /// Appends `record` to `partition`, or the one its key picks, and returns
+    /// the partition and offset.
+    pub fn append(
+        &self,
+        topic: &str,
+        partition: Option<u32>,
+        record: &mut Record,
+    ) -> Result<(u32, u64)> {
+        let (p, range) = self.append_batch(topic, partition, std::slice::from_mut(record))?;
+        Ok((p, range.start))
+    }
+
+    /// Appends `records` to `partition`, or the one the first record's key
+    /// picks, and returns the partition and offsets.
+    pub fn append_batch(
+        &self,
+        topic: &str,
+        partition: Option<u32>,
+        records: &mut [Record],
+    ) -> Result<(u32, Range<u64>)> {
+        let p = match partition {
+            Some(p) => p,
+            None => {
+                let key = records.first().and_then(|r| r.key.as_deref());
+                self.partition_for(topic, key)?
+            }
+        };
+        Ok((p, self.partition(topic, p)?.append_batch(records)?))
+    }
+
+    /// Closes every opened partition.
+    pub fn close(&self) -> Result<()> {
+        for log in self.open_partitions() {
+            log.close()?;
+        }
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Topic {
+    fn new(dir: PathBuf, config: TopicConfig) -> Self {
+        let n = config.partitions as usize;
+        Topic {
+            dir,
+            partitions: Mutex::new(vec![None; n]),
+            opening: (0..n).map(|_| Mutex::new(())).collect(),
+            deleted: RwLock::new(false),
+            config,
+            next: AtomicU32::new(0),
+        }
+    }
+
+    /// Closes the opened partitions once no more can be opened and removes
+    /// the directory.
+    fn remove(&self) -> Result<()> {
+        *self.deleted.write().unwrap() = true;
+        fs::File::open(&self.dir)?.sync_all()?;
+        let logs = std::mem::take(&mut *self.partitions.lock().unwrap());
+        for log in logs.iter().flatten() {
+            log.close()?;
+        }
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Partition `p`, if it is open.
+    fn opened(&self, p: u32) -> Option<Arc<Log>> {
+        self.partitions.lock().unwrap().get(p as usize)?.clone()
+    }
+}
+
+fn unknown_topic(name: &str) -> anyhow::Error {
+    Error::UnknownTopic {
+        topic: name.to_owned(),
+    }
+    .into()
+}
+
+/// Topic names become directory names, so keep them to a portable alphabet.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 249
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid_topic(
+            name,
+            "use 1 to 249 ASCII letters, digits, '.', '_' and '-'",
+        ))
+    }
+}
+
+fn invalid_topic(name: &str, reason: &str) -> anyhow::Error {
+    Error::InvalidTopic {
+        topic: name.to_owned(),
+        reason: reason.to_owned(),
+    }
+    .into()
+}
+
+pub(crate) fn metadata(config: &TopicConfig) -> TopicMetadata {
+    TopicMetadata {
+        partitions: config.partitions,
+        max_store_bytes: config.max_store_bytes,
+        max_index_bytes: config.max_index_bytes,
+        compression: config.compression.map(|c| c.id() as u32),
+        key_index: config.key_index,
+        retention_bytes: config.retention_bytes,
+        retention_ms: config.retention_duration.map(|d| d.as_millis() as u64),
+        compact: config.cleanup_policy.map(|p| p == CleanupPolicy::Compact),
+    }
+}
+
+pub(crate) fn topic_config(meta: &TopicMetadata) -> Result<TopicConfig> {
+    let compression = match meta.compression {
+        Some(id) => Some(
+            u8::try_from(id)
+                .ok()
+                .and_then(Compression::from_id)
+                .ok_or_else(|| anyhow!("unknown compression id {}", id))?,
+        ),
+        None => None,
+    };
+    Ok(TopicConfig {
+        partitions: meta.partitions,
+        max_store_bytes: meta.max_store_bytes,
+        max_index_bytes: meta.max_index_bytes,
+        compression,
+        key_index: meta.key_index,
+        retention_bytes: meta.retention_bytes,
+        retention_duration: meta.retention_ms.map(Duration::from_millis),
+        cleanup_policy: meta.compact.map(|compact| {
+            if compact {
+                CleanupPolicy::Compact
+            } else {
+                CleanupPolicy::Delete
+            }
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn record(key: Option<&str>, value: &str) -> Record {
+        Record {
+            key: key.map(|k| k.as_bytes().to_vec()),
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn topics() -> Result<()> {
+        let dir = tempdir()?;
+        let manager = LogManager::open(dir.path(), Config::default())?;
+        let config = TopicConfig {
+            partitions: 3,
+            compression: Some(Compression::Zstd),
+            retention_duration: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        manager.create_topic("orders", config.clone())?;
+        manager.create_topic(
+            "audit",
+            TopicConfig {
+                partitions: 1,
+                ..Default::default()
+            },
+        )?;
+        let err = manager.create_topic("orders", config.clone()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::TopicExists { .. })
+        ));
+        assert!(manager.create_topic("../escape", config.clone()).is_err());
+        assert_eq!(manager.topics(), ["audit", "orders"]);
+
+        let log = manager.partition("orders", 2)?;
+        assert_eq!(log.config().segment.compression, Compression::Zstd);
+        assert_eq!(
+            log.config().retention_duration,
+            Some(Duration::from_secs(60))
+        );
+        let err = manager.partition("orders", 3).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnknownPartition { partition: 3, .. })
+        ));
+
+        let (p, offset) = manager.append("orders", Some(2), &mut record(None, "a"))?;
+        assert_eq!((p, offset), (2, 0));
+        manager.close()?;
+        drop(log);
+        drop(manager);
+
+        let manager = LogManager::open(dir.path(), Config::default())?;
+        assert_eq!(manager.topics(), ["audit", "orders"]);
+        assert_eq!(manager.topic_config("orders")?, config);
+        assert!(manager.open_partitions().is_empty());
+        // retention reaches partitions nothing touched since the restart
+        assert_eq!(manager.all_partitions().len(), 4);
+        assert_eq!(manager.partition("orders", 2)?.read(0)?.value, b"a");
+
+        manager.delete_topic("orders")?;
+        assert_eq!(manager.topics(), ["audit"]);
+        assert!(!dir.path().join("orders").exists());
+        let err = manager.partition("orders", 0).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnknownTopic { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn partitioning() -> Result<()> {
+        let dir = tempdir()?;
+        let manager = LogManager::open(dir.path(), Config::default())?;
+        manager.create_topic(
+            "events",
+            TopicConfig {
+                partitions: 4,
+                ..Default::default()
+            },
+        )?;
+
+        // records without a key go round-robin
+        let mut seen = vec![];
+        for i in 0..8 {
+            let (p, _) = manager.append("events", None, &mut record(None, &i.to_string()))?;
+            seen.push(p);
+        }
+        assert_eq!(seen, [0, 1, 2, 3, 0, 1, 2, 3]);
+
+        // records with the same key stay on one partition, in order
+        let (p, first) = manager.append("events", None, &mut record(Some("user-1"), "x"))?;
+        let (q, second) = manager.append("events", None, &mut record(Some("user-1"), "y"))?;
+        assert_eq!(p, q);
+        assert_eq!(second, first + 1);
+
+        let (p, range) = manager.append_batch(
+            "events",
+            None,
+            &mut [record(Some("user-1"), "z"), record(None, "w")],
+        )?;
+        assert_eq!(p, q);
+        assert_eq!(range, second + 1..second + 3);
+        Ok(())
+    }
+}
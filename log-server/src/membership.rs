@@ -0,0 +1,768 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use anyhow::Result;
+use log::{debug, info, warn};
+use prost::Message;
+use protos::log::v1::gossip_member::Status;
+use protos::log::v1::gossip_message::Kind;
+use protos::log::v1::{GossipMember, GossipMessage};
+use rand::seq::SliceRandom;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::config::{Cipher, MembershipConfig};
+use crate::encryption::{FrameCipher, Sealed};
+
+/// Largest datagram received.
+const MAX_DATAGRAM: usize = 64 * 1024;
+/// Most member updates piggybacked on one message.
+const MAX_PIGGYBACK: usize = 16;
+/// Each update is piggybacked this many times the log2 of the cluster size.
+const RETRANSMIT_MULT: u32 = 3;
+/// Member events buffered per subscriber.
+const EVENT_BUFFER: usize = 64;
+
+/// A node of the cluster as the other members see it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    /// Address of the member's gRPC services.
+    pub rpc_addr: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemberEvent {
+    /// A member joined, or came back after failing or leaving.
+    Join(Member),
+    /// A member left the cluster on purpose.
+    Leave(Member),
+    /// A member stopped answering probes.
+    Fail(Member),
+}
+
+/// Acts on members joining and leaving; see [`Membership::handle`]. Failed
+/// members are left alone, as they may come back.
+#[tonic::async_trait]
+pub trait MembershipHandler: Send + Sync {
+    async fn join(&self, member: &Member) -> Result<()>;
+
+    async fn leave(&self, member: &Member) -> Result<()>;
+}
+
+/// Discovers the other nodes of a cluster through SWIM-style gossip over UDP.
+///
+/// Every probe interval the next member, round-robin, is pinged. Without an
+/// ack in time, `indirect_checks` others are asked to ping it, and if none
+/// of them gets one either it becomes suspect. A suspect that does not refute
+/// within the suspect timeout is declared failed. Member updates ride along
+/// on every message, and full member lists are exchanged when joining and
+/// every push-pull interval. With [`MembershipConfig::key`] every message is
+/// sealed, and those that fail to open are dropped. Dropping the handle stops
+/// gossiping without leaving, which the others see as a failure.
+pub struct Membership {
+    inner: Arc<Inner>,
+}
+
+impl Membership {
+    /// Binds the gossip socket and joins the cluster through `config.seeds`.
+    /// Spawns tasks, so it must be called within a Tokio runtime.
+    pub async fn start(config: MembershipConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(config.bind_addr).await?;
+        let addr = socket.local_addr()?;
+        let me = GossipMember {
+            name: config.name.clone(),
+            gossip_addr: addr.to_string(),
+            rpc_addr: config.rpc_addr.clone(),
+            status: Status::Alive as i32,
+            incarnation: 0,
+        };
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let (shutdown, _) = watch::channel(false);
+        let cipher = config
+            .key
+            .map(|key| FrameCipher::new(Cipher::ChaCha20Poly1305, "gossip", &key));
+        if cipher.is_none() {
+            warn!("gossip on {} is not authenticated", addr);
+        }
+        let inner = Arc::new(Inner {
+            socket,
+            addr,
+            cipher,
+            state: Mutex::new(State {
+                members: HashMap::from([(config.name.clone(), MemberState::new(me))]),
+                updates: vec![],
+                pending: HashMap::new(),
+                probe_order: vec![],
+            }),
+            events,
+            seq: AtomicU64::new(0),
+            shutdown,
+            config,
+        });
+        tokio::spawn(inner.clone().receive());
+        tokio::spawn(inner.clone().probe());
+        for seed in &inner.config.seeds {
+            inner.send_all(*seed, Kind::PushPull).await?;
+        }
+        Ok(Membership { inner })
+    }
+
+    /// The UDP address this node gossips on.
+    pub fn addr(&self) -> SocketAddr {
+        self.inner.addr
+    }
+
+    /// Every member believed to be up, this node included.
+    pub fn members(&self) -> Vec<Member> {
+        let st = self.inner.state();
+        st.members
+            .values()
+            .filter(|m| m.is_up())
+            .map(|m| m.to_member())
+            .collect()
+    }
+
+    /// Events about the other members from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<MemberEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Calls `handler` on every join and leave event, and every push-pull
+    /// interval for every member up or left, so changes a handler missed or
+    /// failed to make are made eventually. Handlers must tolerate repeats.
+    pub fn handle(&self, handler: Arc<dyn MembershipHandler>) {
+        let inner = self.inner.clone();
+        let mut events = self.subscribe();
+        let mut shutdown = inner.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut tick = time::interval(inner.config.push_pull_interval);
+            loop {
+                let res = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(MemberEvent::Join(m)) => handler.join(&m).await,
+                        Ok(MemberEvent::Leave(m)) => handler.leave(&m).await,
+                        _ => Ok(()),
+                    },
+                    _ = tick.tick() => inner.reconcile(&*handler).await,
+                    _ = shutdown.changed() => return,
+                };
+                if let Err(e) = res {
+                    debug!("membership handler failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Tells the others this node is leaving, then stops gossiping.
+    pub async fn leave(self) -> Result<()> {
+        let (me, others) = {
+            let mut st = self.inner.state();
+            let me = &mut st.members.get_mut(&self.inner.config.name).unwrap().member;
+            me.incarnation += 1;
+            me.set_status(Status::Left);
+            let me = me.clone();
+            let others = self.inner.others(&st, None);
+            (me, others)
+        };
+        info!("{} leaves the cluster", me.name);
+        for addr in others {
+            self.inner
+                .send_to(addr, Kind::Push, 0, String::new(), vec![me.clone()])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.inner.shutdown.send_replace(true);
+    }
+}
+
+struct Inner {
+    config: MembershipConfig,
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Seals and opens every message, when gossip is authenticated.
+    cipher: Option<FrameCipher>,
+    state: Mutex<State>,
+    events: broadcast::Sender<MemberEvent>,
+    seq: AtomicU64,
+    shutdown: watch::Sender<bool>,
+}
+
+struct State {
+    /// Every member ever heard of by name, this node included.
+    members: HashMap<String, MemberState>,
+    /// Updates still to be piggybacked, with how many more times each.
+    updates: Vec<(GossipMember, u32)>,
+    /// Acks waited for, by seq.
+    pending: HashMap<u64, Pending>,
+    /// Members left to probe in this round.
+    probe_order: Vec<String>,
+}
+
+struct MemberState {
+    member: GossipMember,
+    /// When the status last changed.
+    since: Instant,
+}
+
+impl MemberState {
+    fn new(member: GossipMember) -> Self {
+        MemberState {
+            member,
+            since: Instant::now(),
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        matches!(self.member.status(), Status::Alive | Status::Suspect)
+    }
+
+    fn to_member(&self) -> Member {
+        Member {
+            name: self.member.name.clone(),
+            rpc_addr: self.member.rpc_addr.clone(),
+        }
+    }
+}
+
+enum Pending {
+    /// A probe of this node's own.
+    Probe(oneshot::Sender<()>),
+    /// A ping made for another member, whose ack goes back to it under its seq.
+    Forward {
+        to: SocketAddr,
+        seq: u64,
+        at: Instant,
+    },
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let (n, from) = tokio::select! {
+                res = self.socket.recv_from(&mut buf) => match res {
+                    Ok(res) => res,
+                    Err(e) => {
+                        debug!("gossip receive failed: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.changed() => return,
+            };
+            let res = match self.decode(&buf[..n]) {
+                Ok(msg) => self.handle_message(msg, from).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                debug!("dropping gossip from {}: {}", from, e);
+            }
+        }
+    }
+
+    /// Opens a received message if gossip is sealed, and decodes it.
+    fn decode(&self, b: &[u8]) -> Result<GossipMessage> {
+        Ok(match &self.cipher {
+            Some(c) => GossipMessage::decode(&*c.open(Sealed::Gossip, 0, b)?)?,
+            None => GossipMessage::decode(b)?,
+        })
+    }
+
+    async fn handle_message(&self, msg: GossipMessage, from: SocketAddr) -> Result<()> {
+        let kind = msg.kind();
+        self.merge(msg.members);
+        match kind {
+            Kind::Ping => self.send(from, Kind::Ack, msg.seq, String::new()).await,
+            Kind::Ack => {
+                let pending = self.state().pending.remove(&msg.seq);
+                match pending {
+                    Some(Pending::Probe(acked)) => {
+                        let _ = acked.send(());
+                    }
+                    Some(Pending::Forward { to, seq, .. }) => {
+                        self.send(to, Kind::Ack, seq, String::new()).await?
+                    }
+                    None => {}
+                }
+                Ok(())
+            }
+            Kind::PingReq => {
+                let target = msg.target.parse()?;
+                let seq = self.next_seq();
+                let forward = Pending::Forward {
+                    to: from,
+                    seq: msg.seq,
+                    at: Instant::now(),
+                };
+                self.state().pending.insert(seq, forward);
+                self.send(target, Kind::Ping, seq, String::new()).await
+            }
+            Kind::PushPull => self.send_all(from, Kind::Push).await,
+            Kind::Push => Ok(()),
+        }
+    }
+
+    /// Sends a message carrying the pending updates.
+    async fn send(&self, to: SocketAddr, kind: Kind, seq: u64, target: String) -> Result<()> {
+        let members = self.piggyback();
+        self.send_to(to, kind, seq, target, members).await
+    }
+
+    /// Sends a message carrying every member.
+    async fn send_all(&self, to: SocketAddr, kind: Kind) -> Result<()> {
+        let members = {
+            let st = self.state();
+            st.members.values().map(|m| m.member.clone()).collect()
+        };
+        self.send_to(to, kind, 0, String::new(), members).await
+    }
+
+    async fn send_to(
+        &self,
+        to: SocketAddr,
+        kind: Kind,
+        seq: u64,
+        target: String,
+        members: Vec<GossipMember>,
+    ) -> Result<()> {
+        let mut msg = GossipMessage {
+            seq,
+            target,
+            members,
+            ..Default::default()
+        };
+        msg.set_kind(kind);
+        let mut b = msg.encode_to_vec();
+        if let Some(c) = &self.cipher {
+            b = c.seal(Sealed::Gossip, 0, &b);
+        }
+        self.socket.send_to(&b, to).await?;
+        Ok(())
+    }
+
+    /// Takes the updates gossiped the fewest times so far.
+    fn piggyback(&self) -> Vec<GossipMember> {
+        let mut st = self.state();
+        st.updates.sort_by_key(|(_, left)| Reverse(*left));
+        let n = st.updates.len().min(MAX_PIGGYBACK);
+        let members = st.updates[..n].iter().map(|(m, _)| m.clone()).collect();
+        for (_, left) in &mut st.updates[..n] {
+            *left -= 1;
+        }
+        st.updates.retain(|(_, left)| *left > 0);
+        members
+    }
+
+    /// Queues `member` to be piggybacked, replacing older news about it.
+    fn enqueue(&self, st: &mut State, member: GossipMember) {
+        let n = st.members.len() as u32;
+        let times = RETRANSMIT_MULT * (u32::BITS - n.leading_zeros()).max(1);
+        st.updates.retain(|(m, _)| m.name != member.name);
+        st.updates.push((member, times));
+    }
+
+    fn merge(&self, members: Vec<GossipMember>) {
+        let mut st = self.state();
+        for m in members {
+            self.merge_one(&mut st, m);
+        }
+    }
+
+    fn merge_one(&self, st: &mut State, m: GossipMember) {
+        if m.name == self.config.name {
+            let me = &mut st.members.get_mut(&m.name).unwrap().member;
+            // refute, by outbidding whoever says this node is down
+            let refute = me.status() == Status::Alive
+                && m.status() != Status::Alive
+                && m.incarnation >= me.incarnation;
+            if refute {
+                me.incarnation = m.incarnation + 1;
+                let me = me.clone();
+                self.enqueue(st, me);
+            }
+            return;
+        }
+        let old = st.members.get(&m.name).map(|cur| cur.member.status());
+        if let Some(cur) = st.members.get(&m.name) {
+            if !supersedes(&m, &cur.member) {
+                return;
+            }
+        }
+        let up = |s| matches!(s, Status::Alive | Status::Suspect);
+        let member = Member {
+            name: m.name.clone(),
+            rpc_addr: m.rpc_addr.clone(),
+        };
+        let event = match (old, m.status()) {
+            (old, new) if up(new) && !old.is_some_and(up) => Some(MemberEvent::Join(member)),
+            (Some(old), Status::Dead) if up(old) => Some(MemberEvent::Fail(member)),
+            (Some(old), Status::Left) if up(old) => Some(MemberEvent::Leave(member)),
+            _ => None,
+        };
+        st.members
+            .insert(m.name.clone(), MemberState::new(m.clone()));
+        self.enqueue(st, m);
+        if let Some(event) = event {
+            self.emit(event);
+        }
+    }
+
+    fn emit(&self, event: MemberEvent) {
+        debug!("{}: {:?}", self.config.name, event);
+        let _ = self.events.send(event);
+    }
+
+    /// Gossip addresses of up to `n` random members that are up, but for
+    /// this node and `except`.
+    fn random_others(&self, st: &State, n: usize, except: Option<&str>) -> Vec<SocketAddr> {
+        let mut others = self.others(st, except);
+        others.shuffle(&mut rand::thread_rng());
+        others.truncate(n);
+        others
+    }
+
+    fn others(&self, st: &State, except: Option<&str>) -> Vec<SocketAddr> {
+        st.members
+            .values()
+            .filter(|m| m.is_up() && m.member.name != self.config.name)
+            .filter(|m| Some(m.member.name.as_str()) != except)
+            .filter_map(|m| m.member.gossip_addr.parse().ok())
+            .collect()
+    }
+
+    async fn probe(self: Arc<Self>) {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut tick = time::interval(self.config.probe_interval);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_push_pull = Instant::now();
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = shutdown.changed() => return,
+            }
+            self.expire();
+            if last_push_pull.elapsed() >= self.config.push_pull_interval {
+                last_push_pull = Instant::now();
+                let peer = self.random_others(&self.state(), 1, None).pop();
+                if let Some(peer) = peer {
+                    if let Err(e) = self.send_all(peer, Kind::PushPull).await {
+                        debug!("push-pull with {} failed: {}", peer, e);
+                    }
+                }
+            }
+            if let Some(target) = self.next_target() {
+                if let Err(e) = self.probe_member(&target).await {
+                    debug!("probing {} failed: {}", target.name, e);
+                }
+            }
+        }
+    }
+
+    /// The next member up to probe, starting a new shuffled round when every
+    /// one was probed.
+    fn next_target(&self) -> Option<GossipMember> {
+        let mut st = self.state();
+        if st.probe_order.is_empty() {
+            let mut order: Vec<String> = st
+                .members
+                .values()
+                .filter(|m| m.is_up() && m.member.name != self.config.name)
+                .map(|m| m.member.name.clone())
+                .collect();
+            order.shuffle(&mut rand::thread_rng());
+            st.probe_order = order;
+        }
+        while let Some(name) = st.probe_order.pop() {
+            match st.members.get(&name) {
+                Some(m) if m.is_up() => return Some(m.member.clone()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Pings `target`, directly and then through others, and suspects it if
+    /// no ack comes back within the probe interval.
+    async fn probe_member(&self, target: &GossipMember) -> Result<()> {
+        let addr: SocketAddr = target.gossip_addr.parse()?;
+        let seq = self.next_seq();
+        let (acked, mut ack) = oneshot::channel();
+        self.state().pending.insert(seq, Pending::Probe(acked));
+        let acked = async {
+            self.send(addr, Kind::Ping, seq, String::new()).await?;
+            if let Ok(Ok(())) = time::timeout(self.config.probe_timeout, &mut ack).await {
+                return Ok(true);
+            }
+            let helpers = {
+                let st = self.state();
+                self.random_others(&st, self.config.indirect_checks, Some(&target.name))
+            };
+            for helper in helpers {
+                self.send(helper, Kind::PingReq, seq, target.gossip_addr.clone())
+                    .await?;
+            }
+            let rest = self
+                .config
+                .probe_interval
+                .saturating_sub(self.config.probe_timeout);
+            anyhow::Ok(matches!(time::timeout(rest, &mut ack).await, Ok(Ok(()))))
+        }
+        .await;
+        // whether or not a send failed, no ack is waited for any more
+        let mut st = self.state();
+        st.pending.remove(&seq);
+        if acked? {
+            return Ok(());
+        }
+        let suspect = st.members.get_mut(&target.name).filter(|m| {
+            m.member.status() == Status::Alive && m.member.incarnation == target.incarnation
+        });
+        if let Some(m) = suspect {
+            debug!("{} suspects {}", self.config.name, target.name);
+            m.member.set_status(Status::Suspect);
+            m.since = Instant::now();
+            let member = m.member.clone();
+            self.enqueue(&mut st, member);
+        }
+        Ok(())
+    }
+
+    /// Declares suspects that did not refute in time failed, and forgets
+    /// forwarded pings that got no ack.
+    fn expire(&self) {
+        let mut st = self.state();
+        let mut failed = vec![];
+        for m in st.members.values_mut() {
+            if m.member.status() == Status::Suspect
+                && m.since.elapsed() >= self.config.suspect_timeout
+            {
+                m.member.set_status(Status::Dead);
+                m.since = Instant::now();
+                failed.push(m.member.clone());
+            }
+        }
+        for m in failed {
+            info!("{} declares {} failed", self.config.name, m.name);
+            let event = MemberEvent::Fail(Member {
+                name: m.name.clone(),
+                rpc_addr: m.rpc_addr.clone(),
+            });
+            self.enqueue(&mut st, m);
+            self.emit(event);
+        }
+        let timeout = self.config.probe_interval;
+        st.pending.retain(|_, p| match p {
+            Pending::Forward { at, .. } => at.elapsed() < timeout,
+            Pending::Probe(acked) => !acked.is_closed(),
+        });
+    }
+
+    /// Calls `handler` for every member up or left.
+    async fn reconcile(&self, handler: &dyn MembershipHandler) -> Result<()> {
+        let members: Vec<_> = {
+            let st = self.state();
+            st.members
+                .values()
+                .filter(|m| m.member.name != self.config.name)
+                .map(|m| (m.member.status(), m.to_member()))
+                .collect()
+        };
+        for (status, member) in members {
+            match status {
+                Status::Alive | Status::Suspect => handler.join(&member).await?,
+                Status::Left => handler.leave(&member).await?,
+                Status::Dead => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether news `new` about a member overrides what `cur` says: a higher
+/// incarnation always does, and at the same one suspect beats alive and
+/// dead or left beats both.
+fn supersedes(new: &GossipMember, cur: &GossipMember) -> bool {
+    let rank = |m: &GossipMember| match m.status() {
+        Status::Alive => 0,
+        Status::Suspect => 1,
+        Status::Dead | Status::Left => 2,
+    };
+    (new.incarnation, rank(new)) > (cur.incarnation, rank(cur))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use crate::config::{Config, RaftConfig};
+    use crate::replication::ReplicatedLog;
+    use crate::transport::MemNetwork;
+
+    use super::*;
+
+    fn config(name: &str, seeds: Vec<SocketAddr>) -> MembershipConfig {
+        MembershipConfig {
+            name: name.to_owned(),
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            rpc_addr: format!("rpc-{}", name),
+            seeds,
+            probe_interval: Duration::from_millis(50),
+            probe_timeout: Duration::from_millis(20),
+            indirect_checks: 2,
+            suspect_timeout: Duration::from_millis(300),
+            push_pull_interval: Duration::from_millis(200),
+            key: Some([7; 32]),
+        }
+    }
+
+    async fn wait_for(mut f: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if f() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting");
+    }
+
+    async fn next_event(events: &mut broadcast::Receiver<MemberEvent>) -> MemberEvent {
+        time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("an event")
+            .unwrap()
+    }
+
+    fn member(name: &str) -> Member {
+        Member {
+            name: name.to_owned(),
+            rpc_addr: format!("rpc-{}", name),
+        }
+    }
+
+    #[tokio::test]
+    async fn join_leave_fail() -> Result<()> {
+        let first = Membership::start(config("node-0", vec![])).await?;
+        let mut events = first.subscribe();
+        let mut nodes = vec![];
+        for i in 1..4 {
+            let name = format!("node-{}", i);
+            nodes.push(Membership::start(config(&name, vec![first.addr()])).await?);
+        }
+        for i in 1..4 {
+            let name = format!("node-{}", i);
+            assert_eq!(
+                next_event(&mut events).await,
+                MemberEvent::Join(member(&name))
+            );
+        }
+        for node in &nodes {
+            wait_for(|| node.members().len() == 4).await;
+        }
+
+        nodes.pop().unwrap().leave().await?;
+        assert_eq!(
+            next_event(&mut events).await,
+            MemberEvent::Leave(member("node-3"))
+        );
+
+        drop(nodes.pop());
+        assert_eq!(
+            next_event(&mut events).await,
+            MemberEvent::Fail(member("node-2"))
+        );
+        let mut names: Vec<_> = first.members().into_iter().map(|m| m.name).collect();
+        names.sort();
+        assert_eq!(names, ["node-0", "node-1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_other_keys() -> Result<()> {
+        let first = Membership::start(config("node-0", vec![])).await?;
+        let mut other = config("node-1", vec![first.addr()]);
+        other.key = Some([8; 32]);
+        let other = Membership::start(other).await?;
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(first.members().len(), 1);
+        assert_eq!(other.members().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adds_voters() -> Result<()> {
+        let network = MemNetwork::default();
+        let dirs: Vec<_> = (0..3).map(|_| tempdir().unwrap()).collect();
+        let mut nodes = vec![];
+        let mut memberships: Vec<Membership> = vec![];
+        for (i, dir) in dirs.iter().enumerate() {
+            let name = format!("node-{}", i);
+            let rpc_addr = format!("rpc-{}", name);
+            // the first node starts the cluster, the others wait to be added
+            let peers = match i {
+                0 => BTreeMap::from([(name.clone(), rpc_addr.clone())]),
+                _ => BTreeMap::new(),
+            };
+            let raft = RaftConfig {
+                id: name.clone(),
+                peers,
+                heartbeat_interval: Duration::from_millis(20),
+                election_timeout: Duration::from_millis(100),
+                ..Default::default()
+            };
+            let transport = network.transport(&rpc_addr);
+            let node = Arc::new(ReplicatedLog::start(
+                dir.path(),
+                Config::default(),
+                raft,
+                transport,
+            )?);
+            network.register(&rpc_addr, &node);
+            let seeds = memberships.iter().take(1).map(|m| m.addr()).collect();
+            let membership = Membership::start(config(&name, seeds)).await?;
+            membership.handle(node.clone());
+            memberships.push(membership);
+            nodes.push(node);
+        }
+
+        wait_for(|| nodes.iter().all(|n| n.voters().len() == 3)).await;
+        let leader = nodes.iter().find(|n| n.is_leader()).unwrap();
+        let offset = leader
+            .append(protos::log::v1::Record {
+                value: b"hello".to_vec(),
+                ..Default::default()
+            })
+            .await?;
+        wait_for(|| nodes.iter().all(|n| n.log().read(offset).is_ok())).await;
+
+        let leaving = nodes.iter().position(|n| !n.is_leader()).unwrap();
+        memberships.remove(leaving).leave().await?;
+        wait_for(|| {
+            let voters = nodes.iter().find(|n| n.is_leader()).map(|n| n.voters());
+            voters.is_some_and(|v| v.len() == 2 && !v.contains_key(&format!("node-{}", leaving)))
+        })
+        .await;
+        Ok(())
+    }
+}
@@ -1,14 +1,19 @@
 use std::collections::VecDeque;
-use std::io::Read;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
 
+/// Reads its readers one after the other as a single stream. Seeking measures
+/// the readers with `SeekFrom::End(0)`, so each must know its length.
 pub(crate) struct MultiReader<R> {
     pub(crate) inner: VecDeque<R>,
+    /// Index in `inner` of the reader being read.
+    current: usize,
 }
 
 impl<R> Default for MultiReader<R> {
     fn default() -> Self {
         MultiReader {
             inner: VecDeque::new(),
+            current: 0,
         }
     }
 }
@@ -18,11 +23,10 @@ where
     R: Read,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        while !self.inner.is_empty() {
-            let r = self.inner.get_mut(0).unwrap();
+        while let Some(r) = self.inner.get_mut(self.current) {
             let sz = r.read(buf)?;
             if sz == 0 {
-                self.inner.pop_front();
+                self.current += 1;
                 continue;
             } else {
                 return Ok(sz);
@@ -33,6 +37,63 @@ where
     }
 }
 
+impl<R> Seek for MultiReader<R>
+where
+    R: Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => {
+                let mut at = 0;
+                for r in self.inner.iter_mut().take(self.current) {
+                    at += stream_len(r)?;
+                }
+                if let Some(r) = self.inner.get_mut(self.current) {
+                    at += r.stream_position()?;
+                }
+                at.checked_add_signed(d)
+            }
+            SeekFrom::End(d) => {
+                let mut len = 0;
+                for r in self.inner.iter_mut() {
+                    len += stream_len(r)?;
+                }
+                len.checked_add_signed(d)
+            }
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the start"))?;
+
+        let mut start = 0;
+        for i in 0..self.inner.len() {
+            let len = stream_len(&mut self.inner[i])?;
+            if target < start + len || i == self.inner.len() - 1 {
+                self.inner[i].seek(SeekFrom::Start(target - start))?;
+                for r in self.inner.iter_mut().skip(i + 1) {
+                    r.rewind()?;
+                }
+                self.current = i;
+                return Ok(target);
+            }
+            start += len;
+        }
+        if target > 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "seek past the end"));
+        }
+        Ok(0)
+    }
+}
+
+/// Length of `r`, leaving its position where it was.
+fn stream_len<R: Seek>(r: &mut R) -> io::Result<u64> {
+    let pos = r.stream_position()?;
+    let len = r.seek(SeekFrom::End(0))?;
+    if pos != len {
+        r.seek(SeekFrom::Start(pos))?;
+    }
+    Ok(len)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,9 +106,7 @@ mod tests {
 
         let c1 = Cursor::new(s1.as_bytes());
         let c2 = Cursor::new(s2.as_bytes());
-        let mut mr = MultiReader {
-            inner: VecDeque::new(),
-        };
+        let mut mr = MultiReader::default();
         mr.inner.push_back(c1);
         mr.inner.push_back(c2);
 
@@ -65,4 +124,29 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn seek() {
+        let mut mr = MultiReader::default();
+        for s in ["1234", "", "56789"] {
+            mr.inner.push_back(Cursor::new(s.as_bytes()));
+        }
+        let mut rest = String::new();
+        assert_eq!(mr.seek(SeekFrom::Start(3)).unwrap(), 3);
+        mr.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "456789");
+
+        assert_eq!(mr.seek(SeekFrom::End(-2)).unwrap(), 7);
+        let mut b = [0; 1];
+        mr.read_exact(&mut b).unwrap();
+        assert_eq!(mr.stream_position().unwrap(), 8);
+        assert_eq!(mr.seek(SeekFrom::Current(-6)).unwrap(), 2);
+        rest.clear();
+        mr.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "3456789");
+
+        assert_eq!(mr.seek(SeekFrom::Start(9)).unwrap(), 9);
+        assert_eq!(mr.read(&mut b).unwrap(), 0);
+        assert!(mr.seek(SeekFrom::Current(-10)).is_err());
+    }
 }
@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Tracks the next offset the log will assign and wakes readers waiting for it
+/// to move, whether they block a thread or await in a task. The head moves
+/// backward when the log is truncated; subscriptions are told how far.
+pub(crate) struct OffsetNotifier {
+    head: Mutex<Head>,
+    cond: Condvar,
+    tx: watch::Sender<u64>,
+}
+
+struct Head {
+    next: u64,
+    /// For each live subscription, the lowest the head fell to since it last
+    /// looked, `u64::MAX` if it did not fall.
+    lows: Vec<Weak<AtomicU64>>,
+}
+
+/// A subscription to the head, see [`OffsetNotifier::subscribe`].
+pub(crate) struct HeadSubscription {
+    rx: watch::Receiver<u64>,
+    low: Arc<AtomicU64>,
+}
+
+impl HeadSubscription {
+    /// Waits for the head to move. Returns false once the notifier is gone.
+    pub async fn changed(&mut self) -> bool {
+        self.rx.changed().await.is_ok()
+    }
+}
+
+impl OffsetNotifier {
+    pub fn new(next: u64) -> Self {
+        let (tx, _) = watch::channel(next);
+        OffsetNotifier {
+            head: Mutex::new(Head { next, lows: vec![] }),
+            cond: Condvar::new(),
+            tx,
+        }
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.head.lock().unwrap().next
+    }
+
+    /// Publishes a new next offset and wakes every waiter.
+    pub fn advance(&self, next: u64) {
+        let mut head = self.head.lock().unwrap();
+        if next < head.next {
+            head.lows.retain(|low| match low.upgrade() {
+                Some(low) => {
+                    low.fetch_min(next, Ordering::Relaxed);
+                    true
+                }
+                None => false,
+            });
+        }
+        head.next = next;
+        self.tx.send_replace(next);
+        self.cond.notify_all();
+    }
+
+    /// Blocks until a record exists at `offset` or `timeout` passes. Returns
+    /// whether the record exists.
+    pub fn wait_past(&self, offset: u64, timeout: Duration) -> bool {
+        let head = self.head.lock().unwrap();
+        let (head, _) = self
+            .cond
+            .wait_timeout_while(head, timeout, |h| h.next <= offset)
+            .unwrap();
+        head.next > offset
+    }
+
+    /// Async counterpart of [`OffsetNotifier::wait_past`].
+    pub async fn wait_past_async(&self, offset: u64, timeout: Duration) -> bool {
+        let mut rx = self.tx.subscribe();
+        let wait = async {
+            while *rx.borrow() <= offset {
+                if rx.changed().await.is_err() {
+                    return;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(timeout, wait).await;
+        self.next_offset() > offset
+    }
+
+    pub fn subscribe(&self) -> HeadSubscription {
+        let mut head = self.head.lock().unwrap();
+        head.lows.retain(|low| low.strong_count() > 0);
+        let low = Arc::new(AtomicU64::new(u64::MAX));
+        head.lows.push(Arc::downgrade(&low));
+        HeadSubscription {
+            rx: self.tx.subscribe(),
+            low,
+        }
+    }
+
+    /// Returns the next offset and, if the head fell since `sub` last looked,
+    /// the lowest it fell to.
+    pub fn observe(&self, sub: &mut HeadSubscription) -> (u64, Option<u64>) {
+        let head = self.head.lock().unwrap();
+        sub.rx.borrow_and_update();
+        let low = sub.low.swap(u64::MAX, Ordering::Relaxed);
+        (head.next, Some(low).filter(|&low| low != u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn wait_past() {
+        let n = Arc::new(OffsetNotifier::new(0));
+        assert!(!n.wait_past(0, Duration::from_millis(10)));
+
+        let waiter = {
+            let n = n.clone();
+            thread::spawn(move || n.wait_past(0, Duration::from_secs(10)))
+        };
+        n.advance(1);
+        assert!(waiter.join().unwrap());
+        assert!(n.wait_past(0, Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn wait_past_async() {
+        let n = Arc::new(OffsetNotifier::new(3));
+        assert!(!n.wait_past_async(3, Duration::from_millis(10)).await);
+
+        let waiter = {
+            let n = n.clone();
+            tokio::spawn(async move { n.wait_past_async(3, Duration::from_secs(10)).await })
+        };
+        n.advance(4);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn observe_truncation() {
+        let n = OffsetNotifier::new(5);
+        let mut sub = n.subscribe();
+        assert_eq!(n.observe(&mut sub), (5, None));
+
+        // the head passing its old value again does not hide the truncation
+        n.advance(2);
+        n.advance(3);
+        n.advance(7);
+        assert!(sub.changed().await);
+        assert_eq!(n.observe(&mut sub), (7, Some(2)));
+        assert_eq!(n.observe(&mut sub), (7, None));
+    }
+}
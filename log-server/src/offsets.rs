@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use prost::Message;
+
+use protos::log::v1::{OffsetCommitKey, OffsetCommitValue, Record};
+
+use crate::config::{CleanupPolicy, Config, Durability};
+use crate::log::Log;
+
+/// Name of the internal log offsets are committed to, a subdirectory of the
+/// server's log directory.
+pub const OFFSETS_LOG: &str = "__offsets";
+
+/// The offsets of a consumer group by topic and partition, each with the
+/// offset of the record in the offsets log that committed it.
+type GroupOffsets = BTreeMap<(String, u32), (u64, u64)>;
+
+/// Offsets committed by consumer groups, kept as records of an internal
+/// compacted log keyed by group, topic and partition, so the log holds little
+/// more than the latest commit of each. The latest offsets are also kept in
+/// memory, rebuilt from the log on open.
+pub struct OffsetStore {
+    log: Arc<Log>,
+    groups: Mutex<BTreeMap<String, GroupOffsets>>,
+}
+
+impl OffsetStore {
+    /// Opens the offsets log in `dir`, with the segment settings of `config`.
+    pub fn open(dir: &Path, config: &Config) -> Result<Self> {
+        let mut config = config.clone();
+        config.segment.key_index = false;
+        config.durability = Durability::SyncEveryAppend;
+        config.cleanup_policy = CleanupPolicy::Compact;
+        config.retention_bytes = None;
+        config.retention_duration = None;
+        std::fs::create_dir_all(dir)?;
+        let log = Arc::new(Log::new(dir, config)?);
+        let mut groups: BTreeMap<String, GroupOffsets> = BTreeMap::new();
+        for r in log.iter_from(log.lowest_offset()?) {
+            let r = r?;
+            let key = r
+                .key
+                .as_deref()
+                .ok_or_else(|| anyhow!("offset commit at {} has no key", r.offset))?;
+            let key = OffsetCommitKey::decode(key)?;
+            let offsets = groups.entry(key.group).or_default();
+            if r.value.is_empty() {
+                offsets.remove(&(key.topic, key.partition));
+            } else {
+                let value = OffsetCommitValue::decode(&*r.value)?;
+                let offset = value.offset.unwrap_or_default();
+                offsets.insert((key.topic, key.partition), (offset, r.offset));
+            }
+        }
+        groups.retain(|_, offsets| !offsets.is_empty());
+        info!("loaded the offsets of {} consumer groups", groups.len());
+        Ok(OffsetStore {
+            log,
+            groups: Mutex::new(groups),
+        })
+    }
+
+    /// Records `offset` as the next one `group` consumes from the partition,
+    /// once it is on disk.
+    pub fn commit(&self, group: &str, topic: &str, partition: u32, offset: u64) -> Result<()> {
+        if group.is_empty() {
+            return Err(anyhow!("empty consumer group"));
+        }
+        let key = OffsetCommitKey {
+            group: group.to_owned(),
+            topic: topic.to_owned(),
+            partition,
+        };
+        let mut record = Record {
+            key: Some(key.encode_to_vec()),
+            value: OffsetCommitValue {
+                offset: Some(offset),
+            }
+            .encode_to_vec(),
+            ..Default::default()
+        };
+        let at = self.log.append(&mut record)?;
+        // commits racing for a partition apply in the order of the log, as
+        // reopening would, whichever finishes its fsync first
+        let mut groups = self.groups.lock().unwrap();
+        let committed = groups
+            .entry(key.group)
+            .or_default()
+            .entry((key.topic, key.partition))
+            .or_insert((offset, at));
+        if committed.1 < at {
+            *committed = (offset, at);
+        }
+        Ok(())
+    }
+
+    /// The offset `group` last committed for the partition.
+    pub fn fetch(&self, group: &str, topic: &str, partition: u32) -> Option<u64> {
+        let groups = self.groups.lock().unwrap();
+        groups
+            .get(group)?
+            .get(&(topic.to_owned(), partition))
+            .map(|&(offset, _)| offset)
+    }
+
+    /// Groups that committed an offset, sorted.
+    pub fn groups(&self) -> Vec<String> {
+        self.groups.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The internal log, e.g. for a [`RetentionCleaner`](crate::RetentionCleaner)
+    /// to compact.
+    pub fn log(&self) -> &Arc<Log> {
+        &self.log
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.log.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn commit_fetch_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let config = Config::default();
+        let store = OffsetStore::open(dir.path(), &config)?;
+        assert_eq!(store.fetch("billing", "orders", 0), None);
+        store.commit("billing", "orders", 0, 10)?;
+        store.commit("billing", "orders", 1, 4)?;
+        store.commit("search", "orders", 0, 7)?;
+        store.commit("search", "payments", 0, 0)?;
+        store.commit("billing", "orders", 0, 12)?;
+        assert!(store.commit("", "orders", 0, 1).is_err());
+        assert_eq!(store.fetch("billing", "orders", 0), Some(12));
+        assert_eq!(store.groups(), ["billing", "search"]);
+        store.close()?;
+        drop(store);
+
+        let store = OffsetStore::open(dir.path(), &config)?;
+        assert_eq!(store.groups(), ["billing", "search"]);
+        assert_eq!(store.fetch("billing", "orders", 0), Some(12));
+        assert_eq!(store.fetch("billing", "orders", 1), Some(4));
+        assert_eq!(store.fetch("search", "orders", 0), Some(7));
+        assert_eq!(store.fetch("search", "orders", 1), None);
+        assert_eq!(store.fetch("search", "payments", 0), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_to_latest_commits() -> Result<()> {
+        let dir = tempdir()?;
+        let mut config = Config::default();
+        config.segment.max_store_bytes = 256;
+        let store = OffsetStore::open(dir.path(), &config)?;
+        for offset in 0..100 {
+            store.commit("billing", "orders", offset as u32 % 2, offset)?;
+        }
+        let stats = store.log().compact()?;
+        assert!(stats.records_removed > 0);
+        store.close()?;
+        drop(store);
+
+        let store = OffsetStore::open(dir.path(), &config)?;
+        assert_eq!(store.fetch("billing", "orders", 0), Some(98));
+        assert_eq!(store.fetch("billing", "orders", 1), Some(99));
+        Ok(())
+    }
+}
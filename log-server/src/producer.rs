@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use prost::Message;
+use rand::Rng;
+
+use protos::log::v1::{ProducerBatch, ProducerSnapshot};
+
+use crate::error::Error;
+
+/// File in a log's directory holding its [`ProducerSnapshot`].
+pub(crate) const SNAPSHOT_FILE: &str = "producers.snapshot";
+
+/// A new producer id. Ids are random rather than handed out in order, so no
+/// server has to remember the last one; 0 means no producer.
+pub fn new_producer_id() -> u64 {
+    rand::thread_rng().gen_range(1..u64::MAX)
+}
+
+/// The idempotent producer of a batch and the batch's sequence, kept in the
+/// batch's header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct BatchProducer {
+    pub id: u64,
+    pub sequence: u64,
+}
+
+/// The last batch each idempotent producer appended to a log. Batches
+/// record their producer, so the state is rebuilt from the log on open; the
+/// snapshot is only a checkpoint that spares reading the log below its
+/// `next_offset`.
+pub(crate) struct ProducerState {
+    path: PathBuf,
+    /// fsync the snapshot after every write.
+    sync: bool,
+    batches: HashMap<u64, ProducerBatch>,
+}
+
+impl ProducerState {
+    /// Loads the snapshot in `dir`, if there is one, and returns the offset
+    /// batches past it are to be read from.
+    pub fn load(dir: &Path, sync: bool) -> Result<(Self, u64)> {
+        let path = dir.join(SNAPSHOT_FILE);
+        let mut batches = HashMap::new();
+        let mut next_offset = 0;
+        if path.is_file() {
+            let snapshot = ProducerSnapshot::decode(&*fs::read(&path)?)?;
+            next_offset = snapshot.next_offset;
+            for b in snapshot.producers {
+                batches.insert(b.producer_id, b);
+            }
+        }
+        let state = ProducerState {
+            path,
+            sync,
+            batches,
+        };
+        Ok((state, next_offset))
+    }
+
+    /// Checks batch `p.sequence` of producer `p.id` before it is appended:
+    /// returns the offsets of a retried last batch, nothing for the next
+    /// batch or the first of an unknown producer, and fails for any other.
+    pub fn check(&self, p: BatchProducer) -> Result<Option<Range<u64>>> {
+        match self.batches.get(&p.id) {
+            Some(b) if b.sequence == p.sequence => Ok(Some(b.first_offset..b.next_offset)),
+            Some(b) if b.sequence + 1 != p.sequence => Err(Error::OutOfOrderSequence {
+                producer_id: p.id,
+                sequence: p.sequence,
+                expected: b.sequence + 1,
+            }
+            .into()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Records that `p`'s batch was appended at `range` at `timestamp`.
+    pub fn update(&mut self, p: BatchProducer, range: Range<u64>, timestamp: i64) {
+        self.insert(ProducerBatch {
+            producer_id: p.id,
+            sequence: p.sequence,
+            first_offset: range.start,
+            next_offset: range.end,
+            timestamp,
+        });
+    }
+
+    /// Records `batch` unless its producer already has a later one.
+    pub fn insert(&mut self, batch: ProducerBatch) {
+        match self.batches.get(&batch.producer_id) {
+            Some(b) if b.next_offset > batch.next_offset => {}
+            _ => {
+                self.batches.insert(batch.producer_id, batch);
+            }
+        }
+    }
+
+    /// The batches ending in `range`.
+    pub fn ending_in(&self, range: Range<u64>) -> Vec<ProducerBatch> {
+        self.batches
+            .values()
+            .filter(|b| b.next_offset > range.start && b.next_offset <= range.end)
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets the batches reaching `offset` or past it, once the log no
+    /// longer holds them.
+    pub fn truncate_from(&mut self, offset: u64) {
+        self.batches.retain(|_, b| b.next_offset <= offset);
+    }
+
+    /// Forgets the batches that end below `offset`, once the log no longer
+    /// holds them.
+    pub fn remove_below(&mut self, offset: u64) {
+        self.batches.retain(|_, b| b.next_offset > offset);
+    }
+
+    /// Forgets the producers whose last batch was appended before `before`.
+    pub fn expire(&mut self, before: i64) {
+        self.batches.retain(|_, b| b.timestamp >= before);
+    }
+
+    /// Saves the snapshot as of `next_offset`, which the state must hold
+    /// every batch below.
+    pub fn checkpoint(&self, next_offset: u64) -> Result<()> {
+        let snapshot = ProducerSnapshot {
+            producers: self.batches.values().cloned().collect(),
+            next_offset,
+        };
+        let tmp = self.path.with_file_name(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&snapshot.encode_to_vec())?;
+        if self.sync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn batch(id: u64, sequence: u64) -> BatchProducer {
+        BatchProducer { id, sequence }
+    }
+
+    #[test]
+    fn sequences() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut state, next_offset) = ProducerState::load(dir.path(), false)?;
+        assert_eq!(next_offset, 0);
+        assert_eq!(state.check(batch(7, 5))?, None);
+        state.update(batch(7, 5), 0..3, 100);
+        assert_eq!(state.check(batch(7, 5))?, Some(0..3));
+        assert_eq!(state.check(batch(7, 6))?, None);
+        state.update(batch(7, 6), 3..4, 100);
+        state.update(batch(8, 0), 4..5, 200);
+        state.checkpoint(5)?;
+
+        let (mut state, next_offset) = ProducerState::load(dir.path(), false)?;
+        assert_eq!(next_offset, 5);
+        assert_eq!(state.check(batch(7, 6))?, Some(3..4));
+        let err = state.check(batch(7, 5)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::OutOfOrderSequence { expected: 7, .. })
+        ));
+        assert!(state.check(batch(7, 8)).is_err());
+        assert_eq!(state.ending_in(4..5).len(), 1);
+
+        state.expire(150);
+        assert_eq!(state.check(batch(7, 0))?, None);
+        assert_eq!(state.check(batch(8, 0))?, Some(4..5));
+        state.truncate_from(4);
+        assert_eq!(state.check(batch(8, 0))?, None);
+        state.update(batch(8, 1), 4..6, 300);
+        state.remove_below(6);
+        assert_eq!(state.check(batch(8, 1))?, None);
+        Ok(())
+    }
+}
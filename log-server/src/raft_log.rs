@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use prost::Message;
+use protos::log::v1::{HardState, RaftConfiguration, RaftEntry, RaftPeer, RaftSnapshot, Record};
+
+use crate::config::{CleanupPolicy, Config, Durability};
+use crate::log::Log;
+
+/// File under a [`RaftLog`]'s directory holding its [`RaftSnapshot`].
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// Durable state of a Raft node, kept in two [`Log`]s under one directory:
+/// `log` holds the entries, the entry at index `i` being the record at offset
+/// `i`, and `state` gets a [`HardState`] appended whenever the term or vote
+/// changes. The state is fsynced on every append, since a node must not answer
+/// a request before what it promised is on disk. Entries are not: the node
+/// fsyncs them through a [`PendingSync`] without holding the `RaftLog`, so
+/// appends made meanwhile share the flush.
+///
+/// Entries up to the [`RaftSnapshot`] were applied and trimmed; their records
+/// only live on in the replicated log.
+pub(crate) struct RaftLog {
+    dir: PathBuf,
+    entries: Arc<Log>,
+    state: Log,
+    snapshot: RaftSnapshot,
+    /// The voters of `snapshot.config`.
+    snapshot_peers: Option<BTreeMap<String, String>>,
+    last_index: u64,
+    last_term: u64,
+    /// Highest index known to be on disk.
+    durable_index: u64,
+    /// Bumped whenever entries are removed, so a sync started before does not
+    /// vouch for the ones appended in their place.
+    generation: u64,
+    /// Index and voters of every entry past the snapshot that changes the
+    /// configuration.
+    configs: Vec<(u64, BTreeMap<String, String>)>,
+}
+
+/// The entries of a [`RaftLog`] as of when it was taken, to fsync without
+/// holding the `RaftLog`; see [`RaftLog::pending_sync`].
+pub(crate) struct PendingSync {
+    entries: Arc<Log>,
+    last_index: u64,
+    generation: u64,
+}
+
+impl PendingSync {
+    pub fn sync(&self) -> Result<()> {
+        self.entries.sync()
+    }
+}
+
+impl RaftLog {
+    /// Opens the logs under `dir`, with the segment settings of `config`.
+    pub fn open(dir: &Path, config: &Config) -> Result<Self> {
+        let mut config = config.clone();
+        config.segment.initial_offset = 1;
+        config.segment.key_index = false;
+        config.cleanup_policy = CleanupPolicy::Delete;
+        config.retention_bytes = None;
+        config.retention_duration = None;
+        for name in ["log", "state"] {
+            fs::create_dir_all(dir.join(name))?;
+        }
+        config.durability = Durability::OsManaged;
+        let entries = Arc::new(Log::new(&dir.join("log"), config.clone())?);
+        config.durability = Durability::SyncEveryAppend;
+        let state = Log::new(&dir.join("state"), config)?;
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(b) => RaftSnapshot::decode(&*b)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RaftSnapshot::default(),
+            Err(e) => return Err(e.into()),
+        };
+        if entries.highest_offset()? < snapshot.last_index {
+            // a crash cut short installing the snapshot
+            entries.reset(snapshot.last_index + 1)?;
+        }
+        let mut raft_log = RaftLog {
+            dir: dir.to_owned(),
+            entries,
+            state,
+            snapshot_peers: snapshot.config.as_ref().map(peers),
+            snapshot,
+            last_index: 0,
+            last_term: 0,
+            durable_index: 0,
+            generation: 0,
+            configs: vec![],
+        };
+        raft_log.last_index = raft_log.entries.highest_offset()?;
+        raft_log.last_term = raft_log.read_term(raft_log.last_index)?;
+        raft_log.durable_index = raft_log.last_index;
+        if raft_log.last_index > raft_log.snapshot.last_index {
+            for r in raft_log.entries.iter_from(raft_log.snapshot.last_index + 1) {
+                let r = r?;
+                if let Some(config) = RaftEntry::decode(&*r.value)?.config {
+                    raft_log.configs.push((r.offset, peers(&config)));
+                }
+            }
+        }
+        Ok(raft_log)
+    }
+
+    /// The latest saved term and vote, or the initial ones.
+    pub fn hard_state(&self) -> Result<HardState> {
+        let lowest = self.state.lowest_offset()?;
+        match self.state.iter_from(lowest).next_back() {
+            Some(r) => Ok(HardState::decode(&*r?.value)?),
+            None => Ok(HardState::default()),
+        }
+    }
+
+    pub fn save_hard_state(&self, hs: &HardState) -> Result<()> {
+        let mut record = Record {
+            value: hs.encode_to_vec(),
+            ..Default::default()
+        };
+        let offset = self.state.append(&mut record)?;
+        // only the latest one is ever read
+        self.state.truncate(offset - 1)
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.entries.sync()?;
+        self.entries.close()?;
+        self.state.close()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Index of the last entry, 0 if there is none.
+    pub fn last_index(&self) -> u64 {
+        self.last_index
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.last_term
+    }
+
+    /// Index of the last entry known to be on disk.
+    pub fn durable_index(&self) -> u64 {
+        self.durable_index
+    }
+
+    /// What to fsync to make every entry durable, unless they already are.
+    pub fn pending_sync(&self) -> Option<PendingSync> {
+        (self.durable_index < self.last_index).then(|| PendingSync {
+            entries: self.entries.clone(),
+            last_index: self.last_index,
+            generation: self.generation,
+        })
+    }
+
+    /// Records that `pending` was fsynced.
+    pub fn synced(&mut self, pending: &PendingSync) {
+        if pending.generation == self.generation {
+            self.durable_index = self.durable_index.max(pending.last_index);
+        }
+    }
+
+    pub fn snapshot(&self) -> &RaftSnapshot {
+        &self.snapshot
+    }
+
+    /// Term of the entry at `index`; 0 for index 0, before the first entry.
+    /// Fails for entries trimmed into the snapshot, but the last of them.
+    pub fn term(&self, index: u64) -> Result<u64> {
+        if index == self.last_index {
+            return Ok(self.last_term);
+        }
+        self.read_term(index)
+    }
+
+    fn read_term(&self, index: u64) -> Result<u64> {
+        if index == self.snapshot.last_index {
+            return Ok(self.snapshot.last_term);
+        }
+        Ok(self.entry(index)?.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Result<RaftEntry> {
+        if index <= self.snapshot.last_index {
+            return Err(anyhow!("raft entry {} was trimmed", index));
+        }
+        Ok(RaftEntry::decode(&*self.entries.read(index)?.value)?)
+    }
+
+    /// Up to `max` entries from index `from` on.
+    pub fn entries(&self, from: u64, max: usize) -> Result<Vec<RaftEntry>> {
+        if from <= self.snapshot.last_index {
+            return Err(anyhow!("raft entry {} was trimmed", from));
+        }
+        self.entries
+            .iter_from(from)
+            .take(max)
+            .map(|r| Ok(RaftEntry::decode(&*r?.value)?))
+            .collect()
+    }
+
+    /// Appends `entries` after the last one, leaving them to be fsynced.
+    pub fn append(&mut self, entries: &[RaftEntry]) -> Result<()> {
+        let Some(last) = entries.last() else {
+            return Ok(());
+        };
+        let mut records: Vec<_> = entries
+            .iter()
+            .map(|e| Record {
+                value: e.encode_to_vec(),
+                ..Default::default()
+            })
+            .collect();
+        let range = self.entries.append_batch(&mut records)?;
+        for (index, entry) in range.clone().zip(entries) {
+            if let Some(config) = &entry.config {
+                self.configs.push((index, peers(config)));
+            }
+        }
+        self.last_index = range.end - 1;
+        self.last_term = last.term;
+        Ok(())
+    }
+
+    /// The latest configuration in the log, committed or not, with the index
+    /// of its entry.
+    pub fn config(&self) -> Option<(u64, &BTreeMap<String, String>)> {
+        match (self.configs.last(), &self.snapshot_peers) {
+            (Some((index, peers)), _) => Some((*index, peers)),
+            (None, Some(peers)) => Some((self.snapshot.last_index, peers)),
+            (None, None) => None,
+        }
+    }
+
+    /// Removes the entries at and after `index`, which must be past the
+    /// snapshot.
+    pub fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate_from(index)?;
+        self.configs.retain(|(i, _)| *i < index);
+        self.last_index = index - 1;
+        self.last_term = self.read_term(self.last_index)?;
+        self.durable_index = self.durable_index.min(self.last_index);
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// The snapshot that would trim the entries up to `index`.
+    pub fn snapshot_at(&self, index: u64) -> Result<RaftSnapshot> {
+        let config = match self.configs.iter().rfind(|(i, _)| *i <= index) {
+            Some((_, peers)) => Some(configuration(peers)),
+            None => self.snapshot.config.clone(),
+        };
+        Ok(RaftSnapshot {
+            last_index: index,
+            last_term: self.term(index)?,
+            config,
+            next_offset: self.next_data_offset_at(index, 0)?,
+        })
+    }
+
+    /// Makes `snapshot`, already saved with [`save_snapshot`], the start of
+    /// the log and removes the entries it covers, as far as whole segments go.
+    pub fn trim(&mut self, snapshot: RaftSnapshot) -> Result<()> {
+        self.entries.truncate(snapshot.last_index)?;
+        self.configs.retain(|(i, _)| *i > snapshot.last_index);
+        self.snapshot_peers = snapshot.config.as_ref().map(peers);
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    /// Replaces every entry with `snapshot`, already saved with
+    /// [`save_snapshot`], as a follower does with the one its leader sent.
+    pub fn install(&mut self, snapshot: RaftSnapshot) -> Result<()> {
+        self.entries.reset(snapshot.last_index + 1)?;
+        self.configs.clear();
+        self.last_index = snapshot.last_index;
+        self.last_term = snapshot.last_term;
+        self.durable_index = snapshot.last_index;
+        self.generation += 1;
+        self.snapshot_peers = snapshot.config.as_ref().map(peers);
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
+    /// Offset the records after the last entry would start at: one past the
+    /// last record of any entry, or `initial` if no entry has records.
+    pub fn next_data_offset(&self, initial: u64) -> Result<u64> {
+        self.next_data_offset_at(self.last_index, initial)
+    }
+
+    fn next_data_offset_at(&self, mut index: u64, initial: u64) -> Result<u64> {
+        while index > self.snapshot.last_index {
+            if let Some(r) = self.entry(index)?.records.last() {
+                return Ok(r.offset + 1);
+            }
+            index -= 1;
+        }
+        Ok(initial.max(self.snapshot.next_offset))
+    }
+
+    /// Index of the last entry whose records are all below `next_offset`,
+    /// i.e. were already applied to a log that goes up to it. Entries up to
+    /// the snapshot always were.
+    pub fn applied_index(&self, next_offset: u64) -> Result<u64> {
+        let mut index = self.last_index;
+        while index > self.snapshot.last_index {
+            if let Some(r) = self.entry(index)?.records.last() {
+                if r.offset < next_offset {
+                    break;
+                }
+            }
+            index -= 1;
+        }
+        Ok(index)
+    }
+}
+
+/// Saves `snapshot` to the [`RaftLog`] in `dir`, before it trims or
+/// installs it.
+pub(crate) fn save_snapshot(dir: &Path, snapshot: &RaftSnapshot) -> Result<()> {
+    let tmp = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&snapshot.encode_to_vec())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(SNAPSHOT_FILE))?;
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn peers(config: &RaftConfiguration) -> BTreeMap<String, String> {
+    config
+        .peers
+        .iter()
+        .map(|p| (p.id.clone(), p.addr.clone()))
+        .collect()
+}
+
+/// The configuration entry payload for `peers`.
+pub(crate) fn configuration(peers: &BTreeMap<String, String>) -> RaftConfiguration {
+    RaftConfiguration {
+        peers: peers
+            .iter()
+            .map(|(id, addr)| RaftPeer {
+                id: id.clone(),
+                addr: addr.clone(),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entry(term: u64, offsets: &[u64]) -> RaftEntry {
+        RaftEntry {
+            term,
+            records: offsets
+                .iter()
+                .map(|&offset| Record {
+                    offset,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn raft_log() -> Result<()> {
+        let dir = tempdir()?;
+        let mut raft_log = RaftLog::open(dir.path(), &Config::default())?;
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (0, 0));
+        assert_eq!(raft_log.hard_state()?, HardState::default());
+
+        raft_log.append(&[entry(1, &[0, 1]), entry(1, &[]), entry(2, &[2])])?;
+        raft_log.append(&[entry(2, &[3, 4])])?;
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (4, 2));
+        assert_eq!(raft_log.term(2)?, 1);
+        assert_eq!(raft_log.entries(2, 2)?, vec![entry(1, &[]), entry(2, &[2])]);
+        assert_eq!(raft_log.next_data_offset(0)?, 5);
+        assert_eq!(raft_log.applied_index(3)?, 3);
+        assert_eq!(raft_log.applied_index(1)?, 0);
+
+        let pending = raft_log.pending_sync().unwrap();
+        raft_log.truncate_from(3)?;
+        pending.sync()?;
+        raft_log.synced(&pending);
+        assert_eq!(raft_log.durable_index(), 0);
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (2, 1));
+        assert_eq!(raft_log.next_data_offset(0)?, 2);
+        raft_log.append(&[entry(3, &[2])])?;
+        assert_eq!(raft_log.entry(3)?, entry(3, &[2]));
+        let pending = raft_log.pending_sync().unwrap();
+        pending.sync()?;
+        raft_log.synced(&pending);
+        assert_eq!(raft_log.durable_index(), 3);
+        assert!(raft_log.pending_sync().is_none());
+
+        let voters = BTreeMap::from([("node-1".to_owned(), "addr-1".to_owned())]);
+        let mut change = entry(3, &[]);
+        change.config = Some(configuration(&voters));
+        raft_log.append(&[change])?;
+        assert_eq!(raft_log.config(), Some((4, &voters)));
+
+        let hs = HardState {
+            term: 3,
+            voted_for: "node-1".to_owned(),
+        };
+        raft_log.save_hard_state(&hs)?;
+        raft_log.close()?;
+        drop(raft_log);
+        let mut raft_log = RaftLog::open(dir.path(), &Config::default())?;
+        assert_eq!(raft_log.hard_state()?, hs);
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (4, 3));
+        assert_eq!(raft_log.durable_index(), 4);
+        assert_eq!(raft_log.config(), Some((4, &voters)));
+        raft_log.truncate_from(4)?;
+        assert_eq!(raft_log.config(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn snapshots() -> Result<()> {
+        let dir = tempdir()?;
+        let mut config = Config::default();
+        config.segment.max_store_bytes = 64;
+        let mut raft_log = RaftLog::open(dir.path(), &config)?;
+        let voters = BTreeMap::from([("node-1".to_owned(), "addr-1".to_owned())]);
+        let mut change = entry(1, &[]);
+        change.config = Some(configuration(&voters));
+        raft_log.append(&[change])?;
+        for i in 0..20 {
+            raft_log.append(&[entry(2, &[i])])?;
+        }
+        raft_log.append(&[entry(2, &[])])?;
+
+        // entries up to the snapshot go, but for its index and term
+        let snapshot = raft_log.snapshot_at(22)?;
+        assert_eq!(snapshot.next_offset, 20);
+        assert_eq!(snapshot.config, Some(configuration(&voters)));
+        save_snapshot(dir.path(), &snapshot)?;
+        raft_log.trim(snapshot.clone())?;
+        assert!(raft_log.entry(10).is_err());
+        assert!(raft_log.entries(1, 1).is_err());
+        assert_eq!(raft_log.term(22)?, 2);
+        assert_eq!(raft_log.config(), Some((22, &voters)));
+        assert_eq!(raft_log.next_data_offset(0)?, 20);
+        assert_eq!(raft_log.applied_index(0)?, 22);
+        raft_log.append(&[entry(3, &[20])])?;
+        raft_log.close()?;
+        drop(raft_log);
+
+        let mut raft_log = RaftLog::open(dir.path(), &config)?;
+        assert_eq!(raft_log.snapshot(), &snapshot);
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (23, 3));
+        assert_eq!(raft_log.config(), Some((22, &voters)));
+
+        // a follower installing a leader's snapshot drops all it had
+        let snapshot = RaftSnapshot {
+            last_index: 40,
+            last_term: 4,
+            config: None,
+            next_offset: 35,
+        };
+        save_snapshot(dir.path(), &snapshot)?;
+        raft_log.install(snapshot.clone())?;
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (40, 4));
+        assert_eq!(raft_log.next_data_offset(0)?, 35);
+        raft_log.append(&[entry(4, &[35])])?;
+        assert_eq!(raft_log.entry(41)?, entry(4, &[35]));
+        raft_log.close()?;
+        drop(raft_log);
+        let raft_log = RaftLog::open(dir.path(), &config)?;
+        assert_eq!((raft_log.last_index(), raft_log.last_term()), (41, 4));
+        Ok(())
+    }
+}
@@ -0,0 +1,1536 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+use protos::log::v1::{
+    AppendEntriesRequest, AppendEntriesResponse, HardState, InstallSnapshotRequest,
+    InstallSnapshotResponse, RaftEntry, Record, RequestVoteRequest, RequestVoteResponse,
+};
+use rand::Rng;
+use tokio::sync::{watch, Notify};
+use tokio::time;
+
+use crate::config::{Config, Durability, RaftConfig, TimestampType};
+use crate::error::Error;
+use crate::log::Log;
+use crate::membership::{Member, MembershipHandler};
+use crate::producer::BatchProducer;
+use crate::raft_log::{configuration, save_snapshot, RaftLog};
+use crate::retention::{RemovedSegment, RetentionCleaner};
+use crate::segment::now_millis;
+use crate::transport::Transport;
+
+/// Subdirectory of the log directory holding the Raft entries and state.
+const RAFT_DIR: &str = "raft";
+/// Most records sent in one `InstallSnapshot` request.
+const SNAPSHOT_CHUNK: usize = 1024;
+
+/// A [`Log`] replicated across a cluster with Raft.
+///
+/// Appends go to the leader, which stores them as an entry of its Raft log
+/// and sends them to the followers. Once a majority has the entry, every node
+/// applies its records to its own `Log` under the offsets and timestamps the
+/// leader assigned, so reads can go to any node. Dropping the handle stops
+/// the node.
+///
+/// Only the Raft log is fsynced as records come in, and it is trimmed once
+/// its entries were applied and the `Log` synced, so records are not kept
+/// twice for long. A follower that needs trimmed entries gets the records
+/// from the leader's `Log` instead.
+pub struct ReplicatedLog {
+    node: Arc<Node>,
+}
+
+impl ReplicatedLog {
+    /// Opens the log in `dir` and starts taking part in the cluster. The
+    /// voters are `raft.peers` until the log holds a membership change; a
+    /// node that is not one of them waits for the leader to add it. Spawns
+    /// tasks, so it must be called within a Tokio runtime. The log must only
+    /// be written through the returned handle. `config.durability` is not
+    /// used: the Raft log decides when records are durable.
+    pub fn start(
+        dir: &Path,
+        mut config: Config,
+        raft: RaftConfig,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Self> {
+        let raft_dir = dir.join(RAFT_DIR);
+        fs::create_dir_all(&raft_dir)?;
+        let storage = RaftLog::open(&raft_dir, &config)?;
+        config.durability = Durability::OsManaged;
+        let log = Arc::new(Log::new(dir, config)?);
+        let hs = storage.hard_state()?;
+        let applied = storage.applied_index(log.next_offset())?;
+        let (progress, _) = watch::channel((applied, hs.term));
+        let node = Arc::new(Node {
+            state: Mutex::new(State {
+                storage,
+                role: Role::Follower,
+                term: hs.term,
+                voted_for: Some(hs.voted_for).filter(|v| !v.is_empty()),
+                leader: None,
+                commit_index: applied,
+                last_applied: applied,
+                next_data_offset: 0,
+                proposed: HashMap::new(),
+                election_deadline: Instant::now(),
+                heard_from_leader: None,
+                votes: HashSet::new(),
+                followers: HashMap::new(),
+            }),
+            log,
+            transport,
+            progress,
+            sync_wanted: Notify::new(),
+            snapshotting: Mutex::new(()),
+            stopped: AtomicBool::new(false),
+            config: raft,
+        });
+        node.reset_election_deadline(&mut node.state());
+        tokio::spawn(node.clone().run_election_timer());
+        tokio::spawn(node.clone().run_syncer());
+        Ok(ReplicatedLog { node })
+    }
+
+    /// The log committed records are applied to, for reads. Only the node
+    /// may append to it.
+    pub(crate) fn log(&self) -> &Arc<Log> {
+        &self.node.log
+    }
+
+    /// The config of the log committed records are applied to.
+    pub fn config(&self) -> &Config {
+        self.node.log.config()
+    }
+
+    /// Runs a [`RetentionCleaner`] on the log committed records are applied
+    /// to; see [`RetentionCleaner::start`].
+    pub fn start_cleaner<F>(&self, interval: Duration, on_removed: F) -> RetentionCleaner
+    where
+        F: FnMut(Vec<RemovedSegment>) + Send + 'static,
+    {
+        RetentionCleaner::start(self.node.log.clone(), interval, on_removed)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.node.config.id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.node.state().role == Role::Leader
+    }
+
+    /// Address of the current leader, if this node knows it.
+    pub fn leader(&self) -> Option<String> {
+        self.node.leader_addr(&self.node.state())
+    }
+
+    pub fn term(&self) -> u64 {
+        self.node.state().term
+    }
+
+    /// Index of the last Raft entry known to be on a majority.
+    pub fn commit_index(&self) -> u64 {
+        self.node.state().commit_index
+    }
+
+    /// Addresses of the voters by id, as of the latest membership change
+    /// this node has, committed or not.
+    pub fn voters(&self) -> BTreeMap<String, String> {
+        self.node.peers(&self.node.state()).clone()
+    }
+
+    /// Makes node `id` at `addr` a voter, returning once the change is
+    /// committed. Only the leader takes membership changes, one at a time,
+    /// so this fails while another is in progress.
+    pub async fn add_voter(&self, id: &str, addr: &str) -> Result<()> {
+        self.node
+            .change_config(|peers| {
+                peers.insert(id.to_owned(), addr.to_owned());
+            })
+            .await
+    }
+
+    /// Removes node `id` from the voters; see [`ReplicatedLog::add_voter`].
+    /// A leader removing itself steps down once the change commits.
+    pub async fn remove_server(&self, id: &str) -> Result<()> {
+        self.node
+            .change_config(|peers| {
+                peers.remove(id);
+            })
+            .await
+    }
+
+    /// Appends `record` and returns its offset once a majority has it; see
+    /// [`ReplicatedLog::append_batch`].
+    pub async fn append(&self, record: Record) -> Result<u64> {
+        Ok(self.append_batch(vec![record]).await?.start)
+    }
+
+    /// Appends `records` as one Raft entry and returns their offsets once the
+    /// entry is committed and applied on this node. Fails with
+    /// [`Error::NotLeader`] on a follower, and when leadership is lost before
+    /// the entry commits, in which case a later leader may still commit it.
+    pub async fn append_batch(&self, records: Vec<Record>) -> Result<Range<u64>> {
+        self.node.propose(records, None).await
+    }
+
+    /// Appends `records` like [`ReplicatedLog::append_batch`] as batch
+    /// `sequence` of `producer_id`; see [`Log::append_idempotent`]. A retry
+    /// of a batch still being committed waits for it and returns its
+    /// offsets, whichever node the producer first sent it to.
+    pub async fn append_idempotent(
+        &self,
+        producer_id: u64,
+        sequence: u64,
+        records: Vec<Record>,
+    ) -> Result<Range<u64>> {
+        let producer = BatchProducer {
+            id: producer_id,
+            sequence,
+        };
+        self.node.propose(records, Some(producer)).await
+    }
+
+    /// Stops the node, waits for its tasks to finish and closes its logs, so
+    /// the directory can be opened again once it returns. Every call through
+    /// a handle that is still around fails from then on.
+    pub async fn close(&self) -> Result<()> {
+        self.node.stopped.store(true, Ordering::Relaxed);
+        while Arc::strong_count(&self.node) > 1 {
+            time::sleep(self.node.config.heartbeat_interval / 2).await;
+        }
+        let st = self.node.state();
+        st.storage.close()?;
+        self.node.log.close()
+    }
+
+    pub(crate) fn node(&self) -> &Arc<Node> {
+        &self.node
+    }
+}
+
+/// Keeps the voters in line with the gossip membership: the leader adds
+/// members that join and removes those that leave. Failed members stay
+/// voters, as a minority of them does not stop the cluster.
+#[tonic::async_trait]
+impl MembershipHandler for ReplicatedLog {
+    async fn join(&self, member: &Member) -> Result<()> {
+        if !self.is_leader() || self.voters().get(&member.name) == Some(&member.rpc_addr) {
+            return Ok(());
+        }
+        self.add_voter(&member.name, &member.rpc_addr).await
+    }
+
+    async fn leave(&self, member: &Member) -> Result<()> {
+        if !self.is_leader() || !self.voters().contains_key(&member.name) {
+            return Ok(());
+        }
+        self.remove_server(&member.name).await
+    }
+}
+
+impl Drop for ReplicatedLog {
+    fn drop(&mut self) {
+        self.node.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub(crate) struct Node {
+    config: RaftConfig,
+    log: Arc<Log>,
+    transport: Arc<dyn Transport>,
+    state: Mutex<State>,
+    /// Last applied index and current term, published whenever either moves.
+    progress: watch::Sender<(u64, u64)>,
+    /// Wakes the task fsyncing new entries.
+    sync_wanted: Notify,
+    /// Held while trimming the Raft log or installing a snapshot, before the
+    /// state.
+    snapshotting: Mutex<()>,
+    stopped: AtomicBool,
+}
+
+struct State {
+    storage: RaftLog,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    /// Id of the leader of the current term, once known.
+    leader: Option<String>,
+    commit_index: u64,
+    last_applied: u64,
+    /// Offset the records of the next proposed entry start at, while leading.
+    next_data_offset: u64,
+    /// The last batch of each idempotent producer proposed but not applied
+    /// yet, while leading.
+    proposed: HashMap<u64, ProposedBatch>,
+    election_deadline: Instant,
+    /// When an `AppendEntries` of the current leader last came in.
+    heard_from_leader: Option<Instant>,
+    /// Who voted for this node in the current term, while a candidate.
+    votes: HashSet<String>,
+    /// Replication progress of every other node, while leading.
+    followers: HashMap<String, Follower>,
+}
+
+struct Follower {
+    /// Index of the next entry to send.
+    next_index: u64,
+    /// Highest index known to match the leader's log.
+    match_index: u64,
+    /// Wakes the task replicating to this follower.
+    wake: Arc<Notify>,
+    /// Where the follower's log ends, while it gets a snapshot.
+    snapshot_offset: Option<u64>,
+}
+
+/// A batch of an idempotent producer proposed as the entry at `index`.
+struct ProposedBatch {
+    sequence: u64,
+    index: u64,
+}
+
+/// What a leader sends a follower next.
+enum Message {
+    AppendEntries(AppendEntriesRequest),
+    InstallSnapshot(InstallSnapshotRequest),
+}
+
+impl Node {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Locks the state unless the node was stopped, after which nothing may
+    /// touch the logs.
+    fn running_state(&self) -> Result<MutexGuard<'_, State>> {
+        let st = self.state();
+        if self.stopped() {
+            return Err(anyhow!("node {} is stopped", self.config.id));
+        }
+        Ok(st)
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Runs `f` on the blocking pool, since it locks the state and reads or
+    /// writes the logs.
+    async fn blocking<T, F>(self: &Arc<Self>, f: F) -> Result<T>
+    where
+        F: FnOnce(&Arc<Node>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let node = self.clone();
+        tokio::task::spawn_blocking(move || f(&node)).await?
+    }
+
+    /// The voters: those of the latest configuration entry, or the
+    /// configured peers if the log has none.
+    fn peers<'a>(&'a self, st: &'a State) -> &'a BTreeMap<String, String> {
+        st.storage
+            .config()
+            .map_or(&self.config.peers, |(_, peers)| peers)
+    }
+
+    fn is_voter(&self, st: &State) -> bool {
+        self.peers(st).contains_key(&self.config.id)
+    }
+
+    fn quorum(&self, st: &State) -> usize {
+        self.peers(st).len() / 2 + 1
+    }
+
+    fn leader_addr(&self, st: &State) -> Option<String> {
+        st.leader
+            .as_ref()
+            .and_then(|id| self.peers(st).get(id))
+            .cloned()
+    }
+
+    fn not_leader(&self, st: &State) -> anyhow::Error {
+        Error::NotLeader {
+            leader: self.leader_addr(st),
+        }
+        .into()
+    }
+
+    fn reset_election_deadline(&self, st: &mut State) {
+        let timeout = self.config.election_timeout;
+        let jitter = rand::thread_rng().gen_range(0..=timeout.as_millis() as u64);
+        st.election_deadline = Instant::now() + timeout + time::Duration::from_millis(jitter);
+    }
+
+    fn publish(&self, st: &State) {
+        self.progress.send_replace((st.last_applied, st.term));
+    }
+
+    fn persist(&self, st: &State) -> Result<()> {
+        st.storage.save_hard_state(&HardState {
+            term: st.term,
+            voted_for: st.voted_for.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Becomes a follower, moving to `term` if it is newer.
+    fn step_down(&self, st: &mut State, term: u64) -> Result<()> {
+        if term > st.term {
+            st.term = term;
+            st.voted_for = None;
+            st.leader = None;
+            self.persist(st)?;
+        }
+        if st.role != Role::Follower {
+            debug!("{} steps down in term {}", self.config.id, st.term);
+        }
+        st.role = Role::Follower;
+        st.followers.clear();
+        st.proposed.clear();
+        self.publish(st);
+        Ok(())
+    }
+
+    async fn run_election_timer(self: Arc<Self>) {
+        let mut tick = time::interval(self.config.heartbeat_interval / 2);
+        while !self.stopped() {
+            tick.tick().await;
+            let due = {
+                let st = self.state();
+                st.role != Role::Leader
+                    && Instant::now() >= st.election_deadline
+                    && self.is_voter(&st)
+            };
+            if due {
+                if let Err(e) = self.blocking(|node| node.start_election()).await {
+                    error!("{} failed to start an election: {:?}", self.config.id, e);
+                }
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>) -> Result<()> {
+        let mut st = self.running_state()?;
+        st.term += 1;
+        st.role = Role::Candidate;
+        st.voted_for = Some(self.config.id.clone());
+        st.leader = None;
+        st.heard_from_leader = None;
+        st.votes = HashSet::from([self.config.id.clone()]);
+        self.reset_election_deadline(&mut st);
+        self.persist(&st)?;
+        self.publish(&st);
+        debug!("{} starts an election in term {}", self.config.id, st.term);
+        if st.votes.len() >= self.quorum(&st) {
+            return self.become_leader(&mut st);
+        }
+        let req = RequestVoteRequest {
+            term: st.term,
+            candidate_id: self.config.id.clone(),
+            last_log_index: st.storage.last_index(),
+            last_log_term: st.storage.last_term(),
+        };
+        for (peer, addr) in self.peers(&st) {
+            if *peer == self.config.id {
+                continue;
+            }
+            let (node, peer, addr, req) = (self.clone(), peer.clone(), addr.clone(), req.clone());
+            tokio::spawn(async move {
+                let term = req.term;
+                let timeout = node.config.election_timeout;
+                match time::timeout(timeout, node.transport.request_vote(&addr, req)).await {
+                    Ok(Ok(resp)) => {
+                        let res = node
+                            .blocking(move |node| node.handle_vote_response(&peer, term, resp))
+                            .await;
+                        if let Err(e) = res {
+                            error!("{} failed to count a vote: {:?}", node.config.id, e);
+                        }
+                    }
+                    Ok(Err(e)) => debug!("vote request to {} failed: {}", peer, e),
+                    Err(_) => debug!("vote request to {} timed out", peer),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn handle_request_vote(
+        &self,
+        req: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        let mut st = self.running_state()?;
+        // a node removed from the cluster must not unseat a working leader
+        let leader_alive = st
+            .heard_from_leader
+            .is_some_and(|t| t.elapsed() < self.config.election_timeout);
+        if leader_alive {
+            return Ok(RequestVoteResponse {
+                term: st.term,
+                vote_granted: false,
+            });
+        }
+        if req.term > st.term {
+            self.step_down(&mut st, req.term)?;
+        }
+        let up_to_date = (req.last_log_term, req.last_log_index)
+            >= (st.storage.last_term(), st.storage.last_index());
+        let grant = req.term == st.term
+            && up_to_date
+            && st.voted_for.as_ref().is_none_or(|v| *v == req.candidate_id);
+        if grant {
+            st.voted_for = Some(req.candidate_id);
+            self.persist(&st)?;
+            self.reset_election_deadline(&mut st);
+        }
+        Ok(RequestVoteResponse {
+            term: st.term,
+            vote_granted: grant,
+        })
+    }
+
+    fn handle_vote_response(
+        self: &Arc<Self>,
+        peer: &str,
+        term: u64,
+        resp: RequestVoteResponse,
+    ) -> Result<()> {
+        let mut st = self.running_state()?;
+        if resp.term > st.term {
+            return self.step_down(&mut st, resp.term);
+        }
+        let counts = st.role == Role::Candidate
+            && st.term == term
+            && resp.vote_granted
+            && self.peers(&st).contains_key(peer);
+        if counts {
+            st.votes.insert(peer.to_owned());
+            if st.votes.len() >= self.quorum(&st) {
+                return self.become_leader(&mut st);
+            }
+        }
+        Ok(())
+    }
+
+    fn become_leader(self: &Arc<Self>, st: &mut State) -> Result<()> {
+        info!("{} is the leader of term {}", self.config.id, st.term);
+        st.role = Role::Leader;
+        st.leader = Some(self.config.id.clone());
+        st.next_data_offset = st.storage.next_data_offset(self.log.next_offset())?;
+        st.followers.clear();
+        // batches earlier leaders proposed may still commit, so their
+        // retries must wait for them rather than append them again
+        st.proposed.clear();
+        for index in st.last_applied + 1..=st.storage.last_index() {
+            if let Some(p) = entry_producer(&st.storage.entry(index)?) {
+                let batch = ProposedBatch {
+                    sequence: p.sequence,
+                    index,
+                };
+                st.proposed.insert(p.id, batch);
+            }
+        }
+        // entries of earlier terms only commit along with one of this term
+        st.storage.append(&[RaftEntry {
+            term: st.term,
+            ..Default::default()
+        }])?;
+        self.sync_followers(st);
+        self.replicate_now(st)
+    }
+
+    /// Starts replicating to every voter without a [`Follower`] yet, and
+    /// stops for those no longer voters.
+    fn sync_followers(self: &Arc<Self>, st: &mut State) {
+        let peers = self.peers(st).clone();
+        st.followers.retain(|peer, _| peers.contains_key(peer));
+        let next_index = st.storage.last_index() + 1;
+        for peer in peers.into_keys() {
+            if peer == self.config.id || st.followers.contains_key(&peer) {
+                continue;
+            }
+            let wake = Arc::new(Notify::new());
+            let follower = Follower {
+                next_index,
+                match_index: 0,
+                wake: wake.clone(),
+                snapshot_offset: None,
+            };
+            st.followers.insert(peer.clone(), follower);
+            tokio::spawn(self.clone().replicate(peer, st.term, wake));
+        }
+    }
+
+    /// Sends `peer` the entries it lacks, or a heartbeat, for as long as this
+    /// node leads in `term` and `peer` is the [`Follower`] woken by `wake`.
+    async fn replicate(self: Arc<Self>, peer: String, term: u64, wake: Arc<Notify>) {
+        loop {
+            let next = {
+                let (peer, wake) = (peer.clone(), wake.clone());
+                self.blocking(move |node| node.next_message(&peer, term, &wake))
+                    .await
+            };
+            let again = match next {
+                Ok(Some((addr, msg))) => self.send(&peer, term, &addr, msg).await,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("{} failed to read entries: {:?}", self.config.id, e);
+                    false
+                }
+            };
+            if again {
+                continue;
+            }
+            tokio::select! {
+                _ = wake.notified() => {}
+                _ = time::sleep(self.config.heartbeat_interval) => {}
+            }
+        }
+    }
+
+    /// What to send `peer` next, unless this node stopped leading in `term`
+    /// or replicating to it through `wake`: the entries after those it has,
+    /// or a chunk of a snapshot if they were trimmed.
+    fn next_message(
+        &self,
+        peer: &str,
+        term: u64,
+        wake: &Arc<Notify>,
+    ) -> Result<Option<(String, Message)>> {
+        let (addr, snapshot, offset) = {
+            let st = self.state();
+            if self.stopped() || st.role != Role::Leader || st.term != term {
+                return Ok(None);
+            }
+            let f = match st.followers.get(peer) {
+                Some(f) if Arc::ptr_eq(&f.wake, wake) => f,
+                _ => return Ok(None),
+            };
+            let addr = self.peers(&st)[peer].clone();
+            let snapshot = st.storage.snapshot();
+            if f.next_index > snapshot.last_index {
+                let req = self.append_request(&st, f)?;
+                return Ok(Some((addr, Message::AppendEntries(req))));
+            }
+            (addr, snapshot.clone(), f.snapshot_offset)
+        };
+        // the records come from the log, read without the state locked; the
+        // first request only asks the follower where its log ends
+        let mut req = InstallSnapshotRequest {
+            term,
+            leader_id: self.config.id.clone(),
+            snapshot: None,
+            offset: snapshot.next_offset,
+            records: vec![],
+            done: false,
+            producers: vec![],
+        };
+        if let Some(offset) = offset {
+            let from = offset.max(self.log.lowest_offset()?);
+            if from < snapshot.next_offset.min(self.log.next_offset()) {
+                req.records = self
+                    .log
+                    .iter_from(from)
+                    .take_while(|r| r.as_ref().map_or(true, |r| r.offset < snapshot.next_offset))
+                    .take(SNAPSHOT_CHUNK)
+                    .collect::<Result<_>>()?;
+            }
+            req.offset = offset;
+            req.done = req.records.len() < SNAPSHOT_CHUNK;
+            let end = match req.records.last() {
+                Some(r) if !req.done => r.offset + 1,
+                _ => snapshot.next_offset,
+            };
+            req.producers = self.log.producer_batches(offset..end);
+        }
+        req.snapshot = Some(snapshot);
+        Ok(Some((addr, Message::InstallSnapshot(req))))
+    }
+
+    /// Sends `msg` to `peer` at `addr` and handles the response; returns
+    /// whether to send to it again now.
+    async fn send(self: &Arc<Self>, peer: &str, term: u64, addr: &str, msg: Message) -> bool {
+        let timeout = self.config.election_timeout;
+        let peer = peer.to_owned();
+        let (res, what) = match msg {
+            Message::AppendEntries(req) => {
+                let res = time::timeout(timeout, self.transport.append_entries(addr, req)).await;
+                let res = match res {
+                    Ok(Ok(resp)) => Ok(self
+                        .blocking(move |node| node.handle_append_response(&peer, term, resp))
+                        .await),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(e.into()),
+                };
+                (res, "append")
+            }
+            Message::InstallSnapshot(req) => {
+                let last_index = req.snapshot.as_ref().map_or(0, |s| s.last_index);
+                let res = time::timeout(timeout, self.transport.install_snapshot(addr, req)).await;
+                let res = match res {
+                    Ok(Ok(resp)) => Ok(self
+                        .blocking(move |node| {
+                            node.handle_snapshot_response(&peer, term, last_index, resp)
+                        })
+                        .await),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(e.into()),
+                };
+                (res, "snapshot")
+            }
+        };
+        match res {
+            Ok(Ok(again)) => again,
+            Ok(Err(e)) => {
+                error!("{} failed to replicate: {:?}", self.config.id, e);
+                false
+            }
+            Err(e) => {
+                debug!("{} to {} failed: {}", what, addr, e);
+                false
+            }
+        }
+    }
+
+    fn append_request(&self, st: &State, f: &Follower) -> Result<AppendEntriesRequest> {
+        let prev_log_index = f.next_index - 1;
+        Ok(AppendEntriesRequest {
+            term: st.term,
+            leader_id: self.config.id.clone(),
+            prev_log_index,
+            prev_log_term: st.storage.term(prev_log_index)?,
+            entries: st
+                .storage
+                .entries(f.next_index, self.config.max_append_entries)?,
+            leader_commit: st.commit_index,
+        })
+    }
+
+    /// Records how far `peer` got; returns whether to send to it again now.
+    fn handle_append_response(
+        &self,
+        peer: &str,
+        term: u64,
+        resp: AppendEntriesResponse,
+    ) -> Result<bool> {
+        let mut st = self.running_state()?;
+        if resp.term > st.term {
+            self.step_down(&mut st, resp.term)?;
+            return Ok(false);
+        }
+        if st.role != Role::Leader || st.term != term {
+            return Ok(false);
+        }
+        let last_index = st.storage.last_index();
+        let Some(f) = st.followers.get_mut(peer) else {
+            return Ok(false);
+        };
+        if !resp.success {
+            f.next_index = (resp.last_log_index + 1).min(f.next_index - 1).max(1);
+            return Ok(true);
+        }
+        f.match_index = f.match_index.max(resp.last_log_index);
+        f.next_index = f.match_index + 1;
+        let behind = f.next_index <= last_index;
+        self.advance_commit(&mut st)?;
+        Ok(behind)
+    }
+
+    /// Records how far `peer` got with the snapshot up to `last_index`;
+    /// returns whether to send to it again now.
+    fn handle_snapshot_response(
+        &self,
+        peer: &str,
+        term: u64,
+        last_index: u64,
+        resp: InstallSnapshotResponse,
+    ) -> Result<bool> {
+        let mut st = self.running_state()?;
+        if resp.term > st.term {
+            self.step_down(&mut st, resp.term)?;
+            return Ok(false);
+        }
+        if st.role != Role::Leader || st.term != term {
+            return Ok(false);
+        }
+        let Some(f) = st.followers.get_mut(peer) else {
+            return Ok(false);
+        };
+        if !resp.done {
+            let moved = f.snapshot_offset != Some(resp.next_offset);
+            f.snapshot_offset = Some(resp.next_offset);
+            return Ok(moved);
+        }
+        f.snapshot_offset = None;
+        f.match_index = f.match_index.max(last_index);
+        f.next_index = f.next_index.max(f.match_index + 1);
+        self.advance_commit(&mut st)?;
+        Ok(true)
+    }
+
+    /// Commits up to the highest index a majority of the voters has, once it
+    /// is an entry of the current term, and applies what got committed. A
+    /// leader that is no longer a voter steps down once that is committed.
+    fn advance_commit(&self, st: &mut State) -> Result<()> {
+        let mut matched: Vec<u64> = self
+            .peers(st)
+            .keys()
+            .map(|peer| match st.followers.get(peer) {
+                _ if *peer == self.config.id => st.storage.durable_index(),
+                Some(f) => f.match_index,
+                None => 0,
+            })
+            .collect();
+        if matched.is_empty() {
+            return Ok(());
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let n = matched[self.quorum(st) - 1];
+        if n > st.commit_index && st.storage.term(n)? == st.term {
+            st.commit_index = n;
+            self.apply(st)?;
+        }
+        let removed = !self.is_voter(st)
+            && st
+                .storage
+                .config()
+                .is_some_and(|(index, _)| index <= st.commit_index);
+        if st.role == Role::Leader && removed {
+            info!("{} is no longer a voter", self.config.id);
+            self.step_down(st, st.term)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the records of every committed entry not applied yet.
+    fn apply(&self, st: &mut State) -> Result<()> {
+        while st.last_applied < st.commit_index {
+            let entry = st.storage.entry(st.last_applied + 1)?;
+            if entry
+                .records
+                .first()
+                .is_some_and(|r| r.offset >= self.log.next_offset())
+            {
+                self.log.append_at(&entry.records, entry_producer(&entry))?;
+            }
+            st.last_applied += 1;
+        }
+        let applied = st.last_applied;
+        st.proposed.retain(|_, b| b.index > applied);
+        self.publish(st);
+        Ok(())
+    }
+
+    /// Takes the entries of a leader and acknowledges them once they are on
+    /// disk. Requests coming in meanwhile share the fsync.
+    pub(crate) fn handle_append_entries(
+        &self,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let (resp, last_new) = self.append_entries(req)?;
+        if !resp.success {
+            return Ok(resp);
+        }
+        self.sync_entries()?;
+        let st = self.running_state()?;
+        if st.term != resp.term || st.storage.durable_index() < last_new {
+            // replaced by the entries of a newer leader before they got synced
+            return Ok(AppendEntriesResponse {
+                term: st.term,
+                success: false,
+                last_log_index: st.storage.last_index(),
+            });
+        }
+        Ok(resp)
+    }
+
+    /// Appends the entries of a leader without syncing them; returns the
+    /// response and the index of the last entry it acknowledges.
+    fn append_entries(&self, req: AppendEntriesRequest) -> Result<(AppendEntriesResponse, u64)> {
+        let mut st = self.running_state()?;
+        let reply = |st: &State, success, last_log_index| AppendEntriesResponse {
+            term: st.term,
+            success,
+            last_log_index,
+        };
+        if req.term < st.term {
+            return Ok((reply(&st, false, st.storage.last_index()), 0));
+        }
+        if req.term > st.term || st.role != Role::Follower {
+            self.step_down(&mut st, req.term)?;
+        }
+        st.leader = Some(req.leader_id);
+        st.heard_from_leader = Some(Instant::now());
+        self.reset_election_deadline(&mut st);
+
+        let (mut prev_log_index, mut prev_log_term, mut entries) =
+            (req.prev_log_index, req.prev_log_term, req.entries);
+        let snapshot_index = st.storage.snapshot().last_index;
+        if prev_log_index < snapshot_index {
+            // entries up to the snapshot were committed, so they match
+            let skip = snapshot_index - prev_log_index;
+            if skip >= entries.len() as u64 {
+                return Ok((reply(&st, true, snapshot_index), snapshot_index));
+            }
+            entries.drain(..skip as usize);
+            prev_log_index = snapshot_index;
+            prev_log_term = st.storage.term(snapshot_index)?;
+        }
+        let last_index = st.storage.last_index();
+        if prev_log_index > last_index {
+            return Ok((reply(&st, false, last_index), 0));
+        }
+        if st.storage.term(prev_log_index)? != prev_log_term {
+            return Ok((reply(&st, false, prev_log_index - 1), 0));
+        }
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for (index, entry) in (prev_log_index + 1..).zip(entries) {
+            if new.is_empty() && index <= st.storage.last_index() {
+                if st.storage.term(index)? == entry.term {
+                    continue;
+                }
+                if index <= st.commit_index {
+                    return Err(anyhow!("leader conflicts with committed entry {}", index));
+                }
+                st.storage.truncate_from(index)?;
+            }
+            new.push(entry);
+        }
+        st.storage.append(&new)?;
+        let commit = req.leader_commit.min(last_new);
+        if commit > st.commit_index {
+            st.commit_index = commit;
+            self.apply(&mut st)?;
+        }
+        Ok((reply(&st, true, last_new), last_new))
+    }
+
+    /// Takes a chunk of the records of a leader's snapshot, and the snapshot
+    /// itself along with the last one, in place of every entry.
+    pub(crate) fn handle_install_snapshot(
+        &self,
+        req: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let _snapshotting = self.snapshotting.lock().unwrap();
+        let mut st = self.running_state()?;
+        let reply = |st: &State, done| InstallSnapshotResponse {
+            term: st.term,
+            next_offset: self.log.next_offset(),
+            done,
+        };
+        if req.term < st.term {
+            return Ok(reply(&st, false));
+        }
+        if req.term > st.term || st.role != Role::Follower {
+            self.step_down(&mut st, req.term)?;
+        }
+        st.leader = Some(req.leader_id);
+        st.heard_from_leader = Some(Instant::now());
+        self.reset_election_deadline(&mut st);
+
+        let snapshot = req
+            .snapshot
+            .ok_or_else(|| anyhow!("install snapshot request without a snapshot"))?;
+        if snapshot.last_index <= st.last_applied {
+            return Ok(reply(&st, true));
+        }
+        if req.offset != self.log.next_offset() {
+            return Ok(reply(&st, false));
+        }
+        self.log.append_at(&req.records, None)?;
+        self.log.restore_producers(req.producers)?;
+        if !req.done {
+            return Ok(reply(&st, false));
+        }
+        // the records outlive the entries from now on
+        self.log.sync()?;
+        let index = snapshot.last_index;
+        save_snapshot(st.storage.dir(), &snapshot)?;
+        st.storage.install(snapshot)?;
+        info!("{} installed a snapshot up to {}", self.config.id, index);
+        st.commit_index = st.commit_index.max(index);
+        st.last_applied = index;
+        self.publish(&st);
+        Ok(reply(&st, true))
+    }
+
+    /// fsyncs the entries appended so far without holding the state, so
+    /// appends made meanwhile share the next fsync, then lets a leader count
+    /// them.
+    fn sync_entries(&self) -> Result<()> {
+        let Some(pending) = self.running_state()?.storage.pending_sync() else {
+            return Ok(());
+        };
+        pending.sync()?;
+        let mut st = self.running_state()?;
+        st.storage.synced(&pending);
+        if st.role == Role::Leader {
+            self.advance_commit(&mut st)?;
+        }
+        Ok(())
+    }
+
+    /// Trims the Raft log to [`RaftConfig::snapshot_entries`] applied entries
+    /// once twice as many piled up.
+    fn snapshot_if_due(&self) -> Result<()> {
+        let _snapshotting = self.snapshotting.lock().unwrap();
+        let keep = self.config.snapshot_entries.max(1);
+        let (dir, snapshot) = {
+            let st = self.running_state()?;
+            if st.last_applied < st.storage.snapshot().last_index + 2 * keep {
+                return Ok(());
+            }
+            let snapshot = st.storage.snapshot_at(st.last_applied - keep)?;
+            (st.storage.dir().to_owned(), snapshot)
+        };
+        // the records of the trimmed entries only live in the log from now on
+        self.log.sync()?;
+        save_snapshot(&dir, &snapshot)?;
+        debug!(
+            "{} trims its entries up to {}",
+            self.config.id, snapshot.last_index
+        );
+        self.running_state()?.storage.trim(snapshot)
+    }
+
+    /// Fsyncs new entries when woken, and trims the Raft log when due.
+    async fn run_syncer(self: Arc<Self>) {
+        while !self.stopped() {
+            tokio::select! {
+                _ = self.sync_wanted.notified() => {}
+                _ = time::sleep(self.config.heartbeat_interval) => {}
+            }
+            let res = self
+                .blocking(|node| {
+                    node.sync_entries()?;
+                    node.snapshot_if_due()
+                })
+                .await;
+            if let Err(e) = res {
+                if !self.stopped() {
+                    error!("{} failed to sync its entries: {:?}", self.config.id, e);
+                }
+            }
+        }
+    }
+
+    /// Proposes `records` as one entry, as a batch of `producer` if there
+    /// is one, and waits for it to be applied. A retry of a producer's batch
+    /// already applied returns its offsets; one of a batch still in flight
+    /// waits for it first.
+    async fn propose(
+        &self,
+        records: Vec<Record>,
+        producer: Option<BatchProducer>,
+    ) -> Result<Range<u64>> {
+        if records.is_empty() {
+            let next = self.log.next_offset();
+            return Ok(next..next);
+        }
+        let (index, term, range) = loop {
+            let (index, term) = {
+                let mut st = self.running_state()?;
+                if st.role != Role::Leader {
+                    return Err(self.not_leader(&st));
+                }
+                let in_flight = match producer {
+                    Some(p) => self.in_flight(&st, p)?,
+                    None => None,
+                };
+                match in_flight {
+                    Some(InFlight::Applied(range)) => return Ok(range),
+                    Some(InFlight::Proposed(index)) => (index, st.term),
+                    None => break self.append_entry(&mut st, records, producer)?,
+                }
+            };
+            self.wait_progress(index, term).await;
+        };
+        self.wait_applied(index, term).await?;
+        Ok(range)
+    }
+
+    /// Where batch `p` stands if it is a retry of the producer's last one;
+    /// fails if it is neither that nor the next.
+    fn in_flight(&self, st: &State, p: BatchProducer) -> Result<Option<InFlight>> {
+        match st.proposed.get(&p.id) {
+            Some(b) if b.sequence == p.sequence => Ok(Some(InFlight::Proposed(b.index))),
+            Some(b) if b.sequence + 1 != p.sequence => Err(Error::OutOfOrderSequence {
+                producer_id: p.id,
+                sequence: p.sequence,
+                expected: b.sequence + 1,
+            }
+            .into()),
+            Some(_) => Ok(None),
+            None => Ok(self.log.check_producer(p)?.map(InFlight::Applied)),
+        }
+    }
+
+    /// Stamps `records` with their offsets and timestamps and appends them
+    /// as an entry of the current term; returns its index, the term and the
+    /// offsets.
+    fn append_entry(
+        &self,
+        st: &mut State,
+        mut records: Vec<Record>,
+        producer: Option<BatchProducer>,
+    ) -> Result<(u64, u64, Range<u64>)> {
+        let start = st.next_data_offset;
+        let now = now_millis();
+        for (i, r) in records.iter_mut().enumerate() {
+            r.offset = start + i as u64;
+            if r.timestamp == 0 || self.log.config().timestamp_type == TimestampType::LogAppendTime
+            {
+                r.timestamp = now;
+            }
+        }
+        let n = records.len() as u64;
+        let term = st.term;
+        st.storage.append(&[RaftEntry {
+            term,
+            records,
+            producer_id: producer.map_or(0, |p| p.id),
+            sequence: producer.map_or(0, |p| p.sequence),
+            ..Default::default()
+        }])?;
+        st.next_data_offset += n;
+        let index = st.storage.last_index();
+        if let Some(p) = producer {
+            let batch = ProposedBatch {
+                sequence: p.sequence,
+                index,
+            };
+            st.proposed.insert(p.id, batch);
+        }
+        self.replicate_now(st)?;
+        Ok((index, term, start..start + n))
+    }
+
+    /// Appends a configuration entry with the voters `change` leaves and
+    /// waits for it to commit.
+    async fn change_config(
+        self: &Arc<Self>,
+        change: impl FnOnce(&mut BTreeMap<String, String>),
+    ) -> Result<()> {
+        let (index, term) = {
+            let mut st = self.running_state()?;
+            if st.role != Role::Leader {
+                return Err(self.not_leader(&st));
+            }
+            // one change at a time, and only once this term's first entry
+            // committed, keeps any two majorities overlapping
+            let pending = st
+                .storage
+                .config()
+                .is_some_and(|(index, _)| index > st.commit_index);
+            if pending || st.storage.term(st.commit_index)? != st.term {
+                return Err(anyhow!("another membership change is in progress"));
+            }
+            let mut peers = self.peers(&st).clone();
+            change(&mut peers);
+            if peers == *self.peers(&st) {
+                return Ok(());
+            }
+            if peers.is_empty() {
+                return Err(anyhow!("cannot remove the last voter"));
+            }
+            info!("{} changes the voters to {:?}", self.config.id, peers);
+            let term = st.term;
+            st.storage.append(&[RaftEntry {
+                term,
+                config: Some(configuration(&peers)),
+                ..Default::default()
+            }])?;
+            self.sync_followers(&mut st);
+            self.replicate_now(&mut st)?;
+            (st.storage.last_index(), term)
+        };
+        self.wait_applied(index, term).await
+    }
+
+    /// Wakes every follower task and the syncer for a new entry, which
+    /// commits once a majority, this node included, has it on disk.
+    fn replicate_now(&self, st: &mut State) -> Result<()> {
+        for f in st.followers.values() {
+            f.wake.notify_one();
+        }
+        self.sync_wanted.notify_one();
+        self.advance_commit(st)
+    }
+
+    /// Waits until the entry proposed at `index` in `term` is applied, or
+    /// fails once it can no longer be known to commit here.
+    async fn wait_applied(&self, index: u64, term: u64) -> Result<()> {
+        self.wait_progress(index, term).await;
+        let st = self.state();
+        if st.last_applied >= index {
+            // a committed entry of this term can only be the one proposed;
+            // once trimmed, it still is while this node leads in that term
+            let proposed = if index < st.storage.snapshot().last_index {
+                st.role == Role::Leader && st.term == term
+            } else {
+                st.storage.term(index)? == term
+            };
+            if proposed {
+                return Ok(());
+            }
+        }
+        Err(self.not_leader(&st))
+    }
+
+    /// Waits until the entry at `index` is applied, the term moves past
+    /// `term` or the node stops.
+    async fn wait_progress(&self, index: u64, term: u64) {
+        let mut progress = self.progress.subscribe();
+        loop {
+            let (applied, current) = *progress.borrow_and_update();
+            if applied >= index || current != term || self.stopped() {
+                return;
+            }
+            if progress.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Where a retried batch of an idempotent producer stands.
+enum InFlight {
+    /// Applied at these offsets.
+    Applied(Range<u64>),
+    /// Proposed as the entry at this index, not applied yet.
+    Proposed(u64),
+}
+
+/// The idempotent producer of `entry`'s records and their sequence, if any.
+fn entry_producer(entry: &RaftEntry) -> Option<BatchProducer> {
+    (entry.producer_id != 0).then_some(BatchProducer {
+        id: entry.producer_id,
+        sequence: entry.sequence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use tempfile::{tempdir, TempDir};
+
+    use crate::transport::MemNetwork;
+
+    use super::*;
+
+    struct Cluster {
+        dirs: Vec<TempDir>,
+        network: MemNetwork,
+        nodes: Vec<Option<ReplicatedLog>>,
+        /// The first this many nodes start as voters.
+        voters: usize,
+        snapshot_entries: u64,
+    }
+
+    fn raft_config(id: usize, voters: usize) -> RaftConfig {
+        RaftConfig {
+            id: format!("node-{}", id),
+            peers: (0..voters)
+                .map(|i| (format!("node-{}", i), format!("addr-{}", i)))
+                .collect::<BTreeMap<_, _>>(),
+            heartbeat_interval: Duration::from_millis(20),
+            election_timeout: Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    impl Cluster {
+        fn new(n: usize) -> Self {
+            Self::with_voters(n, n)
+        }
+
+        fn with_voters(n: usize, voters: usize) -> Self {
+            Self::build(n, voters, RaftConfig::default().snapshot_entries)
+        }
+
+        fn build(n: usize, voters: usize, snapshot_entries: u64) -> Self {
+            let mut cluster = Cluster {
+                dirs: (0..n).map(|_| tempdir().unwrap()).collect(),
+                network: MemNetwork::default(),
+                nodes: (0..n).map(|_| None).collect(),
+                voters,
+                snapshot_entries,
+            };
+            for i in 0..n {
+                cluster.start(i);
+            }
+            cluster
+        }
+
+        fn start(&mut self, i: usize) {
+            let mut config = Config::default();
+            config.segment.max_store_bytes = 256;
+            let addr = format!("addr-{}", i);
+            let raft = RaftConfig {
+                snapshot_entries: self.snapshot_entries,
+                ..raft_config(i, self.voters)
+            };
+            let node = ReplicatedLog::start(
+                self.dirs[i].path(),
+                config,
+                raft,
+                self.network.transport(&addr),
+            )
+            .unwrap();
+            self.network.register(&addr, &node);
+            self.nodes[i] = Some(node);
+        }
+
+        fn node(&self, i: usize) -> &ReplicatedLog {
+            self.nodes[i].as_ref().unwrap()
+        }
+
+        async fn leader(&self) -> usize {
+            wait_for(|| {
+                (0..self.nodes.len()).find(|&i| {
+                    self.nodes[i].as_ref().is_some_and(|n| n.is_leader())
+                        && !self.network.is_disconnected(&format!("addr-{}", i))
+                })
+            })
+            .await
+        }
+    }
+
+    async fn wait_for<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        for _ in 0..500 {
+            if let Some(t) = f() {
+                return t;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting");
+    }
+
+    fn record(value: &str) -> Record {
+        Record {
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn values(log: &Log) -> Vec<(u64, Vec<u8>)> {
+        log.iter_from(0)
+            .map(|r| r.map(|r| (r.offset, r.value)))
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn single_node() -> Result<()> {
+        let cluster = Cluster::new(1);
+        cluster.leader().await;
+        let node = cluster.node(0);
+        assert_eq!(node.append(record("a")).await?, 0);
+        let range = node.append_batch(vec![record("b"), record("c")]).await?;
+        assert_eq!(range, 1..3);
+        assert_eq!(node.log().read(2)?.value, b"c");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicate() -> Result<()> {
+        let cluster = Cluster::new(3);
+        let leader = cluster.leader().await;
+        let node = cluster.node(leader);
+        for i in 0..10 {
+            assert_eq!(node.append(record(&format!("r{}", i))).await?, i);
+        }
+        let expected = values(node.log());
+        for i in 0..3 {
+            wait_for(|| (values(cluster.node(i).log()) == expected).then_some(())).await;
+        }
+
+        let follower = cluster.node((leader + 1) % 3);
+        let err = follower.append(record("x")).await.unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::NotLeader { leader: Some(addr) }) => {
+                assert_eq!(*addr, format!("addr-{}", leader))
+            }
+            _ => panic!("expected not leader, got {:?}", err),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failover() -> Result<()> {
+        let mut cluster = Cluster::new(3);
+        let old = cluster.leader().await;
+        cluster.node(old).append(record("a")).await?;
+
+        // the old leader is cut off; what it takes meanwhile never commits
+        cluster.network.disconnect(&format!("addr-{}", old));
+        let lost = {
+            let node = cluster.node(old);
+            time::timeout(Duration::from_millis(50), node.append(record("lost"))).await
+        };
+        assert!(lost.is_err());
+        let new = cluster.leader().await;
+        assert_ne!(new, old);
+        assert_eq!(cluster.node(new).append(record("b")).await?, 1);
+
+        // back in the cluster it follows, dropping the entry it had alone
+        cluster.network.reconnect(&format!("addr-{}", old));
+        assert_eq!(cluster.node(new).append(record("c")).await?, 2);
+        let expected = values(cluster.node(new).log());
+        wait_for(|| (values(cluster.node(old).log()) == expected).then_some(())).await;
+        assert!(!cluster.node(old).is_leader());
+
+        // a restarted node keeps its log and catches up without reapplying
+        let follower = (0..3).find(|&i| i != new).unwrap();
+        cluster.nodes[follower].take().unwrap().close().await?;
+        cluster.node(new).append(record("d")).await?;
+        cluster.start(follower);
+        let expected = values(cluster.node(new).log());
+        assert_eq!(expected.len(), 4);
+        wait_for(|| (values(cluster.node(follower).log()) == expected).then_some(())).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applied_then_trimmed() -> Result<()> {
+        let cluster = Cluster::build(1, 1, 1);
+        cluster.leader().await;
+        let node = cluster.node(0).node();
+        let (index, term, range) = {
+            let mut st = node.running_state()?;
+            node.append_entry(&mut st, vec![record("a")], None)?
+        };
+        node.wait_progress(index, term).await;
+        for i in 0..4 {
+            cluster.node(0).append(record(&format!("r{}", i))).await?;
+        }
+        // the entry is trimmed after it was applied but before its proposer
+        // looked at it again
+        node.snapshot_if_due()?;
+        assert!(node.state().storage.snapshot().last_index > index);
+        node.wait_applied(index, term).await?;
+        assert_eq!(range, 0..1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idempotent() -> Result<()> {
+        let cluster = Cluster::new(3);
+        let old = cluster.leader().await;
+        let node = cluster.node(old);
+        let batch = |values: &[&str]| values.iter().map(|v| record(v)).collect::<Vec<_>>();
+        assert_eq!(
+            node.append_idempotent(7, 0, batch(&["a", "b"])).await?,
+            0..2
+        );
+        assert_eq!(
+            node.append_idempotent(7, 0, batch(&["a", "b"])).await?,
+            0..2
+        );
+        assert_eq!(node.append_idempotent(7, 1, batch(&["c"])).await?, 2..3);
+        let err = node
+            .append_idempotent(7, 3, batch(&["e"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::OutOfOrderSequence { expected: 2, .. })
+        ));
+
+        // a retry the old leader committed but could not answer goes to the
+        // next one, which knows the batch too
+        cluster.network.disconnect(&format!("addr-{}", old));
+        let new = cluster.leader().await;
+        let node = cluster.node(new);
+        assert_eq!(node.append_idempotent(7, 1, batch(&["c"])).await?, 2..3);
+        assert_eq!(node.append_idempotent(7, 2, batch(&["d"])).await?, 3..4);
+        assert_eq!(values(node.log()).len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn membership_changes() -> Result<()> {
+        let cluster = Cluster::with_voters(3, 1);
+        assert_eq!(cluster.leader().await, 0);
+        let leader = cluster.node(0);
+        leader.append(record("a")).await?;
+        leader.add_voter("node-1", "addr-1").await?;
+        leader.add_voter("node-2", "addr-2").await?;
+        assert_eq!(leader.voters().len(), 3);
+        let expected = values(leader.log());
+        for i in 1..3 {
+            let node = cluster.node(i);
+            wait_for(|| {
+                (values(node.log()) == expected && node.voters() == leader.voters()).then_some(())
+            })
+            .await;
+        }
+
+        // the leader removing itself hands over to one of the others
+        leader.remove_server("node-0").await?;
+        wait_for(|| (!leader.is_leader()).then_some(())).await;
+        let new = cluster.leader().await;
+        assert_ne!(new, 0);
+        assert_eq!(cluster.node(new).append(record("b")).await?, 1);
+        assert!(!cluster.node(new).voters().contains_key("node-0"));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn snapshots() -> Result<()> {
+        let mut cluster = Cluster::build(3, 3, 4);
+        let leader = cluster.leader().await;
+        let follower = (leader + 1) % 3;
+        cluster.network.disconnect(&format!("addr-{}", follower));
+        for i in 0..30 {
+            let r = record(&format!("r{}", i));
+            match i {
+                5 => {
+                    cluster
+                        .node(leader)
+                        .append_idempotent(9, 0, vec![r])
+                        .await?
+                }
+                _ => cluster.node(leader).append_batch(vec![r]).await?,
+            };
+        }
+        let trimmed = |i: usize| cluster.node(i).node().state().storage.snapshot().last_index;
+        wait_for(|| (trimmed(leader) > 0).then_some(())).await;
+
+        // the entries the follower missed are gone, so it gets the records
+        cluster.network.reconnect(&format!("addr-{}", follower));
+        let expected = values(cluster.node(leader).log());
+        wait_for(|| (values(cluster.node(follower).log()) == expected).then_some(())).await;
+        assert!(trimmed(follower) > 0);
+        let p = BatchProducer { id: 9, sequence: 0 };
+        let retried = |node: &ReplicatedLog| node.log().check_producer(p).unwrap();
+        assert_eq!(retried(cluster.node(follower)), Some(5..6));
+
+        // and goes on from the snapshot, across a restart too
+        cluster.nodes[follower].take().unwrap().close().await?;
+        cluster.start(follower);
+        let leader = cluster.leader().await;
+        cluster.node(leader).append(record("after")).await?;
+        let expected = values(cluster.node(leader).log());
+        assert_eq!(expected.len(), 31);
+        wait_for(|| (values(cluster.node(follower).log()) == expected).then_some(())).await;
+        assert_eq!(retried(cluster.node(follower)), Some(5..6));
+        Ok(())
+    }
+}
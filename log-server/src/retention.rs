@@ -0,0 +1,157 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::error;
+
+use crate::config::CleanupPolicy;
+use crate::log::Log;
+
+/// A segment deleted by retention.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovedSegment {
+    pub base_offset: u64,
+    /// One past the last offset the segment held.
+    pub next_offset: u64,
+    /// Bytes freed on disk.
+    pub bytes: u64,
+}
+
+/// What one [`Log::compact`] run did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Segments rewritten.
+    pub segments: u64,
+    pub records_removed: u64,
+    pub bytes_removed: u64,
+}
+
+/// Background thread that runs [`Log::enforce_retention`] every `interval`
+/// and hands each non-empty report to a callback. Logs with
+/// [`CleanupPolicy::Compact`] are compacted on the same schedule. The thread
+/// stops when the cleaner is dropped.
+pub struct RetentionCleaner {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl RetentionCleaner {
+    pub fn start<F>(log: Arc<Log>, interval: Duration, mut on_removed: F) -> Self
+    where
+        F: FnMut(Vec<RemovedSegment>) + Send + 'static,
+    {
+        Self::start_all(
+            move || vec![log.clone()],
+            interval,
+            move |_, removed| on_removed(removed),
+        )
+    }
+
+    /// Like [`start`](Self::start), but cleans the logs `logs` returns on
+    /// every run, for a set of logs that changes like the partitions of a
+    /// [`LogManager`](crate::LogManager).
+    pub fn start_all<L, F>(logs: L, interval: Duration, mut on_removed: F) -> Self
+    where
+        L: Fn() -> Vec<Arc<Log>> + Send + 'static,
+        F: FnMut(&Log, Vec<RemovedSegment>) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return,
+            }
+            for log in logs() {
+                match log.enforce_retention() {
+                    Ok(removed) if !removed.is_empty() => on_removed(&log, removed),
+                    Ok(_) => {}
+                    Err(e) => error!("retention of {:?} failed: {:?}", log.dir(), e),
+                }
+                if log.config().cleanup_policy == CleanupPolicy::Compact {
+                    if let Err(e) = log.compact() {
+                        error!("compaction of {:?} failed: {:?}", log.dir(), e);
+                    }
+                }
+            }
+        });
+        RetentionCleaner {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for RetentionCleaner {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use protos::log::v1::Record;
+
+    use crate::config::Config;
+
+    use super::*;
+
+    fn fill(log: &Log, n: usize, timestamp: i64) {
+        for _ in 0..n {
+            let mut r = Record {
+                value: "hello world".to_owned().into_bytes(),
+                timestamp,
+                ..Default::default()
+            };
+            log.append(&mut r).unwrap();
+        }
+    }
+
+    #[test]
+    fn retention_bytes() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 64;
+        c.retention_bytes = Some(200);
+        let log = Log::new(dir.path(), c).unwrap();
+        fill(&log, 20, 0);
+
+        let removed = log.enforce_retention().unwrap();
+        assert!(!removed.is_empty());
+        assert_eq!(removed[0].base_offset, 0);
+        let lowest = log.lowest_offset().unwrap();
+        assert_eq!(lowest, removed.last().unwrap().next_offset);
+        assert!(log.read(lowest - 1).is_err());
+        assert_eq!(log.highest_offset().unwrap(), 19);
+        assert!(log.enforce_retention().unwrap().is_empty());
+    }
+
+    #[test]
+    fn retention_duration_keeps_active_segment() {
+        let dir = tempdir().unwrap();
+        let mut c = Config::default();
+        c.segment.max_store_bytes = 64;
+        c.retention_duration = Some(Duration::from_secs(3600));
+        let log = Arc::new(Log::new(dir.path(), c).unwrap());
+        // records from 1970 are long expired
+        fill(&log, 10, 1);
+        let highest = log.highest_offset().unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let cleaner = RetentionCleaner::start(log.clone(), Duration::from_millis(10), move |r| {
+            tx.send(r).unwrap();
+        });
+        let removed = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        drop(cleaner);
+
+        assert_eq!(removed[0].base_offset, 0);
+        let lowest = log.lowest_offset().unwrap();
+        assert_eq!(lowest, removed.last().unwrap().next_offset);
+        assert_eq!(log.highest_offset().unwrap(), highest);
+    }
+}
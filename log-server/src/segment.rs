@@ -1,116 +1,940 @@
-use crate::config::Config;
-use crate::index::Index;
-use crate::store::Store;
+use crate::batch::{self, CompressionStats};
+use crate::config::{Config, TimestampType};
+use crate::encryption;
+use crate::error::Error;
+use crate::index::{self, Index};
+use crate::key_index::{self, KeyIndex};
+use crate::producer::BatchProducer;
+use crate::store::{Store, VERSION_BATCH};
+use crate::time_index::TimeIndex;
 use anyhow::Context;
-use anyhow::Result;
-use bytes::{Bytes, BytesMut};
-use log::debug;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::{debug, warn};
 use prost::Message;
-use protos::log::v1::Record;
+use protos::log::v1::{ProducerBatch, Record};
+use std::io::ErrorKind;
+use std::ops::Range;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
-use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io, slice};
+
+/// A decoded store frame: its position, its records and where the next frame
+/// starts.
+type Frame = (u64, Arc<Vec<Record>>, u64);
 
 pub(crate) struct Segment {
     pub index: Index,
-    pub store: Store,
+    pub store: Arc<Store>,
+    pub time_index: TimeIndex,
+    /// Latest relative offset of each key, if [`SegmentConfig::key_index`] is on.
+    ///
+    /// [`SegmentConfig::key_index`]: crate::config::SegmentConfig::key_index
+    pub key_index: Option<KeyIndex>,
     pub base_offset: u64,
     pub next_offset: u64,
+    /// Largest record timestamp in the segment, 0 while it is empty.
+    pub max_timestamp: i64,
+    /// Store position of the record behind the last time index entry.
+    last_time_index_pos: u64,
+    /// Store position of the frame behind the last index entry.
+    last_index_pos: u64,
+    /// The frame read last, with its position and where the next one starts,
+    /// so reading its records one by one decodes it once.
+    last_frame: Mutex<Option<Frame>>,
     config: Config,
 }
 
 impl Segment {
-    pub fn new(dir: &Path, base_offset: u64, c: &Config) -> io::Result<Self> {
+    pub fn new(dir: &Path, base_offset: u64, c: &Config) -> Result<Self> {
         let store_file_path = dir.join(format!("{}{}", base_offset, ".store"));
         let store_file = std::fs::OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
             .mode(0o644)
             .open(&store_file_path)?;
-        let store = Store::new(store_file)?.with_path(&store_file_path);
+        let store = Arc::new(Store::open(store_file, base_offset, c)?.with_path(&store_file_path));
+        // the indexes are sealed like the store, or not at all
+        let cipher = store.cipher();
 
         let index_file_path = dir.join(format!("{}{}", base_offset, ".index"));
+        index::migrate(&index_file_path)?;
         let index_file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .mode(0o644)
             .open(&index_file_path)?;
-        let index = Index::new(index_file, c)?.with_path(&index_file_path);
+        let index = Index::new(index_file, c, cipher.clone())?.with_path(&index_file_path);
         debug!("index_size={}", index.size());
-        let next_offset = {
-            if index.is_empty() {
-                base_offset
-            } else {
-                let (off, _) = index.read(-1)?;
-                base_offset + (off as u64) + 1
-            }
+
+        let time_index_file_path = dir.join(format!("{}{}", base_offset, ".timeindex"));
+        let time_index_file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&time_index_file_path)?;
+        let time_index =
+            TimeIndex::new(time_index_file, c, cipher.clone())?.with_path(&time_index_file_path);
+
+        let key_index_file_path = dir.join(format!("{}{}", base_offset, ".keyindex"));
+        let key_index = if c.segment.key_index {
+            Some(KeyIndex::new(&key_index_file_path, cipher))
+        } else {
+            // a snapshot left from when the index was on would go stale
+            key_index::remove(&key_index_file_path)?;
+            None
         };
-        Ok(Segment {
+
+        let mut segment = Segment {
             index,
             store,
+            time_index,
+            key_index,
             base_offset,
-            next_offset,
+            next_offset: base_offset,
+            max_timestamp: 0,
+            last_time_index_pos: 0,
+            last_index_pos: 0,
+            last_frame: Mutex::new(None),
             config: c.clone(),
-        })
+        };
+        segment.recover()?;
+        segment.recover_time_index()?;
+        segment.recover_key_index()?;
+        Ok(segment)
+    }
+
+    /// Drops whatever a crash left behind: zero padding from an index that was
+    /// never truncated on close, entries pointing past the end of the store and
+    /// a torn frame at the end of the store. The index is cut back to the last
+    /// entry whose frame is intact; every complete frame from there on is kept
+    /// and indexed again, which also restores `next_offset`. Relative offsets
+    /// must grow from entry to entry; compaction may leave gaps between them
+    /// and a sparse index skips records.
+    fn recover(&mut self) -> Result<()> {
+        let mut entries = self.index.len();
+        let mut from = self.store.data_start();
+        while entries > 0 {
+            let i = entries - 1;
+            let (off, pos) = self.index.read(i)?;
+            if (i == 0 || off > self.index.read(i - 1)?.0) && self.store.check_frame(pos).is_ok() {
+                from = pos;
+                break;
+            }
+            entries -= 1;
+        }
+        let index_size = self.index.size();
+        self.index.truncate(entries)?;
+        let index_discarded = index_size - self.index.size();
+
+        let mut frames = Vec::new();
+        let mut store_end = from;
+        while store_end < self.store.size() {
+            match self.store.check_frame(store_end) {
+                Ok(next) => {
+                    frames.push(store_end);
+                    store_end = next;
+                }
+                Err(_) => break,
+            }
+        }
+        let store_discarded = self.store.size() - store_end;
+        if index_discarded > 0 || store_discarded > 0 {
+            warn!(
+                "recovered segment base_offset={}: discarded {} index bytes and {} store bytes",
+                self.base_offset, index_discarded, store_discarded
+            );
+            self.store.truncate(store_end)?;
+        }
+
+        let last = self.index.last()?;
+        self.last_index_pos = last.map_or(0, |(_, pos)| pos);
+        for pos in frames {
+            let records = self.read_frame(pos)?.0;
+            for r in records.iter() {
+                if self.relative(r.offset).is_none() {
+                    let reason = format!("offset={} is outside the segment", r.offset);
+                    return Err(self.corrupt(pos, reason));
+                }
+            }
+            let (Some(first), Some(last_record)) = (records.first(), records.last()) else {
+                continue;
+            };
+            let rel = (first.offset - self.base_offset) as u32;
+            if last.is_none_or(|(off, _)| rel > off) {
+                self.track_index(rel, pos)?;
+            }
+            self.next_offset = last_record.offset + 1;
+        }
+        Ok(())
+    }
+
+    /// Drops time index entries past the recovered records, then replays the
+    /// records after the last entry to restore the largest timestamp. Segments
+    /// written before the time index existed get it rebuilt this way.
+    fn recover_time_index(&mut self) -> Result<()> {
+        let discarded = self
+            .time_index
+            .recover(self.next_offset - self.base_offset)?;
+        if discarded > 0 {
+            warn!(
+                "recovered segment base_offset={}: discarded {} time index bytes",
+                self.base_offset, discarded
+            );
+        }
+        let last = self.time_index.last();
+        if let Some((ts, _)) = last {
+            self.max_timestamp = ts;
+        }
+        let from = self.base_offset + last.map_or(0, |(_, off)| off as u64);
+        let replay = self
+            .scan(from)
+            .map(|e| e.map(|(pos, r)| (pos, r.offset, r.timestamp)))
+            .collect::<Result<Vec<_>>>()?;
+        // the newest record of each frame, as appending tracked it
+        let mut newest: Option<(u64, i64, u32)> = None;
+        for (pos, offset, ts) in replay {
+            let rel = (offset - self.base_offset) as u32;
+            if last.is_some_and(|(_, off)| rel <= off) {
+                self.last_time_index_pos = pos;
+                continue;
+            }
+            match &mut newest {
+                Some((p, newest_ts, newest_rel)) if *p == pos => {
+                    if ts > *newest_ts {
+                        (*newest_ts, *newest_rel) = (ts, rel);
+                    }
+                }
+                _ => {
+                    if let Some((p, ts, rel)) = newest.replace((pos, ts, rel)) {
+                        self.track_timestamp(ts, rel, p)?;
+                    }
+                }
+            }
+        }
+        if let Some((pos, ts, rel)) = newest {
+            self.track_timestamp(ts, rel, pos)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the key index snapshot and replays the records appended after it
+    /// was saved. A missing or unreadable snapshot, or one covering records
+    /// recovery dropped, is rebuilt from the whole segment.
+    fn recover_key_index(&mut self) -> Result<()> {
+        let mut key_index = match self.key_index.take() {
+            Some(k) => k,
+            None => return Ok(()),
+        };
+        let covered = match key_index.load() {
+            Ok(Some(next)) if next <= self.next_offset => next,
+            Ok(_) => self.base_offset,
+            Err(e) => {
+                warn!(
+                    "rebuilding key index of segment base_offset={}: {}",
+                    self.base_offset, e
+                );
+                self.base_offset
+            }
+        };
+        if covered == self.base_offset {
+            key_index.clear();
+        }
+        let result = self.scan(covered).try_for_each(|e| {
+            let (_, r) = e?;
+            if let Some(key) = &r.key {
+                key_index.insert(key, (r.offset - self.base_offset) as u32);
+            }
+            Ok(())
+        });
+        self.key_index = Some(key_index);
+        result
+    }
+
+    /// Indexes the frame at `pos` by the relative offset `rel` of its first
+    /// record if the index is dense, empty, or at least
+    /// `index_interval_bytes` of store went by since the last entry.
+    fn track_index(&mut self, rel: u32, pos: u64) -> io::Result<()> {
+        let interval = self.config.segment.index_interval_bytes;
+        if interval == 0 || self.index.is_empty() || pos - self.last_index_pos >= interval {
+            self.index.write(rel, pos)?;
+            self.last_index_pos = pos;
+        }
+        Ok(())
+    }
+
+    /// Raises the largest timestamp to `ts` of the frame at `pos`, where `rel`
+    /// is the first record that has it, and, once enough store bytes went by
+    /// since the last entry, records it in the time index.
+    fn track_timestamp(&mut self, ts: i64, rel: u32, pos: u64) -> io::Result<()> {
+        if ts <= self.max_timestamp {
+            return Ok(());
+        }
+        self.max_timestamp = ts;
+        let interval = self.config.segment.time_index_interval_bytes;
+        if self.time_index.last().is_none() || pos - self.last_time_index_pos >= interval {
+            self.time_index.write(ts, rel)?;
+            self.last_time_index_pos = pos;
+        }
+        Ok(())
+    }
+
+    /// Returns the first offset in the segment whose timestamp is at or after `ts`.
+    pub fn offset_for_timestamp(&self, ts: i64) -> Result<Option<u64>> {
+        if ts > self.max_timestamp {
+            return Ok(None);
+        }
+        // every record before the entry's offset is older than the entry
+        let start = self.time_index.lookup_before(ts).map_or(0, |(_, off)| off);
+        for e in self.scan(self.base_offset + start as u64) {
+            let (_, r) = e?;
+            if r.timestamp >= ts {
+                return Ok(Some(r.offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// fsyncs the store and both indexes.
+    pub fn sync(&self) -> Result<()> {
+        self.store.sync()?;
+        self.index.sync()?;
+        self.time_index.sync()?;
+        Ok(())
     }
 
     pub fn close(&mut self) -> Result<()> {
         self.store.close()?;
         self.index.close()?;
+        self.time_index.close()?;
+        if let Some(k) = &mut self.key_index {
+            k.save(self.next_offset)?;
+        }
         Ok(())
     }
 
     pub fn append(&mut self, record: &mut Record) -> Result<u64> {
-        let mut b = BytesMut::new();
-        record.encode(&mut b).with_context(|| "failed to encode")?;
-        let cur = self.next_offset;
-        record.offset = cur;
-        let (_, pos) = self
+        Ok(self.append_batch(slice::from_mut(record), None)?.start)
+    }
+
+    /// Assigns contiguous offsets to `records` and appends them with a single
+    /// store write, as a batch of `producer` if there is one. An empty
+    /// segment takes them even past `max_store_bytes`.
+    pub fn append_batch(
+        &mut self,
+        records: &mut [Record],
+        producer: Option<BatchProducer>,
+    ) -> Result<Range<u64>> {
+        let start = self.stamp(records);
+        self.append_at(records, producer)?;
+        Ok(start..self.next_offset)
+    }
+
+    /// Like [`Segment::append_batch`], but leaves a segment that is not empty
+    /// and has no room for `records` untouched and returns `None`.
+    pub fn try_append_batch(
+        &mut self,
+        records: &mut [Record],
+        producer: Option<BatchProducer>,
+    ) -> Result<Option<Range<u64>>> {
+        let start = self.stamp(records);
+        let appended = self.try_append_at(records, producer)?;
+        Ok(appended.then_some(start..self.next_offset))
+    }
+
+    /// Appends `records` as one batch keeping their offsets and timestamps,
+    /// leaving gaps where offsets skip ahead. Compaction rewrites segments this
+    /// way.
+    pub fn append_at(&mut self, records: &[Record], producer: Option<BatchProducer>) -> Result<()> {
+        let payloads = self.encode(records, producer)?;
+        self.write(records, payloads)
+    }
+
+    /// Like [`Segment::append_at`], but leaves a segment that is not empty
+    /// and has no room for `records` untouched and returns false.
+    pub fn try_append_at(
+        &mut self,
+        records: &[Record],
+        producer: Option<BatchProducer>,
+    ) -> Result<bool> {
+        let fits_index = records
+            .last()
+            .is_none_or(|r| self.relative(r.offset).is_some());
+        if !self.is_empty() && !fits_index {
+            return Ok(false);
+        }
+        let payloads = self.encode(records, producer)?;
+        if !self.is_empty() && !self.has_room_for(&payloads) {
+            return Ok(false);
+        }
+        self.write(records, payloads)?;
+        Ok(true)
+    }
+
+    /// Assigns `records` the offsets from `next_offset` on and sets the
+    /// timestamps [`TimestampType`] asks for, returning the first offset.
+    fn stamp(&self, records: &mut [Record]) -> u64 {
+        let start = self.next_offset;
+        let now = now_millis();
+        for (i, record) in records.iter_mut().enumerate() {
+            record.offset = start + i as u64;
+            if record.timestamp == 0 || self.config.timestamp_type == TimestampType::LogAppendTime {
+                record.timestamp = now;
+            }
+        }
+        start
+    }
+
+    /// Removes every record at or past `offset`. The other records of the
+    /// store frame holding the first of them are written back as a new frame.
+    pub fn truncate_from(&mut self, offset: u64) -> Result<()> {
+        let pos = match self.scan(offset).next() {
+            Some(e) => e?.0,
+            None => {
+                self.next_offset = self.next_offset.min(offset.max(self.base_offset));
+                return Ok(());
+            }
+        };
+        let (frame, _) = self.read_frame(pos)?;
+        self.store.truncate(pos)?;
+        // the frame written back takes the position of the cut one
+        *self.last_frame.lock().unwrap() = None;
+        let mut entries = self.index.len();
+        while entries > 0 && self.index.read(entries - 1)?.1 >= pos {
+            entries -= 1;
+        }
+        self.index.truncate(entries)?;
+        self.last_index_pos = self.index.last()?.map_or(0, |(_, pos)| pos);
+        self.next_offset = frame[0].offset;
+        self.max_timestamp = 0;
+        self.last_time_index_pos = 0;
+        self.recover_time_index()?;
+        self.recover_key_index()?;
+        let kept: Vec<_> = frame
+            .iter()
+            .filter(|r| r.offset < offset)
+            .cloned()
+            .collect();
+        // what is left of the batch is no longer the one its producer appended
+        self.append_at(&kept, None)?;
+        self.next_offset = offset.max(self.base_offset);
+        Ok(())
+    }
+
+    /// Whether frames with `payloads` fit in the segment's store and index
+    /// limits.
+    fn has_room_for(&self, payloads: &[Vec<u8>]) -> bool {
+        let bytes: u64 = payloads
+            .iter()
+            .map(|p| self.store.frame_width(p.len() as u64))
+            .sum();
+        self.store.size() + bytes <= self.config.segment.max_store_bytes
+            && self.indexes_have_room_for(payloads.len())
+    }
+
+    /// Whether the index and the time index can each take an entry for `n`
+    /// more frames.
+    fn indexes_have_room_for(&self, n: usize) -> bool {
+        let segment = &self.config.segment;
+        let n = n as u64;
+        self.index.size() + n * self.index.entry_width() <= segment.max_index_bytes
+            && self.time_index.size() + n * self.time_index.entry_width()
+                <= segment.max_time_index_bytes
+    }
+
+    /// `offset` relative to the base offset, if the index can hold it.
+    fn relative(&self, offset: u64) -> Option<u32> {
+        u32::try_from(offset.checked_sub(self.base_offset)?).ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Checks that `records` go after the segment's and encodes them as one
+    /// batch payload, or as one payload each in stores that predate batches,
+    /// which cannot record a producer.
+    fn encode(&self, records: &[Record], producer: Option<BatchProducer>) -> Result<Vec<Vec<u8>>> {
+        let mut next = self.next_offset;
+        for r in records {
+            if r.offset < next {
+                return Err(anyhow!("offset={} is below next_offset={}", r.offset, next));
+            }
+            if self.relative(r.offset).is_none() {
+                return Err(anyhow!(
+                    "offset={} is too far past base_offset={} for the index",
+                    r.offset,
+                    self.base_offset
+                ));
+            }
+            next = r.offset + 1;
+        }
+        if records.is_empty() {
+            Ok(vec![])
+        } else if self.store.version() >= VERSION_BATCH {
+            Ok(vec![batch::encode(
+                records,
+                self.config.segment.compression,
+                producer,
+            )?])
+        } else {
+            Ok(records.iter().map(|r| r.encode_to_vec()).collect())
+        }
+    }
+
+    /// Stores the `payloads` [`Segment::encode`] made of `records` and
+    /// indexes each frame by its first record.
+    fn write(&mut self, records: &[Record], payloads: Vec<Vec<u8>>) -> Result<()> {
+        let Some(last) = records.last() else {
+            return Ok(());
+        };
+        let next = last.offset + 1;
+        // checked before the store is written, which an index error must not follow
+        if !self.indexes_have_room_for(payloads.len()) {
+            return Err(anyhow!(
+                "indexes of segment base_offset={} have no room for {} frames",
+                self.base_offset,
+                payloads.len()
+            ));
+        }
+        let positions = self
             .store
-            .append(&b)
+            .append_batch(&payloads)
             .with_context(|| "failed to append to store")?;
-        self.index
-            .write((cur - self.base_offset) as u32, pos)
-            .with_context(|| {
-                format!(
-                    "failed to write index with off = {}, pose = {}",
-                    cur - self.base_offset,
-                    pos
-                )
-            })?;
-        self.next_offset += 1;
-        Ok(cur)
+        let per_frame = records.len() / payloads.len();
+        let base_offset = self.base_offset;
+        let rel = |r: &Record| (r.offset - base_offset) as u32;
+        for (frame, pos) in records.chunks(per_frame).zip(positions) {
+            self.track_index(rel(&frame[0]), pos)?;
+            let newest = frame
+                .iter()
+                .reduce(|newest, r| {
+                    if r.timestamp > newest.timestamp {
+                        r
+                    } else {
+                        newest
+                    }
+                })
+                .expect("frames are not empty");
+            self.track_timestamp(newest.timestamp, rel(newest), pos)?;
+            if let Some(k) = &mut self.key_index {
+                for r in frame {
+                    if let Some(key) = &r.key {
+                        k.insert(key, rel(r));
+                    }
+                }
+            }
+        }
+        self.next_offset = next;
+        Ok(())
     }
 
+    /// Reads the record at `offset`: finds the closest index entry at or
+    /// below it and scans the store frames from there.
     pub fn read(&self, offset: u64) -> Result<Record> {
-        let (_, pos) = self.index.read((offset - self.base_offset) as i64)?;
-        let payload = self.store.read(pos)?;
-        let b: Bytes = payload.into();
-        let r = Record::decode(b)?;
-        Ok(r)
+        if self.relative(offset).is_none() {
+            return Err(Error::OffsetOutOfRange { offset }.into());
+        }
+        match self.scan(offset).next() {
+            Some(Ok((_, r))) if r.offset == offset => Ok(r),
+            Some(Err(e)) => Err(e),
+            _ => Err(Error::Compacted { offset }.into()),
+        }
+    }
+
+    /// Reads the latest record with `key` in the segment, if any, scanning
+    /// the whole segment if it keeps no key index.
+    pub fn read_by_key(&self, key: &[u8]) -> Result<Option<Record>> {
+        let key_index = match &self.key_index {
+            Some(k) => k,
+            None => {
+                let mut latest = None;
+                for e in self.scan(self.base_offset) {
+                    let (_, r) = e?;
+                    if r.key.as_deref() == Some(key) {
+                        latest = Some(r);
+                    }
+                }
+                return Ok(latest);
+            }
+        };
+        match key_index.get(key) {
+            Some(rel) => self.read(self.base_offset + rel as u64).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the records at or past `from` in the first store frame holding
+    /// any, so callers can walk the segment a frame at a time. Empty if no
+    /// record is at or past `from`.
+    pub fn read_batch_from(&self, from: u64) -> Result<Vec<Record>> {
+        let mut scan = self.scan(from);
+        match scan.next() {
+            Some(e) => {
+                let mut records = vec![e?.1];
+                records.extend(scan.pending[scan.at..].iter().cloned());
+                Ok(records)
+            }
+            None => Ok(Vec::new()),
+        }
     }
 
+    /// Reads the records below `end`, starting at the frame of the closest
+    /// index entry below it, or at the first frame without one. Empty if no
+    /// record is below `end`.
+    pub fn read_batch_before(&self, end: u64) -> Result<Vec<Record>> {
+        let rel = match end.checked_sub(self.base_offset + 1) {
+            Some(rel) => u32::try_from(rel).unwrap_or(u32::MAX),
+            None => return Ok(Vec::new()),
+        };
+        let mut pos = self
+            .index
+            .floor(rel)?
+            .map_or(self.store.data_start(), |(_, pos)| pos);
+        let mut records = Vec::new();
+        while pos < self.store.size() {
+            let (frame, next) = self.read_frame(pos)?;
+            let done = frame.last().is_some_and(|r| r.offset + 1 >= end);
+            records.extend(frame.iter().filter(|r| r.offset < end).cloned());
+            if done {
+                break;
+            }
+            pos = next;
+        }
+        Ok(records)
+    }
+
+    /// Iterates over every record still in the segment, in offset order.
+    pub fn records(&self) -> impl Iterator<Item = Result<Record>> + '_ {
+        self.scan(self.base_offset).map(|e| e.map(|(_, r)| r))
+    }
+
+    /// Iterates over the records at or past `from` along with the position of
+    /// their store frame, starting at the closest index entry.
+    pub fn scan(&self, from: u64) -> Scan<'_> {
+        Scan {
+            segment: self,
+            next: self.scan_start(from),
+            pos: 0,
+            pending: Arc::default(),
+            at: 0,
+            from,
+            failed: false,
+        }
+    }
+
+    /// Position of the frame of the closest index entry at or below `from`.
+    fn scan_start(&self, from: u64) -> u64 {
+        let start = match from.checked_sub(self.base_offset) {
+            // a failed lookup only costs scanning from the start
+            Some(rel) => self
+                .index
+                .floor(u32::try_from(rel).unwrap_or(u32::MAX))
+                .ok()
+                .flatten(),
+            None => None,
+        };
+        start.map_or(self.store.data_start(), |(_, pos)| pos)
+    }
+
+    /// The batches of idempotent producers holding records at or past `from`,
+    /// stamped with the newest timestamp among their records. Only batches
+    /// with a producer are decoded.
+    pub fn producer_batches(&self, from: u64) -> Result<Vec<ProducerBatch>> {
+        let mut batches = vec![];
+        if self.store.version() < VERSION_BATCH {
+            return Ok(batches);
+        }
+        let mut pos = self.scan_start(from);
+        while pos < self.store.size() {
+            let (payload, next) = read_payload(&self.store, self.base_offset, pos)?;
+            let producer =
+                batch::producer(&payload).map_err(|e| self.corrupt(pos, e.to_string()))?;
+            if let Some(p) = producer {
+                let records =
+                    batch::decode(&payload).map_err(|e| self.corrupt(pos, e.to_string()))?;
+                match (records.first(), records.last()) {
+                    (Some(first), Some(last)) if last.offset >= from => {
+                        batches.push(ProducerBatch {
+                            producer_id: p.id,
+                            sequence: p.sequence,
+                            first_offset: first.offset,
+                            next_offset: last.offset + 1,
+                            timestamp: records.iter().map(|r| r.timestamp).max().unwrap_or(0),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+            pos = next;
+        }
+        Ok(batches)
+    }
+
+    /// Reads the records in the store frame at `pos` and where the next frame
+    /// starts. The frame read last is kept decoded.
+    fn read_frame(&self, pos: u64) -> Result<(Arc<Vec<Record>>, u64)> {
+        if let Some((p, records, next)) = &*self.last_frame.lock().unwrap() {
+            if *p == pos {
+                return Ok((records.clone(), *next));
+            }
+        }
+        let (records, next) = decode_frame(&self.store, self.base_offset, pos)?;
+        let records = Arc::new(records);
+        *self.last_frame.lock().unwrap() = Some((pos, records.clone(), next));
+        Ok((records, next))
+    }
+
+    /// The segment as it is now, to read without the log's lock once it is
+    /// closed.
+    pub fn closed(&self) -> Result<ClosedSegment> {
+        let modified = self.store.modified()?;
+        Ok(ClosedSegment {
+            base_offset: self.base_offset,
+            next_offset: self.next_offset,
+            store: self.store.clone(),
+            size: self.store.size(),
+            modified,
+        })
+    }
+
+    /// Whether `closed` still describes the segment, i.e. it was neither
+    /// replaced nor truncated since.
+    pub fn is_unchanged(&self, closed: &ClosedSegment) -> bool {
+        Arc::ptr_eq(&self.store, &closed.store)
+            && self.next_offset == closed.next_offset
+            && self.store.size() == closed.size
+    }
+
+    /// Sizes of the segment's payloads before and after compression. Stores
+    /// that predate batches hold records uncompressed.
+    pub fn compression_stats(&self) -> Result<CompressionStats> {
+        let mut stats = CompressionStats {
+            base_offset: self.base_offset,
+            ..Default::default()
+        };
+        let mut pos = self.store.data_start();
+        while pos < self.store.size() {
+            let (payload, next) = self.store.read_frame(pos)?;
+            let raw_len = if self.store.version() >= VERSION_BATCH {
+                batch::header(&payload)?.1 as u64
+            } else {
+                payload.len() as u64
+            };
+            stats.batches += 1;
+            stats.uncompressed_bytes += raw_len;
+            stats.compressed_bytes += payload.len() as u64;
+            pos = next;
+        }
+        Ok(stats)
+    }
+
+    fn corrupt(&self, pos: u64, reason: String) -> anyhow::Error {
+        corrupt(self.base_offset, pos, reason)
+    }
+
+    /// Whether the segment is full: its store reached its limit, either index
+    /// has no room for another frame, or the next offset no longer fits the
+    /// index's 4-byte relative offsets.
     pub fn is_maxed(&self) -> bool {
         self.store.size() >= self.config.segment.max_store_bytes
-            || self.index.size() >= self.config.segment.max_index_bytes
+            || !self.indexes_have_room_for(1)
+            || self.relative(self.next_offset).is_none()
+    }
+
+    /// Bytes the segment takes on disk.
+    pub fn size(&self) -> u64 {
+        self.store.size()
+            + self.index.size()
+            + self.time_index.size()
+            + self.key_index.as_ref().map_or(0, KeyIndex::size)
+    }
+
+    /// Paths of the store, index and time index files, plus the key index
+    /// snapshot if there is one.
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = [
+            &self.store.file_path,
+            &self.index.file_path,
+            &self.time_index.file_path,
+        ]
+        .into_iter()
+        .map(|p| p.clone().expect("segment file path"))
+        .collect();
+        if let Some(k) = &self.key_index {
+            paths.push(k.file_path.clone());
+        }
+        paths
     }
 
     pub fn remove(&mut self) -> Result<()> {
         self.close()?;
         fs::remove_file(self.index.file_path.as_ref().expect("index file path"))?;
         fs::remove_file(self.store.file_path.as_ref().expect("store file path"))?;
+        fs::remove_file(
+            self.time_index
+                .file_path
+                .as_ref()
+                .expect("time index file path"),
+        )?;
+        if let Some(k) = &self.key_index {
+            k.remove()?;
+        }
         Ok(())
     }
 }
 
+/// Reads the store frame at `pos` of the segment at `base_offset`: its
+/// records and where the next frame starts.
+fn decode_frame(store: &Store, base_offset: u64, pos: u64) -> Result<(Vec<Record>, u64)> {
+    let (payload, next) = read_payload(store, base_offset, pos)?;
+    let records = if store.version() >= VERSION_BATCH {
+        batch::decode(&payload).map_err(|e| corrupt(base_offset, pos, e.to_string()))?
+    } else {
+        let b: Bytes = payload.into();
+        vec![Record::decode(b).map_err(|e| corrupt(base_offset, pos, e.to_string()))?]
+    };
+    Ok((records, next))
+}
+
+/// Reads the payload of the store frame at `pos` of the segment at
+/// `base_offset` and where the next frame starts.
+fn read_payload(store: &Store, base_offset: u64, pos: u64) -> Result<(Vec<u8>, u64)> {
+    store.read_frame(pos).map_err(|e| {
+        if encryption::is_unauthenticated(&e) {
+            return Error::Unauthenticated {
+                base_offset,
+                pos,
+                key_id: store.key_id().unwrap_or_default().to_owned(),
+            }
+            .into();
+        }
+        match e.kind() {
+            ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                corrupt(base_offset, pos, e.to_string())
+            }
+            _ => e.into(),
+        }
+    })
+}
+
+fn corrupt(base_offset: u64, pos: u64, reason: String) -> anyhow::Error {
+    Error::Corrupt {
+        base_offset,
+        pos,
+        reason,
+    }
+    .into()
+}
+
+/// A closed segment read without the log's lock. Closed segments are never
+/// appended to again, and their store stays readable through its open file
+/// even after compaction replaced or retention removed it.
+pub(crate) struct ClosedSegment {
+    pub base_offset: u64,
+    pub next_offset: u64,
+    store: Arc<Store>,
+    size: u64,
+    /// When the store was last written, i.e. when its last record was
+    /// appended.
+    pub modified: SystemTime,
+}
+
+impl ClosedSegment {
+    /// The records of each store frame, in order.
+    pub fn frames(&self) -> impl Iterator<Item = Result<Vec<Record>>> + '_ {
+        let mut pos = self.store.data_start();
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed || pos >= self.size {
+                return None;
+            }
+            match decode_frame(&self.store, self.base_offset, pos) {
+                Ok((records, next)) => {
+                    pos = next;
+                    Some(Ok(records))
+                }
+                Err(e) => {
+                    failed = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+}
+
+/// Records of a segment in offset order, read frame by frame. Stops after
+/// the first error.
+pub(crate) struct Scan<'a> {
+    segment: &'a Segment,
+    /// Position of the next frame to read.
+    next: u64,
+    /// Position of the frame `pending` came from.
+    pos: u64,
+    pending: Arc<Vec<Record>>,
+    /// Index of the next record of `pending`.
+    at: usize,
+    /// Records below this offset are skipped.
+    from: u64,
+    failed: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            if let Some(r) = self.pending.get(self.at) {
+                self.at += 1;
+                if r.offset >= self.from {
+                    return Some(Ok((self.pos, r.clone())));
+                }
+                continue;
+            }
+            if self.next >= self.segment.store.size() {
+                return None;
+            }
+            match self.segment.read_frame(self.next) {
+                Ok((records, next)) => {
+                    self.pos = self.next;
+                    self.next = next;
+                    // records are sorted, so skip to `from` without a scan
+                    self.at = records.partition_point(|r| r.offset < self.from);
+                    self.pending = records;
+                }
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock before Unix epoch")
+        .as_millis() as i64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::SegmentConfig;
+    use crate::config::{Compression, SegmentConfig};
+    use crate::entry_file;
     use crate::index::ENTRY_WIDTH;
+    use crate::time_index;
+    use protos::log::v1::Header;
+    use std::os::unix::fs::FileExt;
     use tempfile::tempdir;
 
     #[test]
@@ -120,8 +944,11 @@ mod tests {
             segment: SegmentConfig {
                 max_store_bytes: 1024,
                 max_index_bytes: 3 * ENTRY_WIDTH as u64,
+                max_time_index_bytes: 1024,
                 initial_offset: 0,
+                ..Default::default()
             },
+            ..Default::default()
         };
         let mut segment = Segment::new(dir.path(), 16, &config).unwrap();
         assert_eq!(16, segment.next_offset);
@@ -142,10 +969,348 @@ mod tests {
             segment: SegmentConfig {
                 max_store_bytes: r1.value.len() as u64 * 3, // store file is maxed out
                 max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
                 initial_offset: 0,
+                ..Default::default()
             },
+            ..Default::default()
         };
         let segment = Segment::new(dir.path(), 16, &config).unwrap();
         assert!(segment.is_maxed());
     }
+
+    #[test]
+    fn test_corrupt_record() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1024,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
+                initial_offset: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut segment = Segment::new(dir.path(), 4, &config).unwrap();
+        let mut r1 = Record {
+            value: vec![1, 2, 3],
+            ..Default::default()
+        };
+        segment.append(&mut r1).unwrap();
+        segment.close().unwrap();
+        let (_, pos) = segment.index.read(0).unwrap();
+
+        let store_path = segment.store.file_path.clone().unwrap();
+        let file = fs::OpenOptions::new().write(true).open(store_path).unwrap();
+        file.write_all_at(&[0xff], segment.store.size() - 1)
+            .unwrap();
+
+        let err = segment.read(4).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::Corrupt {
+                base_offset,
+                pos: p,
+                ..
+            }) => {
+                assert_eq!(*base_offset, 4);
+                assert_eq!(*p, pos);
+            }
+            _ => panic!("expected corrupt error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_recover_torn_tail() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1024,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
+                initial_offset: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        let mut r1 = Record {
+            value: vec![1, 2, 3],
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            segment.append(&mut r1).unwrap();
+        }
+        segment.close().unwrap();
+        let (_, last_pos) = segment.index.last().unwrap().unwrap();
+        let index_path = segment.index.file_path.clone().unwrap();
+        let store_path = segment.store.file_path.clone().unwrap();
+        drop(segment);
+
+        // an index left zero-padded to max_index_bytes and a torn last frame
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&index_path)
+            .unwrap()
+            .set_len(config.segment.max_index_bytes)
+            .unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&store_path)
+            .unwrap()
+            .set_len(last_pos + 4)
+            .unwrap();
+
+        let segment = Segment::new(dir.path(), 0, &config).unwrap();
+        assert_eq!(2, segment.next_offset);
+        assert_eq!(2 * ENTRY_WIDTH as u64, segment.index.size());
+        assert_eq!(last_pos, segment.store.size());
+        assert_eq!(segment.read(1).unwrap().value, r1.value);
+        drop(segment);
+        assert_eq!(last_pos, fs::metadata(&store_path).unwrap().len());
+        assert_eq!(
+            (entry_file::HEADER_WIDTH + 2 * ENTRY_WIDTH) as u64,
+            fs::metadata(&index_path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_record_fields() {
+        let dir = tempdir().unwrap();
+        let mut config = Config::default();
+        config.segment.max_store_bytes = 1024;
+        config.segment.max_index_bytes = 1024;
+        config.segment.max_time_index_bytes = 1024;
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        let mut r1 = Record {
+            value: vec![1, 2, 3],
+            key: Some(b"user-1".to_vec()),
+            headers: vec![Header {
+                key: "trace-id".to_owned(),
+                value: vec![4, 5],
+            }],
+            timestamp: 42,
+            ..Default::default()
+        };
+        segment.append(&mut r1).unwrap();
+        assert_eq!(segment.read(0).unwrap(), r1);
+
+        let mut r2 = Record {
+            value: vec![1, 2, 3],
+            ..Default::default()
+        };
+        segment.append(&mut r2).unwrap();
+        assert!(r2.timestamp > 42);
+        assert_eq!(segment.read(1).unwrap(), r2);
+
+        config.timestamp_type = TimestampType::LogAppendTime;
+        let mut segment = Segment::new(dir.path(), 2, &config).unwrap();
+        segment.append(&mut r1).unwrap();
+        assert!(r1.timestamp > 42);
+        assert_eq!(segment.read(2).unwrap().timestamp, r1.timestamp);
+    }
+
+    #[test]
+    fn test_offset_for_timestamp() {
+        let dir = tempdir().unwrap();
+        let mut config = Config::default();
+        config.segment.max_store_bytes = 1024;
+        config.segment.max_index_bytes = 1024;
+        config.segment.max_time_index_bytes = 1024;
+        config.segment.time_index_interval_bytes = 32;
+        let mut segment = Segment::new(dir.path(), 10, &config).unwrap();
+        for ts in [100, 100, 200, 150, 300, 400, 500] {
+            let mut r = Record {
+                value: vec![1, 2, 3],
+                timestamp: ts,
+                ..Default::default()
+            };
+            segment.append(&mut r).unwrap();
+        }
+        // the interval skips some increases
+        assert!(segment.time_index.len() < 5);
+        assert_eq!(segment.offset_for_timestamp(50).unwrap(), Some(10));
+        assert_eq!(segment.offset_for_timestamp(150).unwrap(), Some(12));
+        assert_eq!(segment.offset_for_timestamp(201).unwrap(), Some(14));
+        assert_eq!(segment.offset_for_timestamp(500).unwrap(), Some(16));
+        assert_eq!(segment.offset_for_timestamp(501).unwrap(), None);
+
+        // a lost time index is rebuilt from the records
+        segment.close().unwrap();
+        let time_index_path = segment.time_index.file_path.clone().unwrap();
+        drop(segment);
+        fs::remove_file(&time_index_path).unwrap();
+        let segment = Segment::new(dir.path(), 10, &config).unwrap();
+        assert_eq!(segment.max_timestamp, 500);
+        assert_eq!(segment.offset_for_timestamp(201).unwrap(), Some(14));
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = tempdir().unwrap();
+        let mut config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1 << 20,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
+                compression: Compression::Zstd,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let json = |i: u64| Record {
+            value: format!(
+                "{{\"id\":{},\"status\":\"ok\",\"detail\":\"nothing to report\"}}",
+                i
+            )
+            .into_bytes(),
+            ..Default::default()
+        };
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        let mut batch: Vec<_> = (0..20).map(json).collect();
+        assert_eq!(segment.append_batch(&mut batch, None).unwrap(), 0..20);
+        segment.close().unwrap();
+        drop(segment);
+
+        // batches written with the old codec stay readable after a change
+        config.segment.compression = Compression::Lz4;
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        assert_eq!(segment.next_offset, 20);
+        let mut batch: Vec<_> = (20..40).map(json).collect();
+        segment.append_batch(&mut batch, None).unwrap();
+        for (i, r) in segment.records().enumerate() {
+            assert_eq!(r.unwrap().value, json(i as u64).value);
+        }
+        assert_eq!(segment.read(25).unwrap().value, json(25).value);
+
+        let stats = segment.compression_stats().unwrap();
+        assert_eq!(stats.batches, 2);
+        assert!(stats.ratio() > 2.0, "ratio={}", stats.ratio());
+    }
+
+    #[test]
+    fn test_relative_offset_overflow() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1024,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let base = 1 << 40;
+        let mut segment = Segment::new(dir.path(), base, &config).unwrap();
+        let last = Record {
+            value: vec![1],
+            offset: base + u32::MAX as u64,
+            ..Default::default()
+        };
+        segment.append_at(slice::from_ref(&last), None).unwrap();
+        assert_eq!(segment.read(last.offset).unwrap(), last);
+        assert!(segment.is_maxed());
+
+        let mut r = Record::default();
+        assert_eq!(
+            segment
+                .try_append_batch(slice::from_mut(&mut r), None)
+                .unwrap(),
+            None
+        );
+        assert!(segment.append(&mut r).is_err());
+        assert!(segment.read(last.offset + 1).is_err());
+        assert_eq!(segment.next_offset, last.offset + 1);
+    }
+
+    #[test]
+    fn test_sparse_index() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1 << 20,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 1024,
+                index_interval_bytes: 256,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        let record = |i: u64| Record {
+            value: format!("record-{}", i).into_bytes(),
+            ..Default::default()
+        };
+        for i in 0..100 {
+            segment.append(&mut record(i)).unwrap();
+        }
+        let mut batch: Vec<_> = (100..110).map(record).collect();
+        segment.append_batch(&mut batch, None).unwrap();
+        assert!(segment.index.len() < 20, "entries={}", segment.index.len());
+        for i in 0..110 {
+            assert_eq!(segment.read(i).unwrap().value, record(i).value);
+        }
+        assert_eq!(segment.records().count(), 110);
+        segment.close().unwrap();
+        let entries = segment.index.len();
+        drop(segment);
+
+        // records after the last entry are found again on open
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        assert_eq!(segment.next_offset, 110);
+        assert_eq!(segment.index.len(), entries);
+        let mut r = record(110);
+        segment.append(&mut r).unwrap();
+        assert_eq!(r.offset, 110);
+        assert_eq!(segment.read(110).unwrap().value, r.value);
+    }
+
+    #[test]
+    fn test_time_index_full() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            segment: SegmentConfig {
+                max_store_bytes: 1 << 20,
+                max_index_bytes: 1024,
+                max_time_index_bytes: 4 * time_index::ENTRY_WIDTH as u64,
+                index_interval_bytes: 1 << 20,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut segment = Segment::new(dir.path(), 0, &config).unwrap();
+        for ts in 1..=4 {
+            let mut r = Record {
+                value: vec![1, 2, 3],
+                timestamp: ts,
+                ..Default::default()
+            };
+            assert!(!segment.is_maxed());
+            assert!(segment
+                .try_append_batch(slice::from_mut(&mut r), None)
+                .unwrap()
+                .is_some());
+        }
+        // the sparse index has room left, the time index does not
+        assert_eq!(segment.index.len(), 1);
+        assert!(segment.is_maxed());
+
+        // a failed append leaves nothing behind in the store
+        let size = segment.store.size();
+        let mut r = Record {
+            value: vec![1, 2, 3],
+            timestamp: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            segment
+                .try_append_batch(slice::from_mut(&mut r), None)
+                .unwrap(),
+            None
+        );
+        assert!(segment.append(&mut r).is_err());
+        assert_eq!(segment.store.size(), size);
+        assert_eq!(segment.next_offset, 4);
+        assert_eq!(segment.records().count(), 4);
+    }
 }
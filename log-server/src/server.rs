@@ -0,0 +1,1237 @@
+// tonic::Status is large, and every handler has to return it.
+#![allow(clippy::result_large_err)]
+
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use protos::log::v1::{log_server, raft_server, AssignmentStrategy};
+use protos::log::v1::{
+    AppendEntriesRequest, AppendEntriesResponse, CommitOffsetRequest, CommitOffsetResponse,
+    ConsumeRequest, ConsumeResponse, CreateTopicRequest, CreateTopicResponse, DeleteTopicRequest,
+    DeleteTopicResponse, FetchCommittedOffsetRequest, FetchCommittedOffsetResponse,
+    GetServersRequest, GetServersResponse, HeartbeatRequest, HeartbeatResponse,
+    InitProducerRequest, InitProducerResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    JoinGroupRequest, JoinGroupResponse, LeaveGroupRequest, LeaveGroupResponse, ListGroupsRequest,
+    ListGroupsResponse, ListTopicsRequest, ListTopicsResponse, NotLeader, OffsetOutOfRange,
+    ProduceBatchRequest, ProduceBatchResponse, ProduceRequest, ProduceResponse, Record,
+    RequestVoteRequest, RequestVoteResponse, Server, Topic, TopicPartition,
+};
+
+use crate::error::Error;
+use crate::group::GroupCoordinator;
+use crate::log::Log;
+use crate::manager::{metadata, topic_config, LogManager};
+use crate::offsets::OffsetStore;
+use crate::producer::new_producer_id;
+use crate::replication::{Node, ReplicatedLog};
+
+/// Responses buffered per stream before the producing task waits for the client.
+const STREAM_BUFFER: usize = 64;
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// gRPC front end for a [`Log`], to be served through
+/// [`LogServer`](protos::log::v1::log_server::LogServer). Requests without a
+/// topic go to that log; the others to the topics of a [`LogManager`], if one
+/// was added. Consumer group offsets need an [`OffsetStore`], and group
+/// membership a [`GroupCoordinator`].
+#[derive(Clone)]
+pub struct LogService {
+    log: Arc<Log>,
+    replicated: Option<Arc<ReplicatedLog>>,
+    topics: Option<Arc<LogManager>>,
+    offsets: Option<Arc<OffsetStore>>,
+    groups: Option<Arc<GroupCoordinator>>,
+}
+
+impl LogService {
+    pub fn new(log: Arc<Log>) -> Self {
+        LogService {
+            log,
+            replicated: None,
+            topics: None,
+            offsets: None,
+            groups: None,
+        }
+    }
+
+    /// Serves a node of a replicated log: produce requests go through Raft
+    /// and fail with `UNAVAILABLE` on followers, reads are local.
+    pub fn replicated(log: Arc<ReplicatedLog>) -> Self {
+        LogService {
+            log: log.log().clone(),
+            replicated: Some(log),
+            topics: None,
+            offsets: None,
+            groups: None,
+        }
+    }
+
+    /// Also serves the topics of `topics`. They are local to this server,
+    /// even when its own log is replicated.
+    pub fn with_topics(mut self, topics: Arc<LogManager>) -> Self {
+        self.topics = Some(topics);
+        self
+    }
+
+    /// Keeps the offsets consumer groups commit in `offsets`, which are local
+    /// to this server like topics.
+    pub fn with_offsets(mut self, offsets: Arc<OffsetStore>) -> Self {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Lets consumers join groups through `groups`, which then also checks
+    /// every offset commit against the group's generation.
+    pub fn with_groups(mut self, groups: Arc<GroupCoordinator>) -> Self {
+        self.groups = Some(groups);
+        self
+    }
+
+    fn groups(&self) -> Result<&GroupCoordinator, Status> {
+        self.groups
+            .as_deref()
+            .ok_or_else(|| Status::unimplemented("consumer groups are not enabled"))
+    }
+
+    fn offsets(&self) -> Result<&OffsetStore, Status> {
+        self.offsets
+            .as_deref()
+            .ok_or_else(|| Status::unimplemented("consumer group offsets are not enabled"))
+    }
+
+    fn manager(&self) -> Result<Arc<LogManager>, Status> {
+        self.topics
+            .clone()
+            .ok_or_else(|| Status::unimplemented("topics are not enabled"))
+    }
+
+    fn topics(&self, topic: &str) -> anyhow::Result<&LogManager> {
+        self.topics.as_deref().ok_or_else(|| {
+            Error::UnknownTopic {
+                topic: topic.to_owned(),
+            }
+            .into()
+        })
+    }
+
+    /// Fails unless the partition exists, from the topic's metadata alone, so
+    /// no partition is opened or created.
+    fn check_partition(&self, topic: &str, partition: u32) -> anyhow::Result<()> {
+        let partitions = match topic {
+            "" => 1,
+            _ => self.topics(topic)?.topic_config(topic)?.partitions,
+        };
+        if partition < partitions {
+            Ok(())
+        } else {
+            Err(Error::UnknownPartition {
+                topic: topic.to_owned(),
+                partition,
+            }
+            .into())
+        }
+    }
+
+    /// The log of a partition; the server's own log is partition 0 of the
+    /// empty topic.
+    fn log(&self, topic: &str, partition: u32) -> anyhow::Result<Arc<Log>> {
+        match topic {
+            "" if partition == 0 => Ok(self.log.clone()),
+            "" => Err(Error::UnknownPartition {
+                topic: String::new(),
+                partition,
+            }
+            .into()),
+            _ => self.topics(topic)?.partition(topic, partition),
+        }
+    }
+
+    /// Appends `records` to a partition and returns it with their offsets.
+    /// A non-zero `producer_id` makes the append idempotent.
+    async fn append_batch(
+        &self,
+        topic: &str,
+        partition: Option<u32>,
+        producer_id: u64,
+        sequence: u64,
+        mut records: Vec<Record>,
+    ) -> Result<(u32, Range<u64>), Status> {
+        if producer_id != 0 {
+            // a retry has to reach the partition its first attempt did
+            let partition = match (topic, partition) {
+                ("", p) => p.unwrap_or(0),
+                (_, Some(p)) => p,
+                (_, None) => {
+                    return Err(Status::invalid_argument(
+                        "idempotent appends to a topic need a partition",
+                    ))
+                }
+            };
+            if let (Some(r), "") = (&self.replicated, topic) {
+                self.log(topic, partition).map_err(to_status)?;
+                let range = r
+                    .append_idempotent(producer_id, sequence, records)
+                    .await
+                    .map_err(to_status)?;
+                return Ok((0, range));
+            }
+            let (service, topic) = (self.clone(), topic.to_owned());
+            let range = blocking(move || {
+                service.log(&topic, partition)?.append_idempotent(
+                    producer_id,
+                    sequence,
+                    &mut records,
+                )
+            })
+            .await?;
+            return Ok((partition, range));
+        }
+        if !topic.is_empty() {
+            let (service, topic) = (self.clone(), topic.to_owned());
+            return blocking(move || {
+                service
+                    .topics(&topic)?
+                    .append_batch(&topic, partition, &mut records)
+            })
+            .await;
+        }
+        // the server's own log only has partition 0
+        self.log(topic, partition.unwrap_or(0)).map_err(to_status)?;
+        let range = match &self.replicated {
+            Some(r) => r.append_batch(records).await.map_err(to_status)?,
+            None => {
+                let log = self.log.clone();
+                blocking(move || log.append_batch(&mut records)).await?
+            }
+        };
+        Ok((0, range))
+    }
+}
+
+/// Runs `f` on the blocking pool, since log appends and reads do disk I/O and
+/// may fsync, which would stall the runtime thread of an async handler.
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(to_status)
+}
+
+/// Maps log errors to gRPC statuses. Out-of-range reads carry an
+/// [`OffsetOutOfRange`] detail with the requested offset, and appends to a
+/// follower a [`NotLeader`] detail with the leader's address.
+pub(crate) fn to_status(err: anyhow::Error) -> Status {
+    match err.downcast_ref::<Error>() {
+        Some(Error::OffsetOutOfRange { offset }) => Status::with_details(
+            Code::OutOfRange,
+            err.to_string(),
+            OffsetOutOfRange { offset: *offset }.encode_to_vec().into(),
+        ),
+        Some(Error::Compacted { .. }) => Status::not_found(err.to_string()),
+        Some(Error::Corrupt { .. }) => Status::data_loss(err.to_string()),
+        Some(Error::Unauthenticated { .. }) => Status::failed_precondition(err.to_string()),
+        Some(Error::Truncated { .. }) => Status::aborted(err.to_string()),
+        Some(Error::UnknownTopic { .. } | Error::UnknownPartition { .. }) => {
+            Status::not_found(err.to_string())
+        }
+        Some(Error::TopicExists { .. }) => Status::already_exists(err.to_string()),
+        Some(Error::InvalidTopic { .. }) => Status::invalid_argument(err.to_string()),
+        Some(Error::UnknownMember { .. }) => Status::not_found(err.to_string()),
+        Some(Error::StaleGeneration { .. }) => Status::aborted(err.to_string()),
+        Some(Error::NotAssigned { .. }) => Status::failed_precondition(err.to_string()),
+        Some(Error::OutOfOrderSequence { .. }) => Status::failed_precondition(err.to_string()),
+        Some(Error::NotLeader { leader }) => Status::with_details(
+            Code::Unavailable,
+            err.to_string(),
+            NotLeader {
+                leader_addr: leader.clone().unwrap_or_default(),
+            }
+            .encode_to_vec()
+            .into(),
+        ),
+        None => Status::internal(err.to_string()),
+    }
+}
+
+#[tonic::async_trait]
+impl log_server::Log for LogService {
+    async fn produce(
+        &self,
+        request: Request<ProduceRequest>,
+    ) -> Result<Response<ProduceResponse>, Status> {
+        let req = request.into_inner();
+        let record = req
+            .record
+            .ok_or_else(|| Status::invalid_argument("missing record"))?;
+        let (partition, range) = self
+            .append_batch(
+                &req.topic,
+                req.partition,
+                req.producer_id,
+                req.sequence,
+                vec![record],
+            )
+            .await?;
+        Ok(Response::new(ProduceResponse {
+            offset: range.start,
+            partition,
+        }))
+    }
+
+    async fn produce_batch(
+        &self,
+        request: Request<ProduceBatchRequest>,
+    ) -> Result<Response<ProduceBatchResponse>, Status> {
+        let req = request.into_inner();
+        if req.records.is_empty() {
+            return Err(Status::invalid_argument("empty batch"));
+        }
+        let (partition, range) = self
+            .append_batch(
+                &req.topic,
+                req.partition,
+                req.producer_id,
+                req.sequence,
+                req.records,
+            )
+            .await?;
+        Ok(Response::new(ProduceBatchResponse {
+            first_offset: range.start,
+            last_offset: range.end - 1,
+            partition,
+        }))
+    }
+
+    async fn consume(
+        &self,
+        request: Request<ConsumeRequest>,
+    ) -> Result<Response<ConsumeResponse>, Status> {
+        let req = request.into_inner();
+        let service = self.clone();
+        let record =
+            blocking(move || service.log(&req.topic, req.partition)?.read(req.offset)).await?;
+        Ok(Response::new(ConsumeResponse {
+            record: Some(record),
+        }))
+    }
+
+    type ConsumeStreamStream = ResponseStream<ConsumeResponse>;
+
+    /// Streams every record from the requested offset on, then follows the head
+    /// of the log until the client goes away.
+    async fn consume_stream(
+        &self,
+        request: Request<ConsumeRequest>,
+    ) -> Result<Response<Self::ConsumeStreamStream>, Status> {
+        let req = request.into_inner();
+        let service = self.clone();
+        let (topic, partition) = (req.topic, req.partition);
+        let log = blocking(move || service.log(&topic, partition)).await?;
+        let stream = log.subscribe(req.offset).map(|r| {
+            r.map(|record| ConsumeResponse {
+                record: Some(record),
+            })
+            .map_err(to_status)
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type ProduceStreamStream = ResponseStream<ProduceResponse>;
+
+    /// Appends the records of a stream of produce requests in order. Requests
+    /// that arrived while an append was in flight and go to the same
+    /// partition are appended together as one batch.
+    async fn produce_stream(
+        &self,
+        request: Request<Streaming<ProduceRequest>>,
+    ) -> Result<Response<Self::ProduceStreamStream>, Status> {
+        let mut requests = request.into_inner();
+        let service = self.clone();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let (queue_tx, mut queue) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            while let Some(req) = requests.next().await {
+                if queue_tx.send(req).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            let mut pending = None;
+            loop {
+                let req = match pending.take() {
+                    Some(req) => req,
+                    None => match queue.recv().await {
+                        Some(req) => req,
+                        None => break,
+                    },
+                };
+                let first = match req {
+                    Ok(req) if req.record.is_some() => req,
+                    Ok(_) => {
+                        let _ = tx
+                            .send(Err(Status::invalid_argument("missing record")))
+                            .await;
+                        break;
+                    }
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                };
+                let mut records = vec![first.record.clone().expect("checked above")];
+                // idempotent batches keep their own sequence numbers, and
+                // records without a partition may each go elsewhere
+                let batchable =
+                    first.producer_id == 0 && (first.topic.is_empty() || first.partition.is_some());
+                while batchable && records.len() < STREAM_BUFFER {
+                    match queue.try_recv() {
+                        Ok(Ok(ProduceRequest {
+                            record: Some(record),
+                            topic,
+                            partition,
+                            producer_id: 0,
+                            ..
+                        })) if topic == first.topic && partition == first.partition => {
+                            records.push(record)
+                        }
+                        Ok(req) => {
+                            pending = Some(req);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let n = records.len() as u64;
+                let res = service
+                    .append_batch(
+                        &first.topic,
+                        first.partition,
+                        first.producer_id,
+                        first.sequence,
+                        records,
+                    )
+                    .await;
+                let (partition, range) = match res {
+                    Ok(r) => r,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                };
+                for offset in range.start..range.start + n {
+                    if tx
+                        .send(Ok(ProduceResponse { offset, partition }))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn get_servers(
+        &self,
+        _request: Request<GetServersRequest>,
+    ) -> Result<Response<GetServersResponse>, Status> {
+        let servers = match &self.replicated {
+            Some(r) => {
+                let leader = r.leader();
+                r.voters()
+                    .into_iter()
+                    .map(|(id, rpc_addr)| Server {
+                        is_leader: leader.as_ref() == Some(&rpc_addr),
+                        id,
+                        rpc_addr,
+                    })
+                    .collect()
+            }
+            None => vec![],
+        };
+        Ok(Response::new(GetServersResponse { servers }))
+    }
+
+    async fn commit_offset(
+        &self,
+        request: Request<CommitOffsetRequest>,
+    ) -> Result<Response<CommitOffsetResponse>, Status> {
+        let req = request.into_inner();
+        self.offsets()?;
+        if req.group.is_empty() {
+            return Err(Status::invalid_argument("missing group"));
+        }
+        // committing fsyncs the offsets log
+        let service = self.clone();
+        blocking(move || {
+            service.check_partition(&req.topic, req.partition)?;
+            match &service.groups {
+                Some(groups) => groups.commit(
+                    &req.group,
+                    &req.member_id,
+                    req.generation,
+                    &req.topic,
+                    req.partition,
+                    req.offset,
+                ),
+                None => service.offsets.as_ref().expect("checked above").commit(
+                    &req.group,
+                    &req.topic,
+                    req.partition,
+                    req.offset,
+                ),
+            }
+        })
+        .await?;
+        Ok(Response::new(CommitOffsetResponse {}))
+    }
+
+    async fn fetch_committed_offset(
+        &self,
+        request: Request<FetchCommittedOffsetRequest>,
+    ) -> Result<Response<FetchCommittedOffsetResponse>, Status> {
+        let req = request.into_inner();
+        let offset = self.offsets()?.fetch(&req.group, &req.topic, req.partition);
+        Ok(Response::new(FetchCommittedOffsetResponse { offset }))
+    }
+
+    async fn list_groups(
+        &self,
+        _request: Request<ListGroupsRequest>,
+    ) -> Result<Response<ListGroupsResponse>, Status> {
+        let groups = self.offsets()?.groups();
+        Ok(Response::new(ListGroupsResponse { groups }))
+    }
+
+    async fn join_group(
+        &self,
+        request: Request<JoinGroupRequest>,
+    ) -> Result<Response<JoinGroupResponse>, Status> {
+        let req = request.into_inner();
+        let strategy = AssignmentStrategy::from_i32(req.strategy)
+            .ok_or_else(|| Status::invalid_argument("unknown assignment strategy"))?;
+        let session_timeout = Some(req.session_timeout_ms)
+            .filter(|&ms| ms > 0)
+            .map(|ms| Duration::from_millis(ms.into()));
+        let assignment = self
+            .groups()?
+            .join(
+                &req.group,
+                &req.member_id,
+                &req.topics,
+                strategy,
+                session_timeout,
+            )
+            .map_err(to_status)?;
+        Ok(Response::new(JoinGroupResponse {
+            member_id: assignment.member_id,
+            generation: assignment.generation,
+            pending: assignment.pending,
+            assignment: assignment
+                .partitions
+                .into_iter()
+                .map(|(topic, partition)| TopicPartition { topic, partition })
+                .collect(),
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let req = request.into_inner();
+        self.groups()?
+            .heartbeat(&req.group, &req.member_id, req.generation)
+            .map_err(to_status)?;
+        Ok(Response::new(HeartbeatResponse {}))
+    }
+
+    async fn init_producer(
+        &self,
+        _request: Request<InitProducerRequest>,
+    ) -> Result<Response<InitProducerResponse>, Status> {
+        Ok(Response::new(InitProducerResponse {
+            producer_id: new_producer_id(),
+        }))
+    }
+
+    async fn leave_group(
+        &self,
+        request: Request<LeaveGroupRequest>,
+    ) -> Result<Response<LeaveGroupResponse>, Status> {
+        let req = request.into_inner();
+        self.groups()?
+            .leave(&req.group, &req.member_id)
+            .map_err(to_status)?;
+        Ok(Response::new(LeaveGroupResponse {}))
+    }
+
+    async fn create_topic(
+        &self,
+        request: Request<CreateTopicRequest>,
+    ) -> Result<Response<CreateTopicResponse>, Status> {
+        let req = request.into_inner();
+        let config = topic_config(&req.config.unwrap_or_default())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let manager = self.manager()?;
+        blocking(move || manager.create_topic(&req.name, config)).await?;
+        Ok(Response::new(CreateTopicResponse {}))
+    }
+
+    async fn list_topics(
+        &self,
+        _request: Request<ListTopicsRequest>,
+    ) -> Result<Response<ListTopicsResponse>, Status> {
+        let manager = self.manager()?;
+        let topics = manager
+            .topics()
+            .into_iter()
+            // skip topics deleted since they were listed
+            .filter_map(|name| {
+                let config = manager.topic_config(&name).ok()?;
+                Some(Topic {
+                    name,
+                    config: Some(metadata(&config)),
+                })
+            })
+            .collect();
+        Ok(Response::new(ListTopicsResponse { topics }))
+    }
+
+    async fn delete_topic(
+        &self,
+        request: Request<DeleteTopicRequest>,
+    ) -> Result<Response<DeleteTopicResponse>, Status> {
+        let req = request.into_inner();
+        let manager = self.manager()?;
+        blocking(move || manager.delete_topic(&req.name)).await?;
+        Ok(Response::new(DeleteTopicResponse {}))
+    }
+}
+
+/// gRPC endpoint of a [`ReplicatedLog`] for its peers, to be served through
+/// [`RaftServer`](protos::log::v1::raft_server::RaftServer) next to the
+/// [`LogService`].
+pub struct RaftService {
+    node: Weak<Node>,
+}
+
+impl RaftService {
+    pub fn new(log: &ReplicatedLog) -> Self {
+        RaftService {
+            node: Arc::downgrade(log.node()),
+        }
+    }
+
+    fn node(&self) -> Result<Arc<Node>, Status> {
+        self.node
+            .upgrade()
+            .ok_or_else(|| Status::unavailable("node is closed"))
+    }
+}
+
+#[tonic::async_trait]
+impl raft_server::Raft for RaftService {
+    async fn request_vote(
+        &self,
+        request: Request<RequestVoteRequest>,
+    ) -> Result<Response<RequestVoteResponse>, Status> {
+        let node = self.node()?;
+        let req = request.into_inner();
+        let resp = blocking(move || node.handle_request_vote(req)).await?;
+        Ok(Response::new(resp))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesRequest>,
+    ) -> Result<Response<AppendEntriesResponse>, Status> {
+        let node = self.node()?;
+        let req = request.into_inner();
+        let resp = blocking(move || node.handle_append_entries(req)).await?;
+        Ok(Response::new(resp))
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotRequest>,
+    ) -> Result<Response<InstallSnapshotResponse>, Status> {
+        let node = self.node()?;
+        let req = request.into_inner();
+        let resp = blocking(move || node.handle_install_snapshot(req)).await?;
+        Ok(Response::new(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::{Channel, Server};
+
+    use protos::log::v1::log_client::LogClient;
+    use protos::log::v1::log_server::LogServer;
+    use protos::log::v1::raft_server::RaftServer;
+    use protos::log::v1::{Record, TopicMetadata};
+
+    use crate::config::{Config, GroupConfig, RaftConfig, TopicConfig};
+    use crate::group::SystemClock;
+    use crate::offsets::OFFSETS_LOG;
+    use crate::transport::GrpcTransport;
+
+    use super::*;
+
+    async fn setup(log: Arc<Log>) -> LogClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LogServer::new(LogService::new(log)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        LogClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap()
+    }
+
+    fn record(value: &str) -> Record {
+        Record {
+            value: value.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn produce_consume() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let mut client = setup(log).await;
+
+        let res = client
+            .produce(ProduceRequest {
+                record: Some(record("hello world")),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let offset = res.into_inner().offset;
+        let res = client
+            .consume(ConsumeRequest {
+                offset,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().record.unwrap().value, b"hello world");
+
+        let status = client
+            .consume(ConsumeRequest {
+                offset: offset + 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::OutOfRange);
+        let detail = OffsetOutOfRange::decode(status.details()).unwrap();
+        assert_eq!(detail.offset, offset + 1);
+    }
+
+    #[tokio::test]
+    async fn produce_batch() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let mut client = setup(log).await;
+
+        let values = ["first", "second", "third"];
+        let res = client
+            .produce_batch(ProduceBatchRequest {
+                records: values.iter().map(|v| record(v)).collect(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((res.first_offset, res.last_offset), (0, 2));
+        for (offset, v) in values.iter().enumerate() {
+            let res = client
+                .consume(ConsumeRequest {
+                    offset: offset as u64,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(res.into_inner().record.unwrap().value, v.as_bytes());
+        }
+
+        let status = client
+            .produce_batch(ProduceBatchRequest {
+                records: vec![],
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn idempotent_produce() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let mut client = setup(log).await;
+
+        let producer_id = client
+            .init_producer(InitProducerRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .producer_id;
+        assert_ne!(producer_id, 0);
+        let batch = |sequence, values: &[&str]| ProduceBatchRequest {
+            records: values.iter().map(|v| record(v)).collect(),
+            producer_id,
+            sequence,
+            ..Default::default()
+        };
+        let res = client.produce_batch(batch(0, &["a", "b"])).await.unwrap();
+        assert_eq!(res.into_inner().last_offset, 1);
+        // the retry of a batch that timed out is not appended twice
+        let res = client.produce_batch(batch(0, &["a", "b"])).await.unwrap();
+        assert_eq!(res.into_inner().first_offset, 0);
+        let res = client.produce_batch(batch(1, &["c"])).await.unwrap();
+        assert_eq!(res.into_inner().first_offset, 2);
+        let status = client.produce_batch(batch(3, &["e"])).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = client
+            .consume(ConsumeRequest {
+                offset: 3,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::OutOfRange);
+    }
+
+    #[tokio::test]
+    async fn produce_consume_stream() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let mut client = setup(log).await;
+
+        let values: Vec<_> = (0..100).map(|i| format!("message {}", i)).collect();
+        let requests = tokio_stream::iter(values.clone().into_iter().map(|v| ProduceRequest {
+            record: Some(record(&v)),
+            ..Default::default()
+        }));
+        let mut produced = client.produce_stream(requests).await.unwrap().into_inner();
+        for (i, _) in values.iter().enumerate() {
+            let res = produced.next().await.unwrap().unwrap();
+            assert_eq!(res.offset, i as u64);
+        }
+        assert!(produced.next().await.is_none());
+
+        let mut consumed = client
+            .consume_stream(ConsumeRequest {
+                offset: 0,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        for v in values {
+            let res = consumed.next().await.unwrap().unwrap();
+            assert_eq!(res.record.unwrap().value, v.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn topics() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let topics_dir = tempdir().unwrap();
+        let topics = Arc::new(LogManager::open(topics_dir.path(), Config::default()).unwrap());
+        topics
+            .create_topic(
+                "events",
+                TopicConfig {
+                    partitions: 2,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LogServer::new(LogService::new(log).with_topics(topics)))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = LogClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let res = client
+            .produce(ProduceRequest {
+                record: Some(record("to partition 1")),
+                topic: "events".into(),
+                partition: Some(1),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((res.partition, res.offset), (1, 0));
+        let res = client
+            .produce_batch(ProduceBatchRequest {
+                records: vec![record("a"), record("b")],
+                topic: "events".into(),
+                partition: None,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(res.partition, 0);
+        let res = client
+            .consume(ConsumeRequest {
+                offset: 0,
+                topic: "events".into(),
+                partition: 1,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().record.unwrap().value, b"to partition 1");
+
+        // the server's own log is untouched
+        let status = client
+            .consume(ConsumeRequest {
+                offset: 0,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::OutOfRange);
+        for (topic, partition) in [("missing", 0), ("events", 2), ("", 1)] {
+            let status = client
+                .consume(ConsumeRequest {
+                    offset: 0,
+                    topic: topic.into(),
+                    partition,
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::NotFound);
+        }
+
+        client
+            .create_topic(CreateTopicRequest {
+                name: "audit".into(),
+                config: Some(TopicMetadata {
+                    partitions: 1,
+                    compact: Some(true),
+                    ..Default::default()
+                }),
+            })
+            .await
+            .unwrap();
+        for (name, partitions) in [("audit", 1), ("../escape", 1), ("empty", 0)] {
+            let status = client
+                .create_topic(CreateTopicRequest {
+                    name: name.into(),
+                    config: Some(TopicMetadata {
+                        partitions,
+                        ..Default::default()
+                    }),
+                })
+                .await
+                .unwrap_err();
+            let code = match name {
+                "audit" => Code::AlreadyExists,
+                _ => Code::InvalidArgument,
+            };
+            assert_eq!(status.code(), code);
+        }
+        let topics = client
+            .list_topics(ListTopicsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .topics;
+        let names: Vec<_> = topics.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["audit", "events"]);
+        assert_eq!(topics[0].config.as_ref().unwrap().compact, Some(true));
+        assert_eq!(topics[1].config.as_ref().unwrap().partitions, 2);
+
+        client
+            .delete_topic(DeleteTopicRequest {
+                name: "events".into(),
+            })
+            .await
+            .unwrap();
+        let status = client
+            .consume(ConsumeRequest {
+                offset: 0,
+                topic: "events".into(),
+                partition: 1,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert!(!topics_dir.path().join("events").exists());
+    }
+
+    #[tokio::test]
+    async fn consumer_group_offsets() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let offsets_dir = dir.path().join(OFFSETS_LOG);
+        let offsets = OffsetStore::open(&offsets_dir, &Config::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = LogService::new(log).with_offsets(Arc::new(offsets));
+        tokio::spawn(
+            Server::builder()
+                .add_service(LogServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = LogClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let fetch = FetchCommittedOffsetRequest {
+            group: "billing".into(),
+            ..Default::default()
+        };
+        let res = client.fetch_committed_offset(fetch.clone()).await.unwrap();
+        assert_eq!(res.into_inner().offset, None);
+        client
+            .commit_offset(CommitOffsetRequest {
+                group: "billing".into(),
+                offset: 42,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let res = client.fetch_committed_offset(fetch.clone()).await.unwrap();
+        assert_eq!(res.into_inner().offset, Some(42));
+        let status = client
+            .commit_offset(CommitOffsetRequest {
+                group: "billing".into(),
+                topic: "missing".into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        let groups = client
+            .list_groups(ListGroupsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .groups;
+        assert_eq!(groups, ["billing"]);
+    }
+
+    #[tokio::test]
+    async fn consumer_groups() {
+        let dir = tempdir().unwrap();
+        let log = Arc::new(Log::new(dir.path(), Config::default()).unwrap());
+        let offsets = OffsetStore::open(&dir.path().join(OFFSETS_LOG), &Config::default());
+        let offsets = Arc::new(offsets.unwrap());
+        let groups = GroupCoordinator::new(
+            offsets.clone(),
+            GroupConfig::default(),
+            Arc::new(SystemClock),
+        );
+        let service = LogService::new(log)
+            .with_offsets(offsets)
+            .with_groups(Arc::new(groups));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LogServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let mut client = LogClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        let join = JoinGroupRequest {
+            group: "billing".into(),
+            topics: vec![String::new()],
+            ..Default::default()
+        };
+        let first = client.join_group(join.clone()).await.unwrap().into_inner();
+        assert_eq!(first.generation, 1);
+        assert_eq!(first.assignment.len(), 1);
+        let second = client.join_group(join.clone()).await.unwrap().into_inner();
+        assert_eq!(second.generation, 2);
+
+        let status = client
+            .heartbeat(HeartbeatRequest {
+                group: "billing".into(),
+                member_id: first.member_id.clone(),
+                generation: first.generation,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Aborted);
+        let status = client
+            .commit_offset(CommitOffsetRequest {
+                group: "billing".into(),
+                offset: 1,
+                member_id: first.member_id.clone(),
+                generation: first.generation,
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Aborted);
+        // the second member gets its partitions once the first rejoined
+        assert!(second.pending);
+        let rejoin = |member: &JoinGroupResponse| JoinGroupRequest {
+            member_id: member.member_id.clone(),
+            ..join.clone()
+        };
+        let first = client.join_group(rejoin(&first)).await.unwrap();
+        let first = first.into_inner();
+        let second = client.join_group(rejoin(&second)).await.unwrap();
+        let second = second.into_inner();
+        assert!(!first.pending && !second.pending);
+        let (owner, other) = match first.assignment.is_empty() {
+            true => (&second, &first),
+            false => (&first, &second),
+        };
+        let commit = |member: &JoinGroupResponse| CommitOffsetRequest {
+            group: "billing".into(),
+            offset: 1,
+            member_id: member.member_id.clone(),
+            generation: member.generation,
+            ..Default::default()
+        };
+        let status = client.commit_offset(commit(other)).await.unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        client.commit_offset(commit(owner)).await.unwrap();
+
+        client
+            .leave_group(LeaveGroupRequest {
+                group: "billing".into(),
+                member_id: second.member_id.clone(),
+            })
+            .await
+            .unwrap();
+        let status = client
+            .leave_group(LeaveGroupRequest {
+                group: "billing".into(),
+                member_id: second.member_id,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn replicated() {
+        let dirs: Vec<_> = (0..3).map(|_| tempdir().unwrap()).collect();
+        let mut listeners = vec![];
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let mut nodes = vec![];
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let raft = RaftConfig {
+                id: i.to_string(),
+                peers: (0..3)
+                    .map(|j| (j.to_string(), addrs[j].to_string()))
+                    .collect(),
+                heartbeat_interval: Duration::from_millis(20),
+                election_timeout: Duration::from_millis(100),
+                ..Default::default()
+            };
+            let transport = Arc::new(GrpcTransport::default());
+            let node = Arc::new(
+                ReplicatedLog::start(dirs[i].path(), Config::default(), raft, transport).unwrap(),
+            );
+            tokio::spawn(
+                Server::builder()
+                    .add_service(LogServer::new(LogService::replicated(node.clone())))
+                    .add_service(RaftServer::new(RaftService::new(&node)))
+                    .serve_with_incoming(TcpListenerStream::new(listener)),
+            );
+            nodes.push(node);
+        }
+
+        let mut leader = None;
+        for _ in 0..500 {
+            leader = nodes.iter().position(|n| n.is_leader());
+            if leader.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let leader = leader.expect("a leader is elected");
+        let follower = (leader + 1) % 3;
+        let mut client = LogClient::connect(format!("http://{}", addrs[leader]))
+            .await
+            .unwrap();
+        let res = client
+            .produce(ProduceRequest {
+                record: Some(record("replicated")),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().offset, 0);
+
+        let mut client = LogClient::connect(format!("http://{}", addrs[follower]))
+            .await
+            .unwrap();
+        let status = client
+            .produce(ProduceRequest {
+                record: Some(record("rejected")),
+                ..Default::default()
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        let detail = NotLeader::decode(status.details()).unwrap();
+        assert_eq!(detail.leader_addr, addrs[leader].to_string());
+        let servers = client
+            .get_servers(GetServersRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .servers;
+        assert_eq!(servers.len(), 3);
+        let leaders: Vec<_> = servers.iter().filter(|s| s.is_leader).collect();
+        assert_eq!(leaders.len(), 1);
+        assert_eq!(leaders[0].id, leader.to_string());
+        let mut consumed = client
+            .consume_stream(ConsumeRequest {
+                offset: 0,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let res = consumed.next().await.unwrap().unwrap();
+        assert_eq!(res.record.unwrap().value, b"replicated");
+    }
+}
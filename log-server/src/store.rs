@@ -1,33 +1,144 @@
-use std::cell::RefCell;
 use std::fs::File;
 use std::io;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context};
+use log::warn;
+
+use crate::config::{Cipher, Config};
+use crate::encryption::{self, FrameCipher, Sealed};
+use crate::error::Error;
 
 pub(crate) const LEN_WIDTH: u64 = 8;
+pub(crate) const CRC_WIDTH: u64 = 4;
+
+const MAGIC: [u8; 4] = *b"DLOG";
+const VERSION_WIDTH: usize = 4;
+pub(crate) const HEADER_WIDTH: u64 = (MAGIC.len() + VERSION_WIDTH) as u64;
+
+/// Store files written before the header existed: frames are `len | payload`.
+pub(crate) const VERSION_LEGACY: u32 = 0;
+/// Frames are `len | crc32c(len, payload) | payload`.
+pub(crate) const VERSION_CRC: u32 = 1;
+/// Frames as in [`VERSION_CRC`]; each payload is a record batch, see
+/// [`batch`](crate::batch).
+pub(crate) const VERSION_BATCH: u32 = 2;
+pub(crate) const CURRENT_VERSION: u32 = VERSION_BATCH;
+/// As [`VERSION_BATCH`], with payloads sealed by a [`FrameCipher`]. The header
+/// goes on with `cipher u8 | key_id_len u8 | key_id`, then nothing sealed
+/// with the header as context, so a header that was tampered with fails to
+/// open.
+pub(crate) const VERSION_ENCRYPTED: u32 = 3;
+
+/// Buffered writer and the logical file size, which only change together.
+struct Writer {
+    buf: BufWriter<File>,
+    size: u64,
+}
 
 pub(crate) struct Store {
-    mu: Mutex<()>,
     file: File,
     /// [`PathBuf`] of the file
     pub(crate) file_path: Option<PathBuf>,
-    buf: RefCell<BufWriter<File>>,
-    // write
-    size: u64,
+    mu: Mutex<Writer>,
+    version: u32,
+    data_start: u64,
+    cipher: Option<Arc<FrameCipher>>,
+}
+
+/// What the file header says about the frames after it.
+struct Header {
+    version: u32,
+    /// Cipher and key id of encrypted stores.
+    key: Option<(Cipher, String)>,
+    width: u64,
 }
 
 impl Store {
-    pub fn new(file: File) -> io::Result<Store> {
-        let m = file.metadata()?;
+    pub fn new(file: File) -> anyhow::Result<Store> {
+        Self::open(file, 0, &Config::default())
+    }
+
+    /// Opens the store of the segment at `base_offset`, encrypting it if it is
+    /// new and `config.encryption` is set. Existing stores keep the format
+    /// they were created with; encrypted ones look their key up by the id in
+    /// their header and fail with [`Error::Unauthenticated`] if the header
+    /// does not authenticate.
+    pub fn open(file: File, base_offset: u64, config: &Config) -> anyhow::Result<Store> {
+        let mut size = file.metadata()?.len();
+        let mut header = None;
+        if size > 0 {
+            header = read_header(&file, size)?;
+            if header.is_none() {
+                warn!("discarding torn store header of {} bytes", size);
+                file.set_len(0)?;
+                size = 0;
+            }
+        }
+        let is_new = header.is_none();
+        let header = header.unwrap_or_else(|| match &config.encryption {
+            Some(e) => Header {
+                version: VERSION_ENCRYPTED,
+                key: Some((e.cipher, e.key_id.clone())),
+                width: HEADER_WIDTH + 2 + e.key_id.len() as u64 + encryption::OVERHEAD,
+            },
+            None => Header {
+                version: CURRENT_VERSION,
+                key: None,
+                width: HEADER_WIDTH,
+            },
+        });
+        let fields = header.encode()?;
+        let cipher = match &header.key {
+            Some((cipher, key_id)) => {
+                let keys = config.key_provider.as_ref().ok_or_else(|| {
+                    anyhow!(
+                        "store is encrypted with key id {:?} but no key provider is configured",
+                        key_id
+                    )
+                })?;
+                let key = keys
+                    .key(key_id)
+                    .with_context(|| format!("failed to look up key id {:?}", key_id))?;
+                let c = FrameCipher::new(*cipher, key_id, &key).with_context(base_offset, &fields);
+                Some(Arc::new(c))
+            }
+            None => None,
+        };
+        if is_new {
+            let mut b = fields;
+            if let Some(c) = &cipher {
+                b.extend(c.seal(Sealed::Header, 0, &[]));
+            }
+            (&file).write_all(&b)?;
+            size = header.width;
+        } else if let Some(c) = &cipher {
+            let mut sealed = vec![0; (header.width - fields.len() as u64) as usize];
+            file.read_exact_at(&mut sealed, fields.len() as u64)?;
+            if c.open(Sealed::Header, 0, &sealed).is_err() {
+                return Err(Error::Unauthenticated {
+                    base_offset,
+                    pos: 0,
+                    key_id: c.key_id.clone(),
+                }
+                .into());
+            }
+        }
         let write_fd = file.try_clone()?;
         Ok(Store {
-            mu: Mutex::new(()),
             file,
-            buf: RefCell::new(BufWriter::new(write_fd)),
-            size: m.len(),
             file_path: None,
+            mu: Mutex::new(Writer {
+                buf: BufWriter::new(write_fd),
+                size,
+            }),
+            version: header.version,
+            data_start: header.width,
+            cipher,
         })
     }
 
@@ -36,59 +147,289 @@ impl Store {
         self
     }
 
-    pub fn close(&mut self) -> anyhow::Result<()> {
-        let _l = self.mu.lock().unwrap();
-        self.buf.borrow_mut().flush()?;
+    pub fn close(&self) -> anyhow::Result<()> {
+        self.sync()?;
         Ok(())
     }
 
-    pub fn append(&mut self, p: &[u8]) -> io::Result<(u64, u64)> {
-        let _l = self.mu.lock().unwrap();
-        let pos = self.size;
-        let b = (p.len() as u64).to_le_bytes() as [u8; LEN_WIDTH as usize];
-        let buf = &mut self.buf;
-        buf.borrow_mut().write_all(&b)?;
-        let mut w = buf.borrow_mut().write(p)? as u64;
-        w += LEN_WIDTH;
-        self.size += w;
-        Ok((w, pos))
+    pub fn append(&self, p: &[u8]) -> io::Result<(u64, u64)> {
+        let pos = self.append_batch(&[p])?[0];
+        Ok((self.frame_width(p.len() as u64), pos))
     }
 
+    /// Appends one frame per payload with a single write and returns the
+    /// position of each frame.
+    pub fn append_batch<P: AsRef<[u8]>>(&self, payloads: &[P]) -> io::Result<Vec<u64>> {
+        let mut frames = Vec::with_capacity(
+            payloads
+                .iter()
+                .map(|p| self.frame_width(p.as_ref().len() as u64) as usize)
+                .sum(),
+        );
+        let mut w = self.mu.lock().unwrap();
+        let mut positions = Vec::with_capacity(payloads.len());
+        for p in payloads {
+            let pos = w.size + frames.len() as u64;
+            positions.push(pos);
+            match &self.cipher {
+                Some(c) => encode_frame(
+                    &c.seal(Sealed::Frame, pos, p.as_ref()),
+                    self.version,
+                    &mut frames,
+                ),
+                None => encode_frame(p.as_ref(), self.version, &mut frames),
+            }
+        }
+        w.buf.write_all(&frames)?;
+        w.size += frames.len() as u64;
+        Ok(positions)
+    }
+
+    /// Reads the payload of the frame at `pos`, verifying its checksum when the
+    /// store format carries one and decrypting it in encrypted stores.
+    /// Verification failures are reported as [`ErrorKind::InvalidData`];
+    /// authentication failures carry
+    /// [`Unauthenticated`](crate::encryption::Unauthenticated).
     pub fn read(&self, pos: u64) -> io::Result<Vec<u8>> {
-        let _l = self.mu.lock().unwrap();
-        self.buf.borrow_mut().flush()?;
+        self.read_frame(pos).map(|(payload, _)| payload)
+    }
 
+    /// Like [`Store::read`], also returning where the next frame starts.
+    pub fn read_frame(&self, pos: u64) -> io::Result<(Vec<u8>, u64)> {
+        let (payload, next) = self.read_sealed(pos)?;
+        match &self.cipher {
+            Some(c) => Ok((c.open(Sealed::Frame, pos, &payload)?, next)),
+            None => Ok((payload, next)),
+        }
+    }
+
+    /// Verifies the frame at `pos` without decrypting it and returns where the
+    /// next frame starts. Recovery uses this so a wrong key cannot make it
+    /// discard intact frames.
+    pub fn check_frame(&self, pos: u64) -> io::Result<u64> {
+        self.read_sealed(pos).map(|(_, next)| next)
+    }
+
+    fn read_sealed(&self, pos: u64) -> io::Result<(Vec<u8>, u64)> {
+        let mut w = self.mu.lock().unwrap();
+        w.buf.flush()?;
+        let size = w.size;
+
+        let header_width = self.frame_header_width();
+        if pos < self.data_start() || pos + header_width > size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("frame header at pos={} is past size={}", pos, size),
+            ));
+        }
         let mut b = [0u8; LEN_WIDTH as usize];
         self.file.read_exact_at(&mut b, pos)?;
-        let sz = u64::from_le_bytes(b) as usize;
-        let mut b = vec![0; sz];
-        self.file.read_exact_at(&mut b, pos + LEN_WIDTH)?;
-        Ok(b)
+        let sz = u64::from_le_bytes(b);
+        if sz > size - pos - header_width {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("frame at pos={} claims len={} past size={}", pos, sz, size),
+            ));
+        }
+        let mut payload = vec![0; sz as usize];
+        self.file.read_exact_at(&mut payload, pos + header_width)?;
+        if self.version >= VERSION_CRC {
+            let mut c = [0u8; CRC_WIDTH as usize];
+            self.file.read_exact_at(&mut c, pos + LEN_WIDTH)?;
+            let expected = u32::from_le_bytes(c);
+            let actual = crc32c::crc32c_append(crc32c::crc32c(&b), &payload);
+            if expected != actual {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "checksum mismatch at pos={}: expected {:#010x}, got {:#010x}",
+                        pos, expected, actual
+                    ),
+                ));
+            }
+        }
+        Ok((payload, pos + header_width + sz))
+    }
+
+    /// Flushes buffered frames and fsyncs the file.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut w = self.mu.lock().unwrap();
+        w.buf.flush()?;
+        self.file.sync_data()
+    }
+
+    /// Drops every byte at or past `size`.
+    pub fn truncate(&self, size: u64) -> io::Result<()> {
+        let mut w = self.mu.lock().unwrap();
+        w.buf.flush()?;
+        self.file.set_len(size)?;
+        w.size = size;
+        Ok(())
     }
 
     pub fn read_at(&self, buf: &mut [u8], pos: u64) -> io::Result<usize> {
-        let _l = self.mu.lock().unwrap();
-        self.buf.borrow_mut().flush()?;
+        let mut w = self.mu.lock().unwrap();
+        w.buf.flush()?;
         self.file.read_at(buf, pos)
     }
 
     pub fn size(&self) -> u64 {
-        self.size
+        self.mu.lock().unwrap().size
+    }
+
+    /// When the file was last written.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.file.metadata()?.modified()
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Position of the first frame, i.e. the width of the file header.
+    pub fn data_start(&self) -> u64 {
+        self.data_start
+    }
+
+    /// The cipher frames are sealed with, if the store is encrypted; the
+    /// segment's indexes are sealed with it too.
+    pub fn cipher(&self) -> Option<Arc<FrameCipher>> {
+        self.cipher.clone()
+    }
+
+    /// Id of the key frames are encrypted with, if the store is encrypted.
+    pub fn key_id(&self) -> Option<&str> {
+        self.cipher.as_ref().map(|c| c.key_id.as_str())
+    }
+
+    /// Bytes a frame for a payload of `len` bytes takes in the file.
+    pub fn frame_width(&self, len: u64) -> u64 {
+        self.frame_header_width() + self.cipher.as_ref().map_or(0, |c| c.overhead()) + len
+    }
+
+    /// Bytes in front of every payload: the length prefix and, if any, the checksum.
+    pub fn frame_header_width(&self) -> u64 {
+        if self.version >= VERSION_CRC {
+            LEN_WIDTH + CRC_WIDTH
+        } else {
+            LEN_WIDTH
+        }
     }
 }
 
+/// Appends the frame for payload `p` in the layout of store `version` to `out`.
+pub(crate) fn encode_frame(p: &[u8], version: u32, out: &mut Vec<u8>) {
+    let b = (p.len() as u64).to_le_bytes() as [u8; LEN_WIDTH as usize];
+    out.extend_from_slice(&b);
+    if version >= VERSION_CRC {
+        let crc = crc32c::crc32c_append(crc32c::crc32c(&b), p);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    out.extend_from_slice(p);
+}
+
+impl Header {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut b = Vec::with_capacity(self.width as usize);
+        b.extend_from_slice(&MAGIC);
+        b.extend_from_slice(&self.version.to_le_bytes());
+        if let Some((cipher, key_id)) = &self.key {
+            let len = u8::try_from(key_id.len()).map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("key id {:?} is too long", key_id),
+                )
+            })?;
+            b.push(cipher.id());
+            b.push(len);
+            b.extend_from_slice(key_id.as_bytes());
+        }
+        Ok(b)
+    }
+}
+
+/// Reads the header of a non-empty store, or `None` if it was torn while being
+/// written. Legacy stores have no header and start straight with a frame
+/// length, which can never collide with [`MAGIC`] for any realistically sized
+/// record.
+fn read_header(file: &File, size: u64) -> io::Result<Option<Header>> {
+    if size < HEADER_WIDTH {
+        return Ok(None);
+    }
+    let mut header = [0u8; HEADER_WIDTH as usize];
+    file.read_exact_at(&mut header, 0)?;
+    if header[..MAGIC.len()] != MAGIC {
+        return Ok(Some(Header {
+            version: VERSION_LEGACY,
+            key: None,
+            width: 0,
+        }));
+    }
+    let mut v = [0u8; VERSION_WIDTH];
+    v.copy_from_slice(&header[MAGIC.len()..]);
+    let version = u32::from_le_bytes(v);
+    if version > VERSION_ENCRYPTED {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported store version {}", version),
+        ));
+    }
+    if version < VERSION_ENCRYPTED {
+        return Ok(Some(Header {
+            version,
+            key: None,
+            width: HEADER_WIDTH,
+        }));
+    }
+
+    let mut b = [0u8; 2];
+    if size < HEADER_WIDTH + 2 {
+        return Ok(None);
+    }
+    file.read_exact_at(&mut b, HEADER_WIDTH)?;
+    let width = HEADER_WIDTH + 2 + b[1] as u64 + encryption::OVERHEAD;
+    if size < width {
+        return Ok(None);
+    }
+    let cipher = Cipher::from_id(b[0]).ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidData, format!("unknown cipher {}", b[0]))
+    })?;
+    let mut key_id = vec![0u8; b[1] as usize];
+    file.read_exact_at(&mut key_id, HEADER_WIDTH + 2)?;
+    let key_id = String::from_utf8(key_id)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "key id is not UTF-8"))?;
+    Ok(Some(Header {
+        version,
+        key: Some((cipher, key_id)),
+        width,
+    }))
+}
+
 impl Drop for Store {
     fn drop(&mut self) {
         self.close().expect("failed to close")
     }
 }
 
-pub(crate) struct StoreReader<'a> {
-    pub(crate) store: &'a Store,
+/// Streams the raw bytes of a store from position `start` on; stream
+/// positions count from `start`.
+pub(crate) struct StoreReader {
+    pub(crate) store: Arc<Store>,
+    pub(crate) start: u64,
     pub(crate) off: u64,
 }
 
-impl<'a> Read for StoreReader<'a> {
+impl StoreReader {
+    pub fn new(store: Arc<Store>, start: u64) -> Self {
+        StoreReader {
+            store,
+            start,
+            off: start,
+        }
+    }
+}
+
+impl Read for StoreReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let n = self.store.read_at(buf, self.off)?;
         self.off += n as u64;
@@ -96,10 +437,29 @@ impl<'a> Read for StoreReader<'a> {
     }
 }
 
+impl Seek for StoreReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.store.size() - self.start;
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => (self.off - self.start).checked_add_signed(d),
+            SeekFrom::End(d) => len.checked_add_signed(d),
+        };
+        match target {
+            Some(n) if n <= len => {
+                self.off = self.start + n;
+                Ok(n)
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("seek outside the {} bytes of the store", len),
+            )),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::VecDeque;
-
     use tempfile::tempfile;
 
     use crate::multi_reader::MultiReader;
@@ -109,12 +469,12 @@ mod tests {
     #[test]
     fn test_store() {
         let file = tempfile().unwrap();
-        let mut store = Store::new(file).unwrap();
+        let store = Store::new(file).unwrap();
         let r = store.append(&[1, 2, 3]);
         assert!(r.is_ok());
         let r = r.unwrap();
-        assert_eq!(r.0, 11);
-        assert_eq!(r.1, 0);
+        assert_eq!(r.0, 15);
+        assert_eq!(r.1, HEADER_WIDTH);
 
         let read = store.read(r.1).unwrap();
         assert_eq!(&read, &[1, 2, 3]);
@@ -125,59 +485,85 @@ mod tests {
         assert_eq!(width, 3);
     }
 
+    #[test]
+    fn checksum_mismatch() {
+        let file = tempfile().unwrap();
+        let store = Store::new(file.try_clone().unwrap()).unwrap();
+        let (_, pos) = store.append(&[1, 2, 3]).unwrap();
+        store.close().unwrap();
+        file.write_all_at(&[9], pos + LEN_WIDTH + CRC_WIDTH + 1)
+            .unwrap();
+
+        let err = store.read(pos).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn legacy_store() {
+        let file = tempfile().unwrap();
+        (&file).write_all(&3u64.to_le_bytes()).unwrap();
+        (&file).write_all(&[1, 2, 3]).unwrap();
+
+        let store = Store::new(file).unwrap();
+        assert_eq!(store.version(), VERSION_LEGACY);
+        assert_eq!(store.read(0).unwrap(), vec![1, 2, 3]);
+        let (w, pos) = store.append(&[4, 5]).unwrap();
+        assert_eq!((w, pos), (10, 11));
+        assert_eq!(store.read(pos).unwrap(), vec![4, 5]);
+    }
+
     #[test]
     fn store_reader() {
         let f1 = tempfile().unwrap();
-        let mut store1 = Store::new(f1).unwrap();
+        let store1 = Arc::new(Store::new(f1).unwrap());
         store1.append(&[1, 1, 1, 1]).expect("");
         store1.append(&[2, 2, 2, 2]).expect("");
-        let mut sr1 = StoreReader {
-            store: &store1,
-            off: 0,
-        };
+        let start = store1.data_start();
+        let mut sr1 = StoreReader::new(store1, start);
 
-        let mut buf = [0u8; 12];
+        let mut buf = [0u8; 16];
         let n1 = sr1.read(&mut buf).expect("");
-        assert_eq!(n1, 12);
+        assert_eq!(n1, 16);
         let n1 = sr1.read(&mut buf).expect("");
-        assert_eq!(n1, 12);
+        assert_eq!(n1, 16);
         let n1 = sr1.read(&mut buf).expect("");
         assert_eq!(n1, 0);
+
+        assert_eq!(sr1.seek(SeekFrom::End(-4)).unwrap(), 28);
+        assert_eq!(sr1.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[2, 2, 2, 2]);
+        assert_eq!(sr1.seek(SeekFrom::Current(-20)).unwrap(), 12);
+        assert_eq!(sr1.read(&mut buf).unwrap(), 16);
+        assert!(sr1.seek(SeekFrom::Start(33)).is_err());
     }
 
     #[test]
     fn multi_store_reader() {
         let f1 = tempfile().unwrap();
-        let mut store1 = Store::new(f1).unwrap();
+        let store1 = Arc::new(Store::new(f1).unwrap());
         store1.append(&[1, 1, 1, 1]).expect("");
 
         let f2 = tempfile().unwrap();
-        let mut store2 = Store::new(f2).unwrap();
+        let store2 = Arc::new(Store::new(f2).unwrap());
         store2.append(&[2, 2, 2, 2]).expect("");
 
-        let sr1 = StoreReader {
-            store: &store1,
-            off: 0,
-        };
-        let sr2 = StoreReader {
-            store: &store2,
-            off: 0,
-        };
+        let start = store1.data_start();
+        let sr1 = StoreReader::new(store1, start);
+        let sr2 = StoreReader::new(store2, start);
 
-        let mut mr = MultiReader {
-            inner: VecDeque::new(),
-        };
+        let mut mr = MultiReader::default();
         mr.inner.push_back(sr1);
         mr.inner.push_back(sr2);
 
-        let mut b = [0u8; (8 + 4)];
+        let mut b = [0u8; (8 + 4 + 4)];
         for i in 0..3 {
             let n = mr.read(&mut b).expect("");
             if i == 0 {
-                assert_eq!(n, 12);
+                assert_eq!(n, 16);
             }
             if i == 1 {
-                assert_eq!(n, 12);
+                assert_eq!(n, 16);
             }
             if i == 2 {
                 assert_eq!(n, 0);
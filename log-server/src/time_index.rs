@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::encryption::{FrameCipher, Sealed};
+use crate::entry_file::{EntryFile, Format};
+
+const TS_WIDTH: usize = 8;
+const OFF_WIDTH: usize = 4;
+pub(crate) const ENTRY_WIDTH: usize = TS_WIDTH + OFF_WIDTH;
+
+/// Entries of `ts i64 | rel_offset u32`.
+static FORMAT: Format = Format {
+    magic: *b"DTIX",
+    width: ENTRY_WIDTH,
+    sealed: Sealed::TimeIndexEntry,
+    name: "time index",
+};
+
+/// Sparse map from timestamps to relative offsets. Timestamps strictly
+/// increase from one entry to the next; an entry `(ts, off)` means the record
+/// at `off` is the first one in the segment whose timestamp reached `ts`.
+/// `max_time_index_bytes` bounds the entries.
+pub(crate) struct TimeIndex {
+    entries: EntryFile,
+    /// [`PathBuf`] of the file
+    pub(crate) file_path: Option<PathBuf>,
+}
+
+impl TimeIndex {
+    /// Opens a time index, encrypted if `cipher` is given, see
+    /// [`EntryFile::open`]. One written before the header existed is emptied
+    /// like any other without a matching header.
+    pub fn new(file: File, config: &Config, cipher: Option<Arc<FrameCipher>>) -> io::Result<Self> {
+        let max = config.segment.max_time_index_bytes;
+        Ok(TimeIndex {
+            entries: EntryFile::open(file, &FORMAT, max, cipher)?,
+            file_path: None,
+        })
+    }
+
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.file_path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn write(&mut self, ts: i64, off: u32) -> io::Result<()> {
+        let mut entry = [0u8; ENTRY_WIDTH];
+        entry[..TS_WIDTH].copy_from_slice(&ts.to_le_bytes());
+        entry[TS_WIDTH..].copy_from_slice(&off.to_le_bytes());
+        self.entries.append(&entry)
+    }
+
+    /// Reads the `n`th entry.
+    pub fn read(&self, n: u64) -> (i64, u32) {
+        let entry = self.entries.entry(n);
+        let mut ts = [0u8; TS_WIDTH];
+        ts.copy_from_slice(&entry[..TS_WIDTH]);
+        let mut off = [0u8; OFF_WIDTH];
+        off.copy_from_slice(&entry[TS_WIDTH..]);
+        (i64::from_le_bytes(ts), u32::from_le_bytes(off))
+    }
+    pub fn last(&self) -> Option<(i64, u32)> {
+        let n = self.len();
+        if n == 0 {
+            None
+        } else {
+            Some(self.read(n - 1))
+        }
+    }
+
+    /// Returns the last entry whose timestamp is below `ts`.
+    pub fn lookup_before(&self, ts: i64) -> Option<(i64, u32)> {
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.read(mid).0 < ts {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            None
+        } else {
+            Some(self.read(lo - 1))
+        }
+    }
+
+    /// Keeps the longest prefix of entries that increase in both timestamp and
+    /// offset and point below relative offset `end`, dropping the rest.
+    /// Returns the bytes dropped from the file.
+    pub fn recover(&mut self, end: u64) -> io::Result<u64> {
+        let mut valid = 0;
+        let mut prev: Option<(i64, u32)> = None;
+        while valid < self.len() {
+            let (ts, off) = self.read(valid);
+            let ordered = prev.is_none_or(|(pts, poff)| ts > pts && off > poff);
+            if ts <= 0 || off as u64 >= end || !ordered {
+                break;
+            }
+            prev = Some((ts, off));
+            valid += 1;
+        }
+        let before = self.size();
+        self.truncate(valid)?;
+        Ok(before - self.size())
+    }
+
+    pub fn truncate(&mut self, n: u64) -> io::Result<()> {
+        self.entries.truncate(n)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entries.len()
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.entries.sync()
+    }
+
+    pub fn entry_width(&self) -> u64 {
+        self.entries.entry_width()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.entries.size()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.entries.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use tempfile::tempfile;
+
+    use crate::entry_file::{CURRENT_VERSION, HEADER_WIDTH};
+
+    use super::*;
+
+    #[test]
+    fn test_time_index() {
+        let mut config = Config::default();
+        config.segment.max_time_index_bytes = 1024;
+        let mut index = TimeIndex::new(tempfile().unwrap(), &config, None).unwrap();
+        assert_eq!(index.last(), None);
+
+        for (ts, off) in [(100, 0), (200, 3), (300, 7)] {
+            index.write(ts, off).unwrap();
+        }
+        assert_eq!(index.last(), Some((300, 7)));
+        assert_eq!(index.lookup_before(100), None);
+        assert_eq!(index.lookup_before(101), Some((100, 0)));
+        assert_eq!(index.lookup_before(300), Some((200, 3)));
+        assert_eq!(index.lookup_before(1000), Some((300, 7)));
+
+        // only the first two entries point at records that still exist
+        assert_eq!(index.recover(5).unwrap(), ENTRY_WIDTH as u64);
+        assert_eq!(index.last(), Some((200, 3)));
+    }
+
+    #[test]
+    fn test_headerless() {
+        let file = tempfile().unwrap();
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&100i64.to_le_bytes());
+        legacy.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all_at(&legacy, 0).unwrap();
+        let mut config = Config::default();
+        config.segment.max_time_index_bytes = 1024;
+        let index = TimeIndex::new(file.try_clone().unwrap(), &config, None).unwrap();
+        assert_eq!(index.last(), None);
+        drop(index);
+        assert_eq!(file.metadata().unwrap().len(), HEADER_WIDTH as u64);
+        assert_eq!(FORMAT.header_version(&file).unwrap(), Some(CURRENT_VERSION));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, Result};
+use protos::log::v1::raft_client::RaftClient;
+use protos::log::v1::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    RequestVoteRequest, RequestVoteResponse,
+};
+use tonic::transport::{Channel, Endpoint};
+
+use crate::replication::{Node, ReplicatedLog};
+
+/// How a [`ReplicatedLog`] reaches the Raft service of its peers, by the
+/// addresses in [`RaftConfig::peers`](crate::RaftConfig::peers).
+#[tonic::async_trait]
+pub trait Transport: Send + Sync {
+    async fn request_vote(
+        &self,
+        addr: &str,
+        req: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse>;
+
+    async fn append_entries(
+        &self,
+        addr: &str,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse>;
+
+    async fn install_snapshot(
+        &self,
+        addr: &str,
+        req: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse>;
+}
+
+/// Talks to peers over gRPC, keeping one lazily connected channel per address.
+#[derive(Default)]
+pub struct GrpcTransport {
+    clients: Mutex<HashMap<String, RaftClient<Channel>>>,
+}
+
+impl GrpcTransport {
+    fn client(&self, addr: &str) -> Result<RaftClient<Channel>> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(addr) {
+            return Ok(client.clone());
+        }
+        let channel = Endpoint::from_shared(format!("http://{}", addr))?.connect_lazy();
+        let client = RaftClient::new(channel);
+        clients.insert(addr.to_owned(), client.clone());
+        Ok(client)
+    }
+}
+
+#[tonic::async_trait]
+impl Transport for GrpcTransport {
+    async fn request_vote(
+        &self,
+        addr: &str,
+        req: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        Ok(self.client(addr)?.request_vote(req).await?.into_inner())
+    }
+
+    async fn append_entries(
+        &self,
+        addr: &str,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        Ok(self.client(addr)?.append_entries(req).await?.into_inner())
+    }
+
+    async fn install_snapshot(
+        &self,
+        addr: &str,
+        req: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        Ok(self.client(addr)?.install_snapshot(req).await?.into_inner())
+    }
+}
+
+/// Connects the nodes of a cluster living in one process, for tests.
+/// Disconnecting an address fails every call to or from it until it is
+/// reconnected.
+#[derive(Clone, Default)]
+pub struct MemNetwork {
+    inner: Arc<Mutex<MemNetworkInner>>,
+}
+
+#[derive(Default)]
+struct MemNetworkInner {
+    nodes: HashMap<String, Weak<Node>>,
+    disconnected: HashSet<String>,
+}
+
+impl MemNetwork {
+    /// The transport of the node at `addr`.
+    pub fn transport(&self, addr: &str) -> Arc<dyn Transport> {
+        Arc::new(MemTransport {
+            network: self.clone(),
+            from: addr.to_owned(),
+        })
+    }
+
+    /// Makes `log` reachable at `addr`.
+    pub fn register(&self, addr: &str, log: &ReplicatedLog) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .nodes
+            .insert(addr.to_owned(), Arc::downgrade(log.node()));
+    }
+
+    pub fn disconnect(&self, addr: &str) {
+        self.inner
+            .lock()
+            .unwrap()
+            .disconnected
+            .insert(addr.to_owned());
+    }
+
+    pub fn reconnect(&self, addr: &str) {
+        self.inner.lock().unwrap().disconnected.remove(addr);
+    }
+
+    pub fn is_disconnected(&self, addr: &str) -> bool {
+        self.inner.lock().unwrap().disconnected.contains(addr)
+    }
+
+    fn node(&self, from: &str, to: &str) -> Result<Arc<Node>> {
+        let inner = self.inner.lock().unwrap();
+        if inner.disconnected.contains(from) || inner.disconnected.contains(to) {
+            return Err(anyhow!("{} cannot reach {}", from, to));
+        }
+        inner
+            .nodes
+            .get(to)
+            .and_then(Weak::upgrade)
+            .ok_or_else(|| anyhow!("no node at {}", to))
+    }
+}
+
+struct MemTransport {
+    network: MemNetwork,
+    from: String,
+}
+
+#[tonic::async_trait]
+impl Transport for MemTransport {
+    async fn request_vote(
+        &self,
+        addr: &str,
+        req: RequestVoteRequest,
+    ) -> Result<RequestVoteResponse> {
+        self.network
+            .node(&self.from, addr)?
+            .handle_request_vote(req)
+    }
+
+    async fn append_entries(
+        &self,
+        addr: &str,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        self.network
+            .node(&self.from, addr)?
+            .handle_append_entries(req)
+    }
+
+    async fn install_snapshot(
+        &self,
+        addr: &str,
+        req: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        self.network
+            .node(&self.from, addr)?
+            .handle_install_snapshot(req)
+    }
+}
@@ -4,4 +4,2006 @@ pub struct Record {
     pub value: ::prost::alloc::vec::Vec<u8>,
     #[prost(uint64, tag="2")]
     pub offset: u64,
+    #[prost(bytes="vec", optional, tag="3")]
+    pub key: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+    #[prost(message, repeated, tag="4")]
+    pub headers: ::prost::alloc::vec::Vec<Header>,
+    /// Milliseconds since the Unix epoch; who sets it depends on the log's
+    /// timestamp type.
+    #[prost(int64, tag="5")]
+    pub timestamp: i64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Header {
+    #[prost(string, tag="1")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(bytes="vec", tag="2")]
+    pub value: ::prost::alloc::vec::Vec<u8>,
+}
+/// Requests name a topic of the server's log manager, or leave it empty for
+/// the server's own log, which has a single partition 0.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProduceRequest {
+    #[prost(message, optional, tag="1")]
+    pub record: ::core::option::Option<Record>,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    /// Unset to partition by the record's key, or round-robin without one.
+    #[prost(uint32, optional, tag="3")]
+    pub partition: ::core::option::Option<u32>,
+    /// Makes the append idempotent, as a batch of one record; see
+    /// ProduceBatchRequest.
+    #[prost(uint64, tag="4")]
+    pub producer_id: u64,
+    #[prost(uint64, tag="5")]
+    pub sequence: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProduceResponse {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+    #[prost(uint32, tag="2")]
+    pub partition: u32,
+}
+/// Records of a batch get contiguous offsets and are written to one segment of
+/// one partition.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProduceBatchRequest {
+    #[prost(message, repeated, tag="1")]
+    pub records: ::prost::alloc::vec::Vec<Record>,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    /// Unset to partition by the first record's key, or round-robin without one.
+    #[prost(uint32, optional, tag="3")]
+    pub partition: ::core::option::Option<u32>,
+    /// An id from InitProducer makes the append idempotent. The producer numbers
+    /// its batches to each partition in order, from any start; a retry of the
+    /// last batch returns its offsets instead of appending it again. A producer
+    /// idle for long is forgotten and may start over at any sequence. Idempotent
+    /// appends to a topic need an explicit partition.
+    #[prost(uint64, tag="4")]
+    pub producer_id: u64,
+    #[prost(uint64, tag="5")]
+    pub sequence: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProduceBatchResponse {
+    #[prost(uint64, tag="1")]
+    pub first_offset: u64,
+    #[prost(uint64, tag="2")]
+    pub last_offset: u64,
+    #[prost(uint32, tag="3")]
+    pub partition: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConsumeRequest {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub partition: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConsumeResponse {
+    #[prost(message, optional, tag="1")]
+    pub record: ::core::option::Option<Record>,
+}
+/// Sent as the details of an OUT_OF_RANGE status.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OffsetOutOfRange {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+}
+/// Detail of the UNAVAILABLE status a follower answers produce requests with.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NotLeader {
+    /// Address of the leader, empty if the follower knows of none.
+    #[prost(string, tag="1")]
+    pub leader_addr: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetServersRequest {
+}
+/// The voters of a replicated log; empty for a log on a single server.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetServersResponse {
+    #[prost(message, repeated, tag="1")]
+    pub servers: ::prost::alloc::vec::Vec<Server>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Server {
+    #[prost(string, tag="1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub rpc_addr: ::prost::alloc::string::String,
+    #[prost(bool, tag="3")]
+    pub is_leader: bool,
+}
+/// Records the position of a consumer group in a partition.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CommitOffsetRequest {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub partition: u32,
+    /// The next offset the group will consume.
+    #[prost(uint64, tag="4")]
+    pub offset: u64,
+    /// The committing member and its generation; a commit from a member of an
+    /// earlier generation fails with ABORTED, and one for a partition not
+    /// assigned to the member with FAILED_PRECONDITION. Empty for a consumer
+    /// outside the group, which may only commit while the group has no members.
+    #[prost(string, tag="5")]
+    pub member_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="6")]
+    pub generation: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CommitOffsetResponse {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FetchCommittedOffsetRequest {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub partition: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FetchCommittedOffsetResponse {
+    /// Unset if the group never committed an offset for the partition.
+    #[prost(uint64, optional, tag="1")]
+    pub offset: ::core::option::Option<u64>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListGroupsRequest {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListGroupsResponse {
+    #[prost(string, repeated, tag="1")]
+    pub groups: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// Joins a consumer group, or rejoins it after a rebalance. A member joining,
+/// leaving, changing topics or missing its session timeout starts a new
+/// generation with the partitions assigned anew.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JoinGroupRequest {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    /// Empty for a new member, which gets an id in the response.
+    #[prost(string, tag="2")]
+    pub member_id: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub topics: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// The group's strategy is the one of its first member.
+    #[prost(enumeration="AssignmentStrategy", tag="4")]
+    pub strategy: i32,
+    /// How long the member may go without a heartbeat; 0 for the server's default.
+    #[prost(uint32, tag="5")]
+    pub session_timeout_ms: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JoinGroupResponse {
+    #[prost(string, tag="1")]
+    pub member_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub generation: u64,
+    #[prost(message, repeated, tag="3")]
+    pub assignment: ::prost::alloc::vec::Vec<TopicPartition>,
+    /// Members of the previous generation have not all rejoined yet, so the
+    /// assignment is empty; join again shortly with member_id.
+    #[prost(bool, tag="4")]
+    pub pending: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TopicPartition {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub partition: u32,
+}
+/// Keeps a member's session alive. Fails with ABORTED once the group moved to
+/// a new generation, telling the member to rejoin.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeartbeatRequest {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub member_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub generation: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeartbeatResponse {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LeaveGroupRequest {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub member_id: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LeaveGroupResponse {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InitProducerRequest {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InitProducerResponse {
+    #[prost(uint64, tag="1")]
+    pub producer_id: u64,
+}
+/// Creates a topic of the server's log manager. Settings left unset in config
+/// keep the server's.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateTopicRequest {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub config: ::core::option::Option<TopicMetadata>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateTopicResponse {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTopicsRequest {
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTopicsResponse {
+    /// Sorted by name.
+    #[prost(message, repeated, tag="1")]
+    pub topics: ::prost::alloc::vec::Vec<Topic>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Topic {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub config: ::core::option::Option<TopicMetadata>,
+}
+/// Deletes a topic with all its records.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteTopicRequest {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteTopicResponse {
+}
+/// The last batch of every idempotent producer of a log, kept in a file next
+/// to its segments as a checkpoint. Batches record their producer, so those
+/// from next_offset on are found in the log itself.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProducerSnapshot {
+    #[prost(message, repeated, tag="1")]
+    pub producers: ::prost::alloc::vec::Vec<ProducerBatch>,
+    /// The producers include every batch below this offset.
+    #[prost(uint64, tag="2")]
+    pub next_offset: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProducerBatch {
+    #[prost(uint64, tag="1")]
+    pub producer_id: u64,
+    #[prost(uint64, tag="2")]
+    pub sequence: u64,
+    #[prost(uint64, tag="3")]
+    pub first_offset: u64,
+    /// One past the batch's last offset.
+    #[prost(uint64, tag="4")]
+    pub next_offset: u64,
+    /// When the batch was appended, in milliseconds since the epoch.
+    #[prost(int64, tag="5")]
+    pub timestamp: i64,
+}
+/// Key of a record of the internal offsets log. Compaction keeps the latest
+/// commit per key.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OffsetCommitKey {
+    #[prost(string, tag="1")]
+    pub group: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub partition: u32,
+}
+/// Value of a record of the internal offsets log.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OffsetCommitValue {
+    /// Optional so that offset 0 is still encoded: an empty value would be a
+    /// tombstone.
+    #[prost(uint64, optional, tag="1")]
+    pub offset: ::core::option::Option<u64>,
+}
+/// An entry of the Raft log. The leader assigns the records their offsets and
+/// timestamps, so every node applies them identically. A new leader appends an
+/// entry without records to commit the entries of earlier terms.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(message, repeated, tag="2")]
+    pub records: ::prost::alloc::vec::Vec<Record>,
+    /// When set, the voters from this entry on, replacing the previous ones.
+    #[prost(message, optional, tag="3")]
+    pub config: ::core::option::Option<RaftConfiguration>,
+    /// The idempotent producer of the records and its sequence, if any.
+    #[prost(uint64, tag="4")]
+    pub producer_id: u64,
+    #[prost(uint64, tag="5")]
+    pub sequence: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftConfiguration {
+    #[prost(message, repeated, tag="1")]
+    pub peers: ::prost::alloc::vec::Vec<RaftPeer>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftPeer {
+    #[prost(string, tag="1")]
+    pub id: ::prost::alloc::string::String,
+    /// Address of the peer's Raft service.
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestVoteRequest {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(string, tag="2")]
+    pub candidate_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub last_log_index: u64,
+    #[prost(uint64, tag="4")]
+    pub last_log_term: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RequestVoteResponse {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(bool, tag="2")]
+    pub vote_granted: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendEntriesRequest {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(string, tag="2")]
+    pub leader_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub prev_log_index: u64,
+    #[prost(uint64, tag="4")]
+    pub prev_log_term: u64,
+    #[prost(message, repeated, tag="5")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag="6")]
+    pub leader_commit: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AppendEntriesResponse {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(bool, tag="2")]
+    pub success: bool,
+    /// On success the index of the last entry the follower now shares with the
+    /// leader; otherwise the highest index that may still match, for the leader
+    /// to retry after.
+    #[prost(uint64, tag="3")]
+    pub last_log_index: u64,
+}
+/// Where a node's Raft log starts once the entries up to last_index were
+/// trimmed. Their records live on in the node's log, below next_offset.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftSnapshot {
+    #[prost(uint64, tag="1")]
+    pub last_index: u64,
+    #[prost(uint64, tag="2")]
+    pub last_term: u64,
+    /// The voters as of last_index, if a configuration entry came before it.
+    #[prost(message, optional, tag="3")]
+    pub config: ::core::option::Option<RaftConfiguration>,
+    /// Offset after the last record of the entries up to last_index.
+    #[prost(uint64, tag="4")]
+    pub next_offset: u64,
+}
+/// Sent to a follower that needs entries the leader trimmed: the records of
+/// the leader's log from offset on, a chunk at a time, then the snapshot they
+/// end at.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallSnapshotRequest {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(string, tag="2")]
+    pub leader_id: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub snapshot: ::core::option::Option<RaftSnapshot>,
+    /// Where the follower's log has to end for records to apply; the records
+    /// themselves may start later, past records the leader no longer has.
+    #[prost(uint64, tag="4")]
+    pub offset: u64,
+    #[prost(message, repeated, tag="5")]
+    pub records: ::prost::alloc::vec::Vec<Record>,
+    /// Whether records reach snapshot.next_offset, so the follower installs it.
+    #[prost(bool, tag="6")]
+    pub done: bool,
+    /// The idempotent producer batches ending among records.
+    #[prost(message, repeated, tag="7")]
+    pub producers: ::prost::alloc::vec::Vec<ProducerBatch>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InstallSnapshotResponse {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    /// Offset the follower's log goes up to, for the next chunk to start at.
+    #[prost(uint64, tag="2")]
+    pub next_offset: u64,
+    /// Whether the follower has everything up to the snapshot.
+    #[prost(bool, tag="3")]
+    pub done: bool,
+}
+/// Raft state a node persists before answering any request.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HardState {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(string, tag="2")]
+    pub voted_for: ::prost::alloc::string::String,
+}
+/// Settings of a topic, kept in its directory. Unset fields keep the log
+/// manager's defaults.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TopicMetadata {
+    #[prost(uint32, tag="1")]
+    pub partitions: u32,
+    #[prost(uint64, optional, tag="2")]
+    pub max_store_bytes: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag="3")]
+    pub max_index_bytes: ::core::option::Option<u64>,
+    /// Codec id, as written in batch headers.
+    #[prost(uint32, optional, tag="4")]
+    pub compression: ::core::option::Option<u32>,
+    #[prost(bool, optional, tag="5")]
+    pub key_index: ::core::option::Option<bool>,
+    #[prost(uint64, optional, tag="6")]
+    pub retention_bytes: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag="7")]
+    pub retention_ms: ::core::option::Option<u64>,
+    #[prost(bool, optional, tag="8")]
+    pub compact: ::core::option::Option<bool>,
+}
+/// Membership gossip between the nodes of a cluster, sent as UDP datagrams
+/// following SWIM.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GossipMessage {
+    #[prost(enumeration="gossip_message::Kind", tag="1")]
+    pub kind: i32,
+    #[prost(uint64, tag="2")]
+    pub seq: u64,
+    /// For PING_REQ, the gossip address of the member to probe.
+    #[prost(string, tag="3")]
+    pub target: ::prost::alloc::string::String,
+    /// Updates piggybacked on the message, or the full list for PUSH_PULL.
+    #[prost(message, repeated, tag="4")]
+    pub members: ::prost::alloc::vec::Vec<GossipMember>,
+}
+/// Nested message and enum types in `GossipMessage`.
+pub mod gossip_message {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Kind {
+        /// Asks for an ACK with the same seq.
+        Ping = 0,
+        Ack = 1,
+        /// Asks the receiver to ping target and forward its ACK.
+        PingReq = 2,
+        /// Carries the sender's full member list and asks for the receiver's.
+        PushPull = 3,
+        /// Carries member updates and asks for nothing.
+        Push = 4,
+    }
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GossipMember {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub gossip_addr: ::prost::alloc::string::String,
+    /// Address of the member's gRPC services.
+    #[prost(string, tag="3")]
+    pub rpc_addr: ::prost::alloc::string::String,
+    #[prost(enumeration="gossip_member::Status", tag="4")]
+    pub status: i32,
+    /// Bumped by the member itself to refute suspicion, or when leaving.
+    #[prost(uint64, tag="5")]
+    pub incarnation: u64,
+}
+/// Nested message and enum types in `GossipMember`.
+pub mod gossip_member {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Status {
+        Alive = 0,
+        Suspect = 1,
+        Dead = 2,
+        Left = 3,
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AssignmentStrategy {
+    /// Each member gets a contiguous range of the partitions of every topic.
+    Range = 0,
+    /// The partitions of all topics are dealt to the members in turn.
+    RoundRobin = 1,
+}
+/// Generated client implementations.
+pub mod log_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[derive(Debug, Clone)]
+    pub struct LogClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl LogClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> LogClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> LogClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + Send + Sync,
+        {
+            LogClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with `gzip`.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        /// Enable decompressing responses with `gzip`.
+        #[must_use]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        pub async fn produce(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ProduceRequest>,
+        ) -> Result<tonic::Response<super::ProduceResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/Produce");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn produce_batch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ProduceBatchRequest>,
+        ) -> Result<tonic::Response<super::ProduceBatchResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/ProduceBatch");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn consume(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConsumeRequest>,
+        ) -> Result<tonic::Response<super::ConsumeResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/Consume");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn consume_stream(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConsumeRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::ConsumeResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/ConsumeStream");
+            self.inner.server_streaming(request.into_request(), path, codec).await
+        }
+        pub async fn produce_stream(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::ProduceRequest>,
+        ) -> Result<
+            tonic::Response<tonic::codec::Streaming<super::ProduceResponse>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/ProduceStream");
+            self.inner.streaming(request.into_streaming_request(), path, codec).await
+        }
+        pub async fn get_servers(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetServersRequest>,
+        ) -> Result<tonic::Response<super::GetServersResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/GetServers");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn commit_offset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CommitOffsetRequest>,
+        ) -> Result<tonic::Response<super::CommitOffsetResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/CommitOffset");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn fetch_committed_offset(
+            &mut self,
+            request: impl tonic::IntoRequest<super::FetchCommittedOffsetRequest>,
+        ) -> Result<
+            tonic::Response<super::FetchCommittedOffsetResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/log.v1.Log/FetchCommittedOffset",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn list_groups(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListGroupsRequest>,
+        ) -> Result<tonic::Response<super::ListGroupsResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/ListGroups");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn join_group(
+            &mut self,
+            request: impl tonic::IntoRequest<super::JoinGroupRequest>,
+        ) -> Result<tonic::Response<super::JoinGroupResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/JoinGroup");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn heartbeat(
+            &mut self,
+            request: impl tonic::IntoRequest<super::HeartbeatRequest>,
+        ) -> Result<tonic::Response<super::HeartbeatResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/Heartbeat");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn leave_group(
+            &mut self,
+            request: impl tonic::IntoRequest<super::LeaveGroupRequest>,
+        ) -> Result<tonic::Response<super::LeaveGroupResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/LeaveGroup");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn init_producer(
+            &mut self,
+            request: impl tonic::IntoRequest<super::InitProducerRequest>,
+        ) -> Result<tonic::Response<super::InitProducerResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/InitProducer");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn create_topic(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CreateTopicRequest>,
+        ) -> Result<tonic::Response<super::CreateTopicResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/CreateTopic");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn list_topics(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListTopicsRequest>,
+        ) -> Result<tonic::Response<super::ListTopicsResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/ListTopics");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn delete_topic(
+            &mut self,
+            request: impl tonic::IntoRequest<super::DeleteTopicRequest>,
+        ) -> Result<tonic::Response<super::DeleteTopicResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Log/DeleteTopic");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+    }
+}
+/// Generated client implementations.
+pub mod raft_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// Replication between the nodes of a cluster, following Raft.
+    #[derive(Debug, Clone)]
+    pub struct RaftClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl RaftClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> RaftClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> RaftClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + Send + Sync,
+        {
+            RaftClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with `gzip`.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        /// Enable decompressing responses with `gzip`.
+        #[must_use]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        pub async fn request_vote(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RequestVoteRequest>,
+        ) -> Result<tonic::Response<super::RequestVoteResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/log.v1.Raft/RequestVote");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn append_entries(
+            &mut self,
+            request: impl tonic::IntoRequest<super::AppendEntriesRequest>,
+        ) -> Result<tonic::Response<super::AppendEntriesResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/log.v1.Raft/AppendEntries",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn install_snapshot(
+            &mut self,
+            request: impl tonic::IntoRequest<super::InstallSnapshotRequest>,
+        ) -> Result<tonic::Response<super::InstallSnapshotResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/log.v1.Raft/InstallSnapshot",
+            );
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod log_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    ///Generated trait containing gRPC methods that should be implemented for use with LogServer.
+    #[async_trait]
+    pub trait Log: Send + Sync + 'static {
+        async fn produce(
+            &self,
+            request: tonic::Request<super::ProduceRequest>,
+        ) -> Result<tonic::Response<super::ProduceResponse>, tonic::Status>;
+        async fn produce_batch(
+            &self,
+            request: tonic::Request<super::ProduceBatchRequest>,
+        ) -> Result<tonic::Response<super::ProduceBatchResponse>, tonic::Status>;
+        async fn consume(
+            &self,
+            request: tonic::Request<super::ConsumeRequest>,
+        ) -> Result<tonic::Response<super::ConsumeResponse>, tonic::Status>;
+        ///Server streaming response type for the ConsumeStream method.
+        type ConsumeStreamStream: futures_core::Stream<
+                Item = Result<super::ConsumeResponse, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        async fn consume_stream(
+            &self,
+            request: tonic::Request<super::ConsumeRequest>,
+        ) -> Result<tonic::Response<Self::ConsumeStreamStream>, tonic::Status>;
+        ///Server streaming response type for the ProduceStream method.
+        type ProduceStreamStream: futures_core::Stream<
+                Item = Result<super::ProduceResponse, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        async fn produce_stream(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::ProduceRequest>>,
+        ) -> Result<tonic::Response<Self::ProduceStreamStream>, tonic::Status>;
+        async fn get_servers(
+            &self,
+            request: tonic::Request<super::GetServersRequest>,
+        ) -> Result<tonic::Response<super::GetServersResponse>, tonic::Status>;
+        async fn commit_offset(
+            &self,
+            request: tonic::Request<super::CommitOffsetRequest>,
+        ) -> Result<tonic::Response<super::CommitOffsetResponse>, tonic::Status>;
+        async fn fetch_committed_offset(
+            &self,
+            request: tonic::Request<super::FetchCommittedOffsetRequest>,
+        ) -> Result<tonic::Response<super::FetchCommittedOffsetResponse>, tonic::Status>;
+        async fn list_groups(
+            &self,
+            request: tonic::Request<super::ListGroupsRequest>,
+        ) -> Result<tonic::Response<super::ListGroupsResponse>, tonic::Status>;
+        async fn join_group(
+            &self,
+            request: tonic::Request<super::JoinGroupRequest>,
+        ) -> Result<tonic::Response<super::JoinGroupResponse>, tonic::Status>;
+        async fn heartbeat(
+            &self,
+            request: tonic::Request<super::HeartbeatRequest>,
+        ) -> Result<tonic::Response<super::HeartbeatResponse>, tonic::Status>;
+        async fn leave_group(
+            &self,
+            request: tonic::Request<super::LeaveGroupRequest>,
+        ) -> Result<tonic::Response<super::LeaveGroupResponse>, tonic::Status>;
+        async fn init_producer(
+            &self,
+            request: tonic::Request<super::InitProducerRequest>,
+        ) -> Result<tonic::Response<super::InitProducerResponse>, tonic::Status>;
+        async fn create_topic(
+            &self,
+            request: tonic::Request<super::CreateTopicRequest>,
+        ) -> Result<tonic::Response<super::CreateTopicResponse>, tonic::Status>;
+        async fn list_topics(
+            &self,
+            request: tonic::Request<super::ListTopicsRequest>,
+        ) -> Result<tonic::Response<super::ListTopicsResponse>, tonic::Status>;
+        async fn delete_topic(
+            &self,
+            request: tonic::Request<super::DeleteTopicRequest>,
+        ) -> Result<tonic::Response<super::DeleteTopicResponse>, tonic::Status>;
+    }
+    #[derive(Debug)]
+    pub struct LogServer<T: Log> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: Log> LogServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for LogServer<T>
+    where
+        T: Log,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/log.v1.Log/Produce" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProduceSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::ProduceRequest>
+                    for ProduceSvc<T> {
+                        type Response = super::ProduceResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ProduceRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).produce(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ProduceSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/ProduceBatch" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProduceBatchSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::ProduceBatchRequest>
+                    for ProduceBatchSvc<T> {
+                        type Response = super::ProduceBatchResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ProduceBatchRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).produce_batch(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ProduceBatchSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/Consume" => {
+                    #[allow(non_camel_case_types)]
+                    struct ConsumeSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::ConsumeRequest>
+                    for ConsumeSvc<T> {
+                        type Response = super::ConsumeResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConsumeRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).consume(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ConsumeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/ConsumeStream" => {
+                    #[allow(non_camel_case_types)]
+                    struct ConsumeStreamSvc<T: Log>(pub Arc<T>);
+                    impl<
+                        T: Log,
+                    > tonic::server::ServerStreamingService<super::ConsumeRequest>
+                    for ConsumeStreamSvc<T> {
+                        type Response = super::ConsumeResponse;
+                        type ResponseStream = T::ConsumeStreamStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConsumeRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).consume_stream(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ConsumeStreamSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/ProduceStream" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProduceStreamSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::StreamingService<super::ProduceRequest>
+                    for ProduceStreamSvc<T> {
+                        type Response = super::ProduceResponse;
+                        type ResponseStream = T::ProduceStreamStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                tonic::Streaming<super::ProduceRequest>,
+                            >,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).produce_stream(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ProduceStreamSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/GetServers" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetServersSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::GetServersRequest>
+                    for GetServersSvc<T> {
+                        type Response = super::GetServersResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetServersRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).get_servers(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetServersSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/CommitOffset" => {
+                    #[allow(non_camel_case_types)]
+                    struct CommitOffsetSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::CommitOffsetRequest>
+                    for CommitOffsetSvc<T> {
+                        type Response = super::CommitOffsetResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CommitOffsetRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).commit_offset(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CommitOffsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/FetchCommittedOffset" => {
+                    #[allow(non_camel_case_types)]
+                    struct FetchCommittedOffsetSvc<T: Log>(pub Arc<T>);
+                    impl<
+                        T: Log,
+                    > tonic::server::UnaryService<super::FetchCommittedOffsetRequest>
+                    for FetchCommittedOffsetSvc<T> {
+                        type Response = super::FetchCommittedOffsetResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::FetchCommittedOffsetRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).fetch_committed_offset(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = FetchCommittedOffsetSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/ListGroups" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListGroupsSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::ListGroupsRequest>
+                    for ListGroupsSvc<T> {
+                        type Response = super::ListGroupsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListGroupsRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).list_groups(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListGroupsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/JoinGroup" => {
+                    #[allow(non_camel_case_types)]
+                    struct JoinGroupSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::JoinGroupRequest>
+                    for JoinGroupSvc<T> {
+                        type Response = super::JoinGroupResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::JoinGroupRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).join_group(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = JoinGroupSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/Heartbeat" => {
+                    #[allow(non_camel_case_types)]
+                    struct HeartbeatSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::HeartbeatRequest>
+                    for HeartbeatSvc<T> {
+                        type Response = super::HeartbeatResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::HeartbeatRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).heartbeat(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HeartbeatSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/LeaveGroup" => {
+                    #[allow(non_camel_case_types)]
+                    struct LeaveGroupSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::LeaveGroupRequest>
+                    for LeaveGroupSvc<T> {
+                        type Response = super::LeaveGroupResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::LeaveGroupRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).leave_group(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = LeaveGroupSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/InitProducer" => {
+                    #[allow(non_camel_case_types)]
+                    struct InitProducerSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::InitProducerRequest>
+                    for InitProducerSvc<T> {
+                        type Response = super::InitProducerResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::InitProducerRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).init_producer(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = InitProducerSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/CreateTopic" => {
+                    #[allow(non_camel_case_types)]
+                    struct CreateTopicSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::CreateTopicRequest>
+                    for CreateTopicSvc<T> {
+                        type Response = super::CreateTopicResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CreateTopicRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).create_topic(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CreateTopicSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/ListTopics" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListTopicsSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::ListTopicsRequest>
+                    for ListTopicsSvc<T> {
+                        type Response = super::ListTopicsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListTopicsRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).list_topics(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListTopicsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Log/DeleteTopic" => {
+                    #[allow(non_camel_case_types)]
+                    struct DeleteTopicSvc<T: Log>(pub Arc<T>);
+                    impl<T: Log> tonic::server::UnaryService<super::DeleteTopicRequest>
+                    for DeleteTopicSvc<T> {
+                        type Response = super::DeleteTopicResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::DeleteTopicRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).delete_topic(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = DeleteTopicSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        Ok(
+                            http::Response::builder()
+                                .status(200)
+                                .header("grpc-status", "12")
+                                .header("content-type", "application/grpc")
+                                .body(empty_body())
+                                .unwrap(),
+                        )
+                    })
+                }
+            }
+        }
+    }
+    impl<T: Log> Clone for LogServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: Log> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: Log> tonic::transport::NamedService for LogServer<T> {
+        const NAME: &'static str = "log.v1.Log";
+    }
+}
+/// Generated server implementations.
+pub mod raft_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    ///Generated trait containing gRPC methods that should be implemented for use with RaftServer.
+    #[async_trait]
+    pub trait Raft: Send + Sync + 'static {
+        async fn request_vote(
+            &self,
+            request: tonic::Request<super::RequestVoteRequest>,
+        ) -> Result<tonic::Response<super::RequestVoteResponse>, tonic::Status>;
+        async fn append_entries(
+            &self,
+            request: tonic::Request<super::AppendEntriesRequest>,
+        ) -> Result<tonic::Response<super::AppendEntriesResponse>, tonic::Status>;
+        async fn install_snapshot(
+            &self,
+            request: tonic::Request<super::InstallSnapshotRequest>,
+        ) -> Result<tonic::Response<super::InstallSnapshotResponse>, tonic::Status>;
+    }
+    /// Replication between the nodes of a cluster, following Raft.
+    #[derive(Debug)]
+    pub struct RaftServer<T: Raft> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: Raft> RaftServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for RaftServer<T>
+    where
+        T: Raft,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/log.v1.Raft/RequestVote" => {
+                    #[allow(non_camel_case_types)]
+                    struct RequestVoteSvc<T: Raft>(pub Arc<T>);
+                    impl<T: Raft> tonic::server::UnaryService<super::RequestVoteRequest>
+                    for RequestVoteSvc<T> {
+                        type Response = super::RequestVoteResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RequestVoteRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).request_vote(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RequestVoteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Raft/AppendEntries" => {
+                    #[allow(non_camel_case_types)]
+                    struct AppendEntriesSvc<T: Raft>(pub Arc<T>);
+                    impl<
+                        T: Raft,
+                    > tonic::server::UnaryService<super::AppendEntriesRequest>
+                    for AppendEntriesSvc<T> {
+                        type Response = super::AppendEntriesResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::AppendEntriesRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).append_entries(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = AppendEntriesSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/log.v1.Raft/InstallSnapshot" => {
+                    #[allow(non_camel_case_types)]
+                    struct InstallSnapshotSvc<T: Raft>(pub Arc<T>);
+                    impl<
+                        T: Raft,
+                    > tonic::server::UnaryService<super::InstallSnapshotRequest>
+                    for InstallSnapshotSvc<T> {
+                        type Response = super::InstallSnapshotResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::InstallSnapshotRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move {
+                                (*inner).install_snapshot(request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = InstallSnapshotSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        Ok(
+                            http::Response::builder()
+                                .status(200)
+                                .header("grpc-status", "12")
+                                .header("content-type", "application/grpc")
+                                .body(empty_body())
+                                .unwrap(),
+                        )
+                    })
+                }
+            }
+        }
+    }
+    impl<T: Raft> Clone for RaftServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: Raft> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: Raft> tonic::transport::NamedService for RaftServer<T> {
+        const NAME: &'static str = "log.v1.Raft";
+    }
 }